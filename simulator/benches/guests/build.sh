@@ -0,0 +1,12 @@
+#!/bin/sh
+# Rebuilds the benchmark guests. Needs llvm-mc with the RISC-V target and
+# python3, see tests/guests for the linker.
+set -e
+cd "$(dirname "$0")"
+LLVM_MC=${LLVM_MC:-llvm-mc}
+for src in *.s; do
+    name=${src%.s}
+    $LLVM_MC -triple=riscv32 -mattr=-relax -filetype=obj "$src" -o "$name.o"
+    python3 ../../tests/guests/link.py -o "$name.elf" "$name.o"
+    rm "$name.o"
+done
@@ -0,0 +1,68 @@
+# Allocation heavy workload: thousands of small live allocations that are
+# walked over and over, then freed. Prints a checksum and exits with 0.
+
+    .equ    COUNT, 4000
+    .equ    PASSES, 50
+    .equ    SIZE, 16
+
+    .text
+    .globl  _start
+_start:
+    la      s1, ptrs
+    li      s0, 0
+1:  li      a0, SIZE
+    li      a7, 503             # malloc
+    ecall
+    beqz    a0, fail
+    slli    t0, s0, 2
+    add     t0, s1, t0
+    sw      a0, 0(t0)
+    sw      s0, 0(a0)
+    sw      s0, 12(a0)
+    addi    s0, s0, 1
+    li      t1, COUNT
+    blt     s0, t1, 1b
+
+    li      s2, 0               # checksum
+    li      s3, PASSES
+2:  mv      t0, s1
+    li      t2, COUNT
+3:  lw      t3, 0(t0)
+    lw      t4, 0(t3)
+    lw      t5, 12(t3)
+    add     s2, s2, t4
+    xor     s2, s2, t5
+    addi    t4, t4, 1
+    sw      t4, 0(t3)
+    addi    t0, t0, 4
+    addi    t2, t2, -1
+    bnez    t2, 3b
+    addi    s3, s3, -1
+    bnez    s3, 2b
+
+    li      s0, 0
+4:  slli    t0, s0, 2
+    add     t0, s1, t0
+    lw      a0, 0(t0)
+    li      a7, 504             # free
+    ecall
+    bnez    a0, fail
+    addi    s0, s0, 1
+    li      t1, COUNT
+    blt     s0, t1, 4b
+
+    mv      a0, s2
+    li      a7, 501             # print_int
+    ecall
+    li      a0, 0
+    li      a7, 500             # exit
+    ecall
+
+fail:
+    li      a0, 1
+    li      a7, 500
+    ecall
+
+    .data
+ptrs:
+    .fill   COUNT, 4, 0
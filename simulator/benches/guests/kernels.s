@@ -0,0 +1,237 @@
+# CoreMark style workload for benchmarking the interpreter: linked list
+# building, reversal and walking, an 8x8 matrix multiply through a software
+# multiply, bitwise CRC-16 and a small parsing state machine. Prints a
+# checksum and exits with 0.
+
+    .equ    ITERATIONS, 1000
+    .equ    LIST_LEN, 64
+    .equ    MAT_N, 8
+    .equ    CRC_LEN, 256
+
+    .text
+    .globl  _start
+_start:
+    li      s0, ITERATIONS
+    li      s1, 0               # checksum
+1:  call    list_bench
+    add     s1, s1, a0
+    call    matrix_bench
+    xor     s1, s1, a0
+    call    crc_bench
+    add     s1, s1, a0
+    call    state_bench
+    xor     s1, s1, a0
+    slli    t0, s1, 7           # rotate so the kernels can't cancel out
+    srli    s1, s1, 25
+    or      s1, s1, t0
+    addi    s0, s0, -1
+    bnez    s0, 1b
+
+    mv      a0, s1
+    li      a7, 501
+    ecall
+    li      a0, 0
+    li      a7, 500
+    ecall
+
+# Links LIST_LEN nodes of {next, value}, reverses the list and folds the
+# values in list order
+list_bench:
+    la      t0, nodes
+    li      t1, LIST_LEN - 1
+    li      t2, 0
+    mv      t3, t0
+1:  addi    t4, t3, 8
+    sw      t4, 0(t3)
+    sw      t2, 4(t3)
+    addi    t2, t2, 37
+    andi    t2, t2, 0xff
+    mv      t3, t4
+    addi    t1, t1, -1
+    bnez    t1, 1b
+    sw      zero, 0(t3)
+    sw      t2, 4(t3)
+
+    li      t1, 0               # previous
+    mv      t3, t0              # current
+2:  lw      t4, 0(t3)
+    sw      t1, 0(t3)
+    mv      t1, t3
+    mv      t3, t4
+    bnez    t3, 2b
+
+    li      a0, 0
+3:  lw      t2, 4(t1)
+    slli    t6, a0, 5           # a0 = a0 * 31 + value
+    sub     a0, t6, a0
+    add     a0, a0, t2
+    lw      t1, 0(t1)
+    bnez    t1, 3b
+    ret
+
+# a0 * a1 without the M extension
+mul:
+    li      t0, 0
+1:  andi    t1, a1, 1
+    beqz    t1, 2f
+    add     t0, t0, a0
+2:  slli    a0, a0, 1
+    srli    a1, a1, 1
+    bnez    a1, 1b
+    mv      a0, t0
+    ret
+
+# C = A * B with A[i][j] = i + j and B[i][j] = i - j + 3, returns the sum
+# of C
+matrix_bench:
+    addi    sp, sp, -32
+    sw      ra, 28(sp)
+    sw      s2, 24(sp)
+    sw      s3, 20(sp)
+    sw      s4, 16(sp)
+    sw      s5, 12(sp)
+    sw      s6, 8(sp)
+
+    la      t0, mat_a
+    la      t1, mat_b
+    li      t5, MAT_N
+    li      t2, 0
+1:  li      t3, 0
+2:  add     t4, t2, t3
+    sw      t4, 0(t0)
+    sub     t4, t2, t3
+    addi    t4, t4, 3
+    sw      t4, 0(t1)
+    addi    t0, t0, 4
+    addi    t1, t1, 4
+    addi    t3, t3, 1
+    blt     t3, t5, 2b
+    addi    t2, t2, 1
+    blt     t2, t5, 1b
+
+    li      s6, 0               # sum
+    li      s2, 0               # i
+3:  li      s3, 0               # j
+4:  li      s4, 0               # k
+    li      s5, 0               # C[i][j]
+5:  slli    t0, s2, 3
+    add     t0, t0, s4
+    slli    t0, t0, 2
+    la      t1, mat_a
+    add     t1, t1, t0
+    lw      a0, 0(t1)
+    slli    t0, s4, 3
+    add     t0, t0, s3
+    slli    t0, t0, 2
+    la      t1, mat_b
+    add     t1, t1, t0
+    lw      a1, 0(t1)
+    call    mul
+    add     s5, s5, a0
+    addi    s4, s4, 1
+    li      t0, MAT_N
+    blt     s4, t0, 5b
+
+    slli    t0, s2, 3
+    add     t0, t0, s3
+    slli    t0, t0, 2
+    la      t1, mat_c
+    add     t1, t1, t0
+    sw      s5, 0(t1)
+    add     s6, s6, s5
+    addi    s3, s3, 1
+    li      t0, MAT_N
+    blt     s3, t0, 4b
+    addi    s2, s2, 1
+    blt     s2, t0, 3b
+
+    mv      a0, s6
+    lw      ra, 28(sp)
+    lw      s2, 24(sp)
+    lw      s3, 20(sp)
+    lw      s4, 16(sp)
+    lw      s5, 12(sp)
+    lw      s6, 8(sp)
+    addi    sp, sp, 32
+    ret
+
+# CRC-16/CCITT of CRC_LEN generated bytes, one bit at a time
+crc_bench:
+    la      t0, buffer
+    li      t1, CRC_LEN
+    li      t2, 7
+1:  sb      t2, 0(t0)
+    addi    t2, t2, 13
+    addi    t0, t0, 1
+    addi    t1, t1, -1
+    bnez    t1, 1b
+
+    la      t0, buffer
+    li      t1, CRC_LEN
+    li      a0, 0xffff
+    li      t6, 0x1021
+    lui     t5, 0x10
+2:  lbu     t2, 0(t0)
+    slli    t2, t2, 8
+    xor     a0, a0, t2
+    li      t3, 8
+3:  slli    a0, a0, 1
+    and     t4, a0, t5
+    beqz    t4, 4f
+    xor     a0, a0, t6
+4:  slli    a0, a0, 16
+    srli    a0, a0, 16
+    addi    t3, t3, -1
+    bnez    t3, 3b
+    addi    t0, t0, 1
+    addi    t1, t1, -1
+    bnez    t1, 2b
+    ret
+
+# Splits 'input' at commas and counts the tokens that are plain decimal
+# numbers, returns (numbers << 16) | digits
+state_bench:
+    la      t0, input
+    li      a0, 0               # numbers
+    li      a1, 0               # digits
+    li      t1, 0               # 0 between tokens, 1 in a number, 2 other
+    li      t4, ','
+    li      t5, 10
+1:  lbu     t2, 0(t0)
+    beqz    t2, 5f
+    addi    t0, t0, 1
+    bne     t2, t4, 2f
+    addi    t3, t1, -1
+    seqz    t3, t3
+    add     a0, a0, t3
+    li      t1, 0
+    j       1b
+2:  addi    t3, t2, -'0'
+    bgeu    t3, t5, 4f
+    addi    a1, a1, 1
+    bnez    t1, 1b
+3:  li      t1, 1
+    j       1b
+4:  li      t1, 2
+    j       1b
+5:  addi    t3, t1, -1
+    seqz    t3, t3
+    add     a0, a0, t3
+    slli    a0, a0, 16
+    or      a0, a0, a1
+    ret
+
+    .data
+input:
+    .asciz  "5012,1.5e3,-17,0x1f,abc,42,,77,3.14159,100000,x9,9x,2718281828,0"
+    .align  2
+nodes:
+    .zero   LIST_LEN * 8
+mat_a:
+    .zero   MAT_N * MAT_N * 4
+mat_b:
+    .zero   MAT_N * MAT_N * 4
+mat_c:
+    .zero   MAT_N * MAT_N * 4
+buffer:
+    .zero   CRC_LEN
@@ -0,0 +1,53 @@
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+// cargo bench runs each guest in every interpreter configuration and
+// reports the best of RUNS wall clock times, --features jit adds the JIT
+const RUNS: usize = 3;
+const GUESTS: [&str; 2] = ["benches/guests/kernels.elf", "benches/guests/heap.elf"];
+const CONFIGS: [(&str, &[&str]); 4] = [
+    ("decode every fetch", &["--no-decode-cache"]),
+    ("decode cache", &["--no-blocks"]),
+    ("basic blocks", &[]),
+    ("jit", &["--jit"]),
+];
+
+fn run(guest: &str, args: &[&str]) -> (Duration, String) {
+    let mut best = Duration::MAX;
+    let mut stdout = String::new();
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let out = Command::new(env!("CARGO_BIN_EXE_simulator"))
+            .args(args)
+            .arg(guest)
+            .output()
+            .expect("failed to run the simulator");
+        best = best.min(start.elapsed());
+        assert!(out.status.success(), "{} {:?} failed", guest, args);
+        stdout = String::from_utf8_lossy(&out.stdout).into_owned();
+    }
+    (best, stdout)
+}
+
+fn main() {
+    for guest in GUESTS.iter() {
+        println!("{}", guest);
+        let mut baseline = None;
+        let mut checksum = None;
+        for (name, args) in CONFIGS.iter() {
+            if args.contains(&"--jit") && !cfg!(feature = "jit") {
+                continue;
+            }
+            let (time, stdout) = run(guest, args);
+            // every configuration has to compute the same thing
+            assert_eq!(checksum.get_or_insert(stdout.clone()), &stdout);
+            let base = *baseline.get_or_insert(time);
+            println!(
+                "  {:<20} {:>8.3} s  {:>5.2}x",
+                name,
+                time.as_secs_f64(),
+                base.as_secs_f64() / time.as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,36 @@
+// cargo fuzz run decode
+// Every word either decodes to an instruction that encodes back to an
+// equivalent word, or is rejected. Neither path may panic.
+#![no_main]
+#![allow(dead_code)]
+
+#[path = "../../src/csr.rs"]
+mod csr;
+#[path = "../../src/disasm.rs"]
+mod disasm;
+#[path = "../../src/instruction.rs"]
+mod instruction;
+#[path = "../../src/pmp.rs"]
+mod pmp;
+#[path = "../../src/snapshot.rs"]
+mod snapshot;
+#[path = "../../src/symbols.rs"]
+mod symbols;
+#[path = "../../src/util.rs"]
+mod util;
+
+use disasm::disassemble_word;
+use instruction::Instruction;
+use libfuzzer_sys::fuzz_target;
+use std::convert::TryInto;
+
+fuzz_target!(|data: &[u8]| {
+    for word in data.chunks_exact(4) {
+        let raw = u32::from_le_bytes(word.try_into().unwrap());
+        if let Some(inst) = Instruction::try_new(raw) {
+            assert_eq!(Instruction::try_new(inst.encode()), Some(inst));
+            inst.dest_register();
+        }
+        disassemble_word(raw, 0x1000, None);
+    }
+});
@@ -0,0 +1,79 @@
+// cargo fuzz run step
+// Executes one instruction word from random register state. Nothing may
+// panic and illegal encodings must stop the guest with an illegal
+// instruction trap.
+#![no_main]
+#![allow(dead_code)]
+
+#[path = "../../src/clint.rs"]
+mod clint;
+#[path = "../../src/csr.rs"]
+mod csr;
+#[path = "../../src/decode_cache.rs"]
+mod decode_cache;
+#[path = "../../src/disasm.rs"]
+mod disasm;
+#[path = "../../src/hart.rs"]
+mod hart;
+#[path = "../../src/heap.rs"]
+mod heap;
+#[path = "../../src/instruction.rs"]
+mod instruction;
+#[path = "../../src/memory.rs"]
+mod memory;
+#[path = "../../src/mmu.rs"]
+mod mmu;
+#[path = "../../src/page_table.rs"]
+mod page_table;
+#[path = "../../src/plic.rs"]
+mod plic;
+#[path = "../../src/pmp.rs"]
+mod pmp;
+#[path = "../../src/processor.rs"]
+mod processor;
+#[path = "../../src/race.rs"]
+mod race;
+#[path = "../../src/record.rs"]
+mod record;
+#[path = "../../src/sanitizer.rs"]
+mod sanitizer;
+#[path = "../../src/snapshot.rs"]
+mod snapshot;
+#[path = "../../src/symbols.rs"]
+mod symbols;
+#[path = "../../src/syscall.rs"]
+mod syscall;
+#[path = "../../src/trap.rs"]
+mod trap;
+#[path = "../../src/util.rs"]
+mod util;
+
+use instruction::Instruction;
+use libfuzzer_sys::fuzz_target;
+use memory::Memory;
+use processor::Processor;
+use std::convert::TryInto;
+use trap::Trap;
+
+const PC: u32 = 0x1000;
+
+// The instruction word, then initial values of x1..x31
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 4 {
+        return;
+    }
+    let (word, regs) = data.split_at(4);
+    let raw = u32::from_le_bytes(word.try_into().unwrap());
+    let mut mem = Memory::with_code(PC, word);
+    for (i, reg) in regs.chunks_exact(4).take(31).enumerate() {
+        mem.set_register(u32::from_le_bytes(reg.try_into().unwrap()), i as u8 + 1);
+    }
+    Processor::tick(&mut mem);
+    match Instruction::try_new(raw) {
+        None => assert_eq!(mem.trap, Some(Trap::IllegalInstruction { raw })),
+        Some(_) => assert_ne!(mem.trap.map(|t| t.cause()), Some(2)),
+    }
+    if mem.trap.is_some() {
+        assert_eq!(mem.get_pc(), PC);
+    }
+});
@@ -0,0 +1,634 @@
+use crate::decode_cache::IntHasher;
+use crate::instruction::Instruction;
+use crate::memory::{Access, Memory, READ, WRITE};
+use crate::processor::Processor;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::hash::BuildHasherDefault;
+
+const MAX_LEN: usize = 64;
+// writes to x0 go to this extra register instead of being checked for
+pub(crate) const SINK: u8 = 32;
+
+// Straight-line guest code translated to micro-ops with decoding already
+// done. Blocks end at control flow and are chained to their direct
+// successors, only indirect jumps need a lookup. Anything else goes back to
+// Processor::tick: system instructions, traps and stores that change code.
+#[derive(Debug, Default)]
+pub(crate) struct BlockCache {
+    blocks: Vec<Block>,
+    index: HashMap<u32, usize, BuildHasherDefault<IntHasher>>,
+    version: u64, // Memory::code_version the blocks were translated from
+    #[cfg(feature = "jit")]
+    jit: Option<crate::jit::Jit>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Block {
+    pub start: u32,
+    pub end: u32, // pc after the block
+    pub len: u64, // instructions, including a jump or branch at the end
+    pub ops: Vec<Op>,
+    pub exit: Exit,
+    next: [Option<usize>; 2], // chained successors, see Flow::Chain
+}
+
+// One op per instruction before the exit, op 'i' is at start + 4 * i
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Op {
+    Li { rd: u8, val: u32 },
+    AddI { rd: u8, rs1: u8, imm: u32 },
+    SltI { rd: u8, rs1: u8, imm: u32 },
+    SltIU { rd: u8, rs1: u8, imm: u32 },
+    XorI { rd: u8, rs1: u8, imm: u32 },
+    OrI { rd: u8, rs1: u8, imm: u32 },
+    AndI { rd: u8, rs1: u8, imm: u32 },
+    SllI { rd: u8, rs1: u8, shift: u8 },
+    SrlI { rd: u8, rs1: u8, shift: u8 },
+    SraI { rd: u8, rs1: u8, shift: u8 },
+    Add { rd: u8, rs1: u8, rs2: u8 },
+    Sub { rd: u8, rs1: u8, rs2: u8 },
+    Sll { rd: u8, rs1: u8, rs2: u8 },
+    Slt { rd: u8, rs1: u8, rs2: u8 },
+    SltU { rd: u8, rs1: u8, rs2: u8 },
+    Xor { rd: u8, rs1: u8, rs2: u8 },
+    Srl { rd: u8, rs1: u8, rs2: u8 },
+    Sra { rd: u8, rs1: u8, rs2: u8 },
+    Or { rd: u8, rs1: u8, rs2: u8 },
+    And { rd: u8, rs1: u8, rs2: u8 },
+    Lb { rd: u8, rs1: u8, imm: u32 },
+    Lh { rd: u8, rs1: u8, imm: u32 },
+    Lw { rd: u8, rs1: u8, imm: u32 },
+    LbU { rd: u8, rs1: u8, imm: u32 },
+    LhU { rd: u8, rs1: u8, imm: u32 },
+    Sb { rs1: u8, rs2: u8, imm: u32 },
+    Sh { rs1: u8, rs2: u8, imm: u32 },
+    Sw { rs1: u8, rs2: u8, imm: u32 },
+    Nop,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Exit {
+    Jump {
+        rd: u8,
+        target: u32,
+    },
+    Branch {
+        cond: Cond,
+        rs1: u8,
+        rs2: u8,
+        target: u32,
+    },
+    Indirect {
+        rd: u8,
+        rs1: u8,
+        imm: u32,
+    },
+    // the block is full, the next one starts at 'end'
+    Fall,
+    // the instruction at 'end' needs Processor::tick
+    Generic,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Cond {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    LtU,
+    GeU,
+}
+
+pub(crate) enum Flow {
+    // continue at a direct successor, cached in Block::next[slot]
+    Chain(u32, usize),
+    Indirect(u32),
+    Stop(u32),
+}
+
+impl BlockCache {
+    #[cfg(feature = "jit")]
+    pub fn enable_jit(&mut self) {
+        self.jit = Some(crate::jit::Jit::new());
+        self.blocks.clear();
+        self.index.clear();
+    }
+
+    // Runs blocks from the pc until the next instruction needs
+    // Processor::tick or a store hit 'tohost'. The journal has to be off.
+    pub fn run(&mut self, mem: &mut Memory, tohost: Option<u32>) {
+        self.run_for(mem, tohost, usize::MAX);
+    }
+
+    // Like run but stops after at most 'limit' blocks
+    pub fn run_for(&mut self, mem: &mut Memory, tohost: Option<u32>, limit: usize) {
+        if self.version != mem.code_version {
+            self.blocks.clear();
+            self.index.clear();
+            #[cfg(feature = "jit")]
+            if let Some(jit) = &mut self.jit {
+                jit.clear();
+            }
+            self.version = mem.code_version;
+        }
+        mem.last_access = None;
+        let mut x = [0u32; 33];
+        x[..32].copy_from_slice(mem.registers());
+        let mut i = self.lookup(mem, mem.get_pc());
+        for _ in 0..limit {
+            let block = &self.blocks[i];
+            let flow = match self.compiled(i) {
+                #[cfg(feature = "jit")]
+                Some(code) => crate::jit::execute(code, block, &mut x, mem, self.version, tohost),
+                _ => execute(block, &mut x, mem, self.version, tohost),
+            };
+            i = match flow {
+                Flow::Chain(pc, slot) => match self.blocks[i].next[slot] {
+                    Some(next) => next,
+                    None => {
+                        let next = self.lookup(mem, pc);
+                        self.blocks[i].next[slot] = Some(next);
+                        next
+                    }
+                },
+                Flow::Indirect(pc) => self.lookup(mem, pc),
+                Flow::Stop(pc) => {
+                    mem.set_registers(&x[..32]);
+                    mem.set_pc(pc);
+                    return;
+                }
+            };
+        }
+        mem.set_registers(&x[..32]);
+        mem.set_pc(self.blocks[i].start);
+    }
+
+    #[cfg(feature = "jit")]
+    fn compiled(&self, i: usize) -> Option<crate::jit::Code> {
+        self.jit.as_ref().and_then(|jit| jit.code(i))
+    }
+
+    #[cfg(not(feature = "jit"))]
+    fn compiled(&self, _: usize) -> Option<()> {
+        None
+    }
+
+    fn lookup(&mut self, mem: &mut Memory, pc: u32) -> usize {
+        if let Some(&i) = self.index.get(&pc) {
+            return i;
+        }
+        let block = translate(mem, pc);
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            jit.compile(&block);
+        }
+        self.blocks.push(block);
+        self.index.insert(pc, self.blocks.len() - 1);
+        self.blocks.len() - 1
+    }
+}
+
+fn translate(mem: &mut Memory, start: u32) -> Block {
+    use Instruction::*;
+    let mut ops = vec![];
+    let mut pc = start;
+    let rd = |rd: u8| if rd == 0 { SINK } else { rd };
+    let exit = loop {
+        if ops.len() == MAX_LEN {
+            break Exit::Fall;
+        }
+        // traps are raised by Processor::tick
+        let Ok(inst) = Processor::fetch_at(mem, pc) else {
+            break Exit::Generic;
+        };
+        let target = pc.wrapping_add(inst_imm(&inst));
+        // jumps to misaligned targets trap
+        if !target.is_multiple_of(4) {
+            break Exit::Generic;
+        }
+        let op = match inst {
+            LUI { imm, rd: r } => Op::Li {
+                rd: rd(r),
+                val: imm as u32,
+            },
+            AUIPC { imm, rd: r } => Op::Li {
+                rd: rd(r),
+                val: pc.wrapping_add(imm as u32),
+            },
+            JAL { rd: r, .. } => break Exit::Jump { rd: rd(r), target },
+            JALR { imm, rs1, rd: r } => {
+                break Exit::Indirect {
+                    rd: rd(r),
+                    rs1,
+                    imm: imm as u32,
+                }
+            }
+            BEQ { rs1, rs2, .. } => break branch(Cond::Eq, rs1, rs2, target),
+            BNE { rs1, rs2, .. } => break branch(Cond::Ne, rs1, rs2, target),
+            BLT { rs1, rs2, .. } => break branch(Cond::Lt, rs1, rs2, target),
+            BGE { rs1, rs2, .. } => break branch(Cond::Ge, rs1, rs2, target),
+            BLTU { rs1, rs2, .. } => break branch(Cond::LtU, rs1, rs2, target),
+            BGEU { rs1, rs2, .. } => break branch(Cond::GeU, rs1, rs2, target),
+            LB { imm, rs1, rd: r } => Op::Lb {
+                rd: rd(r),
+                rs1,
+                imm: imm as u32,
+            },
+            LH { imm, rs1, rd: r } => Op::Lh {
+                rd: rd(r),
+                rs1,
+                imm: imm as u32,
+            },
+            LW { imm, rs1, rd: r } => Op::Lw {
+                rd: rd(r),
+                rs1,
+                imm: imm as u32,
+            },
+            LBU { imm, rs1, rd: r } => Op::LbU {
+                rd: rd(r),
+                rs1,
+                imm: imm as u32,
+            },
+            LHU { imm, rs1, rd: r } => Op::LhU {
+                rd: rd(r),
+                rs1,
+                imm: imm as u32,
+            },
+            SB { imm, rs1, rs2 } => Op::Sb {
+                rs1,
+                rs2,
+                imm: imm as u32,
+            },
+            SH { imm, rs1, rs2 } => Op::Sh {
+                rs1,
+                rs2,
+                imm: imm as u32,
+            },
+            SW { imm, rs1, rs2 } => Op::Sw {
+                rs1,
+                rs2,
+                imm: imm as u32,
+            },
+            ADDI { imm, rs1: 0, rd: r } => Op::Li {
+                rd: rd(r),
+                val: imm as u32,
+            },
+            ADDI { imm, rs1, rd: r } => Op::AddI {
+                rd: rd(r),
+                rs1,
+                imm: imm as u32,
+            },
+            SLTI { imm, rs1, rd: r } => Op::SltI {
+                rd: rd(r),
+                rs1,
+                imm: imm as u32,
+            },
+            SLTIU { imm, rs1, rd: r } => Op::SltIU {
+                rd: rd(r),
+                rs1,
+                imm: imm as u32,
+            },
+            XORI { imm, rs1, rd: r } => Op::XorI {
+                rd: rd(r),
+                rs1,
+                imm: imm as u32,
+            },
+            ORI { imm, rs1, rd: r } => Op::OrI {
+                rd: rd(r),
+                rs1,
+                imm: imm as u32,
+            },
+            ANDI { imm, rs1, rd: r } => Op::AndI {
+                rd: rd(r),
+                rs1,
+                imm: imm as u32,
+            },
+            SLLI { shift, rs1, rd: r } => Op::SllI {
+                rd: rd(r),
+                rs1,
+                shift,
+            },
+            SRLI { shift, rs1, rd: r } => Op::SrlI {
+                rd: rd(r),
+                rs1,
+                shift,
+            },
+            SRAI { shift, rs1, rd: r } => Op::SraI {
+                rd: rd(r),
+                rs1,
+                shift,
+            },
+            ADD { rs1, rs2, rd: r } => Op::Add {
+                rd: rd(r),
+                rs1,
+                rs2,
+            },
+            SUB { rs1, rs2, rd: r } => Op::Sub {
+                rd: rd(r),
+                rs1,
+                rs2,
+            },
+            SLL { rs1, rs2, rd: r } => Op::Sll {
+                rd: rd(r),
+                rs1,
+                rs2,
+            },
+            SLT { rs1, rs2, rd: r } => Op::Slt {
+                rd: rd(r),
+                rs1,
+                rs2,
+            },
+            SLTU { rs1, rs2, rd: r } => Op::SltU {
+                rd: rd(r),
+                rs1,
+                rs2,
+            },
+            XOR { rs1, rs2, rd: r } => Op::Xor {
+                rd: rd(r),
+                rs1,
+                rs2,
+            },
+            SRL { rs1, rs2, rd: r } => Op::Srl {
+                rd: rd(r),
+                rs1,
+                rs2,
+            },
+            SRA { rs1, rs2, rd: r } => Op::Sra {
+                rd: rd(r),
+                rs1,
+                rs2,
+            },
+            OR { rs1, rs2, rd: r } => Op::Or {
+                rd: rd(r),
+                rs1,
+                rs2,
+            },
+            AND { rs1, rs2, rd: r } => Op::And {
+                rd: rd(r),
+                rs1,
+                rs2,
+            },
+            FENCE { .. } => Op::Nop,
+            FENCE_I | ECALL | EBREAK | MRET | SRET | WFI | SFENCE_VMA { .. } => {
+                break Exit::Generic
+            }
+            CSRRW { .. } | CSRRS { .. } | CSRRC { .. } => break Exit::Generic,
+            CSRRWI { .. } | CSRRSI { .. } | CSRRCI { .. } => break Exit::Generic,
+            LR_W { .. } | SC_W { .. } | AMO { .. } => break Exit::Generic,
+        };
+        ops.push(op);
+        pc = pc.wrapping_add(4);
+    };
+    let len = match exit {
+        Exit::Fall | Exit::Generic => ops.len(),
+        _ => ops.len() + 1,
+    };
+    Block {
+        start,
+        end: start.wrapping_add(4 * len as u32),
+        len: len as u64,
+        ops,
+        exit,
+        next: [None; 2],
+    }
+}
+
+fn inst_imm(inst: &Instruction) -> u32 {
+    use Instruction::*;
+    match *inst {
+        JAL { imm, .. }
+        | BEQ { imm, .. }
+        | BNE { imm, .. }
+        | BLT { imm, .. }
+        | BGE { imm, .. }
+        | BLTU { imm, .. }
+        | BGEU { imm, .. } => imm as u32,
+        _ => 0,
+    }
+}
+
+fn branch(cond: Cond, rs1: u8, rs2: u8, target: u32) -> Exit {
+    Exit::Branch {
+        cond,
+        rs1,
+        rs2,
+        target,
+    }
+}
+
+fn execute(
+    block: &Block,
+    x: &mut [u32; 33],
+    mem: &mut Memory,
+    version: u64,
+    tohost: Option<u32>,
+) -> Flow {
+    let r = |i: u8| i as usize;
+    for (i, op) in block.ops.iter().enumerate() {
+        match *op {
+            Op::Li { rd, val } => x[r(rd)] = val,
+            Op::AddI { rd, rs1, imm } => x[r(rd)] = x[r(rs1)].wrapping_add(imm),
+            Op::SltI { rd, rs1, imm } => x[r(rd)] = ((x[r(rs1)] as i32) < imm as i32) as u32,
+            Op::SltIU { rd, rs1, imm } => x[r(rd)] = (x[r(rs1)] < imm) as u32,
+            Op::XorI { rd, rs1, imm } => x[r(rd)] = x[r(rs1)] ^ imm,
+            Op::OrI { rd, rs1, imm } => x[r(rd)] = x[r(rs1)] | imm,
+            Op::AndI { rd, rs1, imm } => x[r(rd)] = x[r(rs1)] & imm,
+            Op::SllI { rd, rs1, shift } => x[r(rd)] = x[r(rs1)] << shift,
+            Op::SrlI { rd, rs1, shift } => x[r(rd)] = x[r(rs1)] >> shift,
+            Op::SraI { rd, rs1, shift } => x[r(rd)] = (x[r(rs1)] as i32 >> shift) as u32,
+            Op::Add { rd, rs1, rs2 } => x[r(rd)] = x[r(rs1)].wrapping_add(x[r(rs2)]),
+            Op::Sub { rd, rs1, rs2 } => x[r(rd)] = x[r(rs1)].wrapping_sub(x[r(rs2)]),
+            Op::Sll { rd, rs1, rs2 } => x[r(rd)] = x[r(rs1)] << (x[r(rs2)] & 0b11111),
+            Op::Slt { rd, rs1, rs2 } => x[r(rd)] = ((x[r(rs1)] as i32) < (x[r(rs2)] as i32)) as u32,
+            Op::SltU { rd, rs1, rs2 } => x[r(rd)] = (x[r(rs1)] < x[r(rs2)]) as u32,
+            Op::Xor { rd, rs1, rs2 } => x[r(rd)] = x[r(rs1)] ^ x[r(rs2)],
+            Op::Srl { rd, rs1, rs2 } => x[r(rd)] = x[r(rs1)] >> (x[r(rs2)] & 0b11111),
+            Op::Sra { rd, rs1, rs2 } => {
+                x[r(rd)] = ((x[r(rs1)] as i32) >> (x[r(rs2)] & 0b11111)) as u32
+            }
+            Op::Or { rd, rs1, rs2 } => x[r(rd)] = x[r(rs1)] | x[r(rs2)],
+            Op::And { rd, rs1, rs2 } => x[r(rd)] = x[r(rs1)] & x[r(rs2)],
+            Op::Lb { rd, rs1, imm } => match load::<1>(mem, x[r(rs1)].wrapping_add(imm)) {
+                Some(b) => x[r(rd)] = b[0] as i8 as u32,
+                None => return stop(block, mem, i),
+            },
+            Op::Lh { rd, rs1, imm } => match load::<2>(mem, x[r(rs1)].wrapping_add(imm)) {
+                Some(b) => x[r(rd)] = i16::from_le_bytes(b) as u32,
+                None => return stop(block, mem, i),
+            },
+            Op::Lw { rd, rs1, imm } => match load::<4>(mem, x[r(rs1)].wrapping_add(imm)) {
+                Some(b) => x[r(rd)] = u32::from_le_bytes(b),
+                None => return stop(block, mem, i),
+            },
+            Op::LbU { rd, rs1, imm } => match load::<1>(mem, x[r(rs1)].wrapping_add(imm)) {
+                Some(b) => x[r(rd)] = b[0] as u32,
+                None => return stop(block, mem, i),
+            },
+            Op::LhU { rd, rs1, imm } => match load::<2>(mem, x[r(rs1)].wrapping_add(imm)) {
+                Some(b) => x[r(rd)] = u16::from_le_bytes(b) as u32,
+                None => return stop(block, mem, i),
+            },
+            Op::Sb { rs1, rs2, imm } | Op::Sh { rs1, rs2, imm } | Op::Sw { rs1, rs2, imm } => {
+                let len = match *op {
+                    Op::Sb { .. } => 1,
+                    Op::Sh { .. } => 2,
+                    _ => 4,
+                };
+                let addr = x[r(rs1)].wrapping_add(imm);
+                match store(mem, addr, x[r(rs2)], len, version, tohost) {
+                    Stored::Done => {}
+                    Stored::Fault => return stop(block, mem, i),
+                    Stored::Stop => return stop(block, mem, i + 1),
+                }
+            }
+            Op::Nop => {}
+        }
+    }
+    mem.hart.instret += block.len;
+    match block.exit {
+        Exit::Jump { rd, target } => {
+            x[r(rd)] = block.end;
+            Flow::Chain(target, 0)
+        }
+        Exit::Branch {
+            cond,
+            rs1,
+            rs2,
+            target,
+        } => {
+            let (a, b) = (x[r(rs1)], x[r(rs2)]);
+            let taken = match cond {
+                Cond::Eq => a == b,
+                Cond::Ne => a != b,
+                Cond::Lt => (a as i32) < (b as i32),
+                Cond::Ge => (a as i32) >= (b as i32),
+                Cond::LtU => a < b,
+                Cond::GeU => a >= b,
+            };
+            if taken {
+                Flow::Chain(target, 0)
+            } else {
+                Flow::Chain(block.end, 1)
+            }
+        }
+        Exit::Indirect { rd, rs1, imm } => {
+            // read before the link, rd may be rs1
+            let target = x[r(rs1)].wrapping_add(imm) & !1;
+            if !target.is_multiple_of(4) {
+                // the jump traps in Processor::tick, it didn't retire
+                mem.hart.instret -= 1;
+                return Flow::Stop(block.end.wrapping_sub(4));
+            }
+            x[r(rd)] = block.end;
+            Flow::Indirect(target)
+        }
+        Exit::Fall => Flow::Chain(block.end, 0),
+        Exit::Generic => Flow::Stop(block.end),
+    }
+}
+
+// Data accesses of the interpreter and the JIT
+pub(crate) fn load<const N: usize>(mem: &Memory, addr: u32) -> Option<[u8; N]> {
+    if !mem.allows(addr as usize, N, READ) {
+        return None;
+    }
+    mem.try_read(addr as usize, N)?[..].try_into().ok()
+}
+
+pub(crate) enum Stored {
+    Done,
+    Fault,
+    // the rest of the block may be stale, or the guest is done
+    Stop,
+}
+
+pub(crate) fn store(
+    mem: &mut Memory,
+    addr: u32,
+    val: u32,
+    len: usize,
+    version: u64,
+    tohost: Option<u32>,
+) -> Stored {
+    if !mem.allows(addr as usize, len, WRITE)
+        || !mem.try_write(addr as usize, &val.to_le_bytes()[..len])
+    {
+        return Stored::Fault;
+    }
+    mem.last_access = Some(Access {
+        addr,
+        len,
+        write: true,
+    });
+    mem.track_stack(addr as usize);
+    if mem.code_version != version || Some(addr) == tohost {
+        Stored::Stop
+    } else {
+        Stored::Done
+    }
+}
+
+// Leaves the block before op 'i', which Processor::tick runs if it traps
+fn stop(block: &Block, mem: &mut Memory, i: usize) -> Flow {
+    mem.hart.instret += i as u64;
+    Flow::Stop(block.start.wrapping_add(4 * i as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trap::Trap;
+
+    const PC: u32 = 0x1000;
+
+    fn memory(words: &[u32]) -> Memory {
+        let code: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        Memory::with_code(PC, &code)
+    }
+
+    #[test]
+    fn stops_before_a_faulting_load() {
+        // li a0,1; lw a1,0(zero); li a0,2
+        let mut mem = memory(&[0x00100513, 0x00002583, 0x00200513]);
+        BlockCache::default().run(&mut mem, None);
+        assert_eq!(mem.get_pc(), PC + 4);
+        assert_eq!(mem.hart.instret, 1);
+        assert_eq!(mem.get_register(10), 1);
+        Processor::tick(&mut mem);
+        assert_eq!(mem.trap, Some(Trap::LoadAccessFault { addr: 0 }));
+        assert_eq!(mem.get_pc(), PC + 4);
+    }
+
+    #[test]
+    fn chains_loops_until_ecall() {
+        // li a0,10; li a1,0; loop: add a1,a1,a0; addi a0,a0,-1; bnez a0,loop;
+        // li x0,5; ecall
+        let mut mem = memory(&[
+            0x00a00513, 0x00000593, 0x00a585b3, 0xfff50513, 0xfe051ce3, 0x00500013, 0x00000073,
+        ]);
+        let mut blocks = BlockCache::default();
+        blocks.run(&mut mem, None);
+        assert_eq!(mem.get_register(11), 55);
+        assert_eq!(mem.get_register(0), 0);
+        assert_eq!(mem.get_pc(), PC + 24);
+        assert_eq!(mem.hart.instret, 2 + 3 * 10 + 1);
+        // entry block, loop body and the block after it
+        assert_eq!(blocks.blocks.len(), 3);
+    }
+
+    #[test]
+    fn self_modifying_store_ends_the_block() {
+        // la a0,patch; lw a1,8(a0); sw a1,0(a0); patch: li a2,1; ecall;
+        // li a2,2
+        let mut mem = memory(&[
+            0x00000517, 0x01050513, 0x00852583, 0x00b52023, 0x00100613, 0x00000073, 0x00200613,
+        ]);
+        let mut blocks = BlockCache::default();
+        blocks.run(&mut mem, None);
+        assert_eq!(mem.get_pc(), PC + 16);
+        // the stale translation is dropped
+        blocks.run(&mut mem, None);
+        assert_eq!(mem.get_register(12), 2);
+    }
+}
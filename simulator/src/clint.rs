@@ -0,0 +1,73 @@
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+// Memory map of the SiFive CLINT, only the msip registers are modeled
+pub(crate) const BASE: usize = 0x0200_0000;
+const SIZE: usize = 0x1_0000;
+
+// Core-local interruptor. Writing 1 to a hart's msip word raises its
+// machine software interrupt, the way harts send each other IPIs, and
+// writing 0 clears it again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Clint {
+    msip: Vec<bool>,
+}
+
+pub(crate) fn contains(addr: usize) -> bool {
+    (BASE..BASE + SIZE).contains(&addr)
+}
+
+impl Clint {
+    pub fn new(harts: usize) -> Self {
+        Clint {
+            msip: vec![false; harts],
+        }
+    }
+
+    pub fn msip(&self, hart: usize) -> bool {
+        self.msip[hart]
+    }
+
+    // Word at 'offset' from BASE, None if nothing is there
+    pub fn read(&self, offset: usize) -> Option<u32> {
+        self.msip.get(offset / 4).map(|&msip| msip as u32)
+    }
+
+    // Only bit 0 of msip is writable
+    pub fn write(&mut self, offset: usize, val: u32) -> Option<()> {
+        *self.msip.get_mut(offset / 4)? = val & 1 != 0;
+        Some(())
+    }
+
+    pub fn save(&self, w: &mut SnapshotWriter) {
+        w.u32(self.msip.len() as u32);
+        for &msip in self.msip.iter() {
+            w.u8(msip as u8);
+        }
+    }
+
+    pub fn load(r: &mut SnapshotReader) -> Result<Self, String> {
+        let mut clint = Clint::new(0);
+        for _ in 0..r.u32()? {
+            clint.msip.push(r.u8()? != 0);
+        }
+        Ok(clint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn msip_words_follow_bit_zero() {
+        let mut clint = Clint::new(2);
+        clint.write(4, 3).unwrap();
+        assert_eq!((clint.read(0), clint.read(4)), (Some(0), Some(1)));
+        assert!(clint.msip(1));
+        clint.write(4, 2).unwrap();
+        assert!(!clint.msip(1));
+        // mtimecmp and mtime aren't there
+        assert_eq!(clint.read(8), None);
+        assert_eq!(clint.write(0xbff8, 0), None);
+    }
+}
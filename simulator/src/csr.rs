@@ -0,0 +1,626 @@
+use crate::pmp::Pmp;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+pub(crate) const USER: u8 = 0;
+pub(crate) const SUPERVISOR: u8 = 1;
+pub(crate) const MACHINE: u8 = 3;
+
+// mstatus fields
+const SIE: u32 = 1 << 1;
+const MIE: u32 = 1 << 3;
+const SPIE: u32 = 1 << 5;
+const MPIE: u32 = 1 << 7;
+const SPP: u32 = 1 << 8;
+const MPP_SHIFT: u32 = 11;
+const MPP: u32 = 0b11 << MPP_SHIFT;
+const MPRV: u32 = 1 << 17;
+const SUM: u32 = 1 << 18;
+const MXR: u32 = 1 << 19;
+const TVM: u32 = 1 << 20;
+const TW: u32 = 1 << 21;
+const TSR: u32 = 1 << 22;
+const MSTATUS_MASK: u32 = SIE | MIE | SPIE | MPIE | SPP | MPP | MPRV | SUM | MXR | TVM | TW | TSR;
+const SSTATUS_MASK: u32 = SIE | SPIE | SPP | SUM | MXR;
+
+// Interrupt bits of mip and mie, by priority
+const INTERRUPTS: [u32; 6] = [MEI, MSI, MTI, SEI, SSI, STI];
+const SSI: u32 = 1 << 1;
+pub(crate) const MSI: u32 = 1 << 3;
+const STI: u32 = 1 << 5;
+const MTI: u32 = 1 << 7;
+pub(crate) const SEI: u32 = 1 << 9;
+pub(crate) const MEI: u32 = 1 << 11;
+const S_INTERRUPTS: u32 = SSI | STI | SEI;
+const INTERRUPT: u32 = 1 << 31;
+
+// Exceptions S-mode can handle, everything but ecall from M-mode
+const DELEGABLE: u32 = 0xb3ff & !(1 << 11);
+// RV32 with A, I, S and U
+const MISA: u32 = 1 << 30 | 1 | 1 << 8 | 1 << 18 | 1 << 20;
+
+const SSTATUS: u16 = 0x100;
+const SIE_CSR: u16 = 0x104;
+const STVEC: u16 = 0x105;
+const SCOUNTEREN: u16 = 0x106;
+const SENVCFG: u16 = 0x10a;
+const SSCRATCH: u16 = 0x140;
+const SEPC: u16 = 0x141;
+const SCAUSE: u16 = 0x142;
+const STVAL: u16 = 0x143;
+const SIP: u16 = 0x144;
+const SATP: u16 = 0x180;
+const MSTATUS: u16 = 0x300;
+const MISA_CSR: u16 = 0x301;
+const MEDELEG: u16 = 0x302;
+const MIDELEG: u16 = 0x303;
+const MIE_CSR: u16 = 0x304;
+const MTVEC: u16 = 0x305;
+const MCOUNTEREN: u16 = 0x306;
+const MENVCFG: u16 = 0x30a;
+const MSTATUSH: u16 = 0x310;
+const MENVCFGH: u16 = 0x31a;
+const MSCRATCH: u16 = 0x340;
+const MEPC: u16 = 0x341;
+const MCAUSE: u16 = 0x342;
+const MTVAL: u16 = 0x343;
+const MIP: u16 = 0x344;
+const PMPCFG0: u16 = 0x3a0;
+const PMPCFG3: u16 = 0x3a3;
+const PMPADDR0: u16 = 0x3b0;
+const PMPADDR15: u16 = 0x3bf;
+const TSELECT: u16 = 0x7a0;
+const TDATA1: u16 = 0x7a1;
+const TDATA2: u16 = 0x7a2;
+const MCYCLE: u16 = 0xb00;
+const MINSTRET: u16 = 0xb02;
+const MCYCLEH: u16 = 0xb80;
+const MINSTRETH: u16 = 0xb82;
+const CYCLE: u16 = 0xc00;
+const INSTRET: u16 = 0xc02;
+const CYCLEH: u16 = 0xc80;
+const INSTRETH: u16 = 0xc82;
+const MVENDORID: u16 = 0xf11;
+const MARCHID: u16 = 0xf12;
+const MIMPID: u16 = 0xf13;
+const MHARTID: u16 = 0xf14;
+const MCONFIGPTR: u16 = 0xf15;
+
+const NAMES: &[(u16, &str)] = &[
+    (SSTATUS, "sstatus"),
+    (SIE_CSR, "sie"),
+    (STVEC, "stvec"),
+    (SCOUNTEREN, "scounteren"),
+    (SENVCFG, "senvcfg"),
+    (SSCRATCH, "sscratch"),
+    (SEPC, "sepc"),
+    (SCAUSE, "scause"),
+    (STVAL, "stval"),
+    (SIP, "sip"),
+    (SATP, "satp"),
+    (MSTATUS, "mstatus"),
+    (MISA_CSR, "misa"),
+    (MEDELEG, "medeleg"),
+    (MIDELEG, "mideleg"),
+    (MIE_CSR, "mie"),
+    (MTVEC, "mtvec"),
+    (MCOUNTEREN, "mcounteren"),
+    (MENVCFG, "menvcfg"),
+    (MSTATUSH, "mstatush"),
+    (MENVCFGH, "menvcfgh"),
+    (MSCRATCH, "mscratch"),
+    (MEPC, "mepc"),
+    (MCAUSE, "mcause"),
+    (MTVAL, "mtval"),
+    (MIP, "mip"),
+    (0x3a0, "pmpcfg0"),
+    (0x3a1, "pmpcfg1"),
+    (0x3a2, "pmpcfg2"),
+    (0x3a3, "pmpcfg3"),
+    (0x3b0, "pmpaddr0"),
+    (0x3b1, "pmpaddr1"),
+    (0x3b2, "pmpaddr2"),
+    (0x3b3, "pmpaddr3"),
+    (0x3b4, "pmpaddr4"),
+    (0x3b5, "pmpaddr5"),
+    (0x3b6, "pmpaddr6"),
+    (0x3b7, "pmpaddr7"),
+    (0x3b8, "pmpaddr8"),
+    (0x3b9, "pmpaddr9"),
+    (0x3ba, "pmpaddr10"),
+    (0x3bb, "pmpaddr11"),
+    (0x3bc, "pmpaddr12"),
+    (0x3bd, "pmpaddr13"),
+    (0x3be, "pmpaddr14"),
+    (0x3bf, "pmpaddr15"),
+    (TSELECT, "tselect"),
+    (TDATA1, "tdata1"),
+    (TDATA2, "tdata2"),
+    (MCYCLE, "mcycle"),
+    (MINSTRET, "minstret"),
+    (MCYCLEH, "mcycleh"),
+    (MINSTRETH, "minstreth"),
+    (CYCLE, "cycle"),
+    (INSTRET, "instret"),
+    (CYCLEH, "cycleh"),
+    (INSTRETH, "instreth"),
+    (MVENDORID, "mvendorid"),
+    (MARCHID, "marchid"),
+    (MIMPID, "mimpid"),
+    (MHARTID, "mhartid"),
+    (MCONFIGPTR, "mconfigptr"),
+];
+
+pub(crate) fn name(csr: u16) -> Option<&'static str> {
+    NAMES.iter().find(|(c, _)| *c == csr).map(|(_, name)| *name)
+}
+
+// Privilege mode and the machine and supervisor CSRs. Counters follow
+// instret, one instruction per cycle, and ignore writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Csrs {
+    mode: u8,
+    mstatus: u32,
+    medeleg: u32,
+    mideleg: u32,
+    mie: u32,
+    mip: u32,
+    mtvec: u32,
+    mcounteren: u32,
+    mscratch: u32,
+    mepc: u32,
+    mcause: u32,
+    mtval: u32,
+    stvec: u32,
+    scounteren: u32,
+    sscratch: u32,
+    sepc: u32,
+    scause: u32,
+    stval: u32,
+    satp: u32,
+    hartid: u32,
+    pmp: Pmp,
+    lines: u32, // MSIP, MEIP and SEIP driven by the interrupt controllers
+}
+
+impl Default for Csrs {
+    fn default() -> Self {
+        Csrs {
+            mode: MACHINE,
+            mstatus: 0,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mip: 0,
+            mtvec: 0,
+            mcounteren: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            stvec: 0,
+            scounteren: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            hartid: 0,
+            pmp: Pmp::default(),
+            lines: 0,
+        }
+    }
+}
+
+impl Csrs {
+    pub fn for_hart(hartid: u32) -> Self {
+        Csrs {
+            hartid,
+            ..Csrs::default()
+        }
+    }
+
+    pub fn mode(&self) -> u8 {
+        self.mode
+    }
+
+    // None when the CSR doesn't exist or the current mode can't access it
+    pub fn read(&self, csr: u16, instret: u64) -> Option<u32> {
+        if self.mode < (csr >> 8 & 0b11) as u8 {
+            return None;
+        }
+        // TVM traps S-mode accesses to satp
+        if csr == SATP && self.mode == SUPERVISOR && self.mstatus & TVM != 0 {
+            return None;
+        }
+        Some(match csr {
+            SSTATUS => self.mstatus & SSTATUS_MASK,
+            SIE_CSR => self.mie & self.mideleg,
+            STVEC => self.stvec,
+            SCOUNTEREN => self.scounteren,
+            SSCRATCH => self.sscratch,
+            SEPC => self.sepc,
+            SCAUSE => self.scause,
+            STVAL => self.stval,
+            SIP => (self.mip | self.lines) & self.mideleg,
+            SATP => self.satp,
+            MSTATUS => self.mstatus,
+            MISA_CSR => MISA,
+            MEDELEG => self.medeleg,
+            MIDELEG => self.mideleg,
+            MIE_CSR => self.mie,
+            MTVEC => self.mtvec,
+            MCOUNTEREN => self.mcounteren,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.mip | self.lines,
+            PMPCFG0..=PMPCFG3 => self.pmp.cfg((csr - PMPCFG0) as usize),
+            PMPADDR0..=PMPADDR15 => self.pmp.addr((csr - PMPADDR0) as usize),
+            MCYCLE | MINSTRET => instret as u32,
+            MCYCLEH | MINSTRETH => (instret >> 32) as u32,
+            CYCLE | INSTRET | CYCLEH | INSTRETH => {
+                // cycle is bit 0 of the counter enables, instret bit 2
+                let bit = 1 << (csr & 0b11);
+                if self.mode < MACHINE && self.mcounteren & bit == 0
+                    || self.mode == USER && self.scounteren & bit == 0
+                {
+                    return None;
+                }
+                match csr {
+                    CYCLE | INSTRET => instret as u32,
+                    _ => (instret >> 32) as u32,
+                }
+            }
+            SENVCFG | MENVCFG | MSTATUSH | MENVCFGH => 0,
+            // no triggers, type 0 in tdata1 says there is none at tselect
+            TSELECT | TDATA1 | TDATA2 => 0,
+            MHARTID => self.hartid,
+            MVENDORID | MARCHID | MIMPID | MCONFIGPTR => 0,
+            _ => return None,
+        })
+    }
+
+    // Read-only CSRs, the top two address bits set, can't be written. Call
+    // after a successful read.
+    pub fn write(&mut self, csr: u16, val: u32) -> Option<()> {
+        if csr >> 10 == 0b11 {
+            return None;
+        }
+        match csr {
+            SSTATUS => self.mstatus = self.mstatus & !SSTATUS_MASK | val & SSTATUS_MASK,
+            SIE_CSR => self.mie = self.mie & !self.mideleg | val & self.mideleg,
+            STVEC => self.stvec = tvec(val),
+            SCOUNTEREN => self.scounteren = val & 0b111,
+            SSCRATCH => self.sscratch = val,
+            SEPC => self.sepc = val & !3,
+            SCAUSE => self.scause = val,
+            STVAL => self.stval = val,
+            SIP => {
+                let mask = SSI & self.mideleg;
+                self.mip = self.mip & !mask | val & mask;
+            }
+            SATP => self.satp = val,
+            MSTATUS => {
+                let mut val = val & MSTATUS_MASK;
+                // MPP is WARL, H-mode doesn't exist
+                if val & MPP == 2 << MPP_SHIFT {
+                    val = val & !MPP | self.mstatus & MPP;
+                }
+                self.mstatus = val;
+            }
+            MEDELEG => self.medeleg = val & DELEGABLE,
+            MIDELEG => self.mideleg = val & S_INTERRUPTS,
+            MIE_CSR => self.mie = val & (S_INTERRUPTS | MSI | MTI | MEI),
+            MTVEC => self.mtvec = tvec(val),
+            MCOUNTEREN => self.mcounteren = val & 0b111,
+            MSCRATCH => self.mscratch = val,
+            MEPC => self.mepc = val & !3,
+            MCAUSE => self.mcause = val,
+            MTVAL => self.mtval = val,
+            // the M-mode bits come from the interrupt controllers
+            MIP => self.mip = self.mip & !S_INTERRUPTS | val & S_INTERRUPTS,
+            PMPCFG0..=PMPCFG3 => self.pmp.set_cfg((csr - PMPCFG0) as usize, val),
+            PMPADDR0..=PMPADDR15 => self.pmp.set_addr((csr - PMPADDR0) as usize, val),
+            _ => {}
+        }
+        Some(())
+    }
+
+    // Whether a trap with 'cause' has a handler, a trap vector the guest
+    // set up. Without one the simulator handles it.
+    pub fn handles(&self, cause: u32) -> bool {
+        match self.delegated(cause) {
+            true => self.stvec != 0,
+            false => self.mtvec != 0,
+        }
+    }
+
+    fn delegated(&self, cause: u32) -> bool {
+        let deleg = match cause & INTERRUPT {
+            0 => self.medeleg,
+            _ => self.mideleg,
+        };
+        self.mode < MACHINE && deleg >> (cause & !INTERRUPT) & 1 == 1
+    }
+
+    // Enters the handler of a trap taken at 'pc', which has to exist,
+    // returns where it starts
+    pub fn trap(&mut self, cause: u32, tval: u32, pc: u32) -> u32 {
+        let code = cause & !INTERRUPT;
+        let tvec = if self.delegated(cause) {
+            self.scause = cause;
+            self.sepc = pc;
+            self.stval = tval;
+            let spp = if self.mode == USER { 0 } else { SPP };
+            let spie = if self.mstatus & SIE != 0 { SPIE } else { 0 };
+            self.mstatus = self.mstatus & !(SPP | SPIE | SIE) | spp | spie;
+            self.mode = SUPERVISOR;
+            self.stvec
+        } else {
+            self.mcause = cause;
+            self.mepc = pc;
+            self.mtval = tval;
+            let mpp = (self.mode as u32) << MPP_SHIFT;
+            let mpie = if self.mstatus & MIE != 0 { MPIE } else { 0 };
+            self.mstatus = self.mstatus & !(MPP | MPIE | MIE) | mpp | mpie;
+            self.mode = MACHINE;
+            self.mtvec
+        };
+        // vectored mode, interrupts go to base + 4 * cause
+        match (tvec & 1, cause & INTERRUPT) {
+            (1, INTERRUPT) => (tvec & !3) + 4 * code,
+            _ => tvec & !3,
+        }
+    }
+
+    // Returns to the mode in MPP at mepc, None if MRET is illegal
+    pub fn mret(&mut self) -> Option<u32> {
+        if self.mode != MACHINE {
+            return None;
+        }
+        self.mode = ((self.mstatus & MPP) >> MPP_SHIFT) as u8;
+        let mie = if self.mstatus & MPIE != 0 { MIE } else { 0 };
+        let mprv = if self.mode == MACHINE {
+            self.mstatus & MPRV
+        } else {
+            0
+        };
+        self.mstatus = self.mstatus & !(MIE | MPP | MPRV) | mie | MPIE | mprv;
+        Some(self.mepc)
+    }
+
+    // Returns to the mode in SPP at sepc, None if SRET is illegal
+    pub fn sret(&mut self) -> Option<u32> {
+        if self.mode == USER || self.mode == SUPERVISOR && self.mstatus & TSR != 0 {
+            return None;
+        }
+        self.mode = if self.mstatus & SPP != 0 {
+            SUPERVISOR
+        } else {
+            USER
+        };
+        let sie = if self.mstatus & SPIE != 0 { SIE } else { 0 };
+        self.mstatus = self.mstatus & !(SIE | SPP | MPRV) | sie | SPIE;
+        Some(self.sepc)
+    }
+
+    // WFI is illegal in U-mode, and in S-mode with TW set
+    pub fn wfi_allowed(&self) -> bool {
+        match self.mode {
+            USER => false,
+            SUPERVISOR => self.mstatus & TW == 0,
+            _ => true,
+        }
+    }
+
+    // SFENCE.VMA is illegal in U-mode, and in S-mode with TVM set
+    pub fn sfence_allowed(&self) -> bool {
+        match self.mode {
+            USER => false,
+            SUPERVISOR => self.mstatus & TVM == 0,
+            _ => true,
+        }
+    }
+
+    pub fn asid(&self) -> u16 {
+        (self.satp >> 22 & 0x1ff) as u16
+    }
+
+    // Physical page number of the root page table
+    pub fn root(&self) -> u32 {
+        self.satp & 0x3f_ffff
+    }
+
+    // Whether Sv32 translates fetches or, with MPRV, data accesses
+    #[inline]
+    pub fn paging(&self) -> bool {
+        self.satp >> 31 == 1 && (self.mode < MACHINE || self.data_mode() < MACHINE)
+    }
+
+    // Privilege of an access, None when it isn't translated
+    #[inline]
+    pub fn access_mode(&self, fetch: bool) -> Option<u8> {
+        let mode = self.privilege(fetch);
+        (self.satp >> 31 == 1 && mode < MACHINE).then_some(mode)
+    }
+
+    // Privilege mode of an access
+    #[inline]
+    pub fn privilege(&self, fetch: bool) -> u8 {
+        if fetch {
+            self.mode
+        } else {
+            self.data_mode()
+        }
+    }
+
+    pub fn pmp(&self) -> &Pmp {
+        &self.pmp
+    }
+
+    // Whether the PMP can deny any access, it never does for M-mode
+    // without locked entries
+    pub fn pmp_enforced(&self) -> bool {
+        self.mode < MACHINE || self.data_mode() < MACHINE || self.pmp.locked()
+    }
+
+    // Loads and stores of M-mode use MPP's privilege when MPRV is set
+    fn data_mode(&self) -> u8 {
+        match self.mode == MACHINE && self.mstatus & MPRV != 0 {
+            true => ((self.mstatus & MPP) >> MPP_SHIFT) as u8,
+            false => self.mode,
+        }
+    }
+
+    // S-mode may load and store user pages
+    pub fn sum(&self) -> bool {
+        self.mstatus & SUM != 0
+    }
+
+    // Loads may read execute-only pages
+    pub fn mxr(&self) -> bool {
+        self.mstatus & MXR != 0
+    }
+
+    // Levels of the MSI, MEI and SEI lines, as mip bits
+    pub fn lines(&self) -> u32 {
+        self.lines
+    }
+
+    // SEIP reads as the line or'ed with the bit software wrote
+    pub fn set_lines(&mut self, lines: u32) {
+        self.lines = lines & (MSI | MEI | SEI);
+    }
+
+    // Cause of the highest priority interrupt that is pending and enabled.
+    // M-mode interrupts preempt lower modes, delegated ones never stop M-mode.
+    pub fn interrupt(&self) -> Option<u32> {
+        let pending = (self.mip | self.lines) & self.mie;
+        if pending == 0 {
+            return None;
+        }
+        let mut enabled = 0;
+        if self.mode < MACHINE || self.mstatus & MIE != 0 {
+            enabled |= pending & !self.mideleg;
+        }
+        if self.mode < SUPERVISOR || self.mode == SUPERVISOR && self.mstatus & SIE != 0 {
+            enabled |= pending & self.mideleg;
+        }
+        INTERRUPTS
+            .iter()
+            .find(|&&bit| enabled & bit != 0)
+            .map(|bit| INTERRUPT | bit.trailing_zeros())
+    }
+
+    pub fn save(&self, w: &mut SnapshotWriter) {
+        w.u8(self.mode);
+        let mut csrs = *self;
+        for val in csrs.fields() {
+            w.u32(*val);
+        }
+        for val in csrs.pmp.fields() {
+            w.u32(*val);
+        }
+    }
+
+    pub fn load(r: &mut SnapshotReader) -> Result<Self, String> {
+        let mut csrs = Csrs {
+            mode: r.u8()?,
+            ..Csrs::default()
+        };
+        for val in csrs.fields() {
+            *val = r.u32()?;
+        }
+        for val in csrs.pmp.fields() {
+            *val = r.u32()?;
+        }
+        Ok(csrs)
+    }
+
+    fn fields(&mut self) -> [&mut u32; 19] {
+        [
+            &mut self.mstatus,
+            &mut self.medeleg,
+            &mut self.mideleg,
+            &mut self.mie,
+            &mut self.mip,
+            &mut self.mtvec,
+            &mut self.mcounteren,
+            &mut self.mscratch,
+            &mut self.mepc,
+            &mut self.mcause,
+            &mut self.mtval,
+            &mut self.stvec,
+            &mut self.scounteren,
+            &mut self.sscratch,
+            &mut self.sepc,
+            &mut self.scause,
+            &mut self.stval,
+            &mut self.satp,
+            &mut self.hartid,
+        ]
+    }
+}
+
+// Only the direct and vectored modes exist
+fn tvec(val: u32) -> u32 {
+    val & !2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupts_follow_enables_and_delegation() {
+        let mut csrs = Csrs::default();
+        csrs.write(MIP, SSI | STI | MTI).unwrap();
+        csrs.write(MIE_CSR, SSI | STI).unwrap();
+        // MTI comes from the timer, not software
+        assert_eq!(csrs.read(MIP, 0), Some(SSI | STI));
+        assert_eq!(csrs.interrupt(), None);
+        csrs.write(MSTATUS, MIE).unwrap();
+        assert_eq!(csrs.interrupt(), Some(INTERRUPT | 1));
+        // delegated interrupts never stop M-mode
+        csrs.write(MIDELEG, SSI | STI).unwrap();
+        assert_eq!(csrs.interrupt(), None);
+        csrs.mode = SUPERVISOR;
+        assert_eq!(csrs.interrupt(), None);
+        csrs.write(SSTATUS, SIE).unwrap();
+        assert_eq!(csrs.interrupt(), Some(INTERRUPT | 1));
+        csrs.mode = USER;
+        csrs.write(STVEC, 0x2001).unwrap();
+        assert!(csrs.handles(INTERRUPT | 5));
+        assert_eq!(csrs.trap(INTERRUPT | 5, 0, 0x1234), 0x2014);
+        assert_eq!(csrs.mode, SUPERVISOR);
+        assert_eq!(csrs.sepc, 0x1234);
+        assert_eq!(csrs.mstatus & (SIE | SPIE | SPP), SPIE);
+    }
+
+    #[test]
+    fn status_fields_and_returns() {
+        let mut csrs = Csrs::default();
+        csrs.write(MSTATUS, 1 << MPP_SHIFT | MPRV | MIE).unwrap();
+        // MPP is WARL, 2 keeps the old value
+        csrs.write(MSTATUS, 2 << MPP_SHIFT | MPRV).unwrap();
+        assert_eq!(csrs.mstatus, 1 << MPP_SHIFT | MPRV);
+        assert_eq!(csrs.write(MHARTID, 1), None);
+        assert_eq!(csrs.read(MSTATUS, 0), Some(csrs.mstatus));
+        csrs.write(MEPC, 0x1003).unwrap();
+        assert_eq!(csrs.mret(), Some(0x1000));
+        assert_eq!(csrs.mode, SUPERVISOR);
+        // returning below M-mode clears MPRV
+        assert_eq!(csrs.mstatus, MPIE);
+        assert_eq!(csrs.mret(), None);
+        assert_eq!(csrs.read(MSTATUS, 0), None);
+        assert_eq!(csrs.read(SSTATUS, 0), Some(0));
+        assert_eq!(csrs.read(CYCLE, 0), None);
+        csrs.mstatus |= TSR;
+        assert_eq!(csrs.sret(), None);
+        csrs.mstatus &= !TSR;
+        assert_eq!(csrs.sret(), Some(0));
+        assert_eq!(csrs.mode, USER);
+        assert!(!csrs.wfi_allowed());
+    }
+}
@@ -0,0 +1,750 @@
+use crate::disasm::disassemble_word;
+use crate::instruction::{Instruction, REGISTER_NAMES};
+use crate::machine::Machine;
+use crate::plic;
+use crate::record::{Journal, Session};
+use crate::symbols::Symbols;
+use crate::util::*;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+step|s [n]              execute n instructions (default 1)
+next|n                  step over calls
+finish                  run until the current function returns
+continue|c              run until a breakpoint, watchpoint or exit
+break|b <loc>           set breakpoint at address or symbol[+off]
+delete|d <n>            delete breakpoint n
+watch <addr> [len]      stop when len bytes (default 4) at addr change
+unwatch <n>             delete watchpoint n
+info|i b|w              list breakpoints or watchpoints
+regs|r [x|d|a]          dump registers as hex, decimal or ascii
+x <addr> [n] [x|d|a]    dump n words as hex, decimal or n bytes as ascii
+set <reg|pc> <val>      write a register
+write <addr> <val> [b|h|w]
+                        write a byte, half or word (default) to memory
+dis [n]                 disassemble n instructions around pc (default 5)
+record                  start recording for reverse execution
+reverse-step|rs [n]     undo n instructions (default 1)
+reverse-continue|rc     run backwards to a breakpoint or watchpoint
+checkpoint              fork the machine state, copy on write
+restart <n>             return to checkpoint n
+save <file>             write a snapshot of the machine to file
+irq <n> [0|1]           raise (default) or lower interrupt line n of the PLIC
+quit|q                  exit the debugger
+Locations and values: 0x10074, 66676, $sp, _start, puts+0x8";
+
+struct Watchpoint {
+    id: usize,
+    addr: u32,
+    len: usize,
+    old: Vec<u8>,
+}
+
+enum Stop {
+    Breakpoint(usize),
+    Watchpoint(usize, Vec<u8>, Vec<u8>),
+    Ebreak,
+    Exited(i32),
+    HistoryStart,
+}
+
+pub(crate) struct Debugger<'a> {
+    machine: &'a mut Machine,
+    symbols: Symbols,
+    breakpoints: Vec<(usize, u32)>, // id and address
+    next_breakpoint: usize,
+    watchpoints: Vec<Watchpoint>,
+    next_watchpoint: usize,
+    checkpoints: Vec<Machine>,
+    exit_code: Option<i32>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(machine: &'a mut Machine, symbols: Symbols) -> Self {
+        Debugger {
+            machine,
+            symbols,
+            breakpoints: vec![],
+            next_breakpoint: 0,
+            watchpoints: vec![],
+            next_watchpoint: 0,
+            checkpoints: vec![],
+            exit_code: None,
+        }
+    }
+
+    pub fn run(&mut self) -> i32 {
+        let stdin = io::stdin();
+        let mut last = String::new();
+        self.print_location();
+        loop {
+            print!("(dbg) ");
+            io::stdout().flush().unwrap();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            // empty line repeats the previous command
+            if line.trim().is_empty() {
+                line = last.clone();
+            } else {
+                last = line.clone();
+            }
+            let args: Vec<&str> = line.split_whitespace().collect();
+            if args.is_empty() {
+                continue;
+            }
+            match self.command(&args) {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(msg) => println!("{}", msg),
+            }
+        }
+        self.exit_code.unwrap_or(0)
+    }
+
+    // Returns true when the user wants to quit
+    fn command(&mut self, args: &[&str]) -> Result<bool, String> {
+        match args[0] {
+            "step" | "s" => {
+                let n = match args.get(1) {
+                    Some(n) => self.parse_value(n)?,
+                    None => 1,
+                };
+                self.check_running()?;
+                for _ in 0..n {
+                    if let Some(stop) = self.step_one().or_else(|| self.breakpoint_hit()) {
+                        self.report(stop);
+                        return Ok(false);
+                    }
+                }
+                self.print_location();
+            }
+            "next" | "n" => {
+                self.check_running()?;
+                let stop = self.next();
+                self.report_or_location(stop);
+            }
+            "finish" => {
+                self.check_running()?;
+                let stop = self.finish();
+                self.report_or_location(stop);
+            }
+            "continue" | "c" => {
+                self.check_running()?;
+                let stop = self.run_until(|_| false);
+                self.report_or_location(stop);
+            }
+            "record" => {
+                let mem = self.machine.mem_mut();
+                // like --record, refused when bookkeeping can't be undone
+                if mem.sanitizer.is_some() {
+                    return Err("Can't record, the heap sanitizer can't be undone".to_string());
+                }
+                if mem.races.is_some() {
+                    return Err("Can't record, the race detector can't be undone".to_string());
+                }
+                if mem.hart_count() > 1 {
+                    return Err("Can't record with more than one hart".to_string());
+                }
+                if mem.journal.is_none() {
+                    mem.journal = Some(Journal::default());
+                }
+            }
+            "reverse-step" | "rs" => {
+                let n = match args.get(1) {
+                    Some(n) => self.parse_value(n)?,
+                    None => 1,
+                };
+                self.check_recording()?;
+                for _ in 0..n {
+                    if let Some(stop) = self.undo_one() {
+                        self.report(stop);
+                        return Ok(false);
+                    }
+                }
+                self.print_location();
+            }
+            "reverse-continue" | "rc" => {
+                self.check_recording()?;
+                let stop = loop {
+                    if let Some(stop) = self.undo_one() {
+                        break Some(stop);
+                    }
+                    if let Some(stop) = self.breakpoint_hit() {
+                        break Some(stop);
+                    }
+                };
+                self.report_or_location(stop);
+            }
+            "checkpoint" => {
+                self.checkpoints.push(self.machine.fork());
+                println!("Checkpoint {}", self.checkpoints.len() - 1);
+            }
+            "restart" => {
+                let n = self.parse_value(arg(args, 1)?)? as usize;
+                let checkpoint = self
+                    .checkpoints
+                    .get(n)
+                    .ok_or_else(|| format!("No checkpoint {}", n))?;
+                *self.machine = checkpoint.fork();
+                self.exit_code = self.machine.mem().exit_code;
+                self.print_location();
+            }
+            "save" => self.machine.mem().save_snapshot(arg(args, 1)?)?,
+            "break" | "b" => {
+                let addr = self.parse_value(arg(args, 1)?)?;
+                // ids stay the same when others are deleted, like in GDB
+                let id = self.next_breakpoint;
+                self.next_breakpoint += 1;
+                self.breakpoints.push((id, addr));
+                println!(
+                    "Breakpoint {} at 0x{:08x} {}",
+                    id,
+                    addr,
+                    self.symbols.describe(addr)
+                );
+            }
+            "delete" | "d" => {
+                let n = self.parse_value(arg(args, 1)?)? as usize;
+                let i = self
+                    .breakpoints
+                    .iter()
+                    .position(|(id, _)| *id == n)
+                    .ok_or_else(|| format!("No breakpoint {}", n))?;
+                self.breakpoints.remove(i);
+            }
+            "watch" => {
+                let addr = self.parse_value(arg(args, 1)?)?;
+                let len = match args.get(2) {
+                    Some(len) => self.parse_value(len)? as usize,
+                    None => 4,
+                };
+                let old = self
+                    .machine
+                    .mem()
+                    .try_read(addr as usize, len)
+                    .ok_or_else(|| format!("Cannot access memory at 0x{:08x}", addr))?
+                    .to_vec();
+                // stable ids like breakpoints
+                let id = self.next_watchpoint;
+                self.next_watchpoint += 1;
+                self.watchpoints.push(Watchpoint { id, addr, len, old });
+                println!("Watchpoint {} at 0x{:08x} ({} bytes)", id, addr, len);
+            }
+            "unwatch" => {
+                let n = self.parse_value(arg(args, 1)?)? as usize;
+                let i = self
+                    .watchpoints
+                    .iter()
+                    .position(|w| w.id == n)
+                    .ok_or_else(|| format!("No watchpoint {}", n))?;
+                self.watchpoints.remove(i);
+            }
+            "info" | "i" => match arg(args, 1)? {
+                "b" | "break" => {
+                    for (id, addr) in self.breakpoints.iter() {
+                        println!("{:<3} 0x{:08x} {}", id, addr, self.symbols.describe(*addr));
+                    }
+                }
+                "w" | "watch" => {
+                    for w in self.watchpoints.iter() {
+                        println!("{:<3} 0x{:08x} {} bytes", w.id, w.addr, w.len);
+                    }
+                }
+                other => return Err(format!("Unknown info '{}'", other)),
+            },
+            "regs" | "r" => self.print_registers(args.get(1).copied().unwrap_or("")),
+            "x" => {
+                let addr = self.parse_value(arg(args, 1)?)?;
+                let count = match args.get(2) {
+                    Some(n) => self.parse_value(n)? as usize,
+                    None => 4,
+                };
+                self.dump_memory(addr, count, args.get(3).copied().unwrap_or("x"))?;
+            }
+            "set" => {
+                let val = self.parse_value(arg(args, 2)?)?;
+                match arg(args, 1)?.trim_start_matches('$') {
+                    "pc" => self.machine.mem_mut().set_pc(val),
+                    name => {
+                        let ind = parse_register(name)
+                            .ok_or_else(|| format!("Unknown register '{}'", name))?;
+                        self.machine.mem_mut().set_register(val, ind);
+                    }
+                }
+            }
+            "write" => {
+                let addr = self.parse_value(arg(args, 1)?)?;
+                let val = from_u32(self.parse_value(arg(args, 2)?)?);
+                let len = match args.get(3).copied().unwrap_or("w") {
+                    "b" => 1,
+                    "h" => 2,
+                    "w" => 4,
+                    other => return Err(format!("Unknown size '{}'", other)),
+                };
+                if !self.machine.mem_mut().try_write(addr as usize, &val[..len]) {
+                    return Err(format!("Cannot access memory at 0x{:08x}", addr));
+                }
+            }
+            "dis" => {
+                let n = match args.get(1) {
+                    Some(n) => self.parse_value(n)?,
+                    None => 5,
+                };
+                // the window has to fit in the address space
+                if n >= 1 << 29 {
+                    return Err(format!("Can't disassemble {} instructions around pc", n));
+                }
+                let pc = self.machine.mem().get_pc();
+                for i in 0..(2 * n + 1) {
+                    let addr = pc.wrapping_sub(4 * n).wrapping_add(4 * i);
+                    let marker = if addr == pc { "=>" } else { "  " };
+                    println!("{} {}", marker, self.format_instruction(addr));
+                }
+            }
+            "irq" => {
+                let source = self.parse_value(arg(args, 1)?)? as usize;
+                if !(1..plic::SOURCES).contains(&source) {
+                    return Err(format!("Interrupt lines are 1 to {}", plic::SOURCES - 1));
+                }
+                // a replay raises the ones in its recording
+                if let Some(Session::Replay(..)) = self.machine.mem().session {
+                    return Err("Can't raise interrupts while replaying".to_string());
+                }
+                let high = args.get(2).copied().unwrap_or("1") != "0";
+                self.machine.mem_mut().set_irq(source, high);
+            }
+            "help" | "h" => println!("{}", HELP),
+            "quit" | "q" => return Ok(true),
+            other => return Err(format!("Unknown command '{}', try 'help'", other)),
+        }
+        Ok(false)
+    }
+
+    fn check_running(&self) -> Result<(), String> {
+        match self.exit_code {
+            Some(code) => Err(format!("The program has exited with code {}", code)),
+            None => Ok(()),
+        }
+    }
+
+    fn check_recording(&self) -> Result<(), String> {
+        match &self.machine.mem().journal {
+            Some(_) => Ok(()),
+            None => Err("Not recording, start with 'record' or --record".to_string()),
+        }
+    }
+
+    // Reverts one instruction, stops at the start of the history
+    fn undo_one(&mut self) -> Option<Stop> {
+        if self.machine.mem_mut().undo_step().is_none() {
+            return Some(Stop::HistoryStart);
+        }
+        self.exit_code = self.machine.mem().exit_code;
+        self.check_watchpoints()
+    }
+
+    fn check_watchpoints(&mut self) -> Option<Stop> {
+        for w in self.watchpoints.iter_mut() {
+            let cur = self
+                .machine
+                .mem()
+                .try_read(w.addr as usize, w.len)
+                .map(|b| b.to_vec())
+                .unwrap_or_default();
+            if cur != w.old {
+                let old = std::mem::replace(&mut w.old, cur.clone());
+                return Some(Stop::Watchpoint(w.id, old, cur));
+            }
+        }
+        None
+    }
+
+    fn peek(&self) -> Option<Instruction> {
+        let mem = self.machine.mem();
+        let bytes = mem.try_read(mem.get_pc() as usize, 4)?;
+        Instruction::try_new(to_u32(&bytes))
+    }
+
+    // Executes one instruction and checks exit, ebreak and watchpoints
+    fn step_one(&mut self) -> Option<Stop> {
+        let ebreak = matches!(self.peek(), Some(Instruction::EBREAK));
+        if let Some(code) = self.machine.step() {
+            self.exit_code = Some(code);
+            return Some(Stop::Exited(code));
+        }
+        if let Some(stop) = self.check_watchpoints() {
+            return Some(stop);
+        }
+        if ebreak {
+            return Some(Stop::Ebreak);
+        }
+        None
+    }
+
+    // Steps until 'done' returns true for the state after an instruction,
+    // or until a breakpoint, watchpoint or exit
+    fn run_until<F: FnMut(&mut Self) -> bool>(&mut self, mut done: F) -> Option<Stop> {
+        loop {
+            if let Some(stop) = self.step_one() {
+                return Some(stop);
+            }
+            if done(self) {
+                return None;
+            }
+            if let Some(stop) = self.breakpoint_hit() {
+                return Some(stop);
+            }
+        }
+    }
+
+    fn next(&mut self) -> Option<Stop> {
+        let mem = self.machine.mem();
+        let (pc, sp) = (mem.get_pc(), mem.get_register(2));
+        if is_call(self.peek()) {
+            self.run_until(|d| {
+                let mem = d.machine.mem();
+                mem.get_pc() == pc.wrapping_add(4) && mem.get_register(2) >= sp
+            })
+        } else {
+            self.step_one()
+        }
+    }
+
+    fn finish(&mut self) -> Option<Stop> {
+        let mut depth = 0;
+        loop {
+            let inst = self.peek();
+            if let Some(stop) = self.step_one() {
+                return Some(stop);
+            }
+            if is_call(inst) {
+                depth += 1;
+            } else if is_ret(inst) {
+                if depth == 0 {
+                    return None;
+                }
+                depth -= 1;
+            }
+            if let Some(stop) = self.breakpoint_hit() {
+                return Some(stop);
+            }
+        }
+    }
+
+    fn breakpoint_hit(&self) -> Option<Stop> {
+        let pc = self.machine.mem().get_pc();
+        self.breakpoints
+            .iter()
+            .find(|(_, addr)| *addr == pc)
+            .map(|(id, _)| Stop::Breakpoint(*id))
+    }
+
+    fn report(&self, stop: Stop) {
+        match stop {
+            Stop::Breakpoint(i) => println!("Breakpoint {}", i),
+            Stop::Watchpoint(i, old, new) => {
+                println!("Watchpoint {}: {:02x?} -> {:02x?}", i, old, new)
+            }
+            Stop::Ebreak => println!("EBREAK"),
+            Stop::HistoryStart => println!("Reached the start of the recording"),
+            Stop::Exited(code) => {
+                println!("The program exited with code {}", code);
+                return;
+            }
+        }
+        self.print_location();
+    }
+
+    fn report_or_location(&self, stop: Option<Stop>) {
+        match stop {
+            Some(stop) => self.report(stop),
+            None => self.print_location(),
+        }
+    }
+
+    fn print_location(&self) {
+        let pc = self.machine.mem().get_pc();
+        println!("=> {}", self.format_instruction(pc));
+    }
+
+    fn format_instruction(&self, addr: u32) -> String {
+        let location = format!("0x{:08x} {}", addr, self.symbols.describe(addr));
+        match self.machine.mem().try_read(addr as usize, 4) {
+            Some(bytes) => {
+                let raw = to_u32(&bytes);
+                let text = disassemble_word(raw, addr, Some(&self.symbols));
+                format!("{}: {:08x}  {}", location, raw, text.replace('\t', " "))
+            }
+            None => format!("{}: <unmapped>", location),
+        }
+    }
+
+    fn print_registers(&self, fmt: &str) {
+        let mem = self.machine.mem();
+        println!(
+            "pc   0x{:08x} {}",
+            mem.get_pc(),
+            self.symbols.describe(mem.get_pc())
+        );
+        for (i, name) in REGISTER_NAMES.iter().enumerate() {
+            let val = mem.get_register(i as u8);
+            let text = match fmt {
+                "x" => format!("0x{:08x}", val),
+                "d" => format!("{}", val as i32),
+                "a" => ascii(&from_u32(val)),
+                _ => format!("0x{:08x} {:>11}", val, val as i32),
+            };
+            println!("{:<4} {:<4} {}", format!("x{}", i), name, text);
+        }
+    }
+
+    fn dump_memory(&self, addr: u32, count: usize, fmt: &str) -> Result<(), String> {
+        let mem = self.machine.mem();
+        let len = if fmt == "a" { count } else { count * 4 };
+        let bytes = mem
+            .try_read(addr as usize, len)
+            .ok_or_else(|| format!("Cannot access memory at 0x{:08x}", addr))?;
+        match fmt {
+            "a" => {
+                for (i, line) in bytes.chunks(16).enumerate() {
+                    let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+                    println!(
+                        "0x{:08x}: {:<47}  |{}|",
+                        addr as usize + i * 16,
+                        hex.join(" "),
+                        ascii(line)
+                    );
+                }
+            }
+            "x" | "d" => {
+                for (i, line) in bytes.chunks(16).enumerate() {
+                    let words: Vec<String> = line
+                        .chunks(4)
+                        .map(|w| match fmt {
+                            "x" => format!("0x{:08x}", to_u32(w)),
+                            _ => format!("{:>11}", to_u32(w) as i32),
+                        })
+                        .collect();
+                    println!("0x{:08x}: {}", addr as usize + i * 16, words.join(" "));
+                }
+            }
+            other => return Err(format!("Unknown format '{}'", other)),
+        }
+        Ok(())
+    }
+
+    fn parse_value(&self, text: &str) -> Result<u32, String> {
+        if let Some(name) = text.strip_prefix('$') {
+            if name == "pc" {
+                return Ok(self.machine.mem().get_pc());
+            }
+            return parse_register(name)
+                .map(|i| self.machine.mem().get_register(i))
+                .ok_or_else(|| format!("Unknown register '{}'", name));
+        }
+        if let Some(val) = parse_number(text) {
+            return Ok(val);
+        }
+        let (name, off) = match text.find('+') {
+            Some(i) => (
+                &text[..i],
+                parse_number(&text[i + 1..]).ok_or_else(|| format!("Bad offset '{}'", text))?,
+            ),
+            None => (text, 0),
+        };
+        self.symbols
+            .lookup(name)
+            .map(|addr| addr.wrapping_add(off))
+            .ok_or_else(|| format!("No symbol '{}'", name))
+    }
+}
+
+fn arg<'a>(args: &[&'a str], i: usize) -> Result<&'a str, String> {
+    args.get(i)
+        .copied()
+        .ok_or_else(|| format!("'{}' needs more arguments, try 'help'", args[0]))
+}
+
+fn parse_register(name: &str) -> Option<u8> {
+    if let Some(i) = REGISTER_NAMES.iter().position(|n| *n == name) {
+        return Some(i as u8);
+    }
+    if name == "fp" {
+        return Some(8);
+    }
+    match name.strip_prefix('x')?.parse::<u8>() {
+        Ok(i) if i < 32 => Some(i),
+        _ => None,
+    }
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+fn is_call(inst: Option<Instruction>) -> bool {
+    matches!(
+        inst,
+        Some(Instruction::JAL { rd: 1, .. }) | Some(Instruction::JALR { rd: 1, .. })
+    )
+}
+
+fn is_ret(inst: Option<Instruction>) -> bool {
+    matches!(
+        inst,
+        Some(Instruction::JALR {
+            rd: 0,
+            rs1: 1,
+            imm: 0
+        })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use crate::symbols::Symbol;
+
+    const PC: u32 = 0x1000;
+
+    // _start calls func, which adds 10 to a0, and exits with 17
+    fn program() -> Machine {
+        let code = [
+            0x1f400893, // li a7,500
+            0x00100513, // li a0,1
+            0x010000ef, // jal 1018 <func>
+            0x00250513, // addi a0,a0,2
+            0x00450513, // addi a0,a0,4
+            0x00000073, // ecall
+            0x00a50513, // addi a0,a0,10
+            0x00008067, // ret
+        ];
+        let bytes: Vec<u8> = code.iter().flat_map(|w: &u32| w.to_le_bytes()).collect();
+        Machine::new(Memory::with_code(PC, &bytes))
+    }
+
+    fn symbols() -> Symbols {
+        let symbol = |name: &str, addr, size| Symbol {
+            name: name.to_string(),
+            addr,
+            size,
+        };
+        Symbols::from_list(vec![symbol("_start", PC, 24), symbol("func", PC + 24, 8)])
+    }
+
+    fn run(debugger: &mut Debugger, line: &str) -> Result<bool, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        debugger.command(&args)
+    }
+
+    #[test]
+    fn breakpoints_keep_their_ids() {
+        let mut machine = program();
+        let mut debugger = Debugger::new(&mut machine, symbols());
+        run(&mut debugger, "break 0x1010").unwrap();
+        run(&mut debugger, "break func").unwrap();
+        run(&mut debugger, "delete 0").unwrap();
+        assert_eq!(debugger.breakpoints, vec![(1, PC + 24)]);
+        assert!(run(&mut debugger, "delete 0").is_err());
+        run(&mut debugger, "continue").unwrap();
+        assert_eq!(debugger.machine.mem().get_pc(), PC + 24);
+        run(&mut debugger, "delete 1").unwrap();
+        run(&mut debugger, "continue").unwrap();
+        assert_eq!(debugger.exit_code, Some(17));
+        assert!(run(&mut debugger, "step").is_err());
+        // and so do watchpoints
+        let mut machine = program();
+        let mut debugger = Debugger::new(&mut machine, symbols());
+        run(&mut debugger, "watch 0x1000").unwrap();
+        run(&mut debugger, "watch 0x1004").unwrap();
+        run(&mut debugger, "unwatch 0").unwrap();
+        assert!(run(&mut debugger, "unwatch 0").is_err());
+        let ids: Vec<usize> = debugger.watchpoints.iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![1]);
+        run(&mut debugger, "unwatch 1").unwrap();
+        assert!(debugger.watchpoints.is_empty());
+    }
+
+    #[test]
+    fn steps_stop_at_breakpoints() {
+        let mut machine = program();
+        let mut debugger = Debugger::new(&mut machine, symbols());
+        run(&mut debugger, "break func").unwrap();
+        run(&mut debugger, "step 10").unwrap();
+        assert_eq!(debugger.machine.mem().get_pc(), PC + 24);
+        assert_eq!(debugger.exit_code, None);
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let mut machine = program();
+        let mut debugger = Debugger::new(&mut machine, symbols());
+        run(&mut debugger, "step 2").unwrap();
+        assert_eq!(debugger.machine.mem().get_pc(), PC + 8);
+        run(&mut debugger, "next").unwrap();
+        let mem = debugger.machine.mem();
+        assert_eq!((mem.get_pc(), mem.get_register(10)), (PC + 12, 11));
+        run(&mut debugger, "n").unwrap();
+        assert_eq!(debugger.machine.mem().get_pc(), PC + 16);
+        // finish from inside func returns right after the call
+        let mut machine = program();
+        let mut debugger = Debugger::new(&mut machine, symbols());
+        run(&mut debugger, "s 3").unwrap();
+        assert_eq!(debugger.machine.mem().get_pc(), PC + 24);
+        run(&mut debugger, "finish").unwrap();
+        assert_eq!(debugger.machine.mem().get_pc(), PC + 12);
+    }
+
+    #[test]
+    fn record_refuses_what_it_cannot_undo() {
+        let mut machine = program();
+        machine.mem_mut().init_heap(1 << 16, 16);
+        machine.mem_mut().enable_sanitizer();
+        let mut debugger = Debugger::new(&mut machine, symbols());
+        assert!(run(&mut debugger, "record").is_err());
+        assert!(debugger.machine.mem().journal.is_none());
+        let mut machine = program();
+        machine.mem_mut().init_harts(2);
+        let mut debugger = Debugger::new(&mut machine, symbols());
+        let err = run(&mut debugger, "record").unwrap_err();
+        assert!(err.contains("more than one hart"));
+        assert!(debugger.machine.mem().journal.is_none());
+        let mut machine = program();
+        machine.mem_mut().enable_race_detector();
+        let mut debugger = Debugger::new(&mut machine, symbols());
+        assert!(run(&mut debugger, "record").is_err());
+        assert!(debugger.machine.mem().journal.is_none());
+    }
+
+    #[test]
+    fn dis_refuses_windows_that_wrap() {
+        let mut machine = program();
+        let mut debugger = Debugger::new(&mut machine, symbols());
+        run(&mut debugger, "dis 2").unwrap();
+        assert!(run(&mut debugger, "dis 0x40000000").is_err());
+        assert!(run(&mut debugger, "dis 0xffffffff").is_err());
+    }
+
+    #[test]
+    fn irq_refused_in_replays() {
+        let mut machine = program();
+        let mut debugger = Debugger::new(&mut machine, symbols());
+        run(&mut debugger, "irq 1").unwrap();
+        debugger.machine.mem_mut().session = Some(Session::Record(vec![]));
+        run(&mut debugger, "irq 1 0").unwrap();
+        debugger.machine.mem_mut().session = Some(Session::Replay(vec![], 0));
+        assert!(run(&mut debugger, "irq 1").is_err());
+    }
+}
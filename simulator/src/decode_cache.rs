@@ -0,0 +1,98 @@
+use crate::instruction::Instruction;
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+const PAGE_BITS: u32 = 12;
+const WORDS: usize = 1 << (PAGE_BITS - 2);
+
+// Decoded instructions of the code pages executed so far. Writes drop the
+// words they overwrite, so self-modifying code is seen without a FENCE.I.
+#[derive(Debug, Default)]
+pub(crate) struct DecodeCache {
+    pages: Vec<Page>,
+    index: HashMap<u32, usize, BuildHasherDefault<IntHasher>>,
+    last: usize, // page of the previous fetch, loops rarely leave it
+}
+
+#[derive(Debug)]
+struct Page {
+    number: u32,
+    insts: Box<[Option<Instruction>; WORDS]>,
+}
+
+impl DecodeCache {
+    // 'pc' must be word aligned
+    pub fn get(&mut self, pc: u32) -> Option<Instruction> {
+        let number = pc >> PAGE_BITS;
+        let slot = (pc as usize >> 2) & (WORDS - 1);
+        if self.pages.get(self.last).map(|p| p.number) != Some(number) {
+            self.last = self.find(number)?;
+        }
+        self.pages[self.last].insts[slot]
+    }
+
+    #[cold]
+    fn find(&self, number: u32) -> Option<usize> {
+        self.index.get(&number).copied()
+    }
+
+    pub fn insert(&mut self, pc: u32, inst: Instruction) {
+        let number = pc >> PAGE_BITS;
+        let pages = &mut self.pages;
+        let i = *self.index.entry(number).or_insert_with(|| {
+            pages.push(Page {
+                number,
+                insts: Box::new([None; WORDS]),
+            });
+            pages.len() - 1
+        });
+        self.last = i;
+        self.pages[i].insts[(pc as usize >> 2) & (WORDS - 1)] = Some(inst);
+    }
+
+    // Drops the instructions overlapping 'len' bytes at 'addr', true if
+    // there were any
+    pub fn invalidate(&mut self, addr: usize, len: usize) -> bool {
+        let mut dropped = false;
+        if self.pages.is_empty() || len == 0 {
+            return dropped;
+        }
+        let mut word = addr >> 2;
+        while word <= (addr + len - 1) >> 2 {
+            let number = (word >> (PAGE_BITS - 2)) as u32;
+            match self.index.get(&number) {
+                Some(&i) => dropped |= self.pages[i].insts[word & (WORDS - 1)].take().is_some(),
+                // skip to the next page
+                None => word |= WORDS - 1,
+            }
+            word += 1;
+        }
+        dropped
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.index.clear();
+        self.last = 0;
+    }
+}
+
+// Page numbers and pcs are plain integers, SipHash would dominate a lookup
+#[derive(Default)]
+pub(crate) struct IntHasher(u64);
+
+impl Hasher for IntHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 << 8 | *b as u64).wrapping_mul(0x9e3779b97f4a7c15);
+        }
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.0 = (n as u64).wrapping_mul(0x9e3779b97f4a7c15);
+    }
+}
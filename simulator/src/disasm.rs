@@ -0,0 +1,265 @@
+use crate::csr;
+use crate::instruction::{Instruction, REGISTER_NAMES};
+use crate::symbols::Symbols;
+use crate::util::*;
+use elfloader::ElfBinary;
+
+// Renders 'inst' like GNU objdump does, including its pseudo-instructions.
+// Branch and jump targets are resolved relative to 'pc'.
+pub(crate) fn disassemble(inst: &Instruction, pc: u32, symbols: Option<&Symbols>) -> String {
+    use Instruction::*;
+    let r = |i: u8| REGISTER_NAMES[i as usize];
+    let target = |imm: i32| {
+        let addr = (pc as i32).wrapping_add(imm) as u32;
+        match symbols.map(|s| s.describe(addr)) {
+            Some(name) if !name.is_empty() => format!("{:x} {}", addr, name),
+            _ => format!("{:x}", addr),
+        }
+    };
+    let op = |name: &str, args: String| format!("{}\t{}", name, args);
+    match *inst {
+        LUI { imm, rd } => op("lui", format!("{},0x{:x}", r(rd), (imm as u32) >> 12)),
+        AUIPC { imm, rd } => op("auipc", format!("{},0x{:x}", r(rd), (imm as u32) >> 12)),
+        JAL { imm, rd: 0 } => op("j", target(imm)),
+        JAL { imm, rd: 1 } => op("jal", target(imm)),
+        JAL { imm, rd } => op("jal", format!("{},{}", r(rd), target(imm))),
+        JALR {
+            imm: 0,
+            rd: 0,
+            rs1: 1,
+        } => "ret".to_string(),
+        JALR { imm: 0, rd: 0, rs1 } => op("jr", r(rs1).to_string()),
+        JALR { imm: 0, rd: 1, rs1 } => op("jalr", r(rs1).to_string()),
+        JALR { imm, rd, rs1 } => op("jalr", format!("{},{}({})", r(rd), imm, r(rs1))),
+        BEQ { imm, rs1, rs2: 0 } => op("beqz", format!("{},{}", r(rs1), target(imm))),
+        BNE { imm, rs1, rs2: 0 } => op("bnez", format!("{},{}", r(rs1), target(imm))),
+        BLT { imm, rs1, rs2: 0 } => op("bltz", format!("{},{}", r(rs1), target(imm))),
+        BGE { imm, rs1, rs2: 0 } => op("bgez", format!("{},{}", r(rs1), target(imm))),
+        BLT { imm, rs1: 0, rs2 } => op("bgtz", format!("{},{}", r(rs2), target(imm))),
+        BGE { imm, rs1: 0, rs2 } => op("blez", format!("{},{}", r(rs2), target(imm))),
+        BEQ { imm, rs1, rs2 } => branch("beq", rs1, rs2, target(imm)),
+        BNE { imm, rs1, rs2 } => branch("bne", rs1, rs2, target(imm)),
+        BLT { imm, rs1, rs2 } => branch("blt", rs1, rs2, target(imm)),
+        BGE { imm, rs1, rs2 } => branch("bge", rs1, rs2, target(imm)),
+        BLTU { imm, rs1, rs2 } => branch("bltu", rs1, rs2, target(imm)),
+        BGEU { imm, rs1, rs2 } => branch("bgeu", rs1, rs2, target(imm)),
+        LB { imm, rs1, rd } => mem_op("lb", rd, imm, rs1),
+        LH { imm, rs1, rd } => mem_op("lh", rd, imm, rs1),
+        LW { imm, rs1, rd } => mem_op("lw", rd, imm, rs1),
+        LBU { imm, rs1, rd } => mem_op("lbu", rd, imm, rs1),
+        LHU { imm, rs1, rd } => mem_op("lhu", rd, imm, rs1),
+        SB { imm, rs1, rs2 } => mem_op("sb", rs2, imm, rs1),
+        SH { imm, rs1, rs2 } => mem_op("sh", rs2, imm, rs1),
+        SW { imm, rs1, rs2 } => mem_op("sw", rs2, imm, rs1),
+        ADDI {
+            imm: 0,
+            rs1: 0,
+            rd: 0,
+        } => "nop".to_string(),
+        ADDI { imm, rs1: 0, rd } => op("li", format!("{},{}", r(rd), imm)),
+        ADDI { imm: 0, rs1, rd } => op("mv", format!("{},{}", r(rd), r(rs1))),
+        ADDI { imm, rs1, rd } => imm_op("addi", rd, rs1, imm),
+        SLTI { imm, rs1, rd } => imm_op("slti", rd, rs1, imm),
+        SLTIU { imm: 1, rs1, rd } => op("seqz", format!("{},{}", r(rd), r(rs1))),
+        SLTIU { imm, rs1, rd } => imm_op("sltiu", rd, rs1, imm),
+        XORI { imm: -1, rs1, rd } => op("not", format!("{},{}", r(rd), r(rs1))),
+        XORI { imm, rs1, rd } => imm_op("xori", rd, rs1, imm),
+        ORI { imm, rs1, rd } => imm_op("ori", rd, rs1, imm),
+        ANDI { imm, rs1, rd } => imm_op("andi", rd, rs1, imm),
+        SLLI { shift, rs1, rd } => shift_op("slli", rd, rs1, shift),
+        SRLI { shift, rs1, rd } => shift_op("srli", rd, rs1, shift),
+        SRAI { shift, rs1, rd } => shift_op("srai", rd, rs1, shift),
+        ADD { rs1, rs2, rd } => reg_op("add", rd, rs1, rs2),
+        SUB { rs1: 0, rs2, rd } => op("neg", format!("{},{}", r(rd), r(rs2))),
+        SUB { rs1, rs2, rd } => reg_op("sub", rd, rs1, rs2),
+        SLL { rs1, rs2, rd } => reg_op("sll", rd, rs1, rs2),
+        SLT { rs1, rs2: 0, rd } => op("sltz", format!("{},{}", r(rd), r(rs1))),
+        SLT { rs1: 0, rs2, rd } => op("sgtz", format!("{},{}", r(rd), r(rs2))),
+        SLT { rs1, rs2, rd } => reg_op("slt", rd, rs1, rs2),
+        SLTU { rs1: 0, rs2, rd } => op("snez", format!("{},{}", r(rd), r(rs2))),
+        SLTU { rs1, rs2, rd } => reg_op("sltu", rd, rs1, rs2),
+        XOR { rs1, rs2, rd } => reg_op("xor", rd, rs1, rs2),
+        SRL { rs1, rs2, rd } => reg_op("srl", rd, rs1, rs2),
+        SRA { rs1, rs2, rd } => reg_op("sra", rd, rs1, rs2),
+        OR { rs1, rs2, rd } => reg_op("or", rd, rs1, rs2),
+        AND { rs1, rs2, rd } => reg_op("and", rd, rs1, rs2),
+        FENCE {
+            pred: 0b1111,
+            succ: 0b1111,
+        } => "fence".to_string(),
+        FENCE { pred, succ } => op("fence", format!("{},{}", fence_set(pred), fence_set(succ))),
+        FENCE_I => "fence.i".to_string(),
+        ECALL => "ecall".to_string(),
+        EBREAK => "ebreak".to_string(),
+        CSRRS { csr, rs1: 0, rd } => op("csrr", format!("{},{}", r(rd), csr_name(csr))),
+        CSRRW { csr, rs1, rd: 0 } => op("csrw", format!("{},{}", csr_name(csr), r(rs1))),
+        CSRRS { csr, rs1, rd: 0 } => op("csrs", format!("{},{}", csr_name(csr), r(rs1))),
+        CSRRC { csr, rs1, rd: 0 } => op("csrc", format!("{},{}", csr_name(csr), r(rs1))),
+        CSRRWI { csr, uimm, rd: 0 } => op("csrwi", format!("{},{}", csr_name(csr), uimm)),
+        CSRRSI { csr, uimm, rd: 0 } => op("csrsi", format!("{},{}", csr_name(csr), uimm)),
+        CSRRCI { csr, uimm, rd: 0 } => op("csrci", format!("{},{}", csr_name(csr), uimm)),
+        CSRRW { csr, rs1, rd } => csr_op("csrrw", rd, csr, r(rs1).to_string()),
+        CSRRS { csr, rs1, rd } => csr_op("csrrs", rd, csr, r(rs1).to_string()),
+        CSRRC { csr, rs1, rd } => csr_op("csrrc", rd, csr, r(rs1).to_string()),
+        CSRRWI { csr, uimm, rd } => csr_op("csrrwi", rd, csr, uimm.to_string()),
+        CSRRSI { csr, uimm, rd } => csr_op("csrrsi", rd, csr, uimm.to_string()),
+        CSRRCI { csr, uimm, rd } => csr_op("csrrci", rd, csr, uimm.to_string()),
+        MRET => "mret".to_string(),
+        SRET => "sret".to_string(),
+        WFI => "wfi".to_string(),
+        SFENCE_VMA { rs1: 0, rs2: 0 } => "sfence.vma".to_string(),
+        SFENCE_VMA { rs1, rs2: 0 } => op("sfence.vma", r(rs1).to_string()),
+        SFENCE_VMA { rs1, rs2 } => op("sfence.vma", format!("{},{}", r(rs1), r(rs2))),
+        LR_W { rs1, rd, aqrl } => op(&ordered("lr.w", aqrl), format!("{},({})", r(rd), r(rs1))),
+        SC_W { rs1, rs2, rd, aqrl } => amo_op(&ordered("sc.w", aqrl), rd, rs2, rs1),
+        AMO {
+            op,
+            rs1,
+            rs2,
+            rd,
+            aqrl,
+        } => amo_op(&ordered(op.name(), aqrl), rd, rs2, rs1),
+    }
+}
+
+// Disassembles the raw word at 'pc', falling back to a .word directive
+pub(crate) fn disassemble_word(word: u32, pc: u32, symbols: Option<&Symbols>) -> String {
+    match Instruction::try_new(word) {
+        Some(inst) => disassemble(&inst, pc, symbols),
+        None => format!(".word\t0x{:08x}", word),
+    }
+}
+
+// objdump -d style listing of one ELF section
+pub(crate) fn disassemble_section(
+    binary: &ElfBinary,
+    name: &str,
+    symbols: &Symbols,
+) -> Result<String, String> {
+    let section = binary
+        .file
+        .find_section_by_name(name)
+        .ok_or_else(|| format!("No section '{}'", name))?;
+    let start = section.address() as u32;
+    let data = section.raw_data(&binary.file);
+    let mut out = format!("Disassembly of section {}:\n", name);
+    for (i, word) in data.chunks(4).enumerate() {
+        let addr = start + 4 * i as u32;
+        if let Some((sym, 0)) = symbols.find(addr) {
+            out += &format!("\n{:08x} <{}>:\n", addr, sym.name);
+        }
+        if word.len() < 4 {
+            break;
+        }
+        let raw = to_u32(word);
+        out += &format!(
+            "{:8x}:\t{:08x}          \t{}\n",
+            addr,
+            raw,
+            disassemble_word(raw, addr, Some(symbols))
+        );
+    }
+    Ok(out)
+}
+
+fn branch(name: &str, rs1: u8, rs2: u8, target: String) -> String {
+    let r = |i: u8| REGISTER_NAMES[i as usize];
+    format!("{}\t{},{},{}", name, r(rs1), r(rs2), target)
+}
+
+fn mem_op(name: &str, reg: u8, imm: i32, base: u8) -> String {
+    let r = |i: u8| REGISTER_NAMES[i as usize];
+    format!("{}\t{},{}({})", name, r(reg), imm, r(base))
+}
+
+// Suffixes the acquire and release bits of an atomic
+fn ordered(name: &str, aqrl: u8) -> String {
+    let suffix = ["", ".rl", ".aq", ".aqrl"][aqrl as usize];
+    format!("{}{}", name, suffix)
+}
+
+fn amo_op(name: &str, rd: u8, rs2: u8, rs1: u8) -> String {
+    let r = |i: u8| REGISTER_NAMES[i as usize];
+    format!("{}\t{},{},({})", name, r(rd), r(rs2), r(rs1))
+}
+
+fn imm_op(name: &str, rd: u8, rs1: u8, imm: i32) -> String {
+    let r = |i: u8| REGISTER_NAMES[i as usize];
+    format!("{}\t{},{},{}", name, r(rd), r(rs1), imm)
+}
+
+fn shift_op(name: &str, rd: u8, rs1: u8, shift: u8) -> String {
+    let r = |i: u8| REGISTER_NAMES[i as usize];
+    format!("{}\t{},{},0x{:x}", name, r(rd), r(rs1), shift)
+}
+
+fn csr_name(csr: u16) -> String {
+    match csr::name(csr) {
+        Some(name) => name.to_string(),
+        None => format!("0x{:x}", csr),
+    }
+}
+
+fn csr_op(name: &str, rd: u8, csr: u16, src: String) -> String {
+    let r = |i: u8| REGISTER_NAMES[i as usize];
+    format!("{}\t{},{},{}", name, r(rd), csr_name(csr), src)
+}
+
+fn fence_set(set: u8) -> String {
+    let names: String = "iorw"
+        .chars()
+        .enumerate()
+        .filter(|(i, _)| set & (0b1000 >> i) != 0)
+        .map(|(_, c)| c)
+        .collect();
+    if names.is_empty() {
+        "0".to_string()
+    } else {
+        names
+    }
+}
+
+fn reg_op(name: &str, rd: u8, rs1: u8, rs2: u8) -> String {
+    let r = |i: u8| REGISTER_NAMES[i as usize];
+    format!("{}\t{},{},{}", name, r(rd), r(rs1), r(rs2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::Symbol;
+
+    #[test]
+    fn matches_objdump() {
+        let symbols = Symbols::from_list(vec![Symbol {
+            name: "func".to_string(),
+            addr: 0x1000,
+            size: 0x20,
+        }]);
+        // at pc 0x1008, as objdump -d prints them
+        let cases = [
+            (0x00008067, "ret"),
+            (0x00050067, "jr\ta0"),
+            (0x000500e7, "jalr\ta0"),
+            (0x00500513, "li\ta0,5"),
+            (0x00058513, "mv\ta0,a1"),
+            (0x00000013, "nop"),
+            (0x00b04863, "bgtz\ta1,1018 <func+0x18>"),
+            (0xfeb05ce3, "blez\ta1,1000 <func>"),
+            (0x00b50463, "beq\ta0,a1,1010 <func+0x10>"),
+            (0x0100006f, "j\t1018 <func+0x18>"),
+            (0x30002573, "csrr\ta0,mstatus"),
+            (0x30529073, "csrw\tmtvec,t0"),
+            (0x34059573, "csrrw\ta0,mscratch,a1"),
+            (0x0ff0000f, "fence"),
+            (0x0230000f, "fence\tr,rw"),
+            (0x1405a52f, "lr.w.aq\ta0,(a1)"),
+            (0x00000000, ".word\t0x00000000"),
+        ];
+        for (word, text) in cases.iter() {
+            assert_eq!(disassemble_word(*word, 0x1008, Some(&symbols)), *text);
+        }
+        // targets without a symbol are plain addresses
+        assert_eq!(disassemble_word(0x0100006f, 0x2000, None), "j\t2010");
+    }
+}
@@ -0,0 +1,658 @@
+use crate::instruction::REGISTER_NAMES;
+use crate::machine::Machine;
+use crate::record::Change;
+use crate::util::*;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// Number of instructions between checks for a ctrl-c from gdb
+const INTERRUPT_CHECK: u32 = 4096;
+const PC_REGNUM: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+struct Watchpoint {
+    kind: WatchKind,
+    addr: u32,
+    len: u32,
+}
+
+enum Stop {
+    Step,
+    SwBreak,
+    HwBreak,
+    Watch(WatchKind, u32),
+    Interrupted,
+    Exited(i32),
+    HistoryStart,
+}
+
+pub(crate) struct GdbServer<'a> {
+    machine: &'a mut Machine,
+    stream: TcpStream,
+    sw_breakpoints: Vec<u32>,
+    hw_breakpoints: Vec<u32>,
+    watchpoints: Vec<Watchpoint>,
+    exit_code: Option<i32>,
+}
+
+impl<'a> GdbServer<'a> {
+    // Blocks until gdb connects to 'addr' (e.g. "127.0.0.1:1234")
+    pub fn accept(machine: &'a mut Machine, addr: &str) -> io::Result<Self> {
+        // only one thread is reported to gdb
+        if machine.mem().hart_count() > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "gdb can only debug a single hart",
+            ));
+        }
+        let listener = TcpListener::bind(addr)?;
+        eprintln!("Waiting for gdb on {}", listener.local_addr()?);
+        let (stream, peer) = listener.accept()?;
+        eprintln!("gdb connected from {}", peer);
+        GdbServer::new(machine, stream)
+    }
+
+    fn new(machine: &'a mut Machine, stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(GdbServer {
+            machine,
+            stream,
+            sw_breakpoints: vec![],
+            hw_breakpoints: vec![],
+            watchpoints: vec![],
+            exit_code: None,
+        })
+    }
+
+    // Serves packets until gdb detaches or kills the target. On detach the
+    // guest keeps running to completion. Returns the guest exit code.
+    pub fn run(&mut self) -> io::Result<i32> {
+        while let Some(packet) = self.read_packet()? {
+            match packet.as_bytes().first() {
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(match self.exit_code {
+                        Some(code) => code,
+                        None => self.machine.run(),
+                    });
+                }
+                Some(b'k') => break,
+                _ if packet == "vKill" || packet.starts_with("vKill;") => {
+                    self.send("OK")?;
+                    break;
+                }
+                _ => {
+                    let reply = self.handle(&packet)?;
+                    self.send(&reply)?;
+                }
+            }
+        }
+        Ok(self.exit_code.unwrap_or(0))
+    }
+
+    fn handle(&mut self, packet: &str) -> io::Result<String> {
+        let mut chars = packet.chars();
+        let Some(cmd) = chars.next() else {
+            return Ok(String::new());
+        };
+        let body = chars.as_str();
+        let reply = match cmd {
+            '?' => match self.exit_code {
+                Some(code) => format!("W{:02x}", code as u8),
+                None => "S05".to_string(),
+            },
+            'g' => (0..=PC_REGNUM).map(|i| hex_u32(self.read_reg(i))).collect(),
+            'G' => {
+                for i in 0..=PC_REGNUM {
+                    match body.get(i * 8..i * 8 + 8).and_then(parse_hex_le) {
+                        Some(val) => self.write_reg(i, val),
+                        None => break,
+                    }
+                }
+                "OK".to_string()
+            }
+            'p' => match usize::from_str_radix(body, 16) {
+                Ok(i) if i <= PC_REGNUM => hex_u32(self.read_reg(i)),
+                _ => "E01".to_string(),
+            },
+            'P' => match split2(body, '=') {
+                Some((reg, val)) => match (usize::from_str_radix(reg, 16), parse_hex_le(val)) {
+                    (Ok(i), Some(val)) if i <= PC_REGNUM => {
+                        self.write_reg(i, val);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                },
+                None => "E01".to_string(),
+            },
+            'm' => match parse_addr_len(body) {
+                Some((addr, len)) => match self.machine.mem().try_read(addr as usize, len) {
+                    Some(bytes) => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+                    None => "E14".to_string(),
+                },
+                None => "E01".to_string(),
+            },
+            'M' => {
+                let parsed = split2(body, ':')
+                    .and_then(|(head, data)| Some((parse_addr_len(head)?, parse_hex_bytes(data)?)));
+                match parsed {
+                    Some(((addr, len), data)) if data.len() == len => self.write_mem(addr, &data),
+                    _ => "E01".to_string(),
+                }
+            }
+            'Z' | 'z' => self.breakpoint(cmd == 'Z', body),
+            'b' if body == "s" => {
+                let stop = self.reverse_step();
+                self.stop_reply(stop.unwrap_or(Stop::Step))
+            }
+            'b' if body == "c" => {
+                let stop = self.reverse_resume();
+                self.stop_reply(stop)
+            }
+            's' => {
+                if let Some(addr) = parse_hex_u32(body) {
+                    self.machine.mem_mut().set_pc(addr);
+                }
+                let stop = self.step();
+                self.stop_reply(stop.unwrap_or(Stop::Step))
+            }
+            'c' => {
+                if let Some(addr) = parse_hex_u32(body) {
+                    self.machine.mem_mut().set_pc(addr);
+                }
+                let stop = self.resume()?;
+                self.stop_reply(stop)
+            }
+            'H' => "OK".to_string(),
+            'T' => "OK".to_string(),
+            'q' => self.query(body),
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn query(&self, body: &str) -> String {
+        if body.starts_with("Supported") {
+            let mut features = "PacketSize=4000;qXfer:features:read+;swbreak+;hwbreak+".to_string();
+            if self.machine.mem().journal.is_some() {
+                features += ";ReverseStep+;ReverseContinue+";
+            }
+            return features;
+        }
+        if let Some(rest) = body.strip_prefix("Xfer:features:read:target.xml:") {
+            return match parse_addr_len(rest) {
+                Some((off, len)) => {
+                    let xml = target_xml();
+                    let off = (off as usize).min(xml.len());
+                    let end = (off + len).min(xml.len());
+                    let prefix = if end == xml.len() { "l" } else { "m" };
+                    format!("{}{}", prefix, &xml[off..end])
+                }
+                None => "E01".to_string(),
+            };
+        }
+        match body {
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn breakpoint(&mut self, insert: bool, body: &str) -> String {
+        let mut parts = body.split(',');
+        let kind = parts.next();
+        let addr = parts.next().and_then(parse_hex_u32);
+        let len = parts.next().and_then(parse_hex_u32);
+        let (addr, len) = match (addr, len) {
+            (Some(addr), Some(len)) => (addr, len),
+            _ => return "E01".to_string(),
+        };
+        let watch = match kind {
+            Some("0") => {
+                update(&mut self.sw_breakpoints, addr, insert);
+                return "OK".to_string();
+            }
+            Some("1") => {
+                update(&mut self.hw_breakpoints, addr, insert);
+                return "OK".to_string();
+            }
+            Some("2") => WatchKind::Write,
+            Some("3") => WatchKind::Read,
+            Some("4") => WatchKind::Access,
+            _ => return String::new(),
+        };
+        if insert {
+            self.watchpoints.push(Watchpoint {
+                kind: watch,
+                addr,
+                len,
+            });
+        } else {
+            self.watchpoints
+                .retain(|w| !(w.kind == watch && w.addr == addr && w.len == len));
+        }
+        "OK".to_string()
+    }
+
+    // Executes one instruction, reports exits and triggered watchpoints
+    fn step(&mut self) -> Option<Stop> {
+        if let Some(code) = self.exit_code {
+            return Some(Stop::Exited(code));
+        }
+        if let Some(code) = self.machine.step() {
+            self.exit_code = Some(code);
+            return Some(Stop::Exited(code));
+        }
+        let access = self.machine.mem().last_access?;
+        let start = access.addr;
+        let end = start.wrapping_add(access.len as u32);
+        self.watchpoints
+            .iter()
+            .find(|w| {
+                let hit = match w.kind {
+                    WatchKind::Write => access.write,
+                    WatchKind::Read => !access.write,
+                    WatchKind::Access => true,
+                };
+                hit && start < w.addr.wrapping_add(w.len) && w.addr < end
+            })
+            .map(|w| Stop::Watch(w.kind, w.addr))
+    }
+
+    // Undoes one instruction, reports write watchpoints it touched
+    fn reverse_step(&mut self) -> Option<Stop> {
+        let changes = match self.machine.mem_mut().undo_step() {
+            Some(changes) => changes,
+            None => return Some(Stop::HistoryStart),
+        };
+        self.exit_code = self.machine.mem().exit_code;
+        changes.iter().find_map(|change| match change {
+            Change::Memory(start, bytes) => {
+                let start = *start as u32;
+                let end = start.wrapping_add(bytes.len() as u32);
+                self.watchpoints
+                    .iter()
+                    .find(|w| {
+                        w.kind != WatchKind::Read
+                            && start < w.addr.wrapping_add(w.len)
+                            && w.addr < end
+                    })
+                    .map(|w| Stop::Watch(w.kind, w.addr))
+            }
+            _ => None,
+        })
+    }
+
+    fn reverse_resume(&mut self) -> Stop {
+        loop {
+            if let Some(stop) = self.reverse_step() {
+                return stop;
+            }
+            let pc = self.machine.mem().get_pc();
+            if self.sw_breakpoints.contains(&pc) {
+                return Stop::SwBreak;
+            }
+            if self.hw_breakpoints.contains(&pc) {
+                return Stop::HwBreak;
+            }
+        }
+    }
+
+    fn resume(&mut self) -> io::Result<Stop> {
+        let mut count = 0;
+        loop {
+            if let Some(stop) = self.step() {
+                return Ok(stop);
+            }
+            let pc = self.machine.mem().get_pc();
+            if self.sw_breakpoints.contains(&pc) {
+                return Ok(Stop::SwBreak);
+            }
+            if self.hw_breakpoints.contains(&pc) {
+                return Ok(Stop::HwBreak);
+            }
+            count += 1;
+            if count % INTERRUPT_CHECK == 0 && self.interrupted()? {
+                return Ok(Stop::Interrupted);
+            }
+        }
+    }
+
+    // Polls the socket for the 0x03 byte gdb sends on ctrl-c, anything
+    // else is left for read_packet
+    fn interrupted(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; 1];
+        self.stream.set_nonblocking(true)?;
+        let res = self.stream.peek(&mut buf);
+        self.stream.set_nonblocking(false)?;
+        match res {
+            Ok(1) if buf[0] == 0x03 => self.stream.read_exact(&mut buf).map(|_| true),
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Step => "S05".to_string(),
+            Stop::SwBreak => "T05swbreak:;".to_string(),
+            Stop::HwBreak => "T05hwbreak:;".to_string(),
+            Stop::Watch(kind, addr) => {
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T05{}:{:08x};", name, addr)
+            }
+            Stop::Interrupted => "S02".to_string(),
+            Stop::HistoryStart => "T05replaylog:begin;".to_string(),
+            Stop::Exited(code) => format!("W{:02x}", code as u8),
+        }
+    }
+
+    fn read_reg(&self, i: usize) -> u32 {
+        let mem = self.machine.mem();
+        if i == PC_REGNUM {
+            mem.get_pc()
+        } else {
+            mem.get_register(i as u8)
+        }
+    }
+
+    fn write_reg(&mut self, i: usize, val: u32) {
+        let mem = self.machine.mem_mut();
+        if i == PC_REGNUM {
+            mem.set_pc(val);
+        } else {
+            mem.set_register(val, i as u8);
+        }
+    }
+
+    fn write_mem(&mut self, addr: u32, data: &[u8]) -> String {
+        if !self.machine.mem_mut().try_write(addr as usize, data) {
+            return "E14".to_string();
+        }
+        "OK".to_string()
+    }
+
+    // Reads the next packet, acking it. Returns None when gdb disconnects.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0u8; 1];
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+            // acks and stray ctrl-c outside of a continue are ignored
+        }
+        let mut data = vec![];
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        self.stream.read_exact(&mut checksum)?;
+        let expected = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|c| u8::from_str_radix(c, 16).ok());
+        if expected != Some(checksum_of(&data)) {
+            self.stream.write_all(b"-")?;
+            return self.read_packet();
+        }
+        self.stream.write_all(b"+")?;
+        Ok(Some(String::from_utf8_lossy(&unescape(&data)).into_owned()))
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let mut escaped = vec![];
+        for b in data.bytes() {
+            if matches!(b, b'#' | b'$' | b'}' | b'*') {
+                escaped.push(b'}');
+                escaped.push(b ^ 0x20);
+            } else {
+                escaped.push(b);
+            }
+        }
+        let packet = format!(
+            "${}#{:02x}",
+            String::from_utf8_lossy(&escaped),
+            checksum_of(&escaped)
+        );
+        self.stream.write_all(packet.as_bytes())?;
+        // wait for the ack, resend on nack
+        let mut byte = [0u8; 1];
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(());
+            }
+            match byte[0] {
+                b'+' => return Ok(()),
+                b'-' => self.stream.write_all(packet.as_bytes())?,
+                _ => {}
+            }
+        }
+    }
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\">\
+         <architecture>riscv:rv32</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (i, name) in REGISTER_NAMES.iter().enumerate() {
+        let typ = match i {
+            1 => "code_ptr",
+            2 | 3 | 4 | 8 => "data_ptr",
+            _ => "int",
+        };
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>",
+            name, typ, i
+        );
+    }
+    xml += &format!(
+        "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>",
+        PC_REGNUM
+    );
+    xml += "</feature></target>";
+    xml
+}
+
+fn update(list: &mut Vec<u32>, addr: u32, insert: bool) {
+    if insert {
+        if !list.contains(&addr) {
+            list.push(addr);
+        }
+    } else {
+        list.retain(|a| *a != addr);
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut iter = data.iter();
+    while let Some(b) = iter.next() {
+        if *b == b'}' {
+            if let Some(next) = iter.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(*b);
+        }
+    }
+    out
+}
+
+// Registers go over the wire in target byte order
+fn hex_u32(val: u32) -> String {
+    from_u32(val).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex_le(text: &str) -> Option<u32> {
+    let bytes = parse_hex_bytes(text)?;
+    if bytes.len() != 4 {
+        return None;
+    }
+    Some(to_u32(&bytes))
+}
+
+fn split2(text: &str, sep: char) -> Option<(&str, &str)> {
+    let i = text.find(sep)?;
+    Some((&text[..i], &text[i + 1..]))
+}
+
+fn parse_hex_u32(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn parse_addr_len(text: &str) -> Option<(u32, usize)> {
+    let (addr, len) = split2(text, ',')?;
+    Some((parse_hex_u32(addr)?, usize::from_str_radix(len, 16).ok()?))
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    (0..text.len() / 2)
+        .map(|i| u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Client side of the remote protocol
+    struct Client(TcpStream);
+
+    impl Client {
+        fn ack(&mut self) -> u8 {
+            let mut byte = [0u8; 1];
+            self.0.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        // Sends a packet and returns the reply
+        fn ask(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+            self.0.write_all(packet.as_bytes()).unwrap();
+            assert_eq!(self.ack(), b'+');
+            let mut reply = vec![];
+            assert_eq!(self.ack(), b'$');
+            loop {
+                match self.ack() {
+                    b'#' => break,
+                    b => reply.push(b),
+                }
+            }
+            let mut checksum = [0u8; 2];
+            self.0.read_exact(&mut checksum).unwrap();
+            let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16);
+            assert_eq!(checksum, Ok(checksum_of(&reply)));
+            self.0.write_all(b"+").unwrap();
+            String::from_utf8(unescape(&reply)).unwrap()
+        }
+    }
+
+    #[test]
+    fn serves_a_debugging_session() {
+        // li a7,500; li a0,1; jal 0x1018; addi a0,a0,2; addi a0,a0,4;
+        // ecall; addi a0,a0,10; ret
+        let code: Vec<u8> = [
+            0x1f400893u32,
+            0x00100513,
+            0x010000ef,
+            0x00250513,
+            0x00450513,
+            0x00000073,
+            0x00a50513,
+            0x00008067,
+        ]
+        .iter()
+        .flat_map(|w| w.to_le_bytes())
+        .collect();
+        let mut machine = Machine::new(Memory::with_code(0x1000, &code));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut gdb = Client(TcpStream::connect(addr).unwrap());
+            // a bad checksum is nacked, gdb then resends
+            gdb.0.write_all(b"$?#00").unwrap();
+            assert_eq!(gdb.ack(), b'-');
+            assert_eq!(gdb.ask("?"), "S05");
+            assert_eq!(gdb.ask(""), "");
+            let regs = gdb.ask("g");
+            assert_eq!(regs.len(), 33 * 8);
+            assert_eq!(&regs[32 * 8..], "00100000");
+            let regs = format!("{}05000000{}", &regs[..10 * 8], &regs[11 * 8..]);
+            assert_eq!(gdb.ask(&format!("G{}", regs)), "OK");
+            assert_eq!(gdb.ask("pa"), "05000000");
+            assert_eq!(gdb.ask("m1000,4"), "9308401f");
+            // addi a0,a0,4 becomes addi a0,a0,8
+            assert_eq!(gdb.ask("M1010,4:13058500"), "OK");
+            assert_eq!(gdb.ask("m1010,4"), "13058500");
+            assert_eq!(gdb.ask("m0,4"), "E14");
+            assert_eq!(gdb.ask("Z0,1018,4"), "OK");
+            assert_eq!(gdb.ask("c"), "T05swbreak:;");
+            assert_eq!(gdb.ask("p20"), "18100000");
+            assert_eq!(gdb.ask("z0,1018,4"), "OK");
+            assert_eq!(gdb.ask("c"), "W15");
+            gdb.0.write_all(b"$k#6b").unwrap();
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let mut server = GdbServer::new(&mut machine, stream).unwrap();
+        let code = server.run().unwrap();
+        client.join().unwrap();
+        assert_eq!(code, 0x15);
+    }
+
+    #[test]
+    fn ctrl_c_polls_leave_packets_alone() {
+        let mut machine = Machine::new(Memory::with_code(0x1000, &[0; 4]));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut gdb = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut server = GdbServer::new(&mut machine, stream).unwrap();
+        gdb.write_all(b"$?#3f").unwrap();
+        server.stream.peek(&mut [0]).unwrap();
+        assert!(!server.interrupted().unwrap());
+        assert_eq!(server.read_packet().unwrap().as_deref(), Some("?"));
+        gdb.write_all(&[0x03]).unwrap();
+        server.stream.peek(&mut [0]).unwrap();
+        assert!(server.interrupted().unwrap());
+        assert!(!server.interrupted().unwrap());
+    }
+
+    #[test]
+    fn refuses_several_harts() {
+        let mut mem = Memory::with_code(0x1000, &[0; 4]);
+        mem.init_harts(2);
+        let mut machine = Machine::new(mem);
+        assert!(GdbServer::accept(&mut machine, "127.0.0.1:0").is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use crate::csr::Csrs;
+use crate::mmu::Tlb;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+// Architectural state of one hardware thread, everything else is shared by
+// all harts
+#[derive(Debug, Clone)]
+pub(crate) struct Hart {
+    pub registers: [u32; 32],
+    pub pc: u32,
+    pub csrs: Csrs,
+    pub tlb: Tlb,
+    pub instret: u64,
+    pub calls: Option<Vec<u32>>, // pc of every active call, when tracked
+    pub reservation: Option<usize>, // physical address LR.W reserved
+}
+
+impl Hart {
+    pub fn new(id: u32, pc: u32) -> Self {
+        Hart {
+            registers: [0; 32],
+            pc,
+            csrs: Csrs::for_hart(id),
+            tlb: Tlb::default(),
+            instret: 0,
+            calls: None,
+            reservation: None,
+        }
+    }
+
+    pub fn save(&self, w: &mut SnapshotWriter) {
+        w.u32(self.pc);
+        for reg in self.registers.iter() {
+            w.u32(*reg);
+        }
+        w.u64(self.instret);
+        self.csrs.save(w);
+        match &self.calls {
+            Some(calls) => {
+                w.u8(1);
+                w.u32s(calls);
+            }
+            None => w.u8(0),
+        }
+    }
+
+    pub fn load(r: &mut SnapshotReader) -> Result<Self, String> {
+        let mut hart = Hart::new(0, r.u32()?);
+        for reg in hart.registers.iter_mut() {
+            *reg = r.u32()?;
+        }
+        hart.instret = r.u64()?;
+        hart.csrs = Csrs::load(r)?;
+        if r.u8()? != 0 {
+            hart.calls = Some(r.u32s()?);
+        }
+        Ok(hart)
+    }
+}
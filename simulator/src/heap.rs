@@ -0,0 +1,205 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+pub(crate) const DEFAULT_SIZE: usize = 64 << 20;
+pub(crate) const DEFAULT_ALIGN: usize = 16;
+
+// Guest heap allocator. Block metadata lives on the host, so a guest
+// overflowing a block can't corrupt the allocator. Free blocks are binned by
+// power of two size class and taken first fit, lowest address first within
+// a bin, and coalesce with their neighbours when freed.
+#[derive(Debug, Clone)]
+pub(crate) struct Heap {
+    start: usize,
+    size: usize,
+    align: usize,
+    free: BTreeMap<usize, usize>, // start -> size, never adjacent
+    bins: Vec<BTreeSet<usize>>,   // free starts by size class
+    used: BTreeMap<usize, usize>, // start -> size of allocations
+}
+
+impl Heap {
+    // 'align' is a power of two, the minimum alignment and granularity
+    pub fn new(start: usize, size: usize, align: usize) -> Self {
+        assert!(
+            align.is_power_of_two(),
+            "heap alignment must be a power of two"
+        );
+        let mut heap = Heap {
+            start,
+            size,
+            align,
+            free: BTreeMap::new(),
+            bins: vec![BTreeSet::new(); usize::BITS as usize],
+            used: BTreeMap::new(),
+        };
+        if size > 0 {
+            heap.insert_free(start, size);
+        }
+        heap
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn align(&self) -> usize {
+        self.align
+    }
+
+    // Allocated blocks as (start, size), by address
+    pub fn blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.used.iter().map(|(&start, &size)| (start, size))
+    }
+
+    // Size of the block at 'start', rounded up to the granularity
+    pub fn size_of(&self, start: usize) -> Option<usize> {
+        self.used.get(&start).copied()
+    }
+
+    // Rounded size of a request for 'size' bytes, None if it can't fit
+    pub fn rounded(&self, size: usize) -> Option<usize> {
+        let size = round_up(size.max(1), self.align);
+        (size <= self.size).then_some(size)
+    }
+
+    pub fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        let size = self.rounded(size)?;
+        let align = align.max(self.align);
+        let free = &self.free;
+        let start = self.bins[class(size)..].iter().find_map(|bin| {
+            bin.iter().find_map(|&s| {
+                let start = round_up(s, align);
+                (start + size <= s + free[&s]).then_some(start)
+            })
+        })?;
+        self.claim(start, size);
+        Some(start)
+    }
+
+    // Allocates 'size' bytes at exactly 'start', which has to be free
+    pub fn claim(&mut self, start: usize, size: usize) {
+        let (&s, &len) = self
+            .free
+            .range(..=start)
+            .next_back()
+            .expect("heap block not free");
+        assert!(start + size <= s + len, "heap block not free");
+        self.remove_free(s);
+        if start > s {
+            self.insert_free(s, start - s);
+        }
+        if start + size < s + len {
+            self.insert_free(start + size, s + len - start - size);
+        }
+        self.used.insert(start, size);
+    }
+
+    // Returns the size of the freed block, None if 'start' isn't allocated
+    pub fn release(&mut self, start: usize) -> Option<usize> {
+        let size = self.used.remove(&start)?;
+        self.insert_free(start, size);
+        Some(size)
+    }
+
+    // Grows or shrinks the block at 'start' in place, false if the blocks
+    // after it aren't free
+    pub fn resize(&mut self, start: usize, size: usize) -> bool {
+        let (Some(old), Some(size)) = (self.size_of(start), self.rounded(size)) else {
+            return false;
+        };
+        if size <= old {
+            if size < old {
+                self.insert_free(start + size, old - size);
+            }
+        } else {
+            let end = start + old;
+            match self.free.get(&end) {
+                Some(&len) if old + len >= size => {
+                    self.remove_free(end);
+                    if old + len > size {
+                        self.insert_free(start + size, old + len - size);
+                    }
+                }
+                _ => return false,
+            }
+        }
+        self.used.insert(start, size);
+        true
+    }
+
+    fn insert_free(&mut self, mut start: usize, mut size: usize) {
+        if let Some((&s, &len)) = self.free.range(..start).next_back() {
+            if s + len == start {
+                self.remove_free(s);
+                start = s;
+                size += len;
+            }
+        }
+        if let Some(&len) = self.free.get(&(start + size)) {
+            self.remove_free(start + size);
+            size += len;
+        }
+        self.free.insert(start, size);
+        self.bins[class(size)].insert(start);
+    }
+
+    fn remove_free(&mut self, start: usize) {
+        let size = self.free.remove(&start).unwrap();
+        self.bins[class(size)].remove(&start);
+    }
+}
+
+fn class(size: usize) -> usize {
+    (usize::BITS - 1 - size.leading_zeros()) as usize
+}
+
+fn round_up(val: usize, align: usize) -> usize {
+    (val + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_and_coalesces_freed_blocks() {
+        let mut heap = Heap::new(0x1000, 0x100, 16);
+        let a = heap.alloc(20, 0).unwrap();
+        let b = heap.alloc(16, 0).unwrap();
+        let c = heap.alloc(16, 0).unwrap();
+        assert_eq!((a, b, c), (0x1000, 0x1020, 0x1030));
+        assert_eq!(heap.release(b), Some(16));
+        assert_eq!(heap.release(b), None);
+        // first fit takes the hole
+        assert_eq!(heap.alloc(8, 0), Some(b));
+        heap.release(a);
+        heap.release(b);
+        // a and b merged into one 48 byte block
+        assert_eq!(heap.alloc(48, 0), Some(a));
+        heap.release(a);
+        heap.release(c);
+        assert_eq!(heap.alloc(0x100, 0), Some(0x1000));
+        assert_eq!(heap.alloc(1, 0), None);
+    }
+
+    #[test]
+    fn aligns_and_resizes_in_place() {
+        let mut heap = Heap::new(0x1000, 0x1000, 8);
+        let a = heap.alloc(8, 0).unwrap();
+        let b = heap.alloc(8, 0x100).unwrap();
+        assert_eq!(b, 0x1100);
+        // the padding before b is still free
+        assert_eq!(heap.alloc(0xf8, 0), Some(a + 8));
+        assert!(heap.resize(b, 0x200));
+        assert_eq!(heap.size_of(b), Some(0x200));
+        let c = heap.alloc(8, 0).unwrap();
+        assert_eq!(c, 0x1300);
+        assert!(!heap.resize(b, 0x208));
+        assert!(heap.resize(b, 4));
+        assert_eq!(heap.alloc(8, 0), Some(b + 8));
+    }
+}
@@ -1,5 +1,6 @@
-#[cfg_attr(rustfmt, rustfmt_skip)]
-#[derive(Debug)]
+#[rustfmt::skip]
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum Instruction {
     LUI { imm: i32, rd: u8 },            // Load Upper Immediate
     AUIPC { imm: i32, rd: u8 },          
@@ -40,19 +41,146 @@ pub(crate) enum Instruction {
     SRA { rs1: u8, rs2: u8, rd: u8 }, // rs1 >> rs2[4:0] -> rd (arithmetic)
     OR { rs1: u8, rs2: u8, rd: u8 }, // rs1 | rs2 -> rd
     AND { rs1: u8, rs2: u8, rd: u8 }, // rs1 & rs2 -> rd
-    FENCE , // Unnecessary because every operation is in order
+    FENCE { pred: u8, succ: u8 }, // Unnecessary because every operation is in order
+    FENCE_I, // Same, but instruction fetches after it see earlier stores
     ECALL,
     EBREAK,
+    CSRRW { csr: u16, rs1: u8, rd: u8 }, // csr -> rd, rs1 -> csr
+    CSRRS { csr: u16, rs1: u8, rd: u8 }, // csr -> rd, csr | rs1 -> csr
+    CSRRC { csr: u16, rs1: u8, rd: u8 }, // csr -> rd, csr & !rs1 -> csr
+    CSRRWI { csr: u16, uimm: u8, rd: u8 }, // Like CSRRW with a 5 bit immediate
+    CSRRSI { csr: u16, uimm: u8, rd: u8 },
+    CSRRCI { csr: u16, uimm: u8, rd: u8 },
+    MRET, // Return from a machine mode trap handler
+    SRET, // Same for supervisor mode
+    WFI, // Wait for an interrupt
+    SFENCE_VMA { rs1: u8, rs2: u8 }, // Orders page table updates, flushes the TLB
+    LR_W { rs1: u8, rd: u8, aqrl: u8 }, // M[rs1] -> rd and reserves the word
+    SC_W { rs1: u8, rs2: u8, rd: u8, aqrl: u8 }, // rs2 -> M[rs1], 0 -> rd on success, 1 on failure
+    AMO { op: Amo, rs1: u8, rs2: u8, rd: u8, aqrl: u8 }, // M[rs1] -> rd, op(M[rs1], rs2) -> M[rs1]
+}
+
+// Atomic memory operations, by their func5
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Amo {
+    Add = 0b00000,
+    Swap = 0b00001,
+    Xor = 0b00100,
+    Or = 0b01000,
+    And = 0b01100,
+    Min = 0b10000,
+    Max = 0b10100,
+    Minu = 0b11000,
+    Maxu = 0b11100,
+}
+
+impl Amo {
+    const ALL: [Amo; 9] = [
+        Amo::Add,
+        Amo::Swap,
+        Amo::Xor,
+        Amo::Or,
+        Amo::And,
+        Amo::Min,
+        Amo::Max,
+        Amo::Minu,
+        Amo::Maxu,
+    ];
+
+    fn from_func5(func5: u8) -> Option<Self> {
+        Amo::ALL.iter().copied().find(|&op| op as u8 == func5)
+    }
+
+    // New value of the word from the 'old' one and rs2
+    pub fn apply(self, old: u32, val: u32) -> u32 {
+        match self {
+            Amo::Add => old.wrapping_add(val),
+            Amo::Swap => val,
+            Amo::Xor => old ^ val,
+            Amo::Or => old | val,
+            Amo::And => old & val,
+            Amo::Min => (old as i32).min(val as i32) as u32,
+            Amo::Max => (old as i32).max(val as i32) as u32,
+            Amo::Minu => old.min(val),
+            Amo::Maxu => old.max(val),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Amo::Add => "amoadd.w",
+            Amo::Swap => "amoswap.w",
+            Amo::Xor => "amoxor.w",
+            Amo::Or => "amoor.w",
+            Amo::And => "amoand.w",
+            Amo::Min => "amomin.w",
+            Amo::Max => "amomax.w",
+            Amo::Minu => "amominu.w",
+            Amo::Maxu => "amomaxu.w",
+        }
+    }
 }
 
 const MASK_OP: u32 = 0b1111111;
 const MASK_LUI_IMM: u32 = 0b11111111111111111111000000000000;
-const MASK_RD: u32 = 0b111110000000;
 const MASK_11_0: u32 = 0b111111111111 << 20;
 const MASK_11_0_EXTEND: u32 = MASK_LUI_IMM;
+const FUNC7_ALT: u8 = 0b0100000; // SUB, SRA and SRAI
+
+const OP_LUI: u32 = 0b0110111;
+const OP_AUIPC: u32 = 0b0010111;
+const OP_JAL: u32 = 0b1101111;
+const OP_JALR: u32 = 0b1100111;
+const OP_BRANCH: u32 = 0b1100011;
+const OP_LOAD: u32 = 0b0000011;
+const OP_STORE: u32 = 0b0100011;
+const OP_IMM: u32 = 0b0010011;
+const OP_REG: u32 = 0b0110011;
+const OP_MISC_MEM: u32 = 0b0001111;
+const OP_SYSTEM: u32 = 0b1110011;
+const OP_AMO: u32 = 0b0101111;
+const ECALL_WORD: u32 = 0x00000073;
+const EBREAK_WORD: u32 = 0x00100073;
+const MRET_WORD: u32 = 0x30200073;
+const SRET_WORD: u32 = 0x10200073;
+const WFI_WORD: u32 = 0x10500073;
+const FUNC7_SFENCE_VMA: u8 = 0b0001001;
+const FUNC5_LR: u8 = 0b00010;
+const FUNC5_SC: u8 = 0b00011;
+
+pub(crate) const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
 
 impl Instruction {
-    pub fn new(inst: u32) -> Self {
+    // Register written by the instruction, x0 writes are dropped. ECALL
+    // returns the syscall result in a0.
+    pub fn dest_register(&self) -> Option<u8> {
+        use Instruction::*;
+        let rd = match *self {
+            LUI { rd, .. } | AUIPC { rd, .. } | JAL { rd, .. } | JALR { rd, .. } => rd,
+            LB { rd, .. } | LH { rd, .. } | LW { rd, .. } | LBU { rd, .. } | LHU { rd, .. } => rd,
+            ADDI { rd, .. } | SLTI { rd, .. } | SLTIU { rd, .. } | XORI { rd, .. } => rd,
+            ORI { rd, .. } | ANDI { rd, .. } | SLLI { rd, .. } | SRLI { rd, .. } => rd,
+            SRAI { rd, .. } | ADD { rd, .. } | SUB { rd, .. } | SLL { rd, .. } => rd,
+            SLT { rd, .. } | SLTU { rd, .. } | XOR { rd, .. } | SRL { rd, .. } => rd,
+            SRA { rd, .. } | OR { rd, .. } | AND { rd, .. } => rd,
+            CSRRW { rd, .. } | CSRRS { rd, .. } | CSRRC { rd, .. } => rd,
+            CSRRWI { rd, .. } | CSRRSI { rd, .. } | CSRRCI { rd, .. } => rd,
+            LR_W { rd, .. } | SC_W { rd, .. } | AMO { rd, .. } => rd,
+            ECALL => 10,
+            _ => 0,
+        };
+        if rd == 0 {
+            None
+        } else {
+            Some(rd)
+        }
+    }
+
+    pub fn try_new(inst: u32) -> Option<Self> {
         let op = inst & MASK_OP;
         //println!("{:032b}", ((u32::MAX & (0b111111 << 25)) >> 20));
         //println!("{:032b}", (u32::MAX & (0b0 << 21) | (1 << 20)) >> 9);
@@ -64,8 +192,8 @@ impl Instruction {
         //    "0x{:02X}{:02X}{:02X}{:02X}",
         //    bytes[0], bytes[1], bytes[2], bytes[3]
         //);
-        match op {
-            0b0110111 => {
+        Some(match op {
+            OP_LUI => {
                 // LUI
                 let imm = inst & MASK_LUI_IMM;
                 let rd = get_rd(inst);
@@ -74,7 +202,7 @@ impl Instruction {
                     imm: imm as i32,
                 }
             }
-            0b0010111 => {
+            OP_AUIPC => {
                 // AUIPC
                 let imm = inst & MASK_LUI_IMM;
                 let rd = get_rd(inst);
@@ -83,7 +211,7 @@ impl Instruction {
                     imm: imm as i32,
                 }
             }
-            0b1101111 => {
+            OP_JAL => {
                 // JAL
                 let mut base = 0;
                 let b20 = (inst & (1 << 31)) >> 11;
@@ -92,9 +220,9 @@ impl Instruction {
                 let b19_12 = inst & (0b11111111 << 12);
                 if (inst & (1 << 31)) != 0 {
                     let ext = 0b111111111111 << 20;
-                    base = base | ext;
+                    base |= ext;
                 }
-                base = base | b20 | b19_12 | b11 | b10_1;
+                base |= b20 | b19_12 | b11 | b10_1;
 
                 let rd = get_rd(inst);
                 Instruction::JAL {
@@ -102,14 +230,17 @@ impl Instruction {
                     imm: base as i32,
                 }
             }
-            0b1100111 => {
+            OP_JALR => {
                 // JALR
+                if get_func3(inst) != 0 {
+                    return None;
+                }
                 let imm = get_imm_11_0(inst) as i32;
                 let rs1 = get_rs1(inst);
                 let rd = get_rd(inst);
                 Instruction::JALR { imm, rs1, rd }
             }
-            0b1100011 => {
+            OP_BRANCH => {
                 // BEQ, BNE, BLT, BGE, BLTU, BGEU
                 let func3 = get_func3(inst);
                 let imm = get_b_imm(inst) as i32;
@@ -123,12 +254,10 @@ impl Instruction {
                     0b101 => Instruction::BGE { imm, rs1, rs2 },
                     0b110 => Instruction::BLTU { imm, rs1, rs2 },
                     0b111 => Instruction::BGEU { imm, rs1, rs2 },
-                    _ => {
-                        panic!("Undefined B instuction");
-                    }
+                    _ => return None,
                 }
             }
-            0b0000011 => {
+            OP_LOAD => {
                 // LB, LH, LW, LBU, LHU
                 let imm = get_imm_11_0(inst) as i32;
                 let rs1 = get_rs1(inst);
@@ -141,12 +270,10 @@ impl Instruction {
                     0b010 => Instruction::LW { imm, rs1, rd },
                     0b100 => Instruction::LBU { imm, rs1, rd },
                     0b101 => Instruction::LHU { imm, rs1, rd },
-                    _ => {
-                        panic!("Undefined L instuction");
-                    }
+                    _ => return None,
                 }
             }
-            0b0100011 => {
+            OP_STORE => {
                 // SB, SH, SW
                 let imm = get_s_imm(inst) as i32;
                 let rs1 = get_rs1(inst);
@@ -157,12 +284,10 @@ impl Instruction {
                     0b000 => Instruction::SB { imm, rs1, rs2 },
                     0b001 => Instruction::SH { imm, rs1, rs2 },
                     0b010 => Instruction::SW { imm, rs1, rs2 },
-                    _ => {
-                        panic!("Undefined S instuction");
-                    }
+                    _ => return None,
                 }
             }
-            0b0010011 => {
+            OP_IMM => {
                 // ADDI, SLTI, SLTIU, XORI, ORI, ANDI, SLLI, SRLI, SRAI
                 let func3 = get_func3(inst);
                 let func7 = get_func7(inst);
@@ -178,32 +303,26 @@ impl Instruction {
                     0b100 => Instruction::XORI { imm, rd, rs1 },
                     0b110 => Instruction::ORI { imm, rd, rs1 },
                     0b111 => Instruction::ANDI { imm, rd, rs1 },
-                    0b001 => Instruction::SLLI {
+                    // shamt[5] is reserved on RV32, so func7 is all that's left
+                    0b001 if func7 == 0 => Instruction::SLLI {
                         shift: rs2,
                         rs1,
                         rd,
                     },
-                    0b101 => {
-                        if func7 == 0 {
-                            Instruction::SRLI {
-                                shift: rs2,
-                                rs1,
-                                rd,
-                            }
-                        } else {
-                            Instruction::SRAI {
-                                shift: rs2,
-                                rs1,
-                                rd,
-                            }
-                        }
-                    }
-                    _ => {
-                        panic!("Undefined I instuction");
-                    }
+                    0b101 if func7 == 0 => Instruction::SRLI {
+                        shift: rs2,
+                        rs1,
+                        rd,
+                    },
+                    0b101 if func7 == FUNC7_ALT => Instruction::SRAI {
+                        shift: rs2,
+                        rs1,
+                        rd,
+                    },
+                    _ => return None,
                 }
             }
-            0b0110011 => {
+            OP_REG => {
                 // ADD, SUB, SLL, SLT, SLTU, XOR, SRL, SRA, OR, AND
                 let func7 = get_func7(inst);
                 //println!("{:07X}", func7);
@@ -212,42 +331,211 @@ impl Instruction {
                 let rs2 = get_rs2(inst);
                 let rd = get_rd(inst);
 
-                match func3 {
-                    0b000 if func7 == 0 => Instruction::ADD { rs2, rd, rs1 },
-                    0b000 if func7 != 0 => Instruction::SUB { rs2, rd, rs1 },
-                    0b001 => Instruction::SLL { rs2, rd, rs1 },
-                    0b010 => Instruction::SLT { rs2, rd, rs1 },
-                    0b011 => Instruction::SLTU { rs2, rd, rs1 },
-                    0b100 => Instruction::XOR { rs2, rd, rs1 },
-                    0b101 if func7 == 0 => Instruction::SRL { rs2, rd, rs1 },
-                    0b101 if func7 != 0 => Instruction::SRA { rs2, rd, rs1 },
-                    0b110 => Instruction::OR { rs2, rd, rs1 },
-                    0b111 => Instruction::AND { rs2, rd, rs1 },
-                    _ => {
-                        panic!("Undefined R instuction");
-                    }
+                match (func3, func7) {
+                    (0b000, 0) => Instruction::ADD { rs2, rd, rs1 },
+                    (0b000, FUNC7_ALT) => Instruction::SUB { rs2, rd, rs1 },
+                    (0b001, 0) => Instruction::SLL { rs2, rd, rs1 },
+                    (0b010, 0) => Instruction::SLT { rs2, rd, rs1 },
+                    (0b011, 0) => Instruction::SLTU { rs2, rd, rs1 },
+                    (0b100, 0) => Instruction::XOR { rs2, rd, rs1 },
+                    (0b101, 0) => Instruction::SRL { rs2, rd, rs1 },
+                    (0b101, FUNC7_ALT) => Instruction::SRA { rs2, rd, rs1 },
+                    (0b110, 0) => Instruction::OR { rs2, rd, rs1 },
+                    (0b111, 0) => Instruction::AND { rs2, rd, rs1 },
+                    _ => return None,
                 }
             }
-            0b0001111 => {
-                // FENCE
-                Instruction::FENCE
+            OP_MISC_MEM => {
+                // FENCE, FENCE.I. fm, rs1 and rd (and the FENCE.I immediate)
+                // are reserved, the spec asks to ignore them
+                match get_func3(inst) {
+                    0b000 => Instruction::FENCE {
+                        pred: ((inst >> 24) & 0b1111) as u8,
+                        succ: ((inst >> 20) & 0b1111) as u8,
+                    },
+                    0b001 => Instruction::FENCE_I,
+                    _ => return None,
+                }
             }
-            0b1110011 => {
-                // ECALL, EBREAK
-                let imm = get_imm_11_0(inst);
-                if imm == 0 {
-                    Instruction::ECALL
-                } else {
-                    Instruction::EBREAK
+            OP_SYSTEM => {
+                // ECALL, EBREAK, xRET, WFI and the CSR instructions. Whether
+                // the CSR exists is checked when it's accessed.
+                let csr = (inst >> 20) as u16;
+                let rs1 = get_rs1(inst);
+                let rd = get_rd(inst);
+                match get_func3(inst) {
+                    0b000 => match inst {
+                        ECALL_WORD => Instruction::ECALL,
+                        EBREAK_WORD => Instruction::EBREAK,
+                        MRET_WORD => Instruction::MRET,
+                        SRET_WORD => Instruction::SRET,
+                        WFI_WORD => Instruction::WFI,
+                        _ if get_func7(inst) == FUNC7_SFENCE_VMA && rd == 0 => {
+                            Instruction::SFENCE_VMA {
+                                rs1,
+                                rs2: get_rs2(inst),
+                            }
+                        }
+                        _ => return None,
+                    },
+                    0b001 => Instruction::CSRRW { csr, rs1, rd },
+                    0b010 => Instruction::CSRRS { csr, rs1, rd },
+                    0b011 => Instruction::CSRRC { csr, rs1, rd },
+                    0b101 => Instruction::CSRRWI { csr, uimm: rs1, rd },
+                    0b110 => Instruction::CSRRSI { csr, uimm: rs1, rd },
+                    0b111 => Instruction::CSRRCI { csr, uimm: rs1, rd },
+                    _ => return None,
                 }
             }
-            _ => {
-                panic!("Undefined OP Code");
+            OP_AMO => {
+                // LR.W, SC.W and the AMOs. Every access is ordered anyway,
+                // aq and rl are kept for the disassembly.
+                if get_func3(inst) != 0b010 {
+                    return None;
+                }
+                let rs1 = get_rs1(inst);
+                let rs2 = get_rs2(inst);
+                let rd = get_rd(inst);
+                let aqrl = get_func7(inst) & 0b11;
+                match get_func7(inst) >> 2 {
+                    FUNC5_LR if rs2 == 0 => Instruction::LR_W { rs1, rd, aqrl },
+                    FUNC5_SC => Instruction::SC_W { rs1, rs2, rd, aqrl },
+                    func5 => Instruction::AMO {
+                        op: Amo::from_func5(func5)?,
+                        rs1,
+                        rs2,
+                        rd,
+                        aqrl,
+                    },
+                }
             }
+            _ => return None,
+        })
+    }
+
+    // Inverse of try_new. Reserved FENCE fields are encoded as zero, every
+    // other decodable word encodes back to itself.
+    #[cfg(any(test, fuzzing))]
+    pub fn encode(&self) -> u32 {
+        use Instruction::*;
+        match *self {
+            LUI { imm, rd } => u_type(OP_LUI, rd, imm),
+            AUIPC { imm, rd } => u_type(OP_AUIPC, rd, imm),
+            JAL { imm, rd } => {
+                let imm = imm as u32;
+                let b20 = (imm & (1 << 20)) << 11;
+                let b10_1 = (imm & (0b1111111111 << 1)) << 20;
+                let b11 = (imm & (1 << 11)) << 9;
+                let b19_12 = imm & (0b11111111 << 12);
+                b20 | b10_1 | b11 | b19_12 | (rd as u32) << 7 | OP_JAL
+            }
+            JALR { imm, rs1, rd } => i_type(OP_JALR, 0b000, rd, rs1, imm),
+            BEQ { imm, rs1, rs2 } => b_type(0b000, rs1, rs2, imm),
+            BNE { imm, rs1, rs2 } => b_type(0b001, rs1, rs2, imm),
+            BLT { imm, rs1, rs2 } => b_type(0b100, rs1, rs2, imm),
+            BGE { imm, rs1, rs2 } => b_type(0b101, rs1, rs2, imm),
+            BLTU { imm, rs1, rs2 } => b_type(0b110, rs1, rs2, imm),
+            BGEU { imm, rs1, rs2 } => b_type(0b111, rs1, rs2, imm),
+            LB { imm, rs1, rd } => i_type(OP_LOAD, 0b000, rd, rs1, imm),
+            LH { imm, rs1, rd } => i_type(OP_LOAD, 0b001, rd, rs1, imm),
+            LW { imm, rs1, rd } => i_type(OP_LOAD, 0b010, rd, rs1, imm),
+            LBU { imm, rs1, rd } => i_type(OP_LOAD, 0b100, rd, rs1, imm),
+            LHU { imm, rs1, rd } => i_type(OP_LOAD, 0b101, rd, rs1, imm),
+            SB { imm, rs1, rs2 } => s_type(0b000, rs1, rs2, imm),
+            SH { imm, rs1, rs2 } => s_type(0b001, rs1, rs2, imm),
+            SW { imm, rs1, rs2 } => s_type(0b010, rs1, rs2, imm),
+            ADDI { imm, rs1, rd } => i_type(OP_IMM, 0b000, rd, rs1, imm),
+            SLTI { imm, rs1, rd } => i_type(OP_IMM, 0b010, rd, rs1, imm),
+            SLTIU { imm, rs1, rd } => i_type(OP_IMM, 0b011, rd, rs1, imm),
+            XORI { imm, rs1, rd } => i_type(OP_IMM, 0b100, rd, rs1, imm),
+            ORI { imm, rs1, rd } => i_type(OP_IMM, 0b110, rd, rs1, imm),
+            ANDI { imm, rs1, rd } => i_type(OP_IMM, 0b111, rd, rs1, imm),
+            SLLI { shift, rs1, rd } => r_type(OP_IMM, 0b001, 0, rd, rs1, shift),
+            SRLI { shift, rs1, rd } => r_type(OP_IMM, 0b101, 0, rd, rs1, shift),
+            SRAI { shift, rs1, rd } => r_type(OP_IMM, 0b101, FUNC7_ALT, rd, rs1, shift),
+            ADD { rs1, rs2, rd } => r_type(OP_REG, 0b000, 0, rd, rs1, rs2),
+            SUB { rs1, rs2, rd } => r_type(OP_REG, 0b000, FUNC7_ALT, rd, rs1, rs2),
+            SLL { rs1, rs2, rd } => r_type(OP_REG, 0b001, 0, rd, rs1, rs2),
+            SLT { rs1, rs2, rd } => r_type(OP_REG, 0b010, 0, rd, rs1, rs2),
+            SLTU { rs1, rs2, rd } => r_type(OP_REG, 0b011, 0, rd, rs1, rs2),
+            XOR { rs1, rs2, rd } => r_type(OP_REG, 0b100, 0, rd, rs1, rs2),
+            SRL { rs1, rs2, rd } => r_type(OP_REG, 0b101, 0, rd, rs1, rs2),
+            SRA { rs1, rs2, rd } => r_type(OP_REG, 0b101, FUNC7_ALT, rd, rs1, rs2),
+            OR { rs1, rs2, rd } => r_type(OP_REG, 0b110, 0, rd, rs1, rs2),
+            AND { rs1, rs2, rd } => r_type(OP_REG, 0b111, 0, rd, rs1, rs2),
+            FENCE { pred, succ } => (pred as u32) << 24 | (succ as u32) << 20 | OP_MISC_MEM,
+            FENCE_I => 0b001 << 12 | OP_MISC_MEM,
+            ECALL => ECALL_WORD,
+            EBREAK => EBREAK_WORD,
+            CSRRW { csr, rs1, rd } => i_type(OP_SYSTEM, 0b001, rd, rs1, csr as i32),
+            CSRRS { csr, rs1, rd } => i_type(OP_SYSTEM, 0b010, rd, rs1, csr as i32),
+            CSRRC { csr, rs1, rd } => i_type(OP_SYSTEM, 0b011, rd, rs1, csr as i32),
+            CSRRWI { csr, uimm, rd } => i_type(OP_SYSTEM, 0b101, rd, uimm, csr as i32),
+            CSRRSI { csr, uimm, rd } => i_type(OP_SYSTEM, 0b110, rd, uimm, csr as i32),
+            CSRRCI { csr, uimm, rd } => i_type(OP_SYSTEM, 0b111, rd, uimm, csr as i32),
+            MRET => MRET_WORD,
+            SRET => SRET_WORD,
+            WFI => WFI_WORD,
+            SFENCE_VMA { rs1, rs2 } => r_type(OP_SYSTEM, 0, FUNC7_SFENCE_VMA, 0, rs1, rs2),
+            LR_W { rs1, rd, aqrl } => r_type(OP_AMO, 0b010, FUNC5_LR << 2 | aqrl, rd, rs1, 0),
+            SC_W { rs1, rs2, rd, aqrl } => {
+                r_type(OP_AMO, 0b010, FUNC5_SC << 2 | aqrl, rd, rs1, rs2)
+            }
+            AMO {
+                op,
+                rs1,
+                rs2,
+                rd,
+                aqrl,
+            } => r_type(OP_AMO, 0b010, (op as u8) << 2 | aqrl, rd, rs1, rs2),
         }
     }
 }
 
+#[cfg(any(test, fuzzing))]
+fn r_type(op: u32, func3: u8, func7: u8, rd: u8, rs1: u8, rs2: u8) -> u32 {
+    (func7 as u32) << 25
+        | (rs2 as u32) << 20
+        | (rs1 as u32) << 15
+        | (func3 as u32) << 12
+        | (rd as u32) << 7
+        | op
+}
+
+#[cfg(any(test, fuzzing))]
+fn i_type(op: u32, func3: u8, rd: u8, rs1: u8, imm: i32) -> u32 {
+    (imm as u32) << 20 | (rs1 as u32) << 15 | (func3 as u32) << 12 | (rd as u32) << 7 | op
+}
+
+#[cfg(any(test, fuzzing))]
+fn s_type(func3: u8, rs1: u8, rs2: u8, imm: i32) -> u32 {
+    let imm = imm as u32;
+    let b11_5 = (imm & (0b1111111 << 5)) << 20;
+    let b4_0 = (imm & 0b11111) << 7;
+    b11_5 | (rs2 as u32) << 20 | (rs1 as u32) << 15 | (func3 as u32) << 12 | b4_0 | OP_STORE
+}
+
+#[cfg(any(test, fuzzing))]
+fn b_type(func3: u8, rs1: u8, rs2: u8, imm: i32) -> u32 {
+    let imm = imm as u32;
+    let b12 = (imm & (1 << 12)) << 19;
+    let b10_5 = (imm & (0b111111 << 5)) << 20;
+    let b4_1 = (imm & (0b1111 << 1)) << 7;
+    let b11 = (imm & (1 << 11)) >> 4;
+    b12 | b10_5
+        | (rs2 as u32) << 20
+        | (rs1 as u32) << 15
+        | (func3 as u32) << 12
+        | b4_1
+        | b11
+        | OP_BRANCH
+}
+
+#[cfg(any(test, fuzzing))]
+fn u_type(op: u32, rd: u8, imm: i32) -> u32 {
+    (imm as u32) & MASK_LUI_IMM | (rd as u32) << 7 | op
+}
+
 fn get_rd(inst: u32) -> u8 {
     let masked = inst & (0b11111 << 7);
     (masked >> 7) as u8
@@ -280,9 +568,9 @@ fn get_b_imm(inst: u32) -> u32 {
     let b4_1 = (inst & (0b1111 << 8)) >> 7;
     if (inst & (1 << 31)) != 0 {
         let ext = 0b11111111111111111111 << 12;
-        base = base | ext;
+        base |= ext;
     }
-    base = base | b12 | b4_1 | b11 | b10_5;
+    base |= b12 | b4_1 | b11 | b10_5;
     base
 }
 
@@ -292,9 +580,9 @@ fn get_s_imm(inst: u32) -> u32 {
     let b4_0 = (inst & (0b11111 << 7)) >> 7;
     if (inst & (1 << 31)) != 0 {
         let ext = 0b11111111111111111111 << 12;
-        base = base | ext;
+        base |= ext;
     }
-    base = base | b11_5 | b4_0;
+    base |= b11_5 | b4_0;
     base
 }
 
@@ -302,7 +590,256 @@ fn get_imm_11_0(inst: u32) -> u32 {
     let mut base = (inst & MASK_11_0) >> 20;
     if (inst & (1 << 31)) != 0 {
         let ext = MASK_11_0_EXTEND;
-        base = base | ext;
+        base |= ext;
     }
     base
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Instruction::*;
+    use proptest::prelude::*;
+    use proptest::sample::select;
+
+    use super::*;
+
+    const OPCODES: [u32; 12] = [
+        OP_LUI,
+        OP_AUIPC,
+        OP_JAL,
+        OP_JALR,
+        OP_BRANCH,
+        OP_LOAD,
+        OP_STORE,
+        OP_IMM,
+        OP_REG,
+        OP_MISC_MEM,
+        OP_SYSTEM,
+        OP_AMO,
+    ];
+
+    // RV32IA straight from the opcode map, independent of try_new
+    fn legal(word: u32) -> bool {
+        let func3 = (word >> 12) & 0b111;
+        let func7 = word >> 25;
+        match word & MASK_OP {
+            OP_LUI | OP_AUIPC | OP_JAL => true,
+            OP_JALR => func3 == 0,
+            OP_BRANCH => func3 != 0b010 && func3 != 0b011,
+            OP_LOAD => matches!(func3, 0b000 | 0b001 | 0b010 | 0b100 | 0b101),
+            OP_STORE => func3 <= 0b010,
+            OP_IMM => match func3 {
+                0b001 => func7 == 0,
+                0b101 => func7 == 0 || func7 == 0b0100000,
+                _ => true,
+            },
+            OP_REG => func7 == 0 || (func7 == 0b0100000 && (func3 == 0b000 || func3 == 0b101)),
+            OP_MISC_MEM => func3 <= 0b001,
+            OP_SYSTEM => match func3 {
+                0b000 => {
+                    matches!(
+                        word,
+                        0x00000073 | 0x00100073 | 0x30200073 | 0x10200073 | 0x10500073
+                    ) || word & 0xfe007fff == 0x12000073
+                }
+                0b100 => false,
+                _ => true,
+            },
+            OP_AMO => {
+                let func5 = func7 >> 2;
+                let rs2 = (word >> 20) & 0b11111;
+                func3 == 0b010
+                    && match func5 {
+                        0b00010 => rs2 == 0,
+                        0b00011 | 0b00001 | 0b00000 | 0b00100 | 0b01000 | 0b01100 => true,
+                        0b10000 | 0b10100 | 0b11000 | 0b11100 => true,
+                        _ => false,
+                    }
+            }
+            _ => false,
+        }
+    }
+
+    // Fields the spec asks implementations to ignore
+    fn reserved(word: u32) -> u32 {
+        let regs = 0b11111 << 15 | 0b11111 << 7;
+        match (word & MASK_OP, (word >> 12) & 0b111) {
+            (OP_MISC_MEM, 0b000) => 0xf0000000 | regs,
+            (OP_MISC_MEM, 0b001) => 0xfff00000 | regs,
+            _ => 0,
+        }
+    }
+
+    // Uniform words rarely hit the interesting opcodes, so half of them get
+    // a valid one patched in
+    fn word() -> impl Strategy<Value = u32> {
+        prop_oneof![
+            any::<u32>(),
+            (any::<u32>(), select(&OPCODES[..])).prop_map(|(w, op)| w & !MASK_OP | op),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(20_000))]
+
+        #[test]
+        fn decodes_exactly_the_legal_words(word in word()) {
+            prop_assert_eq!(Instruction::try_new(word).is_some(), legal(word), "0x{:08x}", word);
+        }
+
+        #[test]
+        fn encode_inverts_decode(word in word()) {
+            if let Some(inst) = Instruction::try_new(word) {
+                prop_assert_eq!(inst.encode(), word & !reserved(word), "{:?}", inst);
+                prop_assert_eq!(Instruction::try_new(inst.encode()), Some(inst));
+            }
+        }
+    }
+
+    #[test]
+    fn every_opcode_and_function() {
+        for op in 0..=MASK_OP {
+            for func3 in 0..8 {
+                for func7 in 0..128 {
+                    for regs in [0, 0b11111 << 20 | 0b11111 << 15 | 0b11111 << 7] {
+                        let word = func7 << 25 | regs | func3 << 12 | op;
+                        let inst = Instruction::try_new(word);
+                        assert_eq!(inst.is_some(), legal(word), "0x{:08x}", word);
+                        if let Some(inst) = inst {
+                            assert_eq!(inst.encode(), word & !reserved(word), "{:?}", inst);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matches_the_assembler() {
+        // llvm-mc -triple=riscv32 -show-encoding
+        let cases = [
+            (
+                0x800000ef,
+                JAL {
+                    imm: -1048576,
+                    rd: 1,
+                },
+            ),
+            (
+                0x7ff41fe3,
+                BNE {
+                    imm: 4094,
+                    rs1: 8,
+                    rs2: 31,
+                },
+            ),
+            (
+                0xfea12fa3,
+                SW {
+                    imm: -1,
+                    rs1: 2,
+                    rs2: 10,
+                },
+            ),
+            (
+                0x41f35293,
+                SRAI {
+                    shift: 31,
+                    rs1: 6,
+                    rd: 5,
+                },
+            ),
+            (0xfffff7b7, LUI { imm: -4096, rd: 15 }),
+            (
+                0x0310000f,
+                FENCE {
+                    pred: 0b0011,
+                    succ: 0b0001,
+                },
+            ),
+            (0x0000100f, FENCE_I),
+            (
+                0x40b00533,
+                SUB {
+                    rs1: 0,
+                    rs2: 11,
+                    rd: 10,
+                },
+            ),
+            (
+                0x7ff75483,
+                LHU {
+                    imm: 2047,
+                    rs1: 14,
+                    rd: 9,
+                },
+            ),
+            (
+                0x30059573,
+                CSRRW {
+                    csr: 0x300,
+                    rs1: 11,
+                    rd: 10,
+                },
+            ),
+            (
+                0x10017073,
+                CSRRCI {
+                    csr: 0x100,
+                    uimm: 2,
+                    rd: 0,
+                },
+            ),
+            (0x10200073, SRET),
+            (0x10500073, WFI),
+            (0x12b50073, SFENCE_VMA { rs1: 10, rs2: 11 }),
+            (
+                0x1405a52f,
+                LR_W {
+                    rs1: 11,
+                    rd: 10,
+                    aqrl: 0b10,
+                },
+            ),
+            (
+                0x18c5a52f,
+                SC_W {
+                    rs1: 11,
+                    rs2: 12,
+                    rd: 10,
+                    aqrl: 0,
+                },
+            ),
+            (
+                0xe6c5a52f,
+                AMO {
+                    op: Amo::Maxu,
+                    rs1: 11,
+                    rs2: 12,
+                    rd: 10,
+                    aqrl: 0b11,
+                },
+            ),
+        ];
+        for (word, inst) in cases.iter() {
+            assert_eq!(Instruction::try_new(*word), Some(*inst));
+            assert_eq!(inst.encode(), *word, "{:?}", inst);
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_encodings() {
+        let words = [
+            0x00000000, // all zeros is defined to be illegal
+            0xffffffff,
+            0x00004073,           // SYSTEM with func3 100
+            0x30200073 | 1 << 7,  // mret with rd set
+            0x02b50533,           // mul
+            0x1025a52f,           // lr.w with rs2 set
+            0x00051513 | 1 << 25, // slli with shamt[5] set
+        ];
+        for word in words.iter() {
+            assert_eq!(Instruction::try_new(*word), None, "0x{:08x}", word);
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use crate::block::{load, store, Block, Cond, Exit, Flow, Op, Stored, SINK};
+use crate::memory::Memory;
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, SigRef, Signature, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+use std::fmt;
+
+// Compiled block, called with the register file of BlockCache::run and the
+// Env its loads and stores need. Returns the Flow as kind << 32 | pc.
+pub(crate) type Code = unsafe extern "C" fn(*mut u32, *mut Env) -> u64;
+
+const CHAIN_TAKEN: u64 = 0;
+const CHAIN_NOT_TAKEN: u64 = 1;
+const INDIRECT: u64 = 2;
+const STOP: u64 = 3;
+
+// Host code for the blocks of a BlockCache, by block index. Data accesses
+// call back into block::load and block::store, so traps and tohost behave
+// exactly as in the interpreter.
+pub(crate) struct Jit {
+    module: JITModule,
+    ctx: Context,
+    builder: FunctionBuilderContext,
+    code: Vec<Option<Code>>,
+}
+
+impl fmt::Debug for Jit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Jit({} blocks)", self.code.len())
+    }
+}
+
+pub(crate) struct Env<'a> {
+    mem: &'a mut Memory,
+    version: u64,
+    tohost: Option<u32>,
+}
+
+impl Jit {
+    pub fn new() -> Self {
+        let module = new_module();
+        Jit {
+            ctx: module.make_context(),
+            module,
+            builder: FunctionBuilderContext::new(),
+            code: vec![],
+        }
+    }
+
+    pub fn code(&self, i: usize) -> Option<Code> {
+        self.code.get(i).copied().flatten()
+    }
+
+    // The code of block i, failures fall back to the interpreter
+    pub fn compile(&mut self, block: &Block) {
+        let code = self.try_compile(block);
+        if let Err(e) = &code {
+            eprintln!("JIT: block at 0x{:08x}: {}", block.start, e);
+        }
+        self.code.push(code.ok());
+    }
+
+    // Frees the code of every block, they are about to be retranslated
+    pub fn clear(&mut self) {
+        self.code.clear();
+        let old = std::mem::replace(&mut self.module, new_module());
+        // nothing points into the old module any more
+        unsafe { old.free_memory() };
+    }
+
+    fn try_compile(&mut self, block: &Block) -> Result<Code, String> {
+        let ptr = self.module.target_config().pointer_type();
+        let mut sig = self.module.make_signature();
+        sig.params.push(AbiParam::new(ptr));
+        sig.params.push(AbiParam::new(ptr));
+        sig.returns.push(AbiParam::new(types::I64));
+        let id = self
+            .module
+            .declare_anonymous_function(&sig)
+            .map_err(|e| e.to_string())?;
+        self.ctx.func.signature = sig;
+        {
+            let mut b = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder);
+            let entry = b.create_block();
+            b.append_block_params_for_function_params(entry);
+            b.switch_to_block(entry);
+            let params = b.block_params(entry);
+            let (x, env) = (params[0], params[1]);
+            let mut load_sig = Signature::new(b.func.signature.call_conv);
+            load_sig.params.push(AbiParam::new(ptr));
+            load_sig.params.push(AbiParam::new(types::I32));
+            load_sig.params.push(AbiParam::new(types::I32));
+            load_sig.returns.push(AbiParam::new(types::I64));
+            let mut store_sig = load_sig.clone();
+            store_sig.params.push(AbiParam::new(types::I32));
+            store_sig.returns[0] = AbiParam::new(types::I32);
+            let mut t = Translator {
+                load_sig: b.import_signature(load_sig),
+                store_sig: b.import_signature(store_sig),
+                b,
+                ptr,
+                x,
+                env,
+                loaded: [false; 33],
+                dirty: [false; 33],
+            };
+            for i in 0..33 {
+                t.b.declare_var(Variable::from_u32(i), types::I32);
+            }
+            for (i, op) in block.ops.iter().enumerate() {
+                let pc = block.start.wrapping_add(4 * i as u32);
+                t.op(*op, pc);
+            }
+            t.exit(block);
+            t.b.seal_all_blocks();
+            t.b.finalize();
+        }
+        let result = self
+            .module
+            .define_function(id, &mut self.ctx)
+            .map_err(|e| format!("{:?}", e));
+        self.module.clear_context(&mut self.ctx);
+        result?;
+        self.module
+            .finalize_definitions()
+            .map_err(|e| e.to_string())?;
+        let code = self.module.get_finalized_function(id);
+        // compiled with the signature of Code
+        Ok(unsafe { std::mem::transmute::<*const u8, Code>(code) })
+    }
+}
+
+fn new_module() -> JITModule {
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").unwrap();
+    let isa = cranelift_native::builder()
+        .unwrap_or_else(|e| panic!("JIT: unsupported host: {}", e))
+        .finish(settings::Flags::new(flags))
+        .unwrap_or_else(|e| panic!("JIT: {}", e));
+    JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()))
+}
+
+pub(crate) fn execute(
+    code: Code,
+    block: &Block,
+    x: &mut [u32; 33],
+    mem: &mut Memory,
+    version: u64,
+    tohost: Option<u32>,
+) -> Flow {
+    let mut env = Env {
+        mem,
+        version,
+        tohost,
+    };
+    // 'x' and 'env' outlive the call, the code only touches them through
+    // its own offsets and the helpers below
+    let ret = unsafe { code(x.as_mut_ptr(), &mut env) };
+    let pc = ret as u32;
+    let instret = match ret >> 32 {
+        STOP => pc.wrapping_sub(block.start) as u64 / 4,
+        _ => block.len,
+    };
+    env.mem.hart.instret += instret;
+    match ret >> 32 {
+        CHAIN_TAKEN => Flow::Chain(pc, 0),
+        CHAIN_NOT_TAKEN => Flow::Chain(pc, 1),
+        INDIRECT => Flow::Indirect(pc),
+        _ => Flow::Stop(pc),
+    }
+}
+
+// Raw little endian value, bit 32 set when the access faults
+extern "C" fn load_helper(env: *mut Env, addr: u32, len: u32) -> u64 {
+    let env = unsafe { &mut *env };
+    let raw = match len {
+        1 => load::<1>(env.mem, addr).map(|b| b[0] as u32),
+        2 => load::<2>(env.mem, addr)
+            .map(u16::from_le_bytes)
+            .map(|v| v as u32),
+        _ => load::<4>(env.mem, addr).map(u32::from_le_bytes),
+    };
+    raw.map_or(1 << 32, |v| v as u64)
+}
+
+// 0 when the block goes on, 1 on a fault, 2 when it has to stop after
+extern "C" fn store_helper(env: *mut Env, addr: u32, len: u32, val: u32) -> u32 {
+    let env = unsafe { &mut *env };
+    match store(env.mem, addr, val, len as usize, env.version, env.tohost) {
+        Stored::Done => 0,
+        Stored::Fault => 1,
+        Stored::Stop => 2,
+    }
+}
+
+// Guest registers live in variables while the block runs, written back to
+// the register file at every exit
+struct Translator<'a> {
+    b: FunctionBuilder<'a>,
+    ptr: types::Type,
+    x: Value,
+    env: Value,
+    load_sig: SigRef,
+    store_sig: SigRef,
+    loaded: [bool; 33],
+    dirty: [bool; 33],
+}
+
+impl Translator<'_> {
+    fn get(&mut self, r: u8) -> Value {
+        let r = r as usize;
+        if r == 0 {
+            return self.b.ins().iconst(types::I32, 0);
+        }
+        let var = Variable::from_u32(r as u32);
+        if !self.loaded[r] {
+            let val = self
+                .b
+                .ins()
+                .load(types::I32, MemFlags::trusted(), self.x, 4 * r as i32);
+            self.b.def_var(var, val);
+            self.loaded[r] = true;
+        }
+        self.b.use_var(var)
+    }
+
+    fn set(&mut self, r: u8, val: Value) {
+        if r == SINK {
+            return;
+        }
+        self.b.def_var(Variable::from_u32(r as u32), val);
+        self.loaded[r as usize] = true;
+        self.dirty[r as usize] = true;
+    }
+
+    fn imm(&mut self, val: u32) -> Value {
+        self.b.ins().iconst(types::I32, val as i64)
+    }
+
+    // Writes back the registers and returns kind << 32 | pc
+    fn leave(&mut self, kind: u64, pc: Value) {
+        for r in 1..32 {
+            if self.dirty[r] {
+                let val = self.b.use_var(Variable::from_u32(r as u32));
+                self.b
+                    .ins()
+                    .store(MemFlags::trusted(), val, self.x, 4 * r as i32);
+            }
+        }
+        let pc = self.b.ins().uextend(types::I64, pc);
+        let ret = self.b.ins().bor_imm(pc, (kind << 32) as i64);
+        self.b.ins().return_(&[ret]);
+    }
+
+    // Leaves with STOP at 'pc' when 'cond' is set, goes on otherwise
+    fn stop_if(&mut self, cond: Value, pc: Value) {
+        let (out, next) = (self.b.create_block(), self.b.create_block());
+        self.b.ins().brif(cond, out, &[], next, &[]);
+        self.b.switch_to_block(out);
+        self.leave(STOP, pc);
+        self.b.switch_to_block(next);
+    }
+
+    fn op(&mut self, op: Op, pc: u32) {
+        use IntCC::*;
+        match op {
+            Op::Li { rd, val } => {
+                let v = self.imm(val);
+                self.set(rd, v)
+            }
+            Op::AddI { rd, rs1, imm } => self.binary(rd, rs1, imm, |b, a, c| b.ins().iadd(a, c)),
+            Op::SltI { rd, rs1, imm } => self.binary(rd, rs1, imm, |b, a, c| {
+                let lt = b.ins().icmp(SignedLessThan, a, c);
+                b.ins().uextend(types::I32, lt)
+            }),
+            Op::SltIU { rd, rs1, imm } => self.binary(rd, rs1, imm, |b, a, c| {
+                let lt = b.ins().icmp(UnsignedLessThan, a, c);
+                b.ins().uextend(types::I32, lt)
+            }),
+            Op::XorI { rd, rs1, imm } => self.binary(rd, rs1, imm, |b, a, c| b.ins().bxor(a, c)),
+            Op::OrI { rd, rs1, imm } => self.binary(rd, rs1, imm, |b, a, c| b.ins().bor(a, c)),
+            Op::AndI { rd, rs1, imm } => self.binary(rd, rs1, imm, |b, a, c| b.ins().band(a, c)),
+            // shift amounts are taken modulo 32 like on RISC-V
+            Op::SllI { rd, rs1, shift } => {
+                self.binary(rd, rs1, shift as u32, |b, a, c| b.ins().ishl(a, c))
+            }
+            Op::SrlI { rd, rs1, shift } => {
+                self.binary(rd, rs1, shift as u32, |b, a, c| b.ins().ushr(a, c))
+            }
+            Op::SraI { rd, rs1, shift } => {
+                self.binary(rd, rs1, shift as u32, |b, a, c| b.ins().sshr(a, c))
+            }
+            Op::Add { rd, rs1, rs2 } => self.reg(rd, rs1, rs2, |b, a, c| b.ins().iadd(a, c)),
+            Op::Sub { rd, rs1, rs2 } => self.reg(rd, rs1, rs2, |b, a, c| b.ins().isub(a, c)),
+            Op::Sll { rd, rs1, rs2 } => self.reg(rd, rs1, rs2, |b, a, c| b.ins().ishl(a, c)),
+            Op::Slt { rd, rs1, rs2 } => self.reg(rd, rs1, rs2, |b, a, c| {
+                let lt = b.ins().icmp(SignedLessThan, a, c);
+                b.ins().uextend(types::I32, lt)
+            }),
+            Op::SltU { rd, rs1, rs2 } => self.reg(rd, rs1, rs2, |b, a, c| {
+                let lt = b.ins().icmp(UnsignedLessThan, a, c);
+                b.ins().uextend(types::I32, lt)
+            }),
+            Op::Xor { rd, rs1, rs2 } => self.reg(rd, rs1, rs2, |b, a, c| b.ins().bxor(a, c)),
+            Op::Srl { rd, rs1, rs2 } => self.reg(rd, rs1, rs2, |b, a, c| b.ins().ushr(a, c)),
+            Op::Sra { rd, rs1, rs2 } => self.reg(rd, rs1, rs2, |b, a, c| b.ins().sshr(a, c)),
+            Op::Or { rd, rs1, rs2 } => self.reg(rd, rs1, rs2, |b, a, c| b.ins().bor(a, c)),
+            Op::And { rd, rs1, rs2 } => self.reg(rd, rs1, rs2, |b, a, c| b.ins().band(a, c)),
+            Op::Lb { rd, rs1, imm } => self.load(rd, rs1, imm, 1, true, pc),
+            Op::Lh { rd, rs1, imm } => self.load(rd, rs1, imm, 2, true, pc),
+            Op::Lw { rd, rs1, imm } => self.load(rd, rs1, imm, 4, false, pc),
+            Op::LbU { rd, rs1, imm } => self.load(rd, rs1, imm, 1, false, pc),
+            Op::LhU { rd, rs1, imm } => self.load(rd, rs1, imm, 2, false, pc),
+            Op::Sb { rs1, rs2, imm } => self.store(rs1, rs2, imm, 1, pc),
+            Op::Sh { rs1, rs2, imm } => self.store(rs1, rs2, imm, 2, pc),
+            Op::Sw { rs1, rs2, imm } => self.store(rs1, rs2, imm, 4, pc),
+            Op::Nop => {}
+        }
+    }
+
+    fn binary(
+        &mut self,
+        rd: u8,
+        rs1: u8,
+        imm: u32,
+        f: impl FnOnce(&mut FunctionBuilder, Value, Value) -> Value,
+    ) {
+        let a = self.get(rs1);
+        let c = self.imm(imm);
+        let v = f(&mut self.b, a, c);
+        self.set(rd, v);
+    }
+
+    fn reg(
+        &mut self,
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+        f: impl FnOnce(&mut FunctionBuilder, Value, Value) -> Value,
+    ) {
+        let a = self.get(rs1);
+        let c = self.get(rs2);
+        let v = f(&mut self.b, a, c);
+        self.set(rd, v);
+    }
+
+    fn helper(&mut self, helper: usize, sig: SigRef, args: &[Value]) -> Value {
+        let callee = self.b.ins().iconst(self.ptr, helper as i64);
+        let mut all = vec![self.env];
+        all.extend_from_slice(args);
+        let call = self.b.ins().call_indirect(sig, callee, &all);
+        self.b.inst_results(call)[0]
+    }
+
+    fn load(&mut self, rd: u8, rs1: u8, imm: u32, len: u32, signed: bool, pc: u32) {
+        let base = self.get(rs1);
+        let addr = self.b.ins().iadd_imm(base, imm as i32 as i64);
+        let len_v = self.imm(len);
+        let raw = self.helper(
+            load_helper as *const () as usize,
+            self.load_sig,
+            &[addr, len_v],
+        );
+        let fault = self.b.ins().ushr_imm(raw, 32);
+        let pc = self.imm(pc);
+        self.stop_if(fault, pc);
+        let mut val = self.b.ins().ireduce(types::I32, raw);
+        if signed {
+            let narrow = if len == 1 { types::I8 } else { types::I16 };
+            let v = self.b.ins().ireduce(narrow, val);
+            val = self.b.ins().sextend(types::I32, v);
+        }
+        self.set(rd, val);
+    }
+
+    fn store(&mut self, rs1: u8, rs2: u8, imm: u32, len: u32, pc: u32) {
+        let base = self.get(rs1);
+        let addr = self.b.ins().iadd_imm(base, imm as i32 as i64);
+        let val = self.get(rs2);
+        let len_v = self.imm(len);
+        let status = self.helper(
+            store_helper as *const () as usize,
+            self.store_sig,
+            &[addr, len_v, val],
+        );
+        // 1 stops at this store, 2 after it
+        let offset = self.b.ins().ishl_imm(status, 2);
+        let before = self.imm(pc.wrapping_sub(4));
+        let stop_pc = self.b.ins().iadd(before, offset);
+        self.stop_if(status, stop_pc);
+    }
+
+    fn exit(&mut self, block: &Block) {
+        let end = self.imm(block.end);
+        match block.exit {
+            Exit::Jump { rd, target } => {
+                self.set(rd, end);
+                let target = self.imm(target);
+                self.leave(CHAIN_TAKEN, target);
+            }
+            Exit::Branch {
+                cond,
+                rs1,
+                rs2,
+                target,
+            } => {
+                let cc = match cond {
+                    Cond::Eq => IntCC::Equal,
+                    Cond::Ne => IntCC::NotEqual,
+                    Cond::Lt => IntCC::SignedLessThan,
+                    Cond::Ge => IntCC::SignedGreaterThanOrEqual,
+                    Cond::LtU => IntCC::UnsignedLessThan,
+                    Cond::GeU => IntCC::UnsignedGreaterThanOrEqual,
+                };
+                let (a, c) = (self.get(rs1), self.get(rs2));
+                let taken = self.b.ins().icmp(cc, a, c);
+                let (yes, no) = (self.b.create_block(), self.b.create_block());
+                self.b.ins().brif(taken, yes, &[], no, &[]);
+                self.b.switch_to_block(yes);
+                let target = self.imm(target);
+                self.leave(CHAIN_TAKEN, target);
+                self.b.switch_to_block(no);
+                self.leave(CHAIN_NOT_TAKEN, end);
+            }
+            Exit::Indirect { rd, rs1, imm } => {
+                // read before the link, rd may be rs1
+                let base = self.get(rs1);
+                let sum = self.b.ins().iadd_imm(base, imm as i32 as i64);
+                let target = self.b.ins().band_imm(sum, !1);
+                // Processor::tick raises the misaligned jump
+                let misaligned = self.b.ins().band_imm(target, 2);
+                let jump = self.imm(block.end.wrapping_sub(4));
+                self.stop_if(misaligned, jump);
+                self.set(rd, end);
+                self.leave(INDIRECT, target);
+            }
+            Exit::Fall => self.leave(CHAIN_TAKEN, end),
+            Exit::Generic => self.leave(STOP, end),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::BlockCache;
+    use crate::memory::Memory;
+    use crate::processor::Processor;
+    use crate::symbols::Symbols;
+    use crate::util::*;
+    use elfloader::ElfBinary;
+    use std::fs;
+
+    const LIMIT: usize = 1_000_000;
+
+    // Runs 'path' one block at a time in the interpreter and the JIT, the
+    // instructions blocks leave to Processor::tick run on both
+    fn lockstep(path: &str) {
+        let blob = fs::read(path).unwrap();
+        let binary = ElfBinary::new("lockstep", &blob).unwrap();
+        let tohost = Symbols::new(&binary).lookup("tohost");
+        let mut mem = [Memory::new(&binary, &blob), Memory::new(&binary, &blob)];
+        let mut blocks = [BlockCache::default(), BlockCache::default()];
+        blocks[1].enable_jit();
+        for step in 0..LIMIT {
+            for (mem, blocks) in mem.iter_mut().zip(blocks.iter_mut()) {
+                if step % 2 == 0 {
+                    blocks.run_for(mem, tohost, 1);
+                } else {
+                    Processor::tick(mem);
+                }
+            }
+            let [a, b] = &mem;
+            let at = format!("{} after step {} at pc 0x{:08x}", path, step, a.get_pc());
+            assert_eq!(a.get_pc(), b.get_pc(), "{}", at);
+            assert_eq!(a.registers(), b.registers(), "{}", at);
+            assert_eq!(a.hart.instret, b.hart.instret, "{}", at);
+            assert_eq!(a.exit_code, b.exit_code, "{}", at);
+            for header in binary.program_headers() {
+                let (start, size) = (header.virtual_addr() as usize, header.mem_size() as usize);
+                assert!(a.read(start, size) == b.read(start, size), "{}", at);
+            }
+            let done = tohost.map(|addr| to_u32(&a.read(addr as usize, 4)) & 1 == 1);
+            if a.exit_code.is_some() || done == Some(true) {
+                return;
+            }
+        }
+        panic!("{} did not finish within {} steps", path, LIMIT);
+    }
+
+    #[test]
+    fn matches_the_interpreter() {
+        let mut paths: Vec<_> = fs::read_dir("tests/isa")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                let suite = name.starts_with("rv32ui-p-") || name.starts_with("rv32mi-p-");
+                suite && path.extension().is_none()
+            })
+            .collect();
+        paths.push("tests/guests/signature.elf".into());
+        paths.push("tests/guests/alu.elf".into());
+        for path in paths {
+            lockstep(path.to_str().unwrap());
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use crate::memory::Memory;
+use crate::sanitizer::format_stack;
+use std::collections::HashMap;
+
+// Exit code when leaks fail the run, the same as LeakSanitizer's
+pub(crate) const LEAK_EXIT: i32 = 23;
+// Addresses listed per allocation site
+const SHOWN: usize = 8;
+
+// Heap blocks the guest never freed, grouped by allocation call stack with
+// the biggest sites first. None when nothing leaked.
+pub(crate) fn report(mem: &Memory) -> Option<String> {
+    let mut sites: HashMap<&[u32], Vec<(usize, usize)>> = HashMap::new();
+    let allocations = mem.allocations();
+    for (start, site) in allocations.iter().filter(|(_, site)| !site.persistent) {
+        sites
+            .entry(&site.stack[..])
+            .or_default()
+            .push((*start, site.size));
+    }
+    if sites.is_empty() {
+        return None;
+    }
+    let total = |blocks: &[(usize, usize)]| blocks.iter().map(|(_, size)| size).sum::<usize>();
+    let mut sites: Vec<_> = sites.into_iter().collect();
+    sites.sort_by_key(|(stack, blocks)| (std::cmp::Reverse(total(blocks)), stack.to_vec()));
+    let mut out = String::new();
+    let (mut bytes, mut count) = (0, 0);
+    for (stack, blocks) in sites.iter() {
+        out += &format!(
+            "==leak-check== {} bytes in {} block{} allocated",
+            total(blocks),
+            blocks.len(),
+            if blocks.len() == 1 { "" } else { "s" }
+        );
+        match stack.is_empty() {
+            true => out += " before call sites were tracked\n",
+            false => out += &format!(" by:\n{}", format_stack(mem, stack)),
+        }
+        for (start, size) in blocks.iter().take(SHOWN) {
+            out += &format!("  {} bytes at 0x{:08x}\n", size, start);
+        }
+        if blocks.len() > SHOWN {
+            out += &format!("  ... and {} more\n", blocks.len() - SHOWN);
+        }
+        bytes += total(blocks);
+        count += blocks.len();
+    }
+    out += &format!(
+        "==leak-check== SUMMARY: {} bytes leaked in {} block{}\n",
+        bytes,
+        count,
+        if count == 1 { "" } else { "s" }
+    );
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_leaks_by_call_stack() {
+        let mut mem = Memory::with_code(0x1000, &[0; 4]);
+        mem.init_heap(1 << 16, 16);
+        mem.hart.calls = Some(vec![]);
+        mem.track_call(0x1000, 1, 0, 0x1100);
+        let a = mem.malloc(8, 0);
+        mem.malloc(24, 0);
+        let kept = mem.malloc(100, 0);
+        assert!(mem.persist(kept));
+        mem.track_call(0x1104, 0, 1, 0x1004);
+        let b = mem.malloc(40, 0);
+        let text = report(&mem).unwrap();
+        assert!(text.starts_with("==leak-check== 40 bytes in 1 block allocated by:\n"));
+        assert!(text.contains(
+            "32 bytes in 2 blocks allocated by:\n    #0 0x00001000\n    #1 0x00001000\n"
+        ));
+        assert!(text.ends_with("SUMMARY: 72 bytes leaked in 3 blocks\n"));
+        mem.free(a);
+        mem.free(b);
+        mem.free(mem.allocations()[0].0 as u32);
+        assert!(report(&mem).is_none());
+    }
+}
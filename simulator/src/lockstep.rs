@@ -0,0 +1,119 @@
+use crate::disasm::disassemble_word;
+use crate::instruction::REGISTER_NAMES;
+use crate::machine::Machine;
+use crate::memory::Memory;
+use crate::symbols::Symbols;
+use crate::trace::Commit;
+use std::collections::VecDeque;
+use std::fs;
+
+// Retired instructions shown before a divergence
+const CONTEXT: usize = 8;
+
+// Runs 'machine' against the Spike commit log in 'path', comparing hart, pc,
+// rd value and memory effect of every retired instruction. Once the reference
+// ends the guest runs on unchecked. Returns the exit code, or the report of
+// the first divergence.
+pub(crate) fn run(machine: &mut Machine, path: &str, symbols: &Symbols) -> Result<i32, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut history: VecDeque<Commit> = VecDeque::with_capacity(CONTEXT);
+    let mut count = 0;
+    for (i, line) in text.lines().enumerate() {
+        let expected = match Commit::parse_spike(line) {
+            Some(commit) => commit,
+            None if line.trim_start().starts_with("core") => {
+                return Err(format!("{}:{}: bad commit line '{}'", path, i + 1, line))
+            }
+            None => continue,
+        };
+        let mem = machine.mem();
+        if let Some(code) = mem.exit_code {
+            return Err(format!(
+                "Lockstep divergence after {} instructions: guest exited with {}, \
+                 reference continues at {}:{}\n  expected: {}\n{}",
+                count,
+                code,
+                path,
+                i + 1,
+                describe(&expected, symbols),
+                state(mem, mem.hart_id(), &history, symbols)
+            ));
+        }
+        let got = loop {
+            let mem = machine.mem();
+            let (mode, pc, raw) = (mem.csrs().mode(), mem.get_pc(), mem.get_instr());
+            machine.retire();
+            // instructions that trap have no commit line
+            let mem = machine.mem();
+            let commit = (mem.last_trap.is_none() || mem.exit_code.is_some())
+                .then(|| Commit::capture(mode, pc, raw, mem));
+            machine.schedule();
+            if let Some(commit) = commit {
+                break commit;
+            }
+        };
+        if !matches(&got, &expected) {
+            return Err(format!(
+                "Lockstep divergence at instruction {} ({}:{})\n  expected: {}\n  got:      {}\n{}",
+                count + 1,
+                path,
+                i + 1,
+                describe(&expected, symbols),
+                describe(&got, symbols),
+                state(machine.mem(), got.hart as usize, &history, symbols)
+            ));
+        }
+        if history.len() == CONTEXT {
+            history.pop_front();
+        }
+        history.push_back(got);
+        count += 1;
+    }
+    eprintln!("lockstep: {} instructions match {}", count, path);
+    Ok(match machine.mem().exit_code {
+        Some(code) => code,
+        None => machine.run(),
+    })
+}
+
+fn matches(got: &Commit, expected: &Commit) -> bool {
+    let mem = match (got.mem, expected.mem) {
+        (Some(a), Some(b)) => a.addr == b.addr && a.store == b.store,
+        (a, b) => a.is_none() && b.is_none(),
+    };
+    got.hart == expected.hart
+        && got.pc == expected.pc
+        && got.raw == expected.raw
+        && got.rd == expected.rd
+        && mem
+}
+
+fn describe(commit: &Commit, symbols: &Symbols) -> String {
+    let text = disassemble_word(commit.raw, commit.pc, Some(symbols));
+    format!("{:<72} ; {}", commit.spike_line(), text.replace('\t', " "))
+}
+
+// Context window and the full register file of 'hart' after the diverging
+// instruction
+fn state(mem: &Memory, hart: usize, history: &VecDeque<Commit>, symbols: &Symbols) -> String {
+    let hart = mem.get_hart(hart);
+    let mut out = String::from("Last retired instructions:\n");
+    for commit in history.iter() {
+        out += &format!("  {}\n", describe(commit, symbols));
+    }
+    out += "Registers:\n";
+    for row in 0..8 {
+        let cells: Vec<String> = (0..4)
+            .map(|col| {
+                let i = row + 8 * col;
+                format!(
+                    "x{:<2} {:>4} 0x{:08x}",
+                    i, REGISTER_NAMES[i], hart.registers[i]
+                )
+            })
+            .collect();
+        out += &format!("  {}\n", cells.join("   "));
+    }
+    out += &format!("  pc 0x{:08x}", hart.pc);
+    out
+}
@@ -1,18 +1,169 @@
+use crate::block::BlockCache;
 use crate::memory::Memory;
 use crate::processor::Processor;
+use crate::scheduler::Scheduler;
+use crate::trace::{Commit, Tracer};
+use crate::util::*;
 
 pub(crate) struct Machine {
     mem: Memory,
+    tracer: Option<Tracer>,
+    tohost: Option<u32>,
+    blocks: Option<BlockCache>, // None steps one instruction at a time
+    scheduler: Scheduler,
 }
 
 impl Machine {
     pub fn new(mem: Memory) -> Self {
-        Machine { mem }
+        Machine {
+            mem,
+            tracer: None,
+            tohost: None,
+            blocks: Some(BlockCache::default()),
+            scheduler: Scheduler::default(),
+        }
+    }
+
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    // Bare metal test binaries (riscv-tests) report their result by storing
+    // (code << 1) | 1 to 'tohost', the guest exits with 'code' when they do
+    pub fn set_tohost(&mut self, addr: u32) {
+        self.tohost = Some(addr);
+    }
+
+    // Decides how harts interleave when there are several
+    pub fn set_scheduler(&mut self, scheduler: Scheduler) {
+        self.scheduler = scheduler;
+    }
+
+    pub fn disable_blocks(&mut self) {
+        self.blocks = None;
     }
 
-    pub fn run(&mut self) -> u32 {
+    // Compiles blocks to host code, when blocks are on
+    #[cfg(feature = "jit")]
+    pub fn enable_jit(&mut self) {
+        if let Some(blocks) = &mut self.blocks {
+            blocks.enable_jit();
+        }
+    }
+
+    pub fn run(&mut self) -> i32 {
         loop {
-            Processor::tick(&mut self.mem);
+            // blocks skip the per instruction hooks, rely on the decode
+            // cache to notice code changes, and access memory untranslated
+            // and unprotected by the PMP. Interrupts are taken between ticks,
+            // and harts switch between them.
+            let mem = &mut self.mem;
+            if let Some(blocks) = &mut self.blocks {
+                if mem.hart_count() == 1
+                    && self.tracer.is_none()
+                    && mem.journal.is_none()
+                    && !mem.debug
+                    && mem.decode_cache.is_some()
+                    && mem.sanitizer.is_none()
+                    && mem.hart.calls.is_none()
+                    && !mem.csrs().paging()
+                    && !mem.csrs().pmp_enforced()
+                    && mem.csrs().interrupt().is_none()
+                    && !mem.session.as_ref().is_some_and(|s| s.injects())
+                {
+                    blocks.run(mem, self.tohost);
+                    self.check_tohost();
+                    if let Some(code) = self.mem.exit_code {
+                        return code;
+                    }
+                }
+            }
+            if let Some(code) = self.step() {
+                return code;
+            }
+        }
+    }
+
+    // Runs until the pc reaches 'addr', returns the exit code if the guest
+    // exited before that
+    pub fn run_until(&mut self, addr: u32) -> Option<i32> {
+        while self.mem.get_pc() != addr {
+            if let Some(code) = self.step() {
+                return Some(code);
+            }
+        }
+        None
+    }
+
+    // Independent copy of the machine sharing memory until either side writes
+    pub fn fork(&self) -> Self {
+        Machine {
+            mem: self.mem.fork(),
+            tracer: None,
+            tohost: self.tohost,
+            blocks: self.blocks.as_ref().map(|_| BlockCache::default()),
+            scheduler: self.scheduler.clone(),
         }
     }
+
+    // Executes a single instruction, then switches to the hart the scheduler
+    // picks for the next one. Returns the exit code if the guest exited.
+    pub fn step(&mut self) -> Option<i32> {
+        self.retire();
+        self.schedule();
+        self.mem.exit_code
+    }
+
+    // Executes a single instruction of the running hart
+    pub fn retire(&mut self) {
+        self.mem.replay_irqs();
+        if let Some(journal) = &mut self.mem.journal {
+            journal.begin_step();
+        }
+        match &mut self.tracer {
+            Some(tracer) => {
+                let mode = self.mem.csrs().mode();
+                let pc = self.mem.get_pc();
+                let raw = self.mem.get_instr();
+                Processor::tick(&mut self.mem);
+                // like Spike, instructions that trap don't commit
+                if self.mem.last_trap.is_none() {
+                    tracer.commit(&Commit::capture(mode, pc, raw, &self.mem));
+                }
+            }
+            None => Processor::tick(&mut self.mem),
+        }
+        self.check_tohost();
+    }
+
+    pub fn schedule(&mut self) {
+        if self.mem.hart_count() > 1 {
+            let next = self
+                .scheduler
+                .next(self.mem.hart_id(), self.mem.hart_count());
+            self.mem.switch_hart(next);
+        }
+    }
+
+    fn check_tohost(&mut self) {
+        if let (Some(tohost), Some(access)) = (self.tohost, self.mem.last_access) {
+            if access.write && access.addr == tohost {
+                let val = to_u32(&self.mem.read(tohost as usize, 4));
+                if val & 1 == 1 {
+                    if val != 1 {
+                        eprintln!("*** FAILED *** (tohost = {})", val >> 1);
+                    }
+                    self.mem.exit((val >> 1) as i32);
+                }
+            }
+        }
+    }
+
+    pub fn mem(&self) -> &Memory {
+        &self.mem
+    }
+
+    pub fn mem_mut(&mut self) -> &mut Memory {
+        &mut self.mem
+    }
 }
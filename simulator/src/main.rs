@@ -1,25 +1,406 @@
 use elfloader::ElfBinary;
 use std::fs;
 
+mod block;
+mod clint;
+mod csr;
+mod debugger;
+mod decode_cache;
+mod disasm;
+mod gdb;
+mod hart;
+mod heap;
 mod instruction;
+#[cfg(feature = "jit")]
+mod jit;
+mod leak;
+mod lockstep;
 mod machine;
 mod memory;
+mod mmu;
+mod page_table;
+mod plic;
+mod pmp;
 mod processor;
+mod race;
+mod record;
+mod sanitizer;
+mod scheduler;
+mod signature;
+mod snapshot;
+mod symbols;
 mod syscall;
+mod trace;
+mod trap;
 mod util;
 
+use debugger::Debugger;
+use gdb::GdbServer;
 use machine::Machine;
 use memory::Memory;
-use util::*;
+use mmu::Tlb;
+use record::{Journal, Session};
+use scheduler::Scheduler;
+use signature::Signature;
+use symbols::Symbols;
+use trace::{TraceFormat, Tracer};
+use util::parse_number;
 
 fn main() {
-    let binary_blob = fs::read("../main").unwrap();
+    let mut debug = false;
+    let mut gdb = None;
+    let mut record = None;
+    let mut replay = None;
+    let mut snapshot_at = None;
+    let mut save_snapshot = None;
+    let mut restore = None;
+    let mut disassemble = None;
+    let mut trace = None;
+    let mut trace_ranges = vec![];
+    let mut trace_decode = None;
+    let mut lockstep = None;
+    let mut signature = None;
+    let mut granularity = 4;
+    let mut decode_cache = true;
+    let mut blocks = true;
+    let mut jit = false;
+    let mut heap_size = heap::DEFAULT_SIZE;
+    let mut heap_align = heap::DEFAULT_ALIGN;
+    let mut sanitize_heap = false;
+    let mut stack_size = memory::DEFAULT_STACK_SIZE;
+    let mut stack_report = false;
+    let mut leak_check = false;
+    let mut fail_on_leak = false;
+    let mut tlb_entries = mmu::DEFAULT_TLB_ENTRIES;
+    let mut tlb_ways = mmu::DEFAULT_TLB_WAYS;
+    let mut tlb_stats = false;
+    let mut harts = None;
+    let mut quantum = scheduler::DEFAULT_QUANTUM;
+    let mut schedule_seed = None;
+    let mut detect_races = false;
+    let mut path = String::from("../main");
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug" | "-d" => debug = true,
+            "--gdb" => {
+                gdb = Some(
+                    args.next()
+                        .expect("--gdb needs an address like 127.0.0.1:1234"),
+                )
+            }
+            "--record" => record = Some(args.next().expect("--record needs a file")),
+            "--replay" => replay = Some(args.next().expect("--replay needs a file")),
+            "--snapshot-at" => {
+                snapshot_at = Some(args.next().expect("--snapshot-at needs a location"))
+            }
+            "--save-snapshot" => {
+                save_snapshot = Some(args.next().expect("--save-snapshot needs a file"))
+            }
+            "--restore" => restore = Some(args.next().expect("--restore needs a file")),
+            "--disassemble" => {
+                disassemble = Some(args.next().expect("--disassemble needs a section"))
+            }
+            "--trace" => {
+                trace = Some((
+                    args.next().expect("--trace needs a file"),
+                    TraceFormat::Spike,
+                ))
+            }
+            "--trace-binary" => {
+                trace = Some((
+                    args.next().expect("--trace-binary needs a file"),
+                    TraceFormat::Binary,
+                ))
+            }
+            "--trace-range" => trace_ranges.push(
+                args.next()
+                    .expect("--trace-range needs start:end or a symbol"),
+            ),
+            "--trace-decode" => {
+                trace_decode = Some(args.next().expect("--trace-decode needs a file"))
+            }
+            "--lockstep" => {
+                lockstep = Some(args.next().expect("--lockstep needs a reference trace"))
+            }
+            "--signature" => signature = Some(args.next().expect("--signature needs a file")),
+            "--signature-granularity" => {
+                granularity = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--signature-granularity needs a size in bytes")
+            }
+            "--no-decode-cache" => decode_cache = false,
+            "--no-blocks" => blocks = false,
+            "--jit" => jit = true,
+            "--heap-size" => {
+                heap_size =
+                    args.next()
+                        .and_then(|n| parse_number(&n))
+                        .expect("--heap-size needs a size in bytes") as usize
+            }
+            "--sanitize-heap" => sanitize_heap = true,
+            "--stack-size" => {
+                stack_size =
+                    args.next()
+                        .and_then(|n| parse_number(&n))
+                        .expect("--stack-size needs a size in bytes") as usize
+            }
+            "--stack-report" => stack_report = true,
+            "--leak-check" => leak_check = true,
+            "--fail-on-leak" => {
+                leak_check = true;
+                fail_on_leak = true
+            }
+            "--tlb-entries" => {
+                tlb_entries = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .expect("--tlb-entries needs a number of entries")
+            }
+            "--tlb-ways" => {
+                tlb_ways = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n > 0)
+                    .expect("--tlb-ways needs a positive number")
+            }
+            "--tlb-stats" => tlb_stats = true,
+            "--harts" => {
+                harts = Some(
+                    args.next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|&n| n > 0)
+                        .expect("--harts needs a positive number"),
+                )
+            }
+            "--quantum" => {
+                quantum = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n > 0)
+                    .expect("--quantum needs a positive number of instructions")
+            }
+            "--schedule-seed" => {
+                schedule_seed = Some(
+                    args.next()
+                        .and_then(|n| n.parse().ok())
+                        .expect("--schedule-seed needs a number"),
+                )
+            }
+            "--detect-races" => detect_races = true,
+            "--heap-align" => {
+                heap_align =
+                    args.next()
+                        .and_then(|n| parse_number(&n))
+                        .filter(|n| n.is_power_of_two())
+                        .expect("--heap-align needs a power of two") as usize
+            }
+            _ => path = arg,
+        }
+    }
+    let binary_blob = fs::read(&path).unwrap();
     let binary = ElfBinary::new("main", binary_blob.as_slice()).unwrap();
     //binary
     //    .for_each_symbol(|e| println!("{}", e.name()))
     //    .unwrap();
     // TODO: Init GP register with value in the symbol table
-    let mem = Memory::new(&binary, &binary_blob);
+    let symbols = Symbols::new(&binary);
+    if let Some(section) = &disassemble {
+        let listing = disasm::disassemble_section(&binary, section, &symbols)
+            .unwrap_or_else(|e| panic!("{}", e));
+        print!("{}", listing);
+        return;
+    }
+    if let Some(trace) = &trace_decode {
+        trace::decode_binary(trace, &symbols).unwrap_or_else(|e| panic!("{}: {}", trace, e));
+        return;
+    }
+    // fail before running if the binary is not an architecture test
+    let signature = signature.map(|file| {
+        let range = Signature::locate(&symbols, granularity).unwrap_or_else(|e| panic!("{}", e));
+        (file, range)
+    });
+    let mut mem = match &restore {
+        Some(path) => Memory::load_snapshot(path).unwrap_or_else(|e| panic!("{}", e)),
+        None => {
+            let mut mem = Memory::new(&binary, &binary_blob);
+            mem.init_stack(stack_size);
+            mem.init_heap(heap_size, heap_align);
+            mem.init_harts(harts.unwrap_or(1));
+            mem
+        }
+    };
+    if restore.is_some() && harts.is_some() {
+        panic!("--harts can't change the harts of a snapshot");
+    }
+    // undo and replay follow a single hart
+    if mem.hart_count() > 1 && (record.is_some() || replay.is_some()) {
+        panic!("--record and --replay need a single hart");
+    }
+    if save_snapshot.is_some() && (sanitize_heap || detect_races) {
+        panic!("--save-snapshot can't save the heap sanitizer's or race detector's state");
+    }
+    if !decode_cache {
+        mem.decode_cache = None;
+    }
+    if !tlb_entries.is_multiple_of(tlb_ways) {
+        panic!("--tlb-entries has to be a multiple of --tlb-ways");
+    }
+    for hart in mem.harts_mut() {
+        hart.tlb = Tlb::new(tlb_entries, tlb_ways);
+    }
+    mem.symbols = symbols.clone();
+    if sanitize_heap {
+        mem.enable_sanitizer();
+    }
+    if detect_races {
+        mem.enable_race_detector();
+    }
+    if sanitize_heap || leak_check || detect_races {
+        // reports show where blocks were allocated, and the racing accesses
+        for hart in mem.harts_mut() {
+            // a restored snapshot may have them already
+            hart.calls.get_or_insert_with(Vec::new);
+        }
+    }
+    if record.is_some() {
+        mem.session = Some(Session::Record(vec![]));
+        // the undo journal is only useful, and affordable, when debugging.
+        // The heap sanitizer's and race detector's bookkeeping can't be
+        // undone.
+        if (debug || gdb.is_some()) && !sanitize_heap && !detect_races {
+            mem.journal = Some(Journal::default());
+        }
+    }
+    if let Some(path) = &replay {
+        mem.session = Some(Session::load(path).unwrap_or_else(|e| panic!("{}", e)));
+    }
     let mut machine = Machine::new(mem);
-    machine.run();
+    if !blocks {
+        machine.disable_blocks();
+    }
+    machine.set_scheduler(match schedule_seed {
+        Some(seed) => Scheduler::random(quantum, seed),
+        None => Scheduler::round_robin(quantum),
+    });
+    if jit {
+        #[cfg(feature = "jit")]
+        machine.enable_jit();
+        #[cfg(not(feature = "jit"))]
+        panic!("--jit needs a simulator built with --features jit");
+    }
+    if let Some(addr) = symbols.lookup("tohost") {
+        machine.set_tohost(addr);
+    }
+    if let Some((file, format)) = &trace {
+        let mut tracer = Tracer::create(file, *format, symbols.clone())
+            .unwrap_or_else(|e| panic!("{}: {}", file, e));
+        for range in trace_ranges.iter() {
+            tracer.add_range(range).unwrap_or_else(|e| panic!("{}", e));
+        }
+        machine.set_tracer(tracer);
+    }
+    if let Some(path) = &save_snapshot {
+        // run the shared part once, later runs start from the snapshot
+        if let Some(at) = &snapshot_at {
+            let addr = symbols
+                .lookup(at)
+                .or_else(|| parse_number(at))
+                .unwrap_or_else(|| panic!("Unknown location '{}'", at));
+            if let Some(code) = machine.run_until(addr) {
+                eprintln!("Guest exited with {} before reaching {}", code, at);
+                // flushes the trace, process::exit skips destructors
+                drop(machine);
+                std::process::exit(code);
+            }
+        }
+        machine
+            .mem()
+            .save_snapshot(path)
+            .unwrap_or_else(|e| panic!("{}", e));
+        return;
+    }
+    let mut code = if let Some(addr) = gdb {
+        GdbServer::accept(&mut machine, &addr)
+            .and_then(|mut server| server.run())
+            .expect("gdb connection failed")
+    } else if let Some(reference) = &lockstep {
+        match lockstep::run(&mut machine, reference, &symbols) {
+            Ok(code) => code,
+            Err(report) => {
+                eprintln!("{}", report);
+                drop(machine);
+                std::process::exit(1);
+            }
+        }
+    } else if debug {
+        Debugger::new(&mut machine, symbols).run()
+    } else {
+        machine.run()
+    };
+    let mem = machine.mem_mut();
+    if let Some(mut session) = mem.session.take() {
+        if let Err(err) = session.finish(mem, code) {
+            eprintln!("{}", err);
+            code = sanitizer::ABORT;
+        }
+        if let Some(path) = &record {
+            session.save(path).unwrap_or_else(|e| panic!("{}", e));
+        }
+    }
+    if stack_report {
+        let mem = machine.mem();
+        for id in 0..mem.hart_count() {
+            let (used, size) = mem.stack_usage(id);
+            let usage = format!(
+                "{} of {} bytes ({:.1}%)",
+                used,
+                size,
+                100.0 * used as f64 / size.max(1) as f64
+            );
+            match mem.hart_count() {
+                1 => eprintln!("Stack high-water mark: {}", usage),
+                _ => eprintln!("Stack high-water mark of hart {}: {}", id, usage),
+            }
+        }
+    }
+    if tlb_stats {
+        let mem = machine.mem();
+        for id in 0..mem.hart_count() {
+            let stats = mem.get_hart(id).tlb.stats();
+            match mem.hart_count() {
+                1 => eprintln!("{}", stats),
+                _ => eprintln!("hart {} {}", id, stats),
+            }
+        }
+    }
+    if leak_check {
+        if let Some(report) = leak::report(machine.mem()) {
+            eprint!("{}", report);
+            if fail_on_leak && code == 0 {
+                code = leak::LEAK_EXIT;
+            }
+        }
+    }
+    if let Some(races) = &machine.mem().races {
+        if races.races > 0 {
+            eprintln!(
+                "==race-detector== SUMMARY: {} data race{}",
+                races.races,
+                if races.races == 1 { "" } else { "s" }
+            );
+            if code == 0 {
+                code = race::RACE_EXIT;
+            }
+        }
+    }
+    if let Some((file, range)) = &signature {
+        range
+            .dump(machine.mem(), file)
+            .unwrap_or_else(|e| panic!("{}", e));
+    }
+    // flushes the trace, process::exit skips destructors
+    drop(machine);
+    std::process::exit(code);
 }
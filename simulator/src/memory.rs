@@ -1,153 +1,1212 @@
+use crate::clint::{self, Clint};
+use crate::csr::{Csrs, MEI, MSI, SEI};
+use crate::decode_cache::DecodeCache;
+use crate::hart::Hart;
+use crate::heap::Heap;
+use crate::mmu;
+use crate::page_table::{pages, PageTable, PAGE_SIZE};
+use crate::plic::{self, Plic};
+use crate::pmp;
+use crate::race::RaceDetector;
+use crate::record::{Change, Journal, Session};
+use crate::sanitizer::{Sanitizer, ABORT, POISON, REDZONE};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::symbols::Symbols;
+use crate::trap::Trap;
+use crate::util::*;
 use elfloader::ElfBinary;
-use std::sync::RwLock;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use xmas_elf::program::Type;
+use xmas_elf::sections::SHF_ALLOC;
 
 const SP: usize = 2;
+const A0: usize = 10;
+// The stack grows down from here, with an inaccessible guard below it
+const STACK_TOP: usize = 0x7fff_fff0;
+const STACK_GUARD: usize = 64 << 10;
+pub(crate) const DEFAULT_STACK_SIZE: usize = 4 << 20;
+
+// Region permissions, the ELF p_flags bits
+pub(crate) const EXEC: u8 = 1;
+pub(crate) const WRITE: u8 = 2;
+pub(crate) const READ: u8 = 4;
+const RW: u8 = READ | WRITE;
 
 #[derive(Debug)]
 pub(crate) struct Memory {
     _start: usize,
-    segments: Vec<MemorySegment>,
-    registers: [u32; 32],
-    pc: u32,
+    pages: PageTable,
+    regions: BTreeMap<usize, Region>, // by start address
+    protected: Vec<(usize, Region)>,  // regions that aren't RW
+    executable: Vec<(usize, usize)>,  // start and end of X regions
+    sections: Vec<Section>,           // for diagnostics
+    heap: Option<Heap>,               // None until init_heap
+    stack: (usize, usize),            // start and end, set by init_stack
+    stack_low: Vec<usize>,            // lowest address stored to in each hart's share
+    pub hart: Hart,                   // the one running
+    harts: Vec<Hart>,                 // by id, the slot of the running one is stale
+    current: usize,                   // id of the running hart
+    plic: Plic,
+    clint: Clint,
     pub debug: bool,
+    pub exit_code: Option<i32>,
+    pub trap: Option<Trap>,          // exception that stopped the guest
+    pub last_trap: Option<Trap>,     // exception the last executed instruction took
+    pub last_access: Option<Access>, // data access of the last executed instruction
+    pub journal: Option<Journal>,
+    pub session: Option<Session>,
+    pub decode_cache: Option<DecodeCache>, // None decodes every fetch
+    pub sanitizer: Option<Sanitizer>,
+    pub races: Option<RaceDetector>,
+    sites: BTreeMap<usize, Site>, // by the start the guest got
+    pub symbols: Symbols,         // for diagnostics
+    pub code_version: u64,        // bumped when cached code changes
 }
 
-#[derive(Debug)]
-pub(crate) struct MemorySegment {
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Access {
+    pub addr: u32,
+    pub len: usize,
+    pub write: bool,
+}
+
+// Mapped range: an ELF segment, the stack or the heap. Guest accesses to
+// bytes of a region need its permissions.
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    size: usize,
+    flags: u8,
+}
+
+#[derive(Debug, Clone)]
+struct Section {
     start: usize,
     size: usize,
-    content: Vec<u8>,
-    persistent: bool,
+    name: String,
+}
+
+// Where a heap block came from, for leak reports
+#[derive(Debug, Clone)]
+pub(crate) struct Site {
+    pub size: usize,
+    pub stack: Vec<u32>, // pc of the malloc and its callers
+    pub persistent: bool,
 }
 
 impl Memory {
     pub fn new(binary: &ElfBinary, blob: &[u8]) -> Self {
-        let mut segments = vec![];
+        let mut mem = Memory::empty(binary.entry_point() as u32);
         for header_part in binary.program_headers() {
+            if header_part.get_type() != Ok(Type::Load) {
+                continue;
+            }
             let start = header_part.virtual_addr() as usize;
             let size = header_part.mem_size() as usize;
-            let mut content = vec![0u8; size];
+            let flags = header_part.flags().0 as u8 & (READ | WRITE | EXEC);
+            mem.map(start, size, flags);
 
             let file_size = header_part.file_size() as usize;
             let file_offset = header_part.offset() as usize;
-            content[0..file_size].clone_from_slice(&blob[file_offset..(file_offset + file_size)]);
-
-            segments.push(MemorySegment {
-                start,
-                size,
-                content,
-                persistent: true,
-            });
-        }
-        //stack
-        segments.push(MemorySegment {
-            start: 2143289328usize,
-            size: 2usize.pow(22),
-            content: vec![0u8; 2usize.pow(22)],
-            persistent: true,
-        });
-        let mut mem = Memory {
-            segments,
-            _start: binary.entry_point() as usize,
-            registers: [0u32; 32],
-            pc: binary.entry_point() as u32,
+            mem.write(start, &blob[file_offset..(file_offset + file_size)]);
+        }
+        for section in binary.file.section_iter() {
+            if section.flags() & SHF_ALLOC != 0 && section.size() > 0 {
+                mem.sections.push(Section {
+                    start: section.address() as usize,
+                    size: section.size() as usize,
+                    name: section.get_name(&binary.file).unwrap_or("").to_string(),
+                });
+            }
+        }
+        mem
+    }
+
+    // Only 'code' at 'pc' and the stack, to test and fuzz the processor
+    // without a binary
+    #[cfg(any(test, fuzzing))]
+    pub fn with_code(pc: u32, code: &[u8]) -> Self {
+        let mut mem = Memory::empty(pc);
+        mem.init_stack(DEFAULT_STACK_SIZE);
+        mem.map(pc as usize, code.len(), RW | EXEC);
+        mem.write(pc as usize, code);
+        mem
+    }
+
+    fn empty(entry: u32) -> Self {
+        Memory {
+            pages: PageTable::default(),
+            regions: BTreeMap::new(),
+            protected: vec![],
+            executable: vec![],
+            sections: vec![],
+            heap: None,
+            stack: (0, 0),
+            stack_low: vec![],
+            _start: entry as usize,
+            hart: Hart::new(0, entry),
+            harts: vec![Hart::new(0, entry)],
+            current: 0,
+            plic: Plic::new(1),
+            clint: Clint::new(1),
             debug: false,
+            exit_code: None,
+            trap: None,
+            last_trap: None,
+            last_access: None,
+            journal: None,
+            session: None,
+            decode_cache: Some(DecodeCache::default()),
+            sanitizer: None,
+            races: None,
+            sites: BTreeMap::new(),
+            symbols: Symbols::default(),
+            code_version: 0,
+        }
+    }
+
+    // Stack of 'size' bytes below STACK_TOP, at most what fits above the
+    // guard, and the stack pointer at its top
+    pub fn init_stack(&mut self, size: usize) {
+        let size = size.min(STACK_TOP - STACK_GUARD) & !15;
+        let start = STACK_TOP - size;
+        self.map(start - STACK_GUARD, STACK_GUARD, 0);
+        self.map(start, size, RW);
+        self.stack = (start, STACK_TOP);
+        self.stack_low = vec![STACK_TOP];
+        self.hart.registers[SP] = STACK_TOP as u32;
+    }
+
+    // Starts 'count' harts where hart 0 is, with their id in a0 and stacks
+    // of an equal share of the stack each, hart 0's at the top. The shares
+    // are split into regions with a guard below each one.
+    pub fn init_harts(&mut self, count: usize) {
+        assert!(count > 0 && self.harts.len() == 1);
+        self.harts = vec![self.hart.clone()];
+        for id in 1..count {
+            let mut hart = Hart::new(id as u32, self.hart.pc);
+            hart.registers = self.hart.registers;
+            hart.registers[A0] = id as u32;
+            self.harts.push(hart);
+        }
+        // the shares depend on the hart count
+        for id in 1..count {
+            self.harts[id].registers[SP] = self.hart_stack(id).1 as u32;
+        }
+        if self.stack.1 > 0 {
+            self.stack_low = (0..count).map(|id| self.hart_stack(id).1).collect();
+        }
+        if count > 1 && self.stack.1 > 0 {
+            self.regions.remove(&self.stack.0);
+            let (_, guard) = self.stack_share();
+            for id in 0..count {
+                let (start, end) = self.hart_stack(id);
+                if id + 1 < count {
+                    self.map(start - guard, guard, 0);
+                }
+                self.map(start, end - start, RW);
+            }
+        }
+        self.plic = Plic::new(count);
+        self.clint = Clint::new(count);
+    }
+
+    pub fn hart_count(&self) -> usize {
+        self.harts.len()
+    }
+
+    // Id of the running hart
+    pub fn hart_id(&self) -> usize {
+        self.current
+    }
+
+    pub fn get_hart(&self, id: usize) -> &Hart {
+        match id == self.current {
+            true => &self.hart,
+            false => &self.harts[id],
+        }
+    }
+
+    // Every hart, the running one first
+    pub fn harts_mut(&mut self) -> impl Iterator<Item = &mut Hart> {
+        let current = self.current;
+        let parked = self.harts.iter_mut().enumerate();
+        std::iter::once(&mut self.hart).chain(
+            parked
+                .filter(move |(id, _)| *id != current)
+                .map(|(_, hart)| hart),
+        )
+    }
+
+    // Makes hart 'id' the one the processor runs
+    pub fn switch_hart(&mut self, id: usize) {
+        if id != self.current {
+            std::mem::swap(&mut self.hart, &mut self.harts[self.current]);
+            std::mem::swap(&mut self.hart, &mut self.harts[id]);
+            self.current = id;
+        }
+    }
+
+    // Bytes of hart 'id's stack used so far, counting from the top down to
+    // the lowest store, and its size
+    pub fn stack_usage(&self, id: usize) -> (usize, usize) {
+        let (start, end) = self.hart_stack(id);
+        let low = self.stack_low.get(id).copied().unwrap_or(end);
+        (end - low, end - start)
+    }
+
+    // Called for every guest store, counts for the share it's in
+    pub fn track_stack(&mut self, addr: usize) {
+        if addr < self.stack.0 || addr >= self.stack.1 {
+            return;
+        }
+        let share = (0..self.stack_low.len()).find(|&id| {
+            let (start, end) = self.hart_stack(id);
+            start <= addr && addr < end
+        });
+        if let Some(id) = share {
+            self.stack_low[id] = self.stack_low[id].min(addr);
+        }
+    }
+
+    // Size of each hart's share of the stack, and of the guard at the
+    // bottom of the shares but the last, which is above the stack's guard
+    fn stack_share(&self) -> (usize, usize) {
+        let share = ((self.stack.1 - self.stack.0) / self.hart_count()) & !15;
+        (share, STACK_GUARD.min(share / 2) & !15)
+    }
+
+    // Start and end of the stack of hart 'id', above its guard
+    pub fn hart_stack(&self, id: usize) -> (usize, usize) {
+        let (share, guard) = self.stack_share();
+        let end = self.stack.1 - id * share;
+        match id + 1 == self.hart_count() {
+            true => (self.stack.0, end),
+            false => (end - share + guard, end),
+        }
+    }
+
+    // The hart whose stack 'addr' is below, and how far, if it's in the guard
+    pub fn below_stack(&self, addr: u32) -> Option<(usize, usize)> {
+        let addr = addr as usize;
+        let (_, share_guard) = self.stack_share();
+        (0..self.hart_count()).find_map(|id| {
+            let start = self.hart_stack(id).0;
+            let last = id + 1 == self.hart_count();
+            let guard = if last { STACK_GUARD } else { share_guard };
+            (self.stack.1 > 0 && addr < start && addr + guard >= start).then(|| (id, start - addr))
+        })
+    }
+
+    // Cheap copy of the machine state, memory is copied on write
+    pub fn fork(&self) -> Self {
+        Memory {
+            _start: self._start,
+            pages: self.pages.clone(),
+            regions: self.regions.clone(),
+            protected: self.protected.clone(),
+            executable: self.executable.clone(),
+            sections: self.sections.clone(),
+            heap: self.heap.clone(),
+            stack: self.stack,
+            stack_low: self.stack_low.clone(),
+            hart: self.hart.clone(),
+            harts: self.harts.clone(),
+            current: self.current,
+            plic: self.plic.clone(),
+            clint: self.clint.clone(),
+            debug: self.debug,
+            exit_code: self.exit_code,
+            trap: self.trap,
+            last_trap: None,
+            last_access: None,
+            journal: None,
+            session: None,
+            decode_cache: self.decode_cache.as_ref().map(|_| DecodeCache::default()),
+            sanitizer: self.sanitizer.clone(),
+            races: self.races.clone(),
+            sites: self.sites.clone(),
+            symbols: self.symbols.clone(),
+            code_version: 0,
+        }
+    }
+
+    // The heap sanitizer's and race detector's shadow state isn't saved,
+    // a restored run would silently miss what they catch
+    pub fn save_snapshot(&self, path: &str) -> Result<(), String> {
+        if self.sanitizer.is_some() || self.races.is_some() {
+            return Err(
+                "Snapshots can't hold the heap sanitizer's or race detector's state".to_string(),
+            );
+        }
+        let mut w = SnapshotWriter::new();
+        w.u32(self._start as u32);
+        w.u32(self.harts.len() as u32);
+        w.u32(self.current as u32);
+        for id in 0..self.harts.len() {
+            self.get_hart(id).save(&mut w);
+        }
+        match self.exit_code {
+            Some(code) => {
+                w.u8(1);
+                w.u32(code as u32);
+            }
+            None => w.u8(0),
+        }
+        w.u32(self.regions.len() as u32);
+        for (&start, region) in self.regions.iter() {
+            w.u32(start as u32);
+            w.u32(region.size as u32);
+            w.u8(region.flags);
+            // pages within the region, a flag and the content unless it's zero
+            for (addr, len) in pieces(start, region.size) {
+                let bytes = self.read(addr, len);
+                if bytes.iter().all(|&b| b == 0) {
+                    w.u8(0);
+                } else {
+                    w.u8(1);
+                    w.bytes(&bytes);
+                }
+            }
+        }
+        match &self.heap {
+            Some(heap) => {
+                w.u8(1);
+                w.u32(heap.start() as u32);
+                w.u32(heap.size() as u32);
+                w.u32(heap.align() as u32);
+                w.u32(heap.blocks().count() as u32);
+                for (start, size) in heap.blocks() {
+                    w.u32(start as u32);
+                    w.u32(size as u32);
+                }
+            }
+            None => w.u8(0),
+        }
+        w.u32(self.stack.0 as u32);
+        w.u32(self.stack.1 as u32);
+        let lows: Vec<u32> = self.stack_low.iter().map(|&low| low as u32).collect();
+        w.u32s(&lows);
+        self.plic.save(&mut w);
+        self.clint.save(&mut w);
+        w.u32(self.sections.len() as u32);
+        for section in self.sections.iter() {
+            w.u32(section.start as u32);
+            w.u32(section.size as u32);
+            w.str(&section.name);
+        }
+        w.u32(self.sites.len() as u32);
+        for (&start, site) in self.sites.iter() {
+            w.u32(start as u32);
+            w.u32(site.size as u32);
+            w.u32s(&site.stack);
+            w.u8(site.persistent as u8);
+        }
+        w.save(path)
+    }
+
+    pub fn load_snapshot(path: &str) -> Result<Self, String> {
+        let mut r = SnapshotReader::open(path)?;
+        let mut mem = Memory::empty(0);
+        mem._start = r.u32()? as usize;
+        let count = r.u32()?;
+        mem.current = r.u32()? as usize;
+        mem.harts.clear();
+        for _ in 0..count {
+            mem.harts.push(Hart::load(&mut r)?);
+        }
+        if mem.current >= mem.harts.len() {
+            return Err("Snapshot has no running hart".to_string());
+        }
+        mem.hart = mem.harts[mem.current].clone();
+        mem.exit_code = match r.u8()? {
+            0 => None,
+            _ => Some(r.u32()? as i32),
         };
-        mem.registers[SP] = 2143289328 + 2u32.pow(22);
-        mem
+        for _ in 0..r.u32()? {
+            let start = r.u32()? as usize;
+            let size = r.u32()? as usize;
+            let flags = r.u8()?;
+            mem.map(start, size, flags);
+            for (addr, len) in pieces(start, size) {
+                if r.u8()? != 0 {
+                    mem.write(addr, r.bytes(len)?);
+                }
+            }
+        }
+        if r.u8()? != 0 {
+            let (start, size, align) = (r.u32()?, r.u32()?, r.u32()?);
+            let mut heap = Heap::new(start as usize, size as usize, align as usize);
+            for _ in 0..r.u32()? {
+                let (start, size) = (r.u32()?, r.u32()?);
+                heap.claim(start as usize, size as usize);
+            }
+            mem.heap = Some(heap);
+        }
+        mem.stack = (r.u32()? as usize, r.u32()? as usize);
+        mem.stack_low = r.u32s()?.into_iter().map(|low| low as usize).collect();
+        mem.plic = Plic::load(&mut r)?;
+        mem.clint = Clint::load(&mut r)?;
+        for _ in 0..r.u32()? {
+            let (start, size) = (r.u32()? as usize, r.u32()? as usize);
+            let name = r.str()?;
+            mem.sections.push(Section { start, size, name });
+        }
+        for _ in 0..r.u32()? {
+            let start = r.u32()? as usize;
+            let site = Site {
+                size: r.u32()? as usize,
+                stack: r.u32s()?,
+                persistent: r.u8()? != 0,
+            };
+            mem.sites.insert(start, site);
+        }
+        Ok(mem)
     }
 
     pub fn set_register(&mut self, val: u32, ind: u8) {
         if ind == 0 {
             return;
         }
-        self.registers[ind as usize] = val;
+        self.record(Change::Register(ind, self.hart.registers[ind as usize]));
+        self.hart.registers[ind as usize] = val;
     }
 
     pub fn get_register(&self, ind: u8) -> u32 {
-        self.registers[ind as usize]
+        self.hart.registers[ind as usize]
+    }
+
+    // Bulk access for the block engine, which never runs with a journal
+    pub fn registers(&self) -> &[u32; 32] {
+        &self.hart.registers
+    }
+
+    pub fn set_registers(&mut self, registers: &[u32]) {
+        debug_assert!(self.journal.is_none());
+        self.hart.registers.copy_from_slice(registers);
+        self.hart.registers[0] = 0;
     }
 
     pub fn set_pc(&mut self, val: u32) {
-        self.pc = val;
+        self.record(Change::Pc(self.hart.pc));
+        self.hart.pc = val;
     }
 
     pub fn get_pc(&self) -> u32 {
-        self.pc
+        self.hart.pc
+    }
+
+    pub fn csrs(&self) -> &Csrs {
+        &self.hart.csrs
+    }
+
+    pub fn csrs_mut(&mut self) -> &mut Csrs {
+        self.record(Change::Csrs(Box::new(self.hart.csrs)));
+        &mut self.hart.csrs
+    }
+
+    fn plic_mut(&mut self) -> &mut Plic {
+        if self.journal.is_some() {
+            self.record(Change::Plic(Box::new(self.plic.clone())));
+        }
+        &mut self.plic
+    }
+
+    fn clint_mut(&mut self) -> &mut Clint {
+        if self.journal.is_some() {
+            self.record(Change::Clint(Box::new(self.clint.clone())));
+        }
+        &mut self.clint
+    }
+
+    // For device models, raises or lowers their interrupt line
+    pub fn set_irq(&mut self, source: usize, high: bool) {
+        if let Some(session) = &mut self.session {
+            session.irq(self.hart.instret, source, high);
+        }
+        self.plic_mut().set_level(source, high);
+        self.update_lines();
+    }
+
+    // Raises or lowers the interrupt lines a replayed session changed
+    // before the next instruction
+    pub fn replay_irqs(&mut self) {
+        let instret = self.hart.instret;
+        while let Some((source, high)) = self.session.as_mut().and_then(|s| s.due_irq(instret)) {
+            self.set_irq(source, high);
+        }
+    }
+
+    // The PLIC drives the external interrupt lines of every hart, the CLINT
+    // their software interrupt line
+    fn update_lines(&mut self) {
+        for id in 0..self.harts.len() {
+            let mut lines = 0;
+            if self.plic.interrupting(2 * id) {
+                lines |= MEI;
+            }
+            if self.plic.interrupting(2 * id + 1) {
+                lines |= SEI;
+            }
+            if self.clint.msip(id) {
+                lines |= MSI;
+            }
+            if id != self.current {
+                self.harts[id].csrs.set_lines(lines);
+            } else if self.hart.csrs.lines() != lines {
+                self.csrs_mut().set_lines(lines);
+            }
+        }
+    }
+
+    // Whether 'addr' belongs to a device rather than memory
+    pub fn is_mmio(&self, addr: usize) -> bool {
+        plic::contains(addr) || clint::contains(addr)
+    }
+
+    // Device registers take aligned words, None when nothing is at 'addr'
+    pub fn mmio_read(&mut self, addr: usize, len: usize) -> Option<u32> {
+        if len != 4 || !addr.is_multiple_of(4) {
+            return None;
+        }
+        let mut val = if plic::contains(addr) {
+            self.plic_mut().read(addr - plic::BASE)?
+        } else if clint::contains(addr) {
+            self.clint.read(addr - clint::BASE)?
+        } else {
+            return None;
+        };
+        self.update_lines();
+        let instret = self.hart.instret;
+        match self
+            .session
+            .as_mut()
+            .map(|s| s.device_read(instret, addr as u32, val))
+        {
+            Some(Ok(recorded)) => val = recorded,
+            Some(Err(err)) => self.abort(&format!("{}\n", err)),
+            None => {}
+        }
+        Some(val)
+    }
+
+    pub fn mmio_write(&mut self, addr: usize, bytes: &[u8]) -> Option<()> {
+        if bytes.len() != 4 || !addr.is_multiple_of(4) {
+            return None;
+        }
+        if plic::contains(addr) {
+            self.plic_mut().write(addr - plic::BASE, to_u32(bytes))?;
+        } else if clint::contains(addr) {
+            self.clint_mut().write(addr - clint::BASE, to_u32(bytes))?;
+        } else {
+            return None;
+        }
+        self.update_lines();
+        Some(())
     }
 
     pub fn incr_pc(&mut self) {
-        self.pc += 4;
+        self.record(Change::Pc(self.hart.pc));
+        self.hart.pc = self.hart.pc.wrapping_add(4);
+    }
+
+    // Raw word at the pc, 0 (an illegal instruction) if nothing is mapped
+    pub fn get_instr(&self) -> u32 {
+        mmu::peek(self, self.hart.pc, true)
+            .and_then(|pc| self.try_read(pc, 4))
+            .map_or(0, |b| to_u32(&b))
+    }
+
+    pub fn read(&self, start: usize, len: usize) -> Cow<'_, [u8]> {
+        self.try_read(start, len).expect("Invalid Memory Segment")
+    }
+
+    // Like read but for host side tools, never panics. Only accesses that
+    // cross a page boundary are copied.
+    pub fn try_read(&self, start: usize, len: usize) -> Option<Cow<'_, [u8]>> {
+        let offset = start & (PAGE_SIZE - 1);
+        if len == 0 {
+            return Some(Cow::Borrowed(&[]));
+        }
+        if offset + len <= PAGE_SIZE {
+            let page = self.pages.get(start)?;
+            return Some(Cow::Borrowed(&page[offset..offset + len]));
+        }
+        let mut bytes = Vec::with_capacity(len);
+        for page_start in pages(start, len) {
+            let page = self.pages.get(page_start)?;
+            let from = start.max(page_start) - page_start;
+            let to = (start + len).min(page_start + PAGE_SIZE) - page_start;
+            bytes.extend_from_slice(&page[from..to]);
+        }
+        Some(Cow::Owned(bytes))
     }
 
-    pub fn get_instr(&self) -> &[u8] {
-        self.read(self.pc as usize, 4)
+    pub fn write(&mut self, start: usize, bytes: &[u8]) {
+        if !self.try_write(start, bytes) {
+            panic!("Invalid Memory Segment");
+        }
     }
 
-    pub fn read(&self, start: usize, len: usize) -> &[u8] {
-        let segment = self
-            .segments
+    // Like write but false, with nothing written, unless all of it is mapped
+    pub fn try_write(&mut self, start: usize, bytes: &[u8]) -> bool {
+        if bytes.is_empty() {
+            return true;
+        }
+        if !pages(start, bytes.len()).all(|page| self.pages.is_mapped(page)) {
+            return false;
+        }
+        if self.journal.is_some() {
+            let old = self.read(start, bytes.len()).into_owned();
+            self.record(Change::Memory(start, old));
+        }
+        if let Some(cache) = &mut self.decode_cache {
+            if cache.invalidate(start, bytes.len()) {
+                self.code_version += 1;
+            }
+        }
+        let (mut addr, mut rest) = (start, bytes);
+        while !rest.is_empty() {
+            let offset = addr & (PAGE_SIZE - 1);
+            let n = (PAGE_SIZE - offset).min(rest.len());
+            let page = self.pages.get_mut(addr).unwrap();
+            page[offset..offset + n].copy_from_slice(&rest[..n]);
+            addr += n;
+            rest = &rest[n..];
+        }
+        true
+    }
+
+    // Whether the guest may access 'len' bytes at 'start' with 'perm'. Code
+    // has to be inside an X region, so heap pages never hold cached
+    // instructions. Data accesses only need to be mapped outside of regions.
+    // The PMP checks both with the privilege of the access.
+    pub fn allows(&self, start: usize, len: usize, perm: u8) -> bool {
+        self.allows_as(start, len, perm, self.hart.csrs.privilege(perm == EXEC))
+    }
+
+    // Like allows for an access made with the privilege of 'mode'
+    pub fn allows_as(&self, start: usize, len: usize, perm: u8, mode: u8) -> bool {
+        let end = start + len;
+        let region = if perm == EXEC {
+            self.executable.iter().any(|&(s, e)| s <= start && end <= e)
+        } else {
+            self.protected
+                .iter()
+                .all(|&(s, r)| r.flags & perm == perm || s + r.size <= start || s >= end)
+        };
+        region && self.pmp_allows(start, len, perm, mode)
+    }
+
+    #[inline]
+    pub fn pmp_allows(&self, start: usize, len: usize, perm: u8, mode: u8) -> bool {
+        let bits = match perm {
+            EXEC => pmp::X,
+            WRITE => pmp::W,
+            _ => pmp::R,
+        };
+        self.hart.csrs.pmp().allows(start, len, bits, mode)
+    }
+
+    // Region containing 'addr' and its ELF section, for trap diagnostics
+    pub fn describe_region(&self, addr: u32) -> Option<String> {
+        let addr = addr as usize;
+        let (&start, region) = self.regions.range(..=addr).next_back()?;
+        if start + region.size <= addr {
+            return None;
+        }
+        let stack = |addr| (0..self.hart_count()).any(|id| self.hart_stack(id).0 == addr);
+        let kind = if self.stack.1 > 0 && stack(start) {
+            "stack"
+        } else if self.stack.1 > 0 && stack(start + region.size) && region.flags == 0 {
+            "stack guard"
+        } else if self.heap.as_ref().is_some_and(|heap| heap.start() == start) {
+            "heap"
+        } else {
+            "segment"
+        };
+        let flag = |bit, c| if region.flags & bit != 0 { c } else { '-' };
+        let mut out = format!(
+            "{} 0x{:08x}..0x{:08x} {}{}{}",
+            kind,
+            start,
+            start + region.size,
+            flag(READ, 'r'),
+            flag(WRITE, 'w'),
+            flag(EXEC, 'x')
+        );
+        let section = self
+            .sections
+            .iter()
+            .find(|s| s.start <= addr && addr < s.start + s.size);
+        if let Some(section) = section {
+            out += &format!(" {}", section.name);
+        }
+        Some(out)
+    }
+
+    fn map(&mut self, start: usize, size: usize, flags: u8) {
+        let region = Region { size, flags };
+        self.regions.insert(start, region);
+        if flags & RW != RW {
+            self.protected.push((start, region));
+        }
+        if flags & EXEC != 0 {
+            self.executable.push((start, start + size));
+        }
+        if size > 0 {
+            self.pages.map(start, size);
+        }
+    }
+
+    // Like write with 'len' copies of 'val', the heap never holds cached
+    // instructions and its zero pages stay shared
+    fn fill(&mut self, start: usize, len: usize, val: u8) {
+        if self.journal.is_some() {
+            let old = self.read(start, len).into_owned();
+            self.record(Change::Memory(start, old));
+        }
+        self.pages.fill(start, len, val);
+    }
+
+    // Heap of up to 'size' bytes from the page after the highest segment,
+    // smaller when it would run into the stack or out of the address space
+    pub fn init_heap(&mut self, size: usize, align: usize) {
+        let end = self
+            .regions
             .iter()
-            .find(|s| start >= s.start && start < (s.start + s.size))
-            .expect("Invalid Memory Segment");
-        let content_start = start - segment.start;
-        &segment.content[content_start..(content_start + len)]
-    }
-
-    pub fn read_mut(&mut self, start: usize, len: usize) -> &mut [u8] {
-        let segment = self
-            .segments
-            .iter_mut()
-            .find(|s| start >= s.start && start < (s.start + s.size))
-            .expect("Invalid Memory Segment");
-        let content_start = start - segment.start;
-        &mut segment.content[content_start..(content_start + len)]
-    }
-
-    pub fn malloc(&mut self, size: usize, init: u8) -> u32 {
-        let last = self.segments.last().unwrap();
-        let mut last_end = last.start + last.size;
-        match last_end % 4 {
-            0 => {}
-            1 => {
-                last_end += 3;
-            }
-            2 => {
-                last_end += 2;
-            }
-            3 => {
-                last_end += 1;
-            }
-            _ => unreachable!(),
-        };
-        let seg = MemorySegment {
-            start: last_end,
-            size: size,
-            content: vec![init; size],
+            .filter(|(&start, _)| start + STACK_GUARD < self.stack.0 || start >= self.stack.1)
+            .map(|(start, region)| start + region.size)
+            .max()
+            .unwrap_or(0);
+        let start = (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let limit = self
+            .regions
+            .range(start..)
+            .next()
+            .map_or(1 << 32, |(&s, _)| s);
+        let size = size.min(limit.saturating_sub(start));
+        if size > 0 {
+            self.map(start, size, RW);
+        }
+        self.heap = Some(Heap::new(start, size, align));
+    }
+
+    // The heap syscalls return NULL when the heap is out of memory. Blocks
+    // start out zeroed, 'align' 0 is the heap's own alignment.
+    pub fn malloc(&mut self, size: usize, align: usize) -> u32 {
+        let Some(heap) = &mut self.heap else {
+            return 0;
+        };
+        // sanitized blocks sit between redzones, what the guest gets stays
+        // aligned
+        let (left, right) = match self.sanitizer {
+            Some(_) => (align.max(heap.align()).max(REDZONE), REDZONE),
+            None => (0, 0),
+        };
+        let Some(base) = heap.alloc(left + size + right, align) else {
+            return 0;
+        };
+        let end = base + heap.size_of(base).unwrap();
+        self.record(Change::Malloc(base));
+        // the block may still hold what was freed
+        self.fill(base, end - base, 0);
+        self.reset_races(base, end - base);
+        let start = base + left;
+        let stack = self.backtrace();
+        if let Some(sanitizer) = &mut self.sanitizer {
+            sanitizer.malloc(base, start, size, end, stack.clone());
+        }
+        let site = Site {
+            size,
+            stack,
             persistent: false,
         };
-        self.segments.push(seg);
-        last_end as u32
+        self.sites.insert(start, site);
+        start as u32
+    }
+
+    pub fn calloc(&mut self, count: usize, size: usize) -> u32 {
+        let total = match count.checked_mul(size) {
+            Some(total) if total >> 32 == 0 => total,
+            _ => return 0,
+        };
+        let start = self.malloc(total, 0);
+        if let Some(sanitizer) = &mut self.sanitizer {
+            sanitizer.initialize(start as usize, total);
+        }
+        start
+    }
+
+    // Grows or shrinks in place when it can, otherwise moves the content to
+    // a new block. The old block stays allocated when that fails. Sanitized
+    // blocks always move, to catch stale pointers.
+    pub fn realloc(&mut self, start: u32, size: usize) -> u32 {
+        let start = start as usize;
+        if start == 0 {
+            return self.malloc(size, 0);
+        }
+        let old = match (&self.sanitizer, &self.heap) {
+            (Some(sanitizer), _) => sanitizer.size_of(start),
+            (None, Some(heap)) => heap.size_of(start),
+            (None, None) => None,
+        };
+        // the sanitizer reports it as an invalid free
+        if old.is_none() || size == 0 {
+            self.free(start as u32);
+            return 0;
+        }
+        let old = old.unwrap();
+        if let (None, Some(heap)) = (&self.sanitizer, &mut self.heap) {
+            if heap.resize(start, size) {
+                let new = heap.size_of(start).unwrap();
+                self.record(Change::Resize(start, old));
+                if let Some(site) = self.sites.get_mut(&start) {
+                    site.size = size;
+                }
+                if new > old {
+                    self.fill(start + old, new - old, 0);
+                }
+                self.reset_races(start + old.min(new), old.max(new) - old.min(new));
+                return start as u32;
+            }
+        }
+        let moved = self.malloc(size, 0);
+        if moved != 0 {
+            let len = old.min(size);
+            let content = self.read(start, len).into_owned();
+            self.write(moved as usize, &content);
+            if let Some(sanitizer) = &mut self.sanitizer {
+                sanitizer.copy_init(start, moved as usize, len);
+            }
+            self.free(start as u32);
+        }
+        moved
     }
 
     pub fn free(&mut self, start: u32) -> u8 {
-        let seg = self
-            .segments
-            .iter()
-            .enumerate()
-            .find(|(i, s)| s.start == start as usize);
-        let i = match seg {
-            Some((
-                i,
-                MemorySegment {
-                    persistent: false, ..
-                },
-            )) => i,
-            _ => {
-                return 1;
-            }
-        };
-        self.segments.remove(i);
-        0
+        let start = start as usize;
+        if let Some(mut sanitizer) = self.sanitizer.take() {
+            let result = sanitizer.free(self, start);
+            self.sanitizer = Some(sanitizer);
+            return match result {
+                Ok((size, released)) => {
+                    self.sites.remove(&start);
+                    self.fill(start, size, POISON);
+                    for base in released {
+                        self.release(base);
+                    }
+                    0
+                }
+                Err(report) => {
+                    self.abort(&report);
+                    1
+                }
+            };
+        }
+        match self.release(start) {
+            true => {
+                self.sites.remove(&start);
+                0
+            }
+            false => 1,
+        }
+    }
+
+    fn release(&mut self, start: usize) -> bool {
+        match self.heap.as_mut().and_then(|heap| heap.release(start)) {
+            Some(size) => {
+                self.record(Change::Free(start, size));
+                self.reset_races(start, size);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Checks a guest load or store with the heap sanitizer, if any. Stops
+    // the guest and returns false when it's a bug.
+    pub fn sanitize(&mut self, addr: usize, len: usize, write: bool) -> bool {
+        let Some(mut sanitizer) = self.sanitizer.take() else {
+            return true;
+        };
+        let result = sanitizer.check(self, addr, len, write);
+        self.sanitizer = Some(sanitizer);
+        match result {
+            Ok(()) => true,
+            Err(report) => {
+                self.abort(&report);
+                false
+            }
+        }
+    }
+
+    pub fn enable_sanitizer(&mut self) {
+        if let Some(heap) = &self.heap {
+            self.sanitizer = Some(Sanitizer::new(heap));
+        }
+    }
+
+    // Checks a guest access with the race detector, if any. Races are
+    // reported and the guest goes on.
+    pub fn check_race(&mut self, addr: usize, len: usize, write: bool, sync: bool) {
+        let Some(mut races) = self.races.take() else {
+            return;
+        };
+        if self.is_mmio(addr) {
+            races.device(self.current);
+        } else if let Some(report) = races.access(self, addr, len, write, sync) {
+            eprint!("{}", report);
+        }
+        self.races = Some(races);
+    }
+
+    // Heap bytes changing hands don't race with what the last owner did,
+    // malloc and free order nothing between harts
+    fn reset_races(&mut self, start: usize, len: usize) {
+        if let Some(races) = &mut self.races {
+            races.reset(start, len);
+        }
+    }
+
+    pub fn enable_race_detector(&mut self) {
+        self.races = Some(RaceDetector::new(self.hart_count()));
+    }
+
+    // Stores of one hart void what the others reserved with LR.W
+    pub fn break_reservations(&mut self, addr: usize, len: usize) {
+        for (id, hart) in self.harts.iter_mut().enumerate() {
+            let hit = hart
+                .reservation
+                .is_some_and(|r| r < addr + len && addr < r + 4);
+            if id != self.current && hit {
+                hart.reservation = None;
+            }
+        }
+    }
+
+    // Keeps the block at 'start' out of leak reports, false if it isn't
+    // allocated
+    pub fn persist(&mut self, start: u32) -> bool {
+        match self.sites.get_mut(&(start as usize)) {
+            Some(site) => {
+                site.persistent = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Blocks the guest still holds, by address. Blocks from an undone free
+    // have an empty call stack.
+    pub fn allocations(&self) -> Vec<(usize, Site)> {
+        let Some(heap) = &self.heap else {
+            return vec![];
+        };
+        if self.sanitizer.is_some() {
+            // heap blocks include redzones and the quarantine
+            return self
+                .sites
+                .iter()
+                .map(|(&s, site)| (s, site.clone()))
+                .collect();
+        }
+        heap.blocks()
+            .map(|(start, size)| {
+                let site = self.sites.get(&start).cloned().unwrap_or(Site {
+                    size,
+                    stack: vec![],
+                    persistent: false,
+                });
+                (start, site)
+            })
+            .collect()
+    }
+
+    // Shadow call stack of jal/jalr, for the call sites in reports
+    pub fn track_call(&mut self, pc: u32, rd: u8, rs1: u8, target: u32) {
+        let Some(calls) = &mut self.hart.calls else {
+            return;
+        };
+        let link = |r| r == 1 || r == 5;
+        if link(rd) {
+            calls.push(pc);
+        } else if rd == 0 && link(rs1) {
+            // returns may skip frames, like longjmp
+            if let Some(i) = calls.iter().rposition(|&c| c.wrapping_add(4) == target) {
+                calls.truncate(i);
+            }
+        }
+    }
+
+    // The pc followed by the call sites, innermost first
+    pub fn backtrace(&self) -> Vec<u32> {
+        let mut stack = vec![self.hart.pc];
+        if let Some(calls) = &self.hart.calls {
+            stack.extend(calls.iter().rev());
+        }
+        stack
+    }
+
+    pub fn abort(&mut self, report: &str) {
+        eprint!("{}", report);
+        self.exit(ABORT);
+    }
+
+    // Needed when code may have changed without a write
+    pub fn clear_decode_cache(&mut self) {
+        if let Some(cache) = &mut self.decode_cache {
+            cache.clear();
+            self.code_version += 1;
+        }
+    }
+
+    pub fn exit(&mut self, code: i32) {
+        self.record(Change::Exit);
+        self.exit_code = Some(code);
+    }
+
+    fn record(&mut self, change: Change) {
+        if let Some(journal) = &mut self.journal {
+            journal.push(change);
+        }
+    }
+
+    // Reverts the last journaled instruction, returns its changes
+    pub fn undo_step(&mut self) -> Option<Vec<Change>> {
+        let mut journal = self.journal.take()?;
+        let step = journal.pop_step();
+        if let Some(changes) = &step {
+            for change in changes.iter().rev() {
+                match change {
+                    Change::Register(ind, val) => self.hart.registers[*ind as usize] = *val,
+                    Change::Pc(val) => self.hart.pc = *val,
+                    Change::Csrs(csrs) => self.hart.csrs = **csrs,
+                    Change::Plic(plic) => self.plic = (**plic).clone(),
+                    Change::Clint(clint) => self.clint = (**clint).clone(),
+                    Change::Memory(start, bytes) => self.write(*start, bytes),
+                    Change::Malloc(start) => {
+                        self.sites.remove(start);
+                        self.heap.as_mut().unwrap().release(*start);
+                    }
+                    Change::Free(start, size) => self.heap.as_mut().unwrap().claim(*start, *size),
+                    Change::Resize(start, size) => {
+                        self.heap.as_mut().unwrap().resize(*start, *size);
+                    }
+                    Change::Exit => {
+                        self.exit_code = None;
+                        self.trap = None;
+                    }
+                }
+            }
+            self.hart.instret -= 1;
+            // the page tables may have changed back
+            self.hart.tlb.clear();
+            if let Some(session) = &mut self.session {
+                session.rewind(self.hart.instret);
+            }
+        }
+        self.journal = Some(journal);
+        step
+    }
+}
+
+// The parts of 'len' bytes at 'start' in each page
+fn pieces(start: usize, len: usize) -> impl Iterator<Item = (usize, usize)> {
+    pages(start, len)
+        .take_while(move |_| len > 0)
+        .map(move |page| {
+            let from = start.max(page);
+            (from, (start + len).min(page + PAGE_SIZE) - from)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accesses_cross_pages() {
+        let mut mem = Memory::with_code(0x1000, &[0; 2 * PAGE_SIZE]);
+        mem.write(0x1ffe, &[1, 2, 3, 4]);
+        assert_eq!(&mem.read(0x1ffc, 8)[..], &[0, 0, 1, 2, 3, 4, 0, 0]);
+        // the next page is not mapped
+        assert!(mem.try_read(0x2ffe, 4).is_none());
+        assert!(!mem.try_write(0x2ffe, &[5, 6, 7, 8]));
+        assert_eq!(&mem.read(0x2ffe, 2)[..], &[0, 0]);
+    }
+
+    #[test]
+    fn heap_blocks_are_zeroed_and_undone() {
+        let mut mem = Memory::with_code(0x1000, &[0; 4]);
+        mem.init_heap(0x10000, 16);
+        let a = mem.malloc(24, 0) as usize;
+        assert_eq!(a, 0x2000);
+        mem.write(a, &[1; 24]);
+        assert_eq!(mem.free(a as u32), 0);
+        assert_eq!(mem.free(a as u32), 1);
+        assert_eq!(mem.calloc(3, 8) as usize, a);
+        assert_eq!(&mem.read(a, 24)[..], &[0; 24]);
+        assert_eq!(mem.calloc(1 << 16, 1 << 16), 0);
+        assert_eq!(mem.malloc(0x10000, 0), 0);
+
+        mem.journal = Some(Journal::default());
+        mem.journal.as_mut().unwrap().begin_step();
+        mem.hart.instret = 1;
+        mem.write(a, &[2; 24]);
+        let b = mem.malloc(8, 0x100) as usize;
+        // grows in place, then moves past b
+        assert_eq!(mem.realloc(a as u32, 32) as usize, a);
+        let c = mem.realloc(a as u32, 0x200) as usize;
+        assert_eq!(c, b + 0x10);
+        assert_eq!(&mem.read(c, 24)[..], &[2; 24]);
+        assert_eq!(&mem.read(c + 24, 8)[..], &[0; 8]);
+        mem.undo_step();
+        let heap = mem.heap.as_ref().unwrap();
+        assert_eq!(heap.blocks().collect::<Vec<_>>(), vec![(a, 32)]);
+        assert_eq!(&mem.read(a, 24)[..], &[0; 24]);
+    }
+
+    #[test]
+    fn harts_start_at_the_top_of_their_share() {
+        let sps = |count| {
+            let mut mem = Memory::with_code(0x1000, &[0; 4]);
+            mem.init_harts(count);
+            (0..count)
+                .map(|id| mem.get_hart(id).registers[SP])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sps(2), vec![0x7ffffff0, 0x7fdffff0]);
+        assert_eq!(sps(4), vec![0x7ffffff0, 0x7feffff0, 0x7fdffff0, 0x7fcffff0]);
+    }
+
+    #[test]
+    fn snapshots_keep_what_diagnostics_need() {
+        let mut mem = Memory::with_code(0x1000, &[0; 4]);
+        mem.init_heap(1 << 16, 16);
+        mem.sections.push(Section {
+            start: 0x1000,
+            size: 4,
+            name: ".text".to_string(),
+        });
+        mem.hart.calls = Some(vec![]);
+        mem.track_call(0x1000, 1, 0, 0x1100);
+        mem.malloc(8, 0);
+        let path = std::env::temp_dir().join(format!("diagnostics-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        mem.save_snapshot(path).unwrap();
+        let restored = Memory::load_snapshot(path);
+        std::fs::remove_file(path).unwrap();
+        let restored = restored.unwrap();
+        assert!(restored
+            .describe_region(0x1000)
+            .unwrap()
+            .ends_with(" .text"));
+        assert_eq!(restored.allocations()[0].1.stack, vec![0x1000, 0x1000]);
+        assert_eq!(crate::leak::report(&restored), crate::leak::report(&mem));
+        assert_eq!(restored.hart.calls, Some(vec![0x1000]));
+        // the sanitizer's shadow state isn't saved, so it's refused
+        mem.enable_sanitizer();
+        assert!(mem.save_snapshot(path).is_err());
     }
 }
@@ -0,0 +1,382 @@
+use crate::csr::{Csrs, SUPERVISOR, USER};
+use crate::memory::{Memory, EXEC, READ, WRITE};
+use crate::trap::Trap;
+use crate::util::*;
+
+// Sv32 page table entry bits
+const V: u32 = 1 << 0;
+const R: u32 = 1 << 1;
+const W: u32 = 1 << 2;
+const X: u32 = 1 << 3;
+const U: u32 = 1 << 4;
+const G: u32 = 1 << 5;
+const A: u32 = 1 << 6;
+const D: u32 = 1 << 7;
+
+pub(crate) const DEFAULT_TLB_ENTRIES: usize = 64;
+pub(crate) const DEFAULT_TLB_WAYS: usize = 4;
+
+// Set associative cache of 4 KiB translations with FIFO replacement,
+// superpages are cached a 4 KiB page at a time. Without entries every
+// access walks the page table.
+#[derive(Debug, Clone)]
+pub(crate) struct Tlb {
+    ways: usize,
+    entries: Vec<Option<Entry>>, // set after set
+    next: Vec<usize>,            // way each set replaces next
+    pub hits: u64,
+    pub misses: u64,
+    pub flushes: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    vpn: u32,
+    asid: u16,
+    ppn: u32, // of this 4 KiB page, even in a superpage
+    pte: u32, // permission and status bits of the leaf
+    superpage: bool,
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Tlb::new(DEFAULT_TLB_ENTRIES, DEFAULT_TLB_WAYS)
+    }
+}
+
+impl Tlb {
+    // 'entries' is a multiple of 'ways'
+    pub fn new(entries: usize, ways: usize) -> Self {
+        assert!(
+            ways > 0 && entries.is_multiple_of(ways),
+            "TLB entries must be a multiple of its ways"
+        );
+        Tlb {
+            ways,
+            entries: vec![None; entries],
+            next: vec![0; entries / ways],
+            hits: 0,
+            misses: 0,
+            flushes: 0,
+        }
+    }
+
+    fn set(&self, vpn: u32) -> usize {
+        vpn as usize % self.next.len() * self.ways
+    }
+
+    fn lookup(&mut self, vpn: u32, asid: u16) -> Option<Entry> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let set = self.set(vpn);
+        self.entries[set..set + self.ways]
+            .iter()
+            .flatten()
+            .find(|e| e.vpn == vpn && (e.asid == asid || e.pte & G != 0))
+            .copied()
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let set = self.set(entry.vpn);
+        let ways = &mut self.entries[set..set + self.ways];
+        // a re-walked translation replaces its old entry
+        let way = match ways
+            .iter()
+            .position(|e| e.is_some_and(|e| e.vpn == entry.vpn && e.asid == entry.asid))
+        {
+            Some(way) => way,
+            None => {
+                let next = &mut self.next[set / self.ways];
+                let way = *next;
+                *next = (way + 1) % self.ways;
+                way
+            }
+        };
+        ways[way] = Some(entry);
+    }
+
+    // SFENCE.VMA: entries of the page at 'addr' and address space 'asid',
+    // all of them when None. Global entries stay unless all address spaces
+    // are flushed.
+    pub fn flush(&mut self, addr: Option<u32>, asid: Option<u16>) {
+        self.flushes += 1;
+        for slot in self.entries.iter_mut() {
+            let Some(e) = slot else {
+                continue;
+            };
+            let page = addr.is_none_or(|addr| match e.superpage {
+                true => e.vpn >> 10 == addr >> 22,
+                false => e.vpn == addr >> 12,
+            });
+            let space = asid.is_none_or(|asid| e.asid == asid && e.pte & G == 0);
+            if page && space {
+                *slot = None;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.fill(None);
+    }
+
+    pub fn stats(&self) -> String {
+        let lookups = self.hits + self.misses;
+        format!(
+            "TLB: {} hits, {} misses ({:.1}% hits), {} flushes, {} entries in {}-way sets",
+            self.hits,
+            self.misses,
+            match lookups {
+                0 => 0.0,
+                _ => self.hits as f64 * 100.0 / lookups as f64,
+            },
+            self.flushes,
+            self.entries.len(),
+            self.ways
+        )
+    }
+}
+
+// Physical address of a guest access with 'perm', the same address when
+// the access isn't translated. Walks set the A and, for stores, D bits of
+// the leaf.
+#[inline]
+pub(crate) fn translate(mem: &mut Memory, addr: u32, perm: u8) -> Result<usize, Trap> {
+    match mem.csrs().access_mode(perm == EXEC) {
+        Some(mode) => translate_in(mem, mode, addr, perm),
+        None => Ok(addr as usize),
+    }
+}
+
+fn translate_in(mem: &mut Memory, mode: u8, addr: u32, perm: u8) -> Result<usize, Trap> {
+    let csrs = *mem.csrs();
+    let vpn = addr >> 12;
+    let asid = csrs.asid();
+    // a store to a clean page walks again to set D
+    let entry = match mem.hart.tlb.lookup(vpn, asid) {
+        Some(e) if perm != WRITE || e.pte & D != 0 => {
+            mem.hart.tlb.hits += 1;
+            e
+        }
+        _ => {
+            mem.hart.tlb.misses += 1;
+            let (pte_addr, mut pte, superpage) = walk(mem, addr, perm)?;
+            if !permitted(&csrs, mode, pte, perm) {
+                return Err(page_fault(addr, perm));
+            }
+            let dirty = if perm == WRITE { D } else { 0 };
+            if pte & (A | dirty) != A | dirty {
+                pte |= A | dirty;
+                if !mem.allows_as(pte_addr, 4, WRITE, SUPERVISOR)
+                    || !mem.try_write(pte_addr, &pte.to_le_bytes())
+                {
+                    return Err(access_fault(addr, perm));
+                }
+            }
+            let offset = if superpage { vpn & 0x3ff } else { 0 };
+            let entry = Entry {
+                vpn,
+                asid,
+                ppn: (pte >> 10) + offset,
+                pte,
+                superpage,
+            };
+            mem.hart.tlb.insert(entry);
+            entry
+        }
+    };
+    if !permitted(&csrs, mode, entry.pte, perm) {
+        return Err(page_fault(addr, perm));
+    }
+    let phys = (entry.ppn as usize) << 12 | (addr & 0xfff) as usize;
+    // 34 bit physical addresses, only the low 4 GiB exist
+    if phys >> 32 != 0 {
+        return Err(access_fault(addr, perm));
+    }
+    Ok(phys)
+}
+
+// Physical address of 'addr' for host side tools, like translate but
+// without permission checks, A/D updates or the TLB
+pub(crate) fn peek(mem: &Memory, addr: u32, fetch: bool) -> Option<usize> {
+    if mem.csrs().access_mode(fetch).is_none() {
+        return Some(addr as usize);
+    }
+    let (_, pte, superpage) = walk(mem, addr, READ).ok()?;
+    let offset = if superpage {
+        addr & 0x3f_ffff
+    } else {
+        addr & 0xfff
+    };
+    let phys = ((pte >> 10) as usize) << 12 | offset as usize;
+    (phys >> 32 == 0).then_some(phys)
+}
+
+// Leaf entry of 'addr', its address and whether it maps a 4 MiB superpage.
+// The PMP checks page table accesses as S-mode ones.
+fn walk(mem: &Memory, addr: u32, perm: u8) -> Result<(usize, u32, bool), Trap> {
+    let mut table = (mem.csrs().root() as usize) << 12;
+    for level in [1, 0] {
+        let pte_addr = table + 4 * (addr >> (12 + 10 * level) & 0x3ff) as usize;
+        if pte_addr >> 32 != 0 || !mem.allows_as(pte_addr, 4, READ, SUPERVISOR) {
+            return Err(access_fault(addr, perm));
+        }
+        let pte = mem.try_read(pte_addr, 4).ok_or(access_fault(addr, perm))?;
+        let pte = to_u32(&pte);
+        if pte & V == 0 || pte & R == 0 && pte & W != 0 {
+            return Err(page_fault(addr, perm));
+        }
+        if pte & (R | X) != 0 {
+            // superpages have to be aligned
+            if level == 1 && pte >> 10 & 0x3ff != 0 {
+                return Err(page_fault(addr, perm));
+            }
+            return Ok((pte_addr, pte, level == 1));
+        }
+        table = ((pte >> 10) as usize) << 12;
+    }
+    Err(page_fault(addr, perm))
+}
+
+fn permitted(csrs: &Csrs, mode: u8, pte: u32, perm: u8) -> bool {
+    let privilege = match mode {
+        USER => pte & U != 0,
+        // S-mode never runs user code, and needs SUM for its data
+        _ => pte & U == 0 || perm != EXEC && csrs.sum(),
+    };
+    let access = match perm {
+        EXEC => pte & X != 0,
+        READ => pte & R != 0 || csrs.mxr() && pte & X != 0,
+        _ => pte & W != 0,
+    };
+    privilege && access
+}
+
+fn page_fault(addr: u32, perm: u8) -> Trap {
+    match perm {
+        EXEC => Trap::InstructionPageFault { addr },
+        READ => Trap::LoadPageFault { addr },
+        _ => Trap::StorePageFault { addr },
+    }
+}
+
+fn access_fault(addr: u32, perm: u8) -> Trap {
+    match perm {
+        EXEC => Trap::InstructionAccessFault { addr },
+        READ => Trap::LoadAccessFault { addr },
+        _ => Trap::StoreAccessFault { addr },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSTATUS: u16 = 0x300;
+    const SATP: u16 = 0x180;
+    const MPRV: u32 = 1 << 17;
+    const ROOT: usize = 0x7ffe_0000;
+    const TABLE: usize = 0x7ffe_1000;
+
+    // 0x4000_0000 is a user page at 0x1000 and 0x4000_1000 an execute only
+    // user page, 0x8000_0000 a supervisor superpage at 0x7fc0_0000
+    fn mapped() -> Memory {
+        let mut mem = Memory::with_code(0x1000, &[0; 0x1000]);
+        let pte = |ppn: usize, flags: u32| ((ppn as u32) << 10 | flags).to_le_bytes();
+        mem.write(ROOT + 4 * 0x100, &pte(TABLE >> 12, V));
+        mem.write(ROOT + 4 * 0x200, &pte(0x7fc00, R | V));
+        mem.write(TABLE, &pte(1, U | W | R | V));
+        mem.write(TABLE + 4, &pte(1, U | X | A | V));
+        let csrs = mem.csrs_mut();
+        csrs.write(SATP, 1 << 31 | 1 << 22 | (ROOT >> 12) as u32)
+            .unwrap();
+        // a PMP entry covering everything
+        csrs.write(0x3b0, u32::MAX).unwrap();
+        csrs.write(0x3a0, 0x1f).unwrap();
+        mem
+    }
+
+    // M-mode loads and stores with the privilege of 'mode'
+    fn set_status(mem: &mut Memory, mode: u8, bits: u32) {
+        let mstatus = MPRV | (mode as u32) << 11 | bits;
+        mem.csrs_mut().write(MSTATUS, mstatus).unwrap();
+    }
+
+    fn pte(mem: &Memory) -> u32 {
+        to_u32(&mem.read(TABLE, 4))
+    }
+
+    #[test]
+    fn walks_check_permissions_and_set_status_bits() {
+        let mut mem = mapped();
+        // M-mode isn't translated
+        assert_eq!(translate(&mut mem, 0x4000_0004, READ), Ok(0x4000_0004));
+        set_status(&mut mem, USER, 0);
+        assert_eq!(translate(&mut mem, 0x4000_0004, READ), Ok(0x1004));
+        assert_eq!(pte(&mem) & (A | D), A);
+        assert_eq!(translate(&mut mem, 0x4000_0008, READ), Ok(0x1008));
+        assert_eq!(translate(&mut mem, 0x4000_0008, WRITE), Ok(0x1008));
+        assert_eq!(pte(&mem) & (A | D), A | D);
+        assert_eq!((mem.hart.tlb.hits, mem.hart.tlb.misses), (1, 2));
+        let fault = Trap::LoadPageFault { addr: 0x4000_1000 };
+        assert_eq!(translate(&mut mem, 0x4000_1000, READ), Err(fault));
+        assert_eq!(
+            translate(&mut mem, 0x8000_1234, READ),
+            Err(fault_at(0x8000_1234))
+        );
+        assert_eq!(
+            translate(&mut mem, 0x4000_2000, READ),
+            Err(fault_at(0x4000_2000))
+        );
+        set_status(&mut mem, USER, 1 << 19);
+        assert_eq!(translate(&mut mem, 0x4000_1000, READ), Ok(0x1000));
+        set_status(&mut mem, SUPERVISOR, 0);
+        assert_eq!(translate(&mut mem, 0x8000_1234, READ), Ok(0x7fc0_1234));
+        let fault = Trap::StorePageFault { addr: 0x8000_1234 };
+        assert_eq!(translate(&mut mem, 0x8000_1234, WRITE), Err(fault));
+        // supervisor accesses to user pages need SUM
+        assert_eq!(
+            translate(&mut mem, 0x4000_0000, READ),
+            Err(fault_at(0x4000_0000))
+        );
+        set_status(&mut mem, SUPERVISOR, 1 << 18);
+        assert_eq!(translate(&mut mem, 0x4000_0000, READ), Ok(0x1000));
+        assert_eq!(peek(&mem, 0x8000_1234, false), Some(0x7fc0_1234));
+    }
+
+    fn fault_at(addr: u32) -> Trap {
+        Trap::LoadPageFault { addr }
+    }
+
+    #[test]
+    fn flushes_keep_global_entries_of_other_address_spaces() {
+        let mut tlb = Tlb::new(4, 2);
+        let entry = |vpn, asid, pte| Entry {
+            vpn,
+            asid,
+            ppn: vpn,
+            pte,
+            superpage: false,
+        };
+        tlb.insert(entry(1, 1, V));
+        tlb.insert(entry(3, 1, V | G));
+        tlb.insert(entry(2, 2, V));
+        // the set of odd pages is full, 1 goes first
+        tlb.insert(entry(5, 1, V));
+        assert!(tlb.lookup(1, 1).is_none());
+        assert!(tlb.lookup(3, 2).is_some());
+        tlb.flush(None, Some(1));
+        assert!(tlb.lookup(3, 1).is_some());
+        assert!(tlb.lookup(5, 1).is_none());
+        assert!(tlb.lookup(2, 2).is_some());
+        tlb.flush(Some(2 << 12), None);
+        assert!(tlb.lookup(2, 2).is_none());
+        assert!(tlb.lookup(3, 1).is_some());
+        tlb.flush(None, None);
+        assert!(tlb.lookup(3, 1).is_none());
+        assert_eq!(tlb.flushes, 3);
+    }
+}
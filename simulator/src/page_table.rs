@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+pub(crate) const PAGE_BITS: usize = 12;
+pub(crate) const PAGE_SIZE: usize = 1 << PAGE_BITS;
+const TABLE_BITS: usize = 10;
+const TABLE_SIZE: usize = 1 << TABLE_BITS;
+
+pub(crate) type Page = [u8; PAGE_SIZE];
+type Table = [Option<Arc<Page>>; TABLE_SIZE];
+
+// Sparse 32 bit physical memory: a directory of 1024 tables of 1024 pages.
+// Mapped pages start out as the shared zero page and get their own copy on
+// the first write, forks share pages the same way.
+#[derive(Debug, Clone)]
+pub(crate) struct PageTable {
+    dir: Vec<Option<Box<Table>>>,
+    zero: Arc<Page>,
+}
+
+impl Default for PageTable {
+    fn default() -> Self {
+        PageTable {
+            dir: vec![None; TABLE_SIZE],
+            zero: Arc::new([0; PAGE_SIZE]),
+        }
+    }
+}
+
+impl PageTable {
+    pub fn get(&self, addr: usize) -> Option<&Page> {
+        let (d, t) = split(addr)?;
+        self.dir[d].as_ref()?[t].as_deref()
+    }
+
+    pub fn get_mut(&mut self, addr: usize) -> Option<&mut Page> {
+        let (d, t) = split(addr)?;
+        Some(Arc::make_mut(self.dir[d].as_mut()?[t].as_mut()?))
+    }
+
+    pub fn is_mapped(&self, addr: usize) -> bool {
+        self.get(addr).is_some()
+    }
+
+    // Maps the pages overlapping 'len' bytes at 'start' that aren't yet,
+    // as zero pages
+    pub fn map(&mut self, start: usize, len: usize) {
+        for page in pages(start, len) {
+            let Some((d, t)) = split(page) else {
+                break;
+            };
+            let table = self.dir[d].get_or_insert_with(|| Box::new(std::array::from_fn(|_| None)));
+            if table[t].is_none() {
+                table[t] = Some(self.zero.clone());
+            }
+        }
+    }
+
+    // Sets mapped bytes to 'val', zero pages stay shared when 'val' is 0
+    pub fn fill(&mut self, start: usize, len: usize, val: u8) {
+        let mut addr = start;
+        while addr < start + len {
+            let offset = addr & (PAGE_SIZE - 1);
+            let n = (PAGE_SIZE - offset).min(start + len - addr);
+            let zero = &self.zero;
+            if let Some((d, t)) = split(addr) {
+                if let Some(Some(page)) = self.dir[d].as_mut().map(|table| &mut table[t]) {
+                    if val != 0 || !Arc::ptr_eq(page, zero) {
+                        Arc::make_mut(page)[offset..offset + n].fill(val);
+                    }
+                }
+            }
+            addr += n;
+        }
+    }
+}
+
+// Directory and table index of 'addr', None past the 32 bit address space
+fn split(addr: usize) -> Option<(usize, usize)> {
+    if addr >> 32 != 0 {
+        return None;
+    }
+    let page = addr >> PAGE_BITS;
+    Some((page >> TABLE_BITS, page & (TABLE_SIZE - 1)))
+}
+
+// Start addresses of the pages overlapping 'len' bytes at 'start'
+pub(crate) fn pages(start: usize, len: usize) -> impl Iterator<Item = usize> {
+    let first = start >> PAGE_BITS;
+    let last = (start + len.max(1) - 1) >> PAGE_BITS;
+    (first..=last).map(|page| page << PAGE_BITS)
+}
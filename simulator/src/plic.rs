@@ -0,0 +1,245 @@
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
+// Memory map of the SiFive PLIC
+pub(crate) const BASE: usize = 0x0c00_0000;
+const SIZE: usize = 0x0400_0000;
+const PENDING: usize = 0x1000;
+const ENABLES: usize = 0x2000;
+const ENABLES_STRIDE: usize = 0x80;
+const CONTEXTS: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+// Interrupt sources 1 to 63, 0 means none
+pub(crate) const SOURCES: usize = 64;
+// Priorities and thresholds have 3 bits, priority 0 never interrupts
+const MAX_PRIORITY: u32 = 7;
+
+// Platform-level interrupt controller. Every hart has an M-mode context
+// 2 * hart and an S-mode context 2 * hart + 1. Sources are level triggered:
+// the gateway latches a raised line as pending, and a claimed source stays
+// quiet until its handler completes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Plic {
+    priority: [u32; SOURCES],
+    level: u64,
+    pending: u64,
+    claimed: u64,
+    contexts: Vec<Context>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Context {
+    enable: u64,
+    threshold: u32,
+}
+
+pub(crate) fn contains(addr: usize) -> bool {
+    (BASE..BASE + SIZE).contains(&addr)
+}
+
+impl Plic {
+    pub fn new(harts: usize) -> Self {
+        Plic {
+            priority: [0; SOURCES],
+            level: 0,
+            pending: 0,
+            claimed: 0,
+            contexts: vec![Context::default(); 2 * harts],
+        }
+    }
+
+    // Device models drive their line high while they want attention
+    pub fn set_level(&mut self, source: usize, high: bool) {
+        assert!(
+            (1..SOURCES).contains(&source),
+            "PLIC sources are 1 to {}",
+            SOURCES - 1
+        );
+        let bit = 1 << source;
+        if high {
+            self.level |= bit;
+            self.pending |= bit & !self.claimed;
+        } else {
+            self.level &= !bit;
+        }
+    }
+
+    // Pending source 'context' would claim, the highest priority one above
+    // its threshold, the lowest numbered among equals
+    fn best(&self, context: usize) -> Option<usize> {
+        let ctx = &self.contexts[context];
+        let candidates = self.pending & ctx.enable;
+        if candidates == 0 {
+            return None;
+        }
+        (1..SOURCES)
+            .filter(|&s| candidates >> s & 1 == 1 && self.priority[s] > ctx.threshold)
+            .max_by_key(|&s| (self.priority[s], std::cmp::Reverse(s)))
+    }
+
+    // The external interrupt line of 'context', MEIP or SEIP of its hart
+    pub fn interrupting(&self, context: usize) -> bool {
+        self.pending != 0 && self.best(context).is_some()
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(source) => {
+                self.pending &= !(1 << source);
+                self.claimed |= 1 << source;
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    // Completions of sources the context hasn't enabled are ignored
+    fn complete(&mut self, context: usize, source: u32) {
+        let source = source as usize;
+        if !(1..SOURCES).contains(&source) || self.contexts[context].enable >> source & 1 == 0 {
+            return;
+        }
+        let bit = 1 << source;
+        self.claimed &= !bit;
+        self.pending |= bit & self.level;
+    }
+
+    // Word at 'offset' from BASE, None if nothing is there. Reading the
+    // claim register claims.
+    pub fn read(&mut self, offset: usize) -> Option<u32> {
+        let word = |bits: u64, i: usize| (bits >> (32 * i)) as u32;
+        Some(match offset {
+            _ if offset < 4 * SOURCES => self.priority[offset / 4],
+            _ if (PENDING..PENDING + SOURCES / 8).contains(&offset) => {
+                word(self.pending, (offset - PENDING) / 4)
+            }
+            _ if (ENABLES..CONTEXTS).contains(&offset) => {
+                let (context, i) = self.enable_word(offset)?;
+                word(self.contexts[context].enable, i)
+            }
+            _ => match self.context_register(offset)? {
+                (context, 0) => self.contexts[context].threshold,
+                (context, _) => self.claim(context),
+            },
+        })
+    }
+
+    // None if nothing is at 'offset'
+    pub fn write(&mut self, offset: usize, val: u32) -> Option<()> {
+        match offset {
+            // source 0 doesn't exist
+            _ if offset < 4 * SOURCES => {
+                if offset >= 4 {
+                    self.priority[offset / 4] = val.min(MAX_PRIORITY);
+                }
+            }
+            _ if (PENDING..PENDING + SOURCES / 8).contains(&offset) => {}
+            _ if (ENABLES..CONTEXTS).contains(&offset) => {
+                let (context, i) = self.enable_word(offset)?;
+                let enable = &mut self.contexts[context].enable;
+                let mask = (u32::MAX as u64) << (32 * i);
+                *enable = (*enable & !mask | (val as u64) << (32 * i)) & !1;
+            }
+            _ => match self.context_register(offset)? {
+                (context, 0) => self.contexts[context].threshold = val.min(MAX_PRIORITY),
+                (context, _) => self.complete(context, val),
+            },
+        }
+        Some(())
+    }
+
+    fn enable_word(&self, offset: usize) -> Option<(usize, usize)> {
+        let offset = offset - ENABLES;
+        let (context, i) = (offset / ENABLES_STRIDE, offset % ENABLES_STRIDE / 4);
+        (context < self.contexts.len() && i < SOURCES / 32).then_some((context, i))
+    }
+
+    // Context and register, 0 the threshold and 1 claim/complete
+    fn context_register(&self, offset: usize) -> Option<(usize, usize)> {
+        let offset = offset.checked_sub(CONTEXTS)?;
+        let (context, reg) = (offset / CONTEXT_STRIDE, offset % CONTEXT_STRIDE / 4);
+        (context < self.contexts.len() && reg < 2).then_some((context, reg))
+    }
+
+    pub fn save(&self, w: &mut SnapshotWriter) {
+        for priority in self.priority.iter() {
+            w.u32(*priority);
+        }
+        w.u64(self.level);
+        w.u64(self.pending);
+        w.u64(self.claimed);
+        w.u32(self.contexts.len() as u32);
+        for ctx in self.contexts.iter() {
+            w.u64(ctx.enable);
+            w.u32(ctx.threshold);
+        }
+    }
+
+    pub fn load(r: &mut SnapshotReader) -> Result<Self, String> {
+        let mut plic = Plic::new(0);
+        for priority in plic.priority.iter_mut() {
+            *priority = r.u32()?;
+        }
+        plic.level = r.u64()?;
+        plic.pending = r.u64()?;
+        plic.claimed = r.u64()?;
+        for _ in 0..r.u32()? {
+            let enable = r.u64()?;
+            let threshold = r.u32()?;
+            plic.contexts.push(Context { enable, threshold });
+        }
+        Ok(plic)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLAIM: usize = CONTEXTS + 4;
+
+    #[test]
+    fn claims_follow_priority_threshold_and_enables() {
+        let mut plic = Plic::new(1);
+        plic.write(4 * 3, 2).unwrap();
+        plic.write(4 * 5, 6).unwrap();
+        plic.write(4 * 40, 9).unwrap();
+        assert_eq!(plic.read(4 * 40), Some(7));
+        plic.set_level(3, true);
+        plic.set_level(5, true);
+        plic.set_level(40, true);
+        assert_eq!(plic.read(PENDING), Some(1 << 3 | 1 << 5));
+        assert_eq!(plic.read(PENDING + 4), Some(1 << 8));
+        assert!(!plic.interrupting(0));
+        plic.write(ENABLES, 1 << 3 | 1 << 5).unwrap();
+        plic.write(CONTEXTS, 2).unwrap();
+        assert!(plic.interrupting(0));
+        assert!(!plic.interrupting(1));
+        assert_eq!(plic.read(CLAIM), Some(5));
+        // 3 is at the threshold
+        assert_eq!(plic.read(CLAIM), Some(0));
+        plic.write(CONTEXTS, 1).unwrap();
+        assert_eq!(plic.read(CLAIM), Some(3));
+        plic.write(ENABLES + ENABLES_STRIDE + 4, 1 << 8).unwrap();
+        assert!(plic.interrupting(1));
+        assert_eq!(plic.read(CONTEXTS + CONTEXT_STRIDE + 4), Some(40));
+        assert_eq!(plic.read(0x1234_0000), None);
+    }
+
+    #[test]
+    fn completed_sources_pend_again_while_raised() {
+        let mut plic = Plic::new(1);
+        plic.write(4 * 7, 1).unwrap();
+        plic.write(ENABLES, 1 << 7).unwrap();
+        plic.set_level(7, true);
+        assert_eq!(plic.read(CLAIM), Some(7));
+        // claimed sources don't pend again until completed
+        plic.set_level(7, true);
+        assert!(!plic.interrupting(0));
+        plic.write(CLAIM, 7).unwrap();
+        assert_eq!(plic.read(CLAIM), Some(7));
+        plic.set_level(7, false);
+        plic.write(CLAIM, 7).unwrap();
+        assert!(!plic.interrupting(0));
+    }
+}
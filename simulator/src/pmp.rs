@@ -0,0 +1,175 @@
+use crate::csr::MACHINE;
+
+// Permission bits of an entry's configuration
+pub(crate) const R: u8 = 1 << 0;
+pub(crate) const W: u8 = 1 << 1;
+pub(crate) const X: u8 = 1 << 2;
+const A_SHIFT: u8 = 3;
+const L: u8 = 1 << 7;
+
+// Address matching modes
+const OFF: u8 = 0;
+const TOR: u8 = 1;
+const NA4: u8 = 2;
+
+const ENTRIES: usize = 16;
+
+// Physical memory protection with 16 entries and 4 byte granularity. The
+// configuration is kept packed the way pmpcfg0-3 hold it, four entries to
+// a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct Pmp {
+    cfg: [u32; ENTRIES / 4],
+    addr: [u32; ENTRIES], // bits 33:2 of the physical address
+}
+
+impl Pmp {
+    pub fn cfg(&self, reg: usize) -> u32 {
+        self.cfg[reg]
+    }
+
+    // Locked entries keep their configuration until reset
+    pub fn set_cfg(&mut self, reg: usize, val: u32) {
+        let mut cfg = self.cfg[reg].to_le_bytes();
+        for (old, new) in cfg.iter_mut().zip(val.to_le_bytes()) {
+            if *old & L != 0 {
+                continue;
+            }
+            // bits 6:5 are reserved, and so is W without R
+            *old = new & !0b0110_0000;
+            if *old & (R | W) == W {
+                *old &= !W;
+            }
+        }
+        self.cfg[reg] = u32::from_le_bytes(cfg);
+    }
+
+    pub fn addr(&self, i: usize) -> u32 {
+        self.addr[i]
+    }
+
+    // Locked entries can't move, neither can the bottom of a locked TOR
+    // range above them
+    pub fn set_addr(&mut self, i: usize, val: u32) {
+        let locked_tor = i + 1 < ENTRIES && {
+            let next = self.entry(i + 1);
+            next & L != 0 && next >> A_SHIFT & 0b11 == TOR
+        };
+        if self.entry(i) & L == 0 && !locked_tor {
+            self.addr[i] = val;
+        }
+    }
+
+    fn entry(&self, i: usize) -> u8 {
+        (self.cfg[i / 4] >> (8 * (i % 4))) as u8
+    }
+
+    // Whether an entry also applies to M-mode
+    #[inline]
+    pub fn locked(&self) -> bool {
+        self.cfg.iter().fold(0, |acc, cfg| acc | cfg) & 0x8080_8080 != 0
+    }
+
+    // Bytes matched by entry 'i', as a half-open range
+    fn range(&self, i: usize) -> Option<(u64, u64)> {
+        let addr = (self.addr[i] as u64) << 2;
+        match self.entry(i) >> A_SHIFT & 0b11 {
+            OFF => None,
+            TOR => {
+                let bottom = if i == 0 {
+                    0
+                } else {
+                    (self.addr[i - 1] as u64) << 2
+                };
+                Some((bottom, addr))
+            }
+            NA4 => Some((addr, addr + 4)),
+            // NAPOT, the trailing ones encode the size
+            _ => {
+                let size = 1u64 << (self.addr[i].trailing_ones() + 3);
+                Some((addr & !(size - 1), (addr & !(size - 1)) + size))
+            }
+        }
+    }
+
+    // Whether 'mode' may access 'len' bytes at 'start' with the 'perm'
+    // bits. The lowest matching entry decides and has to cover all of the
+    // bytes. Without a match only M-mode has access.
+    #[inline]
+    pub fn allows(&self, start: usize, len: usize, perm: u8, mode: u8) -> bool {
+        (mode == MACHINE && !self.locked()) || self.check(start, len, perm, mode)
+    }
+
+    fn check(&self, start: usize, len: usize, perm: u8, mode: u8) -> bool {
+        let (start, end) = (start as u64, (start + len) as u64);
+        for i in 0..ENTRIES {
+            let Some((bottom, top)) = self.range(i) else {
+                continue;
+            };
+            if start < top && bottom < end {
+                let cfg = self.entry(i);
+                return bottom <= start
+                    && end <= top
+                    && (mode == MACHINE && cfg & L == 0 || cfg & perm == perm);
+            }
+        }
+        mode == MACHINE
+    }
+
+    pub fn fields(&mut self) -> impl Iterator<Item = &mut u32> {
+        self.cfg.iter_mut().chain(self.addr.iter_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csr::USER;
+
+    const NAPOT: u8 = 3 << A_SHIFT;
+
+    #[test]
+    fn lowest_matching_entry_decides() {
+        let mut pmp = Pmp::default();
+        // 0: 0x1000..0x1004 read only, 1: TOR up to 0x2000 RX,
+        // 2: NAPOT 0x0..0x8000 RW
+        pmp.set_addr(0, 0x1000 >> 2);
+        pmp.set_addr(1, 0x2000 >> 2);
+        pmp.set_addr(2, 0x0fff);
+        let cfg = [NA4 << A_SHIFT | R, TOR << A_SHIFT | R | X, NAPOT | R | W, 0];
+        pmp.set_cfg(0, u32::from_le_bytes(cfg));
+        assert!(pmp.allows(0x1000, 4, R, USER));
+        assert!(!pmp.allows(0x1000, 4, W, USER));
+        assert!(pmp.allows(0x1800, 4, X, USER));
+        // the TOR entry starts at entry 0's address
+        assert!(!pmp.allows(0x0ffc, 4, X, USER));
+        assert!(pmp.allows(0x0ffc, 4, W, USER));
+        // straddling two entries fails
+        assert!(!pmp.allows(0x1ffe, 4, R, USER));
+        assert!(!pmp.allows(0x8000, 4, R, USER));
+        assert!(pmp.allows(0x8000, 4, R, MACHINE));
+        assert!(pmp.allows(0x1000, 4, W, MACHINE));
+    }
+
+    #[test]
+    fn locked_entries_bind_machine_mode() {
+        let mut pmp = Pmp::default();
+        pmp.set_addr(0, 0x1000 >> 2);
+        pmp.set_addr(1, 0x2000 >> 2);
+        pmp.set_cfg(0, ((L | TOR << A_SHIFT | R) as u32) << 8);
+        assert!(pmp.locked());
+        assert!(!pmp.allows(0x1000, 4, W, MACHINE));
+        assert!(pmp.allows(0x1000, 4, R, MACHINE));
+        // neither the entry nor the bottom of its range can change
+        pmp.set_cfg(0, 0);
+        pmp.set_addr(0, 0);
+        pmp.set_addr(1, 0);
+        assert_eq!(
+            (pmp.cfg(0), pmp.addr(0), pmp.addr(1)),
+            (0x8900, 0x400, 0x800)
+        );
+        // W without R is reserved
+        pmp.set_cfg(1, (NAPOT | W) as u32);
+        assert_eq!(pmp.cfg(1), NAPOT as u32);
+    }
+}
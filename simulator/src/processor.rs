@@ -1,19 +1,69 @@
 pub(crate) struct Processor;
 
+use crate::disasm::disassemble;
 use crate::instruction::Instruction;
-use crate::memory::Memory;
+use crate::memory::{Access, Memory, EXEC, READ, WRITE};
+use crate::mmu;
 use crate::syscall::Syscall;
+use crate::trap::Trap;
 use crate::util::*;
+use std::borrow::Cow;
 
 impl Processor {
     pub fn tick(mem: &mut Memory) {
+        mem.last_access = None;
+        mem.last_trap = None;
+        mem.hart.instret += 1;
+        match Processor::execute(mem) {
+            Ok(()) => Processor::interrupt(mem),
+            Err(trap) => Processor::raise(mem, trap),
+        }
+    }
+
+    // Enters the guest's trap handler, or stops the guest if it has none
+    fn raise(mem: &mut Memory, trap: Trap) {
+        // unless the heap sanitizer already stopped the guest
+        if mem.exit_code.is_some() {
+            return;
+        }
+        if mem.csrs().handles(trap.cause()) {
+            let pc = mem.get_pc();
+            let handler = mem.csrs_mut().trap(trap.cause(), trap.value(), pc);
+            mem.set_pc(handler);
+            mem.last_trap = Some(trap);
+            return;
+        }
+        eprintln!("{}", trap.describe(mem));
+        mem.trap = Some(trap);
+        mem.exit(trap.exit_code());
+    }
+
+    // Interrupts are taken between instructions, mepc is the next one.
+    // Without a handler they stay pending.
+    fn interrupt(mem: &mut Memory) {
+        if let Some(cause) = mem.csrs().interrupt() {
+            if mem.csrs().handles(cause) && mem.exit_code.is_none() {
+                let pc = mem.get_pc();
+                let handler = mem.csrs_mut().trap(cause, 0, pc);
+                mem.set_pc(handler);
+                // whoever raised it wrote a device register before
+                let hart = mem.hart_id();
+                if let Some(races) = &mut mem.races {
+                    races.device(hart);
+                }
+            }
+        }
+    }
+
+    // Architectural state is left untouched when the instruction traps
+    fn execute(mem: &mut Memory) -> Result<(), Trap> {
         use Instruction::*;
-        let inst = Processor::fetch(mem);
+        let inst = Processor::fetch(mem)?;
         if mem.debug {
-            let pc_bytes = from_u32(mem.get_pc());
             println!(
-                "0x{:02X}{:02X}{:02X}{:02X} {:#?}",
-                pc_bytes[3], pc_bytes[2], pc_bytes[1], pc_bytes[0], inst
+                "0x{:08X} {}",
+                mem.get_pc(),
+                disassemble(&inst, mem.get_pc(), None)
             );
         }
         match inst {
@@ -22,63 +72,69 @@ impl Processor {
                 mem.incr_pc();
             }
             AUIPC { imm, rd } => {
-                mem.set_register((mem.get_pc() as i32 + imm) as u32, rd);
+                mem.set_register(mem.get_pc().wrapping_add(imm as u32), rd);
                 mem.incr_pc();
             }
             JAL { imm, rd } => {
                 let pc = mem.get_pc();
-                mem.set_register(pc + 4, rd);
-                mem.set_pc((pc as i32 + imm) as u32);
+                let target = Processor::target(pc.wrapping_add(imm as u32))?;
+                mem.track_call(pc, rd, 0, target);
+                mem.set_register(pc.wrapping_add(4), rd);
+                mem.set_pc(target);
             }
             JALR { imm, rs1, rd } => {
-                let rv = mem.get_register(rs1) as i32;
-                mem.set_register(mem.get_pc() + 4, rd);
-                mem.set_pc((rv + imm) as u32);
+                let pc = mem.get_pc();
+                let target = mem.get_register(rs1).wrapping_add(imm as u32) & !1;
+                let target = Processor::target(target)?;
+                mem.track_call(pc, rd, rs1, target);
+                mem.set_register(pc.wrapping_add(4), rd);
+                mem.set_pc(target);
             }
             BEQ { imm, rs1, rs2 } => {
                 if mem.get_register(rs1) == mem.get_register(rs2) {
-                    mem.set_pc((mem.get_pc() as i32 + imm) as u32);
+                    mem.set_pc(Processor::target(mem.get_pc().wrapping_add(imm as u32))?);
                 } else {
                     mem.incr_pc();
                 }
             }
             BNE { imm, rs1, rs2 } => {
                 if mem.get_register(rs1) != mem.get_register(rs2) {
-                    mem.set_pc((mem.get_pc() as i32 + imm) as u32);
+                    mem.set_pc(Processor::target(mem.get_pc().wrapping_add(imm as u32))?);
                 } else {
                     mem.incr_pc();
                 }
             }
             BLT { imm, rs1, rs2 } => {
                 if (mem.get_register(rs1) as i32) < (mem.get_register(rs2) as i32) {
-                    mem.set_pc((mem.get_pc() as i32 + imm) as u32);
+                    mem.set_pc(Processor::target(mem.get_pc().wrapping_add(imm as u32))?);
                 } else {
                     mem.incr_pc();
                 }
             }
             BGE { imm, rs1, rs2 } => {
                 if (mem.get_register(rs1) as i32) >= (mem.get_register(rs2) as i32) {
-                    mem.set_pc((mem.get_pc() as i32 + imm) as u32);
+                    mem.set_pc(Processor::target(mem.get_pc().wrapping_add(imm as u32))?);
                 } else {
                     mem.incr_pc();
                 }
             }
             BLTU { imm, rs1, rs2 } => {
-                if (mem.get_register(rs1) as u32) < (mem.get_register(rs2) as u32) {
-                    mem.set_pc((mem.get_pc() as i32 + imm) as u32);
+                if mem.get_register(rs1) < mem.get_register(rs2) {
+                    mem.set_pc(Processor::target(mem.get_pc().wrapping_add(imm as u32))?);
                 } else {
                     mem.incr_pc();
                 }
             }
             BGEU { imm, rs1, rs2 } => {
-                if (mem.get_register(rs1) as u32) >= (mem.get_register(rs2) as u32) {
-                    mem.set_pc((mem.get_pc() as i32 + imm) as u32);
+                if mem.get_register(rs1) >= mem.get_register(rs2) {
+                    mem.set_pc(Processor::target(mem.get_pc().wrapping_add(imm as u32))?);
                 } else {
                     mem.incr_pc();
                 }
             }
             LB { imm, rs1, rd } => {
-                let bytes = mem.read((mem.get_register(rs1) as i32 + imm) as usize, 1);
+                let addr = mem.get_register(rs1).wrapping_add(imm as u32) as usize;
+                let bytes = Processor::load(mem, addr, 1)?;
                 let sign = bytes[0] & (1 << 7);
                 let ext = if sign == 0 { 0u8 } else { 0xffu8 };
                 let new_bytes = [bytes[0], ext, ext, ext];
@@ -88,7 +144,8 @@ impl Processor {
                 mem.incr_pc();
             }
             LH { imm, rs1, rd } => {
-                let bytes = mem.read((mem.get_register(rs1) as i32 + imm) as usize, 2);
+                let addr = mem.get_register(rs1).wrapping_add(imm as u32) as usize;
+                let bytes = Processor::load(mem, addr, 2)?;
                 let sign = bytes[1] & (1 << 7);
                 let ext = if sign == 0 { 0u8 } else { 0xffu8 };
                 let new_bytes = [bytes[0], bytes[1], ext, ext];
@@ -98,13 +155,15 @@ impl Processor {
                 mem.incr_pc();
             }
             LW { imm, rs1, rd } => {
-                let bytes = mem.read((mem.get_register(rs1) as i32 + imm) as usize, 4);
+                let addr = mem.get_register(rs1).wrapping_add(imm as u32) as usize;
+                let bytes = Processor::load(mem, addr, 4)?;
                 let val = to_u32(&bytes);
                 mem.set_register(val, rd);
                 mem.incr_pc();
             }
             LBU { imm, rs1, rd } => {
-                let bytes = mem.read((mem.get_register(rs1) as i32 + imm) as usize, 1);
+                let addr = mem.get_register(rs1).wrapping_add(imm as u32) as usize;
+                let bytes = Processor::load(mem, addr, 1)?;
                 let new_bytes = [bytes[0], 0, 0, 0];
                 let val = to_u32(&new_bytes);
                 //println!("lbu bytes {} {}", bytes[0], val);
@@ -112,7 +171,8 @@ impl Processor {
                 mem.incr_pc();
             }
             LHU { imm, rs1, rd } => {
-                let bytes = mem.read((mem.get_register(rs1) as i32 + imm) as usize, 2);
+                let addr = mem.get_register(rs1).wrapping_add(imm as u32) as usize;
+                let bytes = Processor::load(mem, addr, 2)?;
                 let new_bytes = [bytes[0], bytes[1], 0, 0];
                 let val = to_u32(&new_bytes);
                 mem.set_register(val, rd);
@@ -120,28 +180,24 @@ impl Processor {
             }
             SB { imm, rs1, rs2 } => {
                 let reg_bytes = from_u32(mem.get_register(rs2));
-                let bytes = mem.read_mut((mem.get_register(rs1) as i32 + imm) as usize, 1);
-                bytes[0] = reg_bytes[0];
+                let addr = mem.get_register(rs1).wrapping_add(imm as u32) as usize;
+                Processor::store(mem, addr, &reg_bytes[..1])?;
                 mem.incr_pc();
             }
             SH { imm, rs1, rs2 } => {
                 let reg_bytes = from_u32(mem.get_register(rs2));
-                let bytes = mem.read_mut((mem.get_register(rs1) as i32 + imm) as usize, 2);
-                bytes[0] = reg_bytes[0];
-                bytes[1] = reg_bytes[1];
+                let addr = mem.get_register(rs1).wrapping_add(imm as u32) as usize;
+                Processor::store(mem, addr, &reg_bytes[..2])?;
                 mem.incr_pc();
             }
             SW { imm, rs1, rs2 } => {
                 let reg_bytes = from_u32(mem.get_register(rs2));
-                let bytes = mem.read_mut((mem.get_register(rs1) as i32 + imm) as usize, 4);
-                bytes[0] = reg_bytes[0];
-                bytes[1] = reg_bytes[1];
-                bytes[2] = reg_bytes[2];
-                bytes[3] = reg_bytes[3];
+                let addr = mem.get_register(rs1).wrapping_add(imm as u32) as usize;
+                Processor::store(mem, addr, &reg_bytes)?;
                 mem.incr_pc();
             }
             ADDI { imm, rs1, rd } => {
-                mem.set_register((mem.get_register(rs1) as i32 + imm) as u32, rd);
+                mem.set_register(mem.get_register(rs1).wrapping_add(imm as u32), rd);
                 mem.incr_pc();
             }
             SLTI { imm, rs1, rd } => {
@@ -153,18 +209,10 @@ impl Processor {
                 mem.incr_pc();
             }
             SLTIU { imm, rs1, rd } => {
-                if imm == 1 {
-                    if rs1 == 0 {
-                        mem.set_register(1, rd);
-                    } else {
-                        mem.set_register(0, rd);
-                    }
+                if mem.get_register(rs1) < (imm as u32) {
+                    mem.set_register(1, rd);
                 } else {
-                    if (mem.get_register(rs1) as u32) < (imm as u32) {
-                        mem.set_register(1, rd);
-                    } else {
-                        mem.set_register(0, rd);
-                    }
+                    mem.set_register(0, rd);
                 }
                 mem.incr_pc();
             }
@@ -194,21 +242,21 @@ impl Processor {
             }
             ADD { rs1, rs2, rd } => {
                 mem.set_register(
-                    ((mem.get_register(rs1) as i32) + (mem.get_register(rs2) as i32)) as u32,
+                    mem.get_register(rs1).wrapping_add(mem.get_register(rs2)),
                     rd,
                 );
                 mem.incr_pc();
             }
             SUB { rs1, rs2, rd } => {
                 mem.set_register(
-                    ((mem.get_register(rs2) as i32) - (mem.get_register(rs1) as i32)) as u32,
+                    mem.get_register(rs1).wrapping_sub(mem.get_register(rs2)),
                     rd,
                 );
                 mem.incr_pc();
             }
             SLL { rs1, rs2, rd } => {
                 mem.set_register(
-                    mem.get_register(rs2) << (mem.get_register(rs1) & 0b11111),
+                    mem.get_register(rs1) << (mem.get_register(rs2) & 0b11111),
                     rd,
                 );
                 mem.incr_pc();
@@ -222,18 +270,10 @@ impl Processor {
                 mem.incr_pc();
             }
             SLTU { rs2, rs1, rd } => {
-                if rs1 == 0 {
-                    if rs2 != 0 {
-                        mem.set_register(1, rd);
-                    } else {
-                        mem.set_register(0, rd);
-                    }
+                if mem.get_register(rs1) < mem.get_register(rs2) {
+                    mem.set_register(1, rd);
                 } else {
-                    if (mem.get_register(rs1) as u32) < (mem.get_register(rs2)) {
-                        mem.set_register(1, rd);
-                    } else {
-                        mem.set_register(0, rd);
-                    }
+                    mem.set_register(0, rd);
                 }
                 mem.incr_pc();
             }
@@ -246,14 +286,14 @@ impl Processor {
             }
             SRL { rs1, rs2, rd } => {
                 mem.set_register(
-                    mem.get_register(rs2) >> (mem.get_register(rs1) & 0b11111),
+                    mem.get_register(rs1) >> (mem.get_register(rs2) & 0b11111),
                     rd,
                 );
                 mem.incr_pc();
             }
             SRA { rs1, rs2, rd } => {
                 mem.set_register(
-                    ((mem.get_register(rs2) as i32) >> (mem.get_register(rs1) & 0b11111)) as u32,
+                    ((mem.get_register(rs1) as i32) >> (mem.get_register(rs2) & 0b11111)) as u32,
                     rd,
                 );
                 mem.incr_pc();
@@ -273,6 +313,13 @@ impl Processor {
                 mem.incr_pc();
             }
             ECALL => {
+                let trap = Trap::EnvironmentCall {
+                    mode: mem.csrs().mode(),
+                };
+                if mem.csrs().handles(trap.cause()) {
+                    return Err(trap);
+                }
+                // the simulator provides the syscalls
                 let code = mem.get_register(17);
                 //println!("ECALL RECEIVED {} {}", code, mem.get_register(10));
                 let ret = Syscall::call(
@@ -292,20 +339,564 @@ impl Processor {
                 mem.incr_pc();
             }
             EBREAK => {
+                let trap = Trap::Breakpoint { addr: mem.get_pc() };
+                if mem.csrs().handles(trap.cause()) {
+                    return Err(trap);
+                }
                 println!("EBREAK RECEIVED");
                 mem.debug = !mem.debug;
                 mem.incr_pc();
             }
-            FENCE => {
-                // do nothing
+            FENCE { pred, succ } => {
+                // accesses are already in order, only the race detector cares
+                let hart = mem.hart_id();
+                if let Some(races) = &mut mem.races {
+                    races.fence(hart, pred, succ);
+                }
+                mem.incr_pc();
+            }
+            FENCE_I => {
+                // stores already dropped what they overwrote
+                mem.clear_decode_cache();
                 mem.incr_pc();
             }
+            CSRRW { csr, rs1, rd } => {
+                let val = mem.get_register(rs1);
+                Processor::csr(mem, csr, rd, true, |_| val)?;
+            }
+            CSRRS { csr, rs1, rd } => {
+                let val = mem.get_register(rs1);
+                Processor::csr(mem, csr, rd, rs1 != 0, |old| old | val)?;
+            }
+            CSRRC { csr, rs1, rd } => {
+                let val = mem.get_register(rs1);
+                Processor::csr(mem, csr, rd, rs1 != 0, |old| old & !val)?;
+            }
+            CSRRWI { csr, uimm, rd } => {
+                Processor::csr(mem, csr, rd, true, |_| uimm as u32)?;
+            }
+            CSRRSI { csr, uimm, rd } => {
+                Processor::csr(mem, csr, rd, uimm != 0, |old| old | uimm as u32)?;
+            }
+            CSRRCI { csr, uimm, rd } => {
+                Processor::csr(mem, csr, rd, uimm != 0, |old| old & !(uimm as u32))?;
+            }
+            MRET => {
+                let mut csrs = *mem.csrs();
+                let pc = csrs.mret().ok_or_else(|| Processor::illegal(mem))?;
+                *mem.csrs_mut() = csrs;
+                mem.set_pc(pc);
+            }
+            SRET => {
+                let mut csrs = *mem.csrs();
+                let pc = csrs.sret().ok_or_else(|| Processor::illegal(mem))?;
+                *mem.csrs_mut() = csrs;
+                mem.set_pc(pc);
+            }
+            WFI => {
+                if !mem.csrs().wfi_allowed() {
+                    return Err(Processor::illegal(mem));
+                }
+                // returning right away is allowed, pending interrupts are
+                // taken after it
+                mem.incr_pc();
+            }
+            SFENCE_VMA { rs1, rs2 } => {
+                if !mem.csrs().sfence_allowed() {
+                    return Err(Processor::illegal(mem));
+                }
+                let addr = (rs1 != 0).then(|| mem.get_register(rs1));
+                let asid = (rs2 != 0).then(|| mem.get_register(rs2) as u16 & 0x1ff);
+                mem.hart.tlb.flush(addr, asid);
+                mem.incr_pc();
+            }
+            LR_W { rs1, rd, .. } => {
+                let addr = mem.get_register(rs1);
+                if !addr.is_multiple_of(4) {
+                    return Err(Trap::LoadAccessFault { addr });
+                }
+                let val = to_u32(&Processor::load_with(mem, addr as usize, 4, true)?);
+                mem.hart.reservation = Some(Processor::physical(mem, addr, 4, READ)?);
+                mem.set_register(val, rd);
+                mem.incr_pc();
+            }
+            SC_W { rs1, rs2, rd, .. } => {
+                let addr = mem.get_register(rs1);
+                if !addr.is_multiple_of(4) {
+                    return Err(Trap::StoreAccessFault { addr });
+                }
+                let phys = Processor::physical(mem, addr, 4, WRITE)?;
+                let reserved = mem.hart.reservation == Some(phys);
+                if reserved {
+                    let bytes = from_u32(mem.get_register(rs2));
+                    Processor::store_with(mem, addr as usize, &bytes, true)?;
+                }
+                mem.hart.reservation = None;
+                mem.set_register(!reserved as u32, rd);
+                mem.incr_pc();
+            }
+            AMO {
+                op, rs1, rs2, rd, ..
+            } => {
+                let addr = mem.get_register(rs1);
+                if !addr.is_multiple_of(4) {
+                    return Err(Trap::StoreAccessFault { addr });
+                }
+                // AMOs fault like stores, even when the load fails
+                let old =
+                    Processor::load_with(mem, addr as usize, 4, true).map_err(Trap::as_store)?;
+                let old = to_u32(&old);
+                let bytes = from_u32(op.apply(old, mem.get_register(rs2)));
+                Processor::store_with(mem, addr as usize, &bytes, true)?;
+                mem.set_register(old, rd);
+                mem.incr_pc();
+            }
+        }
+        Ok(())
+    }
+
+    // Jumps and taken branches to a misaligned target trap before writing rd
+    fn target(target: u32) -> Result<u32, Trap> {
+        match target.is_multiple_of(4) {
+            true => Ok(target),
+            false => Err(Trap::InstructionAddressMisaligned { addr: target }),
+        }
+    }
+
+    fn illegal(mem: &Memory) -> Trap {
+        Trap::IllegalInstruction {
+            raw: mem.get_instr(),
+        }
+    }
+
+    // Reads 'csr' into rd, and when 'write' stores 'op' of the old value
+    fn csr(
+        mem: &mut Memory,
+        csr: u16,
+        rd: u8,
+        write: bool,
+        op: impl Fn(u32) -> u32,
+    ) -> Result<(), Trap> {
+        // counters read what retired before this instruction
+        let old = mem.csrs().read(csr, mem.hart.instret - 1);
+        let old = old.ok_or_else(|| Processor::illegal(mem))?;
+        if write {
+            let mut csrs = *mem.csrs();
+            csrs.write(csr, op(old))
+                .ok_or_else(|| Processor::illegal(mem))?;
+            *mem.csrs_mut() = csrs;
         }
+        mem.set_register(old, rd);
+        mem.incr_pc();
+        Ok(())
+    }
+
+    fn load(mem: &mut Memory, addr: usize, len: usize) -> Result<Cow<'_, [u8]>, Trap> {
+        Processor::load_with(mem, addr, len, false)
+    }
+
+    fn store(mem: &mut Memory, addr: usize, bytes: &[u8]) -> Result<(), Trap> {
+        Processor::store_with(mem, addr, bytes, false)
+    }
+
+    // Loads and stores check the physical address, last_access has the
+    // virtual one. 'sync' is set for the atomics.
+    fn load_with(
+        mem: &mut Memory,
+        addr: usize,
+        len: usize,
+        sync: bool,
+    ) -> Result<Cow<'_, [u8]>, Trap> {
+        let fault = Trap::LoadAccessFault { addr: addr as u32 };
+        let phys = Processor::physical(mem, addr as u32, len, READ)?;
+        if !mem.allows(phys, len, READ) {
+            return Err(fault);
+        }
+        let device = if mem.is_mmio(phys) {
+            Some(mem.mmio_read(phys, len).ok_or(fault)?)
+        } else if mem.try_read(phys, len).is_none() || !mem.sanitize(phys, len, false) {
+            return Err(fault);
+        } else {
+            None
+        };
+        mem.check_race(phys, len, false, sync);
+        mem.last_access = Some(Access {
+            addr: addr as u32,
+            len,
+            write: false,
+        });
+        Ok(match device {
+            Some(val) => Cow::Owned(val.to_le_bytes().to_vec()),
+            None => mem.read(phys, len),
+        })
+    }
+
+    fn store_with(mem: &mut Memory, addr: usize, bytes: &[u8], sync: bool) -> Result<(), Trap> {
+        let fault = Trap::StoreAccessFault { addr: addr as u32 };
+        let phys = Processor::physical(mem, addr as u32, bytes.len(), WRITE)?;
+        if !mem.allows(phys, bytes.len(), WRITE) {
+            return Err(fault);
+        }
+        if mem.is_mmio(phys) {
+            mem.mmio_write(phys, bytes).ok_or(fault)?;
+        } else if !mem.sanitize(phys, bytes.len(), true) || !mem.try_write(phys, bytes) {
+            return Err(fault);
+        }
+        mem.check_race(phys, bytes.len(), true, sync);
+        mem.break_reservations(phys, bytes.len());
+        mem.last_access = Some(Access {
+            addr: addr as u32,
+            len: bytes.len(),
+            write: true,
+        });
+        mem.track_stack(phys);
+        Ok(())
+    }
+
+    // Translates both pages of a misaligned access that crosses a page
+    // boundary. They have to be physically contiguous, otherwise it's an
+    // access fault, which the spec allows for misaligned accesses.
+    fn physical(mem: &mut Memory, addr: u32, len: usize, perm: u8) -> Result<usize, Trap> {
+        if !mem.csrs().paging() {
+            return Ok(addr as usize);
+        }
+        let phys = mmu::translate(mem, addr, perm)?;
+        let last = addr.wrapping_add(len as u32 - 1);
+        if last >> 12 != addr >> 12 && mmu::translate(mem, last, perm)? != phys + len - 1 {
+            return Err(match perm {
+                READ => Trap::LoadAccessFault { addr },
+                _ => Trap::StoreAccessFault { addr },
+            });
+        }
+        Ok(phys)
+    }
+
+    fn fetch(mem: &mut Memory) -> Result<Instruction, Trap> {
+        Processor::fetch_at(mem, mem.get_pc())
+    }
+
+    pub fn fetch_at(mem: &mut Memory, pc: u32) -> Result<Instruction, Trap> {
+        if !pc.is_multiple_of(4) {
+            return Err(Trap::InstructionAddressMisaligned { addr: pc });
+        }
+        // the decode cache holds physical addresses, and doesn't know
+        // about the PMP, which can change
+        let phys = mmu::translate(mem, pc, EXEC)?;
+        let fault = Trap::InstructionAccessFault { addr: pc };
+        if !mem.pmp_allows(phys, 4, EXEC, mem.csrs().mode()) {
+            return Err(fault);
+        }
+        if let Some(inst) = mem.decode_cache.as_mut().and_then(|c| c.get(phys as u32)) {
+            return Ok(inst);
+        }
+        if !mem.allows(phys, 4, EXEC) {
+            return Err(fault);
+        }
+        let bytes = mem.try_read(phys, 4).ok_or(fault)?;
+        let raw = to_u32(&bytes);
+        let inst = Instruction::try_new(raw).ok_or(Trap::IllegalInstruction { raw })?;
+        if let Some(cache) = &mut mem.decode_cache {
+            cache.insert(phys as u32, inst);
+        }
+        Ok(inst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PC: u32 = 0x1000;
+
+    fn run(word: u32) -> Memory {
+        let mut mem = Memory::with_code(PC, &word.to_le_bytes());
+        Processor::tick(&mut mem);
+        mem
+    }
+
+    // S and U-mode need a PMP entry, firmware would set up this one
+    // covering everything
+    fn unprotected(code: &[u8]) -> Memory {
+        let mut mem = Memory::with_code(PC, code);
+        let csrs = mem.csrs_mut();
+        csrs.write(0x3b0, u32::MAX).unwrap();
+        csrs.write(0x3a0, 0x1f).unwrap();
+        mem
+    }
+
+    #[test]
+    fn illegal_instruction_traps() {
+        let mem = run(0xc0001073);
+        assert_eq!(mem.trap, Some(Trap::IllegalInstruction { raw: 0xc0001073 }));
+        assert_eq!(mem.exit_code, Some(132));
+        assert_eq!(mem.get_pc(), PC);
+    }
+
+    #[test]
+    fn unmapped_load_traps_without_writing_rd() {
+        // lw a0,0(zero)
+        let mem = run(0x00002503);
+        assert_eq!(mem.trap, Some(Trap::LoadAccessFault { addr: 0 }));
+        assert_eq!(mem.get_register(10), 0);
+        assert_eq!(mem.get_pc(), PC);
+    }
+
+    #[test]
+    fn stores_replace_cached_instructions() {
+        // li a0,1 gets cached, then overwritten with li a0,2
+        let mut mem = run(0x00100513);
+        assert_eq!(mem.get_register(10), 1);
+        mem.write(PC as usize, &0x00200513u32.to_le_bytes());
+        mem.set_pc(PC);
+        Processor::tick(&mut mem);
+        assert_eq!(mem.get_register(10), 2);
+    }
+
+    #[test]
+    fn fetch_outside_memory_traps() {
+        // jalr zero,0(zero) then fetch from 0
+        let mut mem = run(0x00000067);
+        assert_eq!(mem.trap, None);
+        Processor::tick(&mut mem);
+        assert_eq!(mem.trap, Some(Trap::InstructionAccessFault { addr: 0 }));
+    }
+
+    #[test]
+    fn stack_overflow_hits_the_guard() {
+        // sw zero,-20(sp)
+        let mut mem = run(0xfe012623);
+        assert_eq!(mem.stack_usage(0), (20, 4 << 20));
+        let sp = mem.get_register(2) - (4 << 20);
+        mem.set_register(sp, 2);
+        mem.set_pc(PC);
+        Processor::tick(&mut mem);
+        let trap = Trap::StoreAccessFault { addr: sp - 20 };
+        assert_eq!(mem.trap, Some(trap));
+        assert!(trap
+            .describe(&mem)
+            .starts_with("Stack overflow at pc 0x00001000 in ??: 'sw zero,-20(sp)'"));
+        assert_eq!(mem.stack_usage(0), (20, 4 << 20));
+    }
+
+    #[test]
+    fn harts_overflow_into_their_own_guard() {
+        // sw zero,-20(sp) from the bottom of hart 0's share
+        let mut mem = Memory::with_code(PC, &0xfe012623u32.to_le_bytes());
+        mem.init_harts(2);
+        let (start, end) = mem.hart_stack(0);
+        assert_eq!(mem.hart_stack(1).1, end - (2 << 20));
+        mem.set_register(start as u32, 2);
+        Processor::tick(&mut mem);
+        let trap = Trap::StoreAccessFault {
+            addr: start as u32 - 20,
+        };
+        assert_eq!(mem.trap, Some(trap));
+        assert!(trap
+            .describe(&mem)
+            .contains("20 bytes below hart 0's 2031616 byte stack"));
+        // each hart has its own high-water mark
+        mem.set_register(end as u32 - 8, 2);
+        mem.set_pc(PC);
+        Processor::tick(&mut mem);
+        assert_eq!(mem.stack_usage(0), (28, 2031616));
+        assert_eq!(mem.stack_usage(1), (0, 2 << 20));
+    }
+
+    #[test]
+    fn traps_are_delegated_to_supervisor_mode() {
+        // M-mode delegates ecalls from U-mode, then drops to S-mode and
+        // U-mode with mret and sret
+        let program = [
+            (0x00, 0x000012b7),  // lui t0,0x1
+            (0x04, 0x10028293),  // addi t0,t0,256
+            (0x08, 0x30529073),  // csrw mtvec,t0
+            (0x0c, 0x10000293),  // li t0,256
+            (0x10, 0x30229073),  // csrw medeleg,t0
+            (0x14, 0x000012b7),  // lui t0,0x1
+            (0x18, 0x08028293),  // addi t0,t0,128
+            (0x1c, 0x10529073),  // csrw stvec,t0
+            (0x20, 0x000012b7),  // lui t0,0x1
+            (0x24, 0x07028293),  // addi t0,t0,112
+            (0x28, 0x14129073),  // csrw sepc,t0
+            (0x2c, 0x000012b7),  // lui t0,0x1
+            (0x30, 0x80028293),  // addi t0,t0,-2048
+            (0x34, 0x30029073),  // csrw mstatus,t0
+            (0x38, 0x000012b7),  // lui t0,0x1
+            (0x3c, 0x06028293),  // addi t0,t0,96
+            (0x40, 0x34129073),  // csrw mepc,t0
+            (0x44, 0x30200073),  // mret
+            (0x60, 0x10200073),  // sret
+            (0x70, 0x00000073),  // ecall
+            (0x80, 0x142025f3),  // csrr a1,scause
+            (0x84, 0x14102673),  // csrr a2,sepc
+            (0x88, 0x30002573),  // csrr a0,mstatus
+            (0x100, 0x342026f3), // csrr a3,mcause
+            (0x104, 0x34302773), // csrr a4,mtval
+            (0x108, 0x300027f3), // csrr a5,mstatus
+        ];
+        let mut code = vec![0; 0x10c];
+        for (offset, word) in program {
+            code[offset..offset + 4].copy_from_slice(&u32::to_le_bytes(word));
+        }
+        let mut mem = unprotected(&code);
+        for _ in 0..26 {
+            Processor::tick(&mut mem);
+        }
+        assert_eq!(mem.trap, None);
+        assert_eq!(mem.get_pc(), PC + 0x10c);
+        assert_eq!(mem.csrs().mode(), crate::csr::MACHINE);
+        // the ecall went to S-mode, reading mstatus there to M-mode
+        assert_eq!((mem.get_register(11), mem.get_register(12)), (8, 0x1070));
+        assert_eq!(mem.get_register(10), 0);
+        assert_eq!(
+            (mem.get_register(13), mem.get_register(14)),
+            (2, 0x30002573)
+        );
+        assert_eq!(mem.get_register(15) >> 11 & 0b11, 1);
+    }
+
+    #[test]
+    fn user_mode_runs_through_page_tables() {
+        // M-mode maps 0x4000_0000 to the code at 0x1000 and returns to
+        // U-mode there, where a load from an unmapped page faults
+        let program = [
+            (0x00, 0x800802b7),  // lui t0,0x80080
+            (0x04, 0xfe028293),  // addi t0,t0,-32
+            (0x08, 0x18029073),  // csrw satp,t0
+            (0x0c, 0x000012b7),  // lui t0,0x1
+            (0x10, 0x10028293),  // addi t0,t0,256
+            (0x14, 0x30529073),  // csrw mtvec,t0
+            (0x18, 0x400002b7),  // lui t0,0x40000
+            (0x1c, 0x04028293),  // addi t0,t0,64
+            (0x20, 0x34129073),  // csrw mepc,t0
+            (0x24, 0x30200073),  // mret
+            (0x40, 0x400002b7),  // lui t0,0x40000
+            (0x44, 0x0042a583),  // lw a1,4(t0)
+            (0x48, 0x00002503),  // lw a0,0(zero)
+            (0x100, 0x34202673), // csrr a2,mcause
+            (0x104, 0x343026f3), // csrr a3,mtval
+        ];
+        let mut code = vec![0; 0x108];
+        for (offset, word) in program {
+            code[offset..offset + 4].copy_from_slice(&u32::to_le_bytes(word));
+        }
+        let mut mem = unprotected(&code);
+        // page tables on the stack, 0x7ffe_0000 is the root
+        mem.write(0x7ffe_0400, &u32::to_le_bytes(0x7ffe1 << 10 | 1));
+        mem.write(0x7ffe_1000, &u32::to_le_bytes(1 << 10 | 0b11011));
+        for _ in 0..15 {
+            Processor::tick(&mut mem);
+        }
+        assert_eq!(mem.trap, None);
+        assert_eq!(mem.get_pc(), PC + 0x108);
+        assert_eq!(mem.get_register(11), 0xfe028293);
+        assert_eq!((mem.get_register(12), mem.get_register(13)), (13, 0));
+        // the walk set the accessed bit
+        assert_eq!(mem.read(0x7ffe_1000, 1)[0], 0b1011011);
+    }
+
+    #[test]
+    fn pmp_denies_user_stores() {
+        // sw zero,-4(sp), a U-mode store through MPRV
+        let mut mem = unprotected(&0xfe012e23u32.to_le_bytes());
+        let csrs = mem.csrs_mut();
+        csrs.write(0x3a0, 0x1d).unwrap();
+        csrs.write(0x300, 1 << 17).unwrap();
+        Processor::tick(&mut mem);
+        let addr = mem.get_register(2) - 4;
+        assert_eq!(mem.trap, Some(Trap::StoreAccessFault { addr }));
+    }
+
+    #[test]
+    fn external_interrupts_come_from_the_plic() {
+        // a nop, and a handler claiming with lw a0,4(t0)
+        let mut code = vec![0; 0x104];
+        code[..4].copy_from_slice(&0x00000013u32.to_le_bytes());
+        code[0x100..].copy_from_slice(&0x0042a503u32.to_le_bytes());
+        let mut mem = Memory::with_code(PC, &code);
+        mem.set_register(0x0c20_0000, 5);
+        let csrs = mem.csrs_mut();
+        csrs.write(0x305, PC + 0x100).unwrap();
+        csrs.write(0x304, 1 << 11).unwrap();
+        csrs.write(0x300, 1 << 3).unwrap();
+        // source 2 with priority 1, enabled for M-mode
+        mem.mmio_write(0x0c00_0008, &1u32.to_le_bytes()).unwrap();
+        mem.mmio_write(0x0c00_2000, &4u32.to_le_bytes()).unwrap();
+        mem.set_irq(2, true);
+        Processor::tick(&mut mem);
+        assert_eq!(mem.get_pc(), PC + 0x100);
+        assert_eq!(mem.csrs().read(0x342, 0), Some(1 << 31 | 11));
+        Processor::tick(&mut mem);
+        assert_eq!(mem.get_register(10), 2);
+        assert_eq!(mem.csrs().lines(), 0);
+    }
+
+    #[test]
+    fn harts_send_ipis_through_the_clint() {
+        // csrr a1,mhartid and sw a2,4(t0), a store to hart 1's msip
+        let mut code = vec![0; 0x104];
+        code[..4].copy_from_slice(&0xf14025f3u32.to_le_bytes());
+        code[4..8].copy_from_slice(&0x00c2a223u32.to_le_bytes());
+        let mut mem = Memory::with_code(PC, &code);
+        mem.set_register(0x0200_0000, 5);
+        mem.set_register(1, 12);
+        mem.init_harts(2);
+        assert_eq!(mem.get_hart(1).registers[10], 1);
+        assert!(mem.get_hart(1).registers[2] < mem.get_register(2));
+        mem.switch_hart(1);
+        let csrs = mem.csrs_mut();
+        csrs.write(0x305, PC + 0x100).unwrap();
+        csrs.write(0x304, 1 << 3).unwrap();
+        csrs.write(0x300, 1 << 3).unwrap();
+        mem.switch_hart(0);
+        Processor::tick(&mut mem);
+        Processor::tick(&mut mem);
+        assert_eq!(mem.get_register(11), 0);
+        assert_eq!(mem.csrs().lines(), 0);
+        mem.switch_hart(1);
+        assert_eq!(mem.csrs().lines(), 1 << 3);
+        Processor::tick(&mut mem);
+        assert_eq!(mem.get_register(11), 1);
+        assert_eq!(mem.get_pc(), PC + 0x100);
+        assert_eq!(mem.csrs().read(0x342, 0), Some(1 << 31 | 3));
     }
 
-    fn fetch(mem: &Memory) -> Instruction {
-        let bytes = mem.get_instr();
-        let inst = Instruction::new(to_u32(bytes));
-        inst
+    #[test]
+    fn segment_permissions_are_enforced() {
+        let blob = std::fs::read("tests/guests/signature.elf").unwrap();
+        let binary = elfloader::ElfBinary::new("signature", &blob).unwrap();
+        let fresh = || {
+            let mut mem = Memory::new(&binary, &blob);
+            // a1 points into .text (r-x), a2 into .data (rw-)
+            mem.set_register(mem.get_pc(), 11);
+            mem.set_register(mem.get_pc() + 0x1000, 12);
+            mem
+        };
+        let at = |mem: &mut Memory, word: u32| {
+            let pc = mem.get_pc() as usize;
+            mem.write(pc, &word.to_le_bytes());
+            Processor::tick(mem);
+            mem.trap
+        };
+        // lw a0,0(a1)
+        assert_eq!(at(&mut fresh(), 0x0005a503), None);
+        // sw a0,0(a1)
+        let mut mem = fresh();
+        let pc = mem.get_pc();
+        assert_eq!(
+            at(&mut mem, 0x00a5a023),
+            Some(Trap::StoreAccessFault { addr: pc })
+        );
+        assert!(Trap::StoreAccessFault { addr: pc }
+            .describe(&mem)
+            .contains("segment 0x80000000..0x80000040 r-x .text"));
+        // jalr zero,0(a2)
+        let mut mem = fresh();
+        assert_eq!(at(&mut mem, 0x00060067), None);
+        Processor::tick(&mut mem);
+        assert_eq!(
+            mem.trap,
+            Some(Trap::InstructionAccessFault { addr: pc + 0x1000 })
+        );
     }
 }
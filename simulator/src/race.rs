@@ -0,0 +1,363 @@
+use crate::memory::Memory;
+use crate::sanitizer::format_stack;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+// Exit code when races fail the run, the same as ThreadSanitizer's
+pub(crate) const RACE_EXIT: i32 = 66;
+// FENCE predecessor and successor bits
+const R: u8 = 0b0010;
+const W: u8 = 0b0001;
+
+// Happens-before race detection in the spirit of ThreadSanitizer. Every
+// hart has a vector clock, and every word remembers the last write of each
+// of its bytes and each hart's last read since. Two accesses by different
+// harts to a byte race when one of them writes and neither happens before
+// the other.
+//
+// LR/SC and AMOs acquire and release the word they access. Device
+// registers, and taking interrupts, acquire and release one clock shared
+// by all of them, so IPIs synchronize. Plain accesses synchronize through
+// fences: the first store after a release fence publishes the hart's
+// clock, and an acquire fence takes the clocks published by what the hart
+// loaded since. Writes that publish a clock are meant to be read
+// concurrently, they only race with plain writes.
+#[derive(Debug, Clone)]
+pub(crate) struct RaceDetector {
+    harts: Vec<HartClock>,
+    device: Vec<u32>,               // clock of the device registers
+    shadow: HashMap<usize, Shadow>, // by word address
+    reported: HashSet<(u32, u32)>,  // pcs of the pairs already reported
+    pub races: usize,
+}
+
+#[derive(Debug, Clone)]
+struct HartClock {
+    clock: Vec<u32>,
+    releasing: bool,    // the next store publishes the clock
+    acquired: Vec<u32>, // published clocks loaded since the last fence
+}
+
+#[derive(Debug, Clone, Default)]
+struct Shadow {
+    writes: Vec<Access>,                   // the last one of each byte
+    reads: Vec<Access>,                    // the last ones of each hart since the writes
+    published: Option<(u8, Rc<Vec<u32>>)>, // the newest clock published, and by which bytes
+}
+
+#[derive(Debug, Clone)]
+struct Access {
+    hart: usize,
+    clock: u32, // the hart's own entry when it accessed
+    write: bool,
+    synced: bool, // a write that published a clock
+    bytes: u8,    // mask of the bytes of the word it still holds for
+    stack: Rc<[u32]>,
+}
+
+impl RaceDetector {
+    pub fn new(harts: usize) -> Self {
+        let harts = (0..harts)
+            .map(|id| {
+                let mut clock = vec![0; harts];
+                clock[id] = 1;
+                HartClock {
+                    clock,
+                    releasing: false,
+                    acquired: vec![],
+                }
+            })
+            .collect();
+        RaceDetector {
+            harts,
+            device: vec![],
+            shadow: HashMap::new(),
+            reported: HashSet::new(),
+            races: 0,
+        }
+    }
+
+    // Checks a guest load or store of the running hart, 'sync' for the
+    // atomics. Returns the report of a race not seen before.
+    pub fn access(
+        &mut self,
+        mem: &Memory,
+        addr: usize,
+        len: usize,
+        write: bool,
+        sync: bool,
+    ) -> Option<String> {
+        let id = mem.hart_id();
+        if sync {
+            for (word, bytes) in words(addr, len) {
+                let published = self.shadow.get(&word).and_then(|s| s.published.clone());
+                if let Some((by, published)) = published {
+                    if by & bytes != 0 {
+                        join(&mut self.harts[id].clock, &published);
+                    }
+                }
+            }
+        }
+        let hart = &mut self.harts[id];
+        let synced = write && (sync || hart.releasing);
+        let access = Access {
+            hart: id,
+            clock: hart.clock[id],
+            write,
+            synced,
+            bytes: 0,
+            stack: mem.backtrace().into(),
+        };
+        let published = synced.then(|| Rc::new(hart.clock.clone()));
+        let mut race = None;
+        for (word, bytes) in words(addr, len) {
+            let shadow = self.shadow.entry(word).or_default();
+            let unordered = |prev: &Access| {
+                prev.bytes & bytes != 0 && prev.hart != id && prev.clock > hart.clock[prev.hart]
+            };
+            let racing = |prev: &&Access| unordered(prev) && !(prev.synced && (!write || synced));
+            if let Some(prev) = shadow.writes.iter().find(racing) {
+                race = race.or_else(|| Some(prev.clone()));
+            }
+            let access = Access {
+                bytes,
+                ..access.clone()
+            };
+            if write {
+                if !synced {
+                    if let Some(prev) = shadow.reads.iter().find(|prev| unordered(prev)) {
+                        race = race.or_else(|| Some(prev.clone()));
+                    }
+                }
+                forget(&mut shadow.writes, bytes, |_| true);
+                shadow.writes.push(access);
+                forget(&mut shadow.reads, bytes, |_| true);
+                shadow.published = match (&published, shadow.published.take()) {
+                    (Some(clock), _) => Some((bytes, clock.clone())),
+                    (None, Some((by, clock))) if by & !bytes != 0 => Some((by & !bytes, clock)),
+                    _ => None,
+                };
+            } else {
+                forget(&mut shadow.reads, bytes, |prev| prev.hart == id);
+                shadow.reads.push(access);
+                if let Some((by, published)) = &shadow.published {
+                    if by & bytes != 0 {
+                        join(&mut hart.acquired, published);
+                    }
+                }
+            }
+        }
+        if synced {
+            hart.clock[id] += 1;
+            hart.releasing = false;
+        }
+        let prev = race?;
+        if !self.reported.insert((prev.stack[0], access.stack[0])) {
+            return None;
+        }
+        self.races += 1;
+        Some(report(mem, addr, len, &access, &prev))
+    }
+
+    // Forgets the accesses to 'len' bytes at 'start', which the heap hands
+    // out again. malloc and free don't synchronize, the next owner of the
+    // bytes starts afresh like in ThreadSanitizer.
+    pub fn reset(&mut self, start: usize, len: usize) {
+        let end = start + len;
+        // big blocks are cheaper to find in the shadow than word by word
+        let touched: Vec<usize> = if len / 4 > self.shadow.len() {
+            let near = |word: usize| word + 4 > start && word < end;
+            self.shadow.keys().copied().filter(|&w| near(w)).collect()
+        } else {
+            words(start, len).map(|(word, _)| word).collect()
+        };
+        for word in touched {
+            let Some(shadow) = self.shadow.get_mut(&word) else {
+                continue;
+            };
+            let bytes = mask(word, start, end);
+            forget(&mut shadow.writes, bytes, |_| true);
+            forget(&mut shadow.reads, bytes, |_| true);
+            if let Some((by, _)) = &mut shadow.published {
+                *by &= !bytes;
+                if *by == 0 {
+                    shadow.published = None;
+                }
+            }
+            if shadow.writes.is_empty() && shadow.reads.is_empty() && shadow.published.is_none() {
+                self.shadow.remove(&word);
+            }
+        }
+    }
+
+    // Device registers are accessed in order, like one atomic, and
+    // interrupts come from them
+    pub fn device(&mut self, id: usize) {
+        let hart = &mut self.harts[id];
+        join(&mut hart.clock, &self.device);
+        self.device = hart.clock.clone();
+        hart.clock[id] += 1;
+    }
+
+    // Acquire fences order earlier loads before later accesses, release
+    // fences earlier accesses before later stores
+    pub fn fence(&mut self, id: usize, pred: u8, succ: u8) {
+        let hart = &mut self.harts[id];
+        if pred & R != 0 && succ & (R | W) != 0 {
+            let acquired = std::mem::take(&mut hart.acquired);
+            join(&mut hart.clock, &acquired);
+        }
+        if pred & (R | W) != 0 && succ & W != 0 {
+            hart.releasing = true;
+        }
+    }
+}
+
+// Words 'len' bytes at 'addr' touch, with the mask of the bytes in each
+fn words(addr: usize, len: usize) -> impl Iterator<Item = (usize, u8)> {
+    (addr & !3..addr + len)
+        .step_by(4)
+        .map(move |word| (word, mask(word, addr, addr + len)))
+}
+
+// Bytes of 'word' from 'start' to 'end'
+fn mask(word: usize, start: usize, end: usize) -> u8 {
+    (0..4)
+        .filter(|i| start <= word + i && word + i < end)
+        .fold(0, |mask, i| mask | 1 << i)
+}
+
+// Drops 'bytes' from the accesses 'which' picks, and the accesses left
+// with none
+fn forget(accesses: &mut Vec<Access>, bytes: u8, which: impl Fn(&Access) -> bool) {
+    for prev in accesses.iter_mut().filter(|prev| which(prev)) {
+        prev.bytes &= !bytes;
+    }
+    accesses.retain(|prev| prev.bytes != 0);
+}
+
+// Happens after both
+fn join(clock: &mut Vec<u32>, other: &[u32]) {
+    if clock.len() < other.len() {
+        clock.resize(other.len(), 0);
+    }
+    for (mine, theirs) in clock.iter_mut().zip(other.iter()) {
+        *mine = (*mine).max(*theirs);
+    }
+}
+
+fn report(mem: &Memory, addr: usize, len: usize, access: &Access, prev: &Access) -> String {
+    let what = |access: &Access| if access.write { "write" } else { "read" };
+    let mut out = format!(
+        "==race-detector== data race: {} byte {} at 0x{:08x} {}\n",
+        len,
+        what(access),
+        addr,
+        mem.symbols.describe(addr as u32)
+    );
+    out += &format!("  {} by hart {}:\n", what(access), access.hart);
+    out += &format_stack(mem, &access.stack);
+    out += &format!("  previous {} by hart {}:\n", what(prev), prev.hart);
+    out += &format_stack(mem, &prev.stack);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: usize = 0x2000;
+    const FLAG: usize = 0x2004;
+
+    fn harts() -> Memory {
+        let mut mem = Memory::with_code(0x1000, &[0; 4]);
+        mem.init_harts(2);
+        mem.enable_race_detector();
+        mem
+    }
+
+    // Plain or atomic access by 'hart' at 'pc'
+    fn access(mem: &mut Memory, hart: usize, pc: u32, addr: usize, write: bool, sync: bool) {
+        mem.switch_hart(hart);
+        mem.set_pc(pc);
+        mem.check_race(addr, 4, write, sync);
+    }
+
+    fn fence(mem: &mut Memory, hart: usize) {
+        mem.races.as_mut().unwrap().fence(hart, 0b1111, 0b1111);
+    }
+
+    fn races(mem: &Memory) -> usize {
+        mem.races.as_ref().unwrap().races
+    }
+
+    #[test]
+    fn unordered_accesses_race_once() {
+        let mut mem = harts();
+        access(&mut mem, 0, 0x1000, DATA, true, false);
+        access(&mut mem, 1, 0x1004, DATA, false, false);
+        access(&mut mem, 1, 0x1004, DATA, false, false);
+        assert_eq!(races(&mem), 1);
+        // reads don't race with reads, nor accesses with other bytes
+        access(&mut mem, 0, 0x1008, DATA, false, false);
+        access(&mut mem, 1, 0x100c, FLAG, true, false);
+        mem.check_race(FLAG + 8, 1, true, false);
+        mem.switch_hart(0);
+        mem.check_race(FLAG + 9, 2, true, false);
+        assert_eq!(races(&mem), 1);
+    }
+
+    #[test]
+    fn fences_publish_stores() {
+        let mut mem = harts();
+        access(&mut mem, 0, 0x1000, DATA, true, false);
+        fence(&mut mem, 0);
+        access(&mut mem, 0, 0x1004, FLAG, true, false);
+        access(&mut mem, 1, 0x1008, FLAG, false, false);
+        fence(&mut mem, 1);
+        access(&mut mem, 1, 0x100c, DATA, false, false);
+        access(&mut mem, 1, 0x1010, FLAG, true, false);
+        assert_eq!(races(&mem), 0);
+        // without the release fence, the flag and the data race
+        access(&mut mem, 0, 0x1014, DATA + 8, true, false);
+        access(&mut mem, 0, 0x1018, FLAG + 8, true, false);
+        access(&mut mem, 1, 0x101c, FLAG + 8, false, false);
+        fence(&mut mem, 1);
+        access(&mut mem, 1, 0x1020, DATA + 8, false, false);
+        assert_eq!(races(&mem), 2);
+    }
+
+    #[test]
+    fn atomics_synchronize() {
+        let mut mem = harts();
+        // a spinlock taken and released with amoswap
+        for hart in 0..2 {
+            access(&mut mem, hart, 0x1000, FLAG, false, true);
+            access(&mut mem, hart, 0x1000, FLAG, true, true);
+            access(&mut mem, hart, 0x1004, DATA, true, false);
+            access(&mut mem, hart, 0x1008, FLAG, false, true);
+            access(&mut mem, hart, 0x1008, FLAG, true, true);
+        }
+        assert_eq!(races(&mem), 0);
+        access(&mut mem, 0, 0x100c, DATA, false, false);
+        assert_eq!(races(&mem), 1);
+    }
+
+    #[test]
+    fn reused_heap_blocks_start_afresh() {
+        let mut mem = harts();
+        mem.init_heap(1 << 16, 16);
+        mem.switch_hart(0);
+        let block = mem.malloc(16, 0) as usize;
+        access(&mut mem, 0, 0x1000, block + 4, true, false);
+        mem.free(block as u32);
+        mem.switch_hart(1);
+        assert_eq!(mem.malloc(16, 0) as usize, block);
+        access(&mut mem, 1, 0x1004, block + 4, true, false);
+        access(&mut mem, 1, 0x1008, block + 4, false, false);
+        assert_eq!(races(&mem), 0);
+        // the block is still shared while it's allocated
+        access(&mut mem, 0, 0x100c, block + 4, false, false);
+        assert_eq!(races(&mem), 1);
+    }
+}
@@ -0,0 +1,463 @@
+use crate::clint::Clint;
+use crate::csr::Csrs;
+use crate::memory::Memory;
+use crate::plic::Plic;
+use std::fs;
+
+const SESSION_HEADER: &str = "rv32-sim-session 2";
+
+// One architectural state change, holding the value it replaced
+#[derive(Debug)]
+pub(crate) enum Change {
+    Register(u8, u32),
+    Pc(u32),
+    Csrs(Box<Csrs>),
+    Plic(Box<Plic>),
+    Clint(Box<Clint>),
+    Memory(usize, Vec<u8>),
+    Malloc(usize),
+    Free(usize, usize),   // and the size of the block
+    Resize(usize, usize), // and the size it had
+    Exit,
+}
+
+// Undo log of every executed instruction, used for reverse execution
+#[derive(Debug, Default)]
+pub(crate) struct Journal {
+    steps: Vec<Vec<Change>>,
+}
+
+impl Journal {
+    pub fn begin_step(&mut self) {
+        self.steps.push(vec![]);
+    }
+
+    pub fn push(&mut self, change: Change) {
+        if let Some(step) = self.steps.last_mut() {
+            step.push(change);
+        }
+    }
+
+    pub fn pop_step(&mut self) -> Option<Vec<Change>> {
+        self.steps.pop()
+    }
+}
+
+// Nondeterministic inputs of a run: syscall results, device register
+// reads and interrupt lines raised from outside the guest, e.g. by the
+// debugger. Recording them is enough to replay a run bit exact.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Event {
+    Syscall {
+        instret: u64,
+        code: i32,
+        ret: i32,
+    },
+    Read {
+        instret: u64,
+        addr: u32,
+        val: u32,
+    },
+    Irq {
+        instret: u64,
+        source: usize,
+        high: bool,
+    }, // before the instruction after 'instret'
+    Exit {
+        instret: u64,
+        code: i32,
+    },
+    Final {
+        pc: u32,
+        registers: Vec<u32>,
+    },
+}
+
+#[derive(Debug)]
+pub(crate) enum Session {
+    Record(Vec<Event>),
+    Replay(Vec<Event>, usize), // events and the index of the next one
+}
+
+impl Event {
+    fn instret(&self) -> u64 {
+        match self {
+            Event::Syscall { instret, .. }
+            | Event::Read { instret, .. }
+            | Event::Irq { instret, .. }
+            | Event::Exit { instret, .. } => *instret,
+            Event::Final { .. } => u64::MAX,
+        }
+    }
+}
+
+impl Session {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut lines = text.lines();
+        if lines.next() != Some(SESSION_HEADER) {
+            return Err(format!("{}: not a session recording", path));
+        }
+        let mut events = vec![];
+        for line in lines {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let num = |i: usize| -> Result<i64, String> {
+                parts
+                    .get(i)
+                    .and_then(|p| p.parse().ok())
+                    .ok_or_else(|| format!("{}: bad line '{}'", path, line))
+            };
+            let event = match parts.first() {
+                Some(&"syscall") => Event::Syscall {
+                    instret: num(1)? as u64,
+                    code: num(2)? as i32,
+                    ret: num(3)? as i32,
+                },
+                Some(&"read") => Event::Read {
+                    instret: num(1)? as u64,
+                    addr: num(2)? as u32,
+                    val: num(3)? as u32,
+                },
+                Some(&"irq") => Event::Irq {
+                    instret: num(1)? as u64,
+                    source: num(2)? as usize,
+                    high: num(3)? != 0,
+                },
+                Some(&"exit") => Event::Exit {
+                    instret: num(1)? as u64,
+                    code: num(2)? as i32,
+                },
+                Some(&"final") => Event::Final {
+                    pc: num(1)? as u32,
+                    registers: (2..parts.len())
+                        .map(|i| num(i).map(|v| v as u32))
+                        .collect::<Result<_, _>>()?,
+                },
+                _ => return Err(format!("{}: bad line '{}'", path, line)),
+            };
+            events.push(event);
+        }
+        Ok(Session::Replay(events, 0))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let events = match self {
+            Session::Record(events) => events,
+            Session::Replay(..) => return Ok(()),
+        };
+        let mut text = format!("{}\n", SESSION_HEADER);
+        for event in events {
+            match event {
+                Event::Syscall { instret, code, ret } => {
+                    text += &format!("syscall {} {} {}\n", instret, code, ret)
+                }
+                Event::Read { instret, addr, val } => {
+                    text += &format!("read {} {} {}\n", instret, addr, val)
+                }
+                Event::Irq {
+                    instret,
+                    source,
+                    high,
+                } => text += &format!("irq {} {} {}\n", instret, source, *high as u8),
+                Event::Exit { instret, code } => text += &format!("exit {} {}\n", instret, code),
+                Event::Final { pc, registers } => {
+                    let regs: Vec<String> = registers.iter().map(|r| r.to_string()).collect();
+                    text += &format!("final {} {}\n", pc, regs.join(" "))
+                }
+            }
+        }
+        fs::write(path, text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // Records or checks a syscall, in replay the recorded result wins
+    pub fn syscall(&mut self, instret: u64, code: i32, ret: i32) -> Result<i32, String> {
+        match self {
+            Session::Record(events) => {
+                events.push(Event::Syscall { instret, code, ret });
+                Ok(ret)
+            }
+            Session::Replay(events, next) => {
+                let event = events.get(*next);
+                *next += 1;
+                match event {
+                    Some(Event::Syscall {
+                        instret: i,
+                        code: c,
+                        ret,
+                    }) if *i == instret && *c == code => Ok(*ret),
+                    other => Err(format!(
+                        "Replay diverged at instruction {}: syscall {}, recording has {:?}",
+                        instret, code, other
+                    )),
+                }
+            }
+        }
+    }
+
+    // Records or checks a device register read, in replay the recorded
+    // value wins
+    pub fn device_read(&mut self, instret: u64, addr: u32, val: u32) -> Result<u32, String> {
+        match self {
+            Session::Record(events) => {
+                events.push(Event::Read { instret, addr, val });
+                Ok(val)
+            }
+            Session::Replay(events, next) => {
+                let event = events.get(*next);
+                *next += 1;
+                match event {
+                    Some(Event::Read {
+                        instret: i,
+                        addr: a,
+                        val,
+                    }) if *i == instret && *a == addr => Ok(*val),
+                    other => Err(format!(
+                        "Replay diverged at instruction {}: read of 0x{:08x}, recording has {:?}",
+                        instret, addr, other
+                    )),
+                }
+            }
+        }
+    }
+
+    // Records an interrupt line raised or lowered from outside the guest
+    pub fn irq(&mut self, instret: u64, source: usize, high: bool) {
+        if let Session::Record(events) = self {
+            events.push(Event::Irq {
+                instret,
+                source,
+                high,
+            });
+        }
+    }
+
+    // In replay, the next interrupt line change if it's due before the
+    // instruction after 'instret'
+    pub fn due_irq(&mut self, instret: u64) -> Option<(usize, bool)> {
+        let Session::Replay(events, next) = self else {
+            return None;
+        };
+        match events.get(*next) {
+            Some(&Event::Irq {
+                instret: i,
+                source,
+                high,
+            }) if i <= instret => {
+                *next += 1;
+                Some((source, high))
+            }
+            _ => None,
+        }
+    }
+
+    // Whether a replay still has interrupts to inject, which needs the run
+    // to go one instruction at a time
+    pub fn injects(&self) -> bool {
+        match self {
+            Session::Record(_) => false,
+            Session::Replay(events, next) => events[*next..]
+                .iter()
+                .any(|e| matches!(e, Event::Irq { .. })),
+        }
+    }
+
+    // Forgets events after 'instret' when execution goes backwards
+    pub fn rewind(&mut self, instret: u64) {
+        match self {
+            Session::Record(events) => events.retain(|e| e.instret() <= instret),
+            Session::Replay(events, next) => {
+                while *next > 0 && events[*next - 1].instret() > instret {
+                    *next -= 1;
+                }
+            }
+        }
+    }
+
+    // Called once the guest is done, records or verifies the final state
+    pub fn finish(&mut self, mem: &Memory, code: i32) -> Result<(), String> {
+        let exit = Event::Exit {
+            instret: mem.hart.instret,
+            code,
+        };
+        let last = Event::Final {
+            pc: mem.get_pc(),
+            registers: (0..32).map(|i| mem.get_register(i)).collect(),
+        };
+        match self {
+            Session::Record(events) => {
+                events.push(exit);
+                events.push(last);
+            }
+            Session::Replay(events, next) => {
+                for expected in [exit, last].iter() {
+                    let recorded = events.get(*next);
+                    *next += 1;
+                    if recorded != Some(expected) {
+                        return Err(format!(
+                            "Replay diverged at exit: recorded {:?}, got {:?}",
+                            recorded, expected
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::sanitizer::ABORT;
+
+    const PC: u32 = 0x1000;
+
+    fn machine(code: &[u32]) -> Machine {
+        let bytes: Vec<u8> = code.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let mut mem = Memory::with_code(PC, &bytes);
+        mem.init_heap(1 << 16, 16);
+        Machine::new(mem)
+    }
+
+    // s0 = malloc(16), then exits with 3
+    const MALLOC: [u32; 7] = [
+        0x01000513, // li a0,16
+        0x1f700893, // li a7,503
+        0x00000073, // ecall
+        0x00050413, // mv s0,a0
+        0x00300513, // li a0,3
+        0x1f400893, // li a7,500
+        0x00000073, // ecall
+    ];
+
+    fn run(machine: &mut Machine, session: Session) -> Result<Session, String> {
+        machine.mem_mut().session = Some(session);
+        let code = machine.run();
+        let mem = machine.mem_mut();
+        let mut session = mem.session.take().unwrap();
+        session.finish(mem, code)?;
+        Ok(session)
+    }
+
+    #[test]
+    fn replays_recorded_syscalls() {
+        let mut recorded = machine(&MALLOC);
+        let session = run(&mut recorded, Session::Record(vec![])).unwrap();
+        let path = std::env::temp_dir().join(format!("session-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        session.save(path).unwrap();
+        let loaded = Session::load(path);
+        fs::remove_file(path).unwrap();
+        // finish fails unless the final state matches
+        let mut replayed = machine(&MALLOC);
+        run(&mut replayed, loaded.unwrap()).unwrap();
+        let s0 = recorded.mem().get_register(8);
+        assert_ne!(s0, 0);
+        assert_eq!(replayed.mem().get_register(8), s0);
+        // the recorded result wins over what the syscall returns
+        let Session::Record(mut events) = session else {
+            unreachable!()
+        };
+        if let Event::Syscall { ret, .. } = &mut events[0] {
+            *ret = 0x1234;
+        }
+        let mut replayed = machine(&MALLOC);
+        replayed.mem_mut().session = Some(Session::Replay(events.clone(), 0));
+        assert_eq!(replayed.run(), 3);
+        assert_eq!(replayed.mem().get_register(8), 0x1234);
+        // a different final state, or syscall, is a divergence
+        let mut replayed = machine(&MALLOC);
+        let err = run(&mut replayed, Session::Replay(events.clone(), 0)).unwrap_err();
+        assert!(err.starts_with("Replay diverged at exit"));
+        if let Event::Syscall { code, .. } = &mut events[0] {
+            *code = 504;
+        }
+        let mut replayed = machine(&MALLOC);
+        replayed.mem_mut().session = Some(Session::Replay(events, 0));
+        assert_eq!(replayed.run(), ABORT);
+    }
+
+    #[test]
+    fn replays_device_reads_and_interrupts() {
+        // a nop, then j . until an external interrupt, which claims with
+        // lw a0,4(t0), and exits with the source once mtvec is cleared
+        let interrupted = || {
+            let mut code = vec![0x00000013, 0x0000006f];
+            code.resize(0x40, 0);
+            code.extend([0x0042a503, 0x30501073, 0x1f400893, 0x00000073]);
+            let mut machine = machine(&code);
+            let mem = machine.mem_mut();
+            mem.set_register(0x0c20_0000, 5);
+            let csrs = mem.csrs_mut();
+            csrs.write(0x305, PC + 0x100).unwrap();
+            csrs.write(0x304, 1 << 11).unwrap();
+            csrs.write(0x300, 1 << 3).unwrap();
+            mem.mmio_write(0x0c00_0008, &1u32.to_le_bytes()).unwrap();
+            mem.mmio_write(0x0c00_2000, &4u32.to_le_bytes()).unwrap();
+            machine
+        };
+        let mut recorded = interrupted();
+        recorded.mem_mut().session = Some(Session::Record(vec![]));
+        for _ in 0..3 {
+            recorded.step();
+        }
+        recorded.mem_mut().set_irq(2, true);
+        let session = recorded.mem_mut().session.take().unwrap();
+        let session = run(&mut recorded, session);
+        assert_eq!(recorded.mem().exit_code, Some(2));
+        let path = std::env::temp_dir().join(format!("session-irq-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        session.unwrap().save(path).unwrap();
+        let loaded = Session::load(path).unwrap();
+        fs::remove_file(path).unwrap();
+        let Session::Replay(events, _) = &loaded else {
+            unreachable!()
+        };
+        let irq = Event::Irq {
+            instret: 3,
+            source: 2,
+            high: true,
+        };
+        let read = Event::Read {
+            instret: 5,
+            addr: 0x0c20_0004,
+            val: 2,
+        };
+        assert_eq!(events[..2], [irq, read]);
+        // nothing raises the interrupt but the recording
+        let mut replayed = interrupted();
+        run(&mut replayed, loaded).unwrap();
+        assert_eq!(replayed.mem().exit_code, Some(2));
+    }
+
+    #[test]
+    fn reverse_steps_restore_the_state() {
+        let mut machine = machine(&[
+            0x00500513, // li a0,5
+            0xff010113, // addi sp,sp,-16
+            0x00a12623, // sw a0,12(sp)
+            0x00a10023, // sb a0,0(sp)
+            0x00150513, // addi a0,a0,1
+        ]);
+        let mem = machine.mem_mut();
+        mem.journal = Some(Journal::default());
+        let sp = mem.get_register(2) as usize;
+        let state = |mem: &Memory| {
+            let registers: Vec<u32> = (0..32).map(|i| mem.get_register(i)).collect();
+            (mem.get_pc(), registers, mem.read(sp - 16, 16).to_vec())
+        };
+        mem.write(sp - 16, &[0xaa; 16]);
+        let before = state(mem);
+        for _ in 0..5 {
+            machine.step();
+        }
+        let after = state(machine.mem());
+        assert_ne!(after, before);
+        for _ in 0..5 {
+            assert!(machine.mem_mut().undo_step().is_some());
+        }
+        assert_eq!(state(machine.mem()), before);
+        assert!(machine.mem_mut().undo_step().is_none());
+    }
+}
@@ -0,0 +1,299 @@
+use crate::heap::Heap;
+use crate::memory::Memory;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+pub(crate) const REDZONE: usize = 16;
+// Freed blocks stay poisoned until this many bytes are freed after them
+const QUARANTINE: usize = 4 << 20;
+pub(crate) const POISON: u8 = 0xfd;
+// Shell style exit code of an aborted process
+pub(crate) const ABORT: i32 = 128 + 6;
+
+// Heap checking in the spirit of ASan: sanitized blocks get redzones, freed
+// blocks sit in a quarantine, and every guest load and store is checked
+// against them.
+#[derive(Debug, Clone)]
+pub(crate) struct Sanitizer {
+    heap: (usize, usize), // start and end
+    live: BTreeMap<usize, Block>,
+    freed: BTreeMap<usize, Block>,
+    quarantine: VecDeque<usize>,
+    quarantined: usize,
+    warned: HashSet<u32>, // pcs already reported for uninitialized reads
+}
+
+// Allocation, keyed by the start of its heap block including redzones
+#[derive(Debug, Clone)]
+struct Block {
+    start: usize, // what the guest got
+    size: usize,
+    end: usize, // of the heap block
+    init: Vec<bool>,
+    allocated: Vec<u32>,
+    freed: Vec<u32>,
+}
+
+impl Sanitizer {
+    // Blocks already allocated, like in a restored snapshot, have no redzones
+    pub fn new(heap: &Heap) -> Self {
+        let live = heap
+            .blocks()
+            .map(|(start, size)| {
+                let block = Block {
+                    start,
+                    size,
+                    end: start + size,
+                    init: vec![true; size],
+                    allocated: vec![],
+                    freed: vec![],
+                };
+                (start, block)
+            })
+            .collect();
+        Sanitizer {
+            heap: (heap.start(), heap.start() + heap.size()),
+            live,
+            freed: BTreeMap::new(),
+            quarantine: VecDeque::new(),
+            quarantined: 0,
+            warned: HashSet::new(),
+        }
+    }
+
+    pub fn malloc(&mut self, base: usize, start: usize, size: usize, end: usize, stack: Vec<u32>) {
+        let block = Block {
+            start,
+            size,
+            end,
+            init: vec![false; size],
+            allocated: stack,
+            freed: vec![],
+        };
+        self.live.insert(base, block);
+    }
+
+    // Size of the live block the guest got at 'start'
+    pub fn size_of(&self, start: usize) -> Option<usize> {
+        self.base_of(&self.live, start)
+            .map(|base| self.live[&base].size)
+    }
+
+    fn base_of(&self, blocks: &BTreeMap<usize, Block>, start: usize) -> Option<usize> {
+        let (&base, block) = blocks.range(..=start).next_back()?;
+        (block.start == start).then_some(base)
+    }
+
+    // Marks 'len' bytes from the start of a block written by the host
+    pub fn initialize(&mut self, start: usize, len: usize) {
+        if let Some(base) = self.base_of(&self.live, start) {
+            self.live.get_mut(&base).unwrap().init[..len].fill(true);
+        }
+    }
+
+    // Quarantines the block at 'start', returns its size and the heap
+    // blocks that left the quarantine and can be reused
+    pub fn free(&mut self, mem: &Memory, start: usize) -> Result<(usize, Vec<usize>), String> {
+        let Some(base) = self.base_of(&self.live, start) else {
+            let kind = match self.base_of(&self.freed, start) {
+                Some(_) => "double-free",
+                None => "invalid-free",
+            };
+            let report = self.report(mem, kind, &format!("free(0x{:08x})", start), start);
+            return Err(report);
+        };
+        let mut block = self.live.remove(&base).unwrap();
+        block.freed = mem.backtrace();
+        let size = block.size;
+        self.quarantined += block.end - base;
+        self.freed.insert(base, block);
+        self.quarantine.push_back(base);
+        let mut released = vec![];
+        while self.quarantined > QUARANTINE {
+            let Some(base) = self.quarantine.pop_front() else {
+                break;
+            };
+            let block = self.freed.remove(&base).unwrap();
+            self.quarantined -= block.end - base;
+            released.push(base);
+        }
+        Ok((size, released))
+    }
+
+    // Checks a guest load or store, only uninitialized reads are allowed,
+    // they are reported once per pc
+    pub fn check(
+        &mut self,
+        mem: &Memory,
+        addr: usize,
+        len: usize,
+        write: bool,
+    ) -> Result<(), String> {
+        if addr + len <= self.heap.0 || addr >= self.heap.1 {
+            return Ok(());
+        }
+        let pc = mem.get_pc();
+        let access = format!(
+            "{} byte {} at 0x{:08x}",
+            len,
+            if write { "write" } else { "read" },
+            addr
+        );
+        let found = |blocks: &BTreeMap<usize, Block>| {
+            blocks
+                .range(..=addr)
+                .next_back()
+                .filter(|(_, block)| addr < block.end)
+                .map(|(&base, _)| base)
+        };
+        if let Some(base) = found(&self.live) {
+            let block = self.live.get_mut(&base).unwrap();
+            if addr < block.start || addr + len > block.start + block.size {
+                let report = self.report(mem, "heap-buffer-overflow", &access, addr);
+                return Err(report);
+            }
+            let offset = addr - block.start;
+            let init = &mut block.init[offset..offset + len];
+            let uninit = !write && !init.iter().all(|&i| i);
+            init.fill(true);
+            if uninit && self.warned.insert(pc) {
+                let report = self.report(mem, "uninitialized-read", &access, addr);
+                eprint!("{}", report);
+            }
+            return Ok(());
+        }
+        let kind = match found(&self.freed) {
+            Some(_) => "heap-use-after-free",
+            None => "wild-heap-access",
+        };
+        let report = self.report(mem, kind, &access, addr);
+        Err(report)
+    }
+
+    // Moves the initialized state along with the content realloc copies
+    pub fn copy_init(&mut self, from: usize, to: usize, len: usize) {
+        let (Some(from), Some(to)) = (self.base_of(&self.live, from), self.base_of(&self.live, to))
+        else {
+            return;
+        };
+        let init = self.live[&from].init[..len].to_vec();
+        self.live.get_mut(&to).unwrap().init[..len].copy_from_slice(&init);
+    }
+
+    // What went wrong where, the block around 'addr' with its call sites,
+    // and a hexdump
+    fn report(&self, mem: &Memory, kind: &str, what: &str, addr: usize) -> String {
+        let pc = mem.get_pc();
+        let mut out = format!(
+            "==heap-sanitizer== {}: {} by pc 0x{:08x} {}\n",
+            kind,
+            what,
+            pc,
+            mem.symbols.describe(pc)
+        );
+        let near = self
+            .live
+            .iter()
+            .chain(self.freed.iter())
+            .filter(|(&base, block)| base <= addr + REDZONE && addr < block.end + REDZONE)
+            .min_by_key(|(_, block)| block.start.abs_diff(addr));
+        if let Some((_, block)) = near {
+            let relation = if addr < block.start {
+                format!("{} bytes before", block.start - addr)
+            } else if addr >= block.start + block.size {
+                format!("{} bytes after", addr - block.start - block.size)
+            } else {
+                format!("{} bytes into", addr - block.start)
+            };
+            out += &format!(
+                "  0x{:08x} is {} the {} byte block at 0x{:08x}\n",
+                addr, relation, block.size, block.start
+            );
+            if !block.allocated.is_empty() {
+                out += "  allocated by:\n";
+                out += &format_stack(mem, &block.allocated);
+            }
+            if !block.freed.is_empty() {
+                out += "  freed by:\n";
+                out += &format_stack(mem, &block.freed);
+            }
+        }
+        let first = (addr & !15).saturating_sub(16);
+        for line in (first..first + 48).step_by(16) {
+            if let Some(bytes) = mem.try_read(line, 16) {
+                let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                let mark = if (line..line + 16).contains(&addr) {
+                    "=>"
+                } else {
+                    "  "
+                };
+                out += &format!("{} 0x{:08x}: {}\n", mark, line, hex.join(" "));
+            }
+        }
+        out
+    }
+}
+
+pub(crate) fn format_stack(mem: &Memory, stack: &[u32]) -> String {
+    stack
+        .iter()
+        .enumerate()
+        .map(|(i, pc)| {
+            let frame = format!("    #{} 0x{:08x} {}", i, pc, mem.symbols.describe(*pc));
+            // no trailing space when the pc has no symbol
+            frame.trim_end().to_string() + "\n"
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitized() -> Memory {
+        let mut mem = Memory::with_code(0x1000, &[0; 4]);
+        mem.init_heap(1 << 16, 16);
+        mem.enable_sanitizer();
+        mem
+    }
+
+    #[test]
+    fn redzones_catch_overflows() {
+        let mut mem = sanitized();
+        let a = mem.malloc(12, 0) as usize;
+        assert_eq!(a, 0x2000 + REDZONE);
+        assert!(mem.sanitize(a, 12, true));
+        assert!(mem.sanitize(a + 8, 4, false));
+        assert!(!mem.sanitize(a + 10, 4, false));
+        assert_eq!(mem.exit_code, Some(ABORT));
+        let mut mem = sanitized();
+        let a = mem.malloc(12, 0) as usize;
+        assert!(!mem.sanitize(a - 1, 1, true));
+    }
+
+    #[test]
+    fn freed_blocks_stay_poisoned() {
+        let mut mem = sanitized();
+        let a = mem.malloc(8, 0) as usize;
+        assert_eq!(mem.free(a as u32), 0);
+        assert_eq!(&mem.read(a, 8)[..], &[POISON; 8]);
+        // quarantined, not reused
+        assert_ne!(mem.malloc(8, 0) as usize, a);
+        assert!(!mem.sanitize(a, 4, false));
+        let mut mem = sanitized();
+        let a = mem.malloc(8, 0);
+        assert_eq!(mem.free(a), 0);
+        assert_eq!(mem.free(a), 1);
+        assert_eq!(mem.exit_code, Some(ABORT));
+    }
+
+    #[test]
+    fn realloc_moves_and_keeps_initialized_bytes() {
+        let mut mem = sanitized();
+        let a = mem.calloc(2, 4);
+        let b = mem.realloc(a, 16) as usize;
+        assert_ne!(b, a as usize);
+        assert!(mem.sanitize(b, 8, false));
+        assert!(!mem.sanitize(a as usize, 4, false));
+        assert_eq!(mem.sanitizer.as_ref().unwrap().size_of(b), Some(16));
+    }
+}
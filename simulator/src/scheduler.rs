@@ -0,0 +1,103 @@
+pub(crate) const DEFAULT_QUANTUM: u64 = 1000;
+
+// Decides which hart runs the next instruction. Round robin runs every hart
+// for 'quantum' instructions in turn. The random schedule runs a random
+// hart for 1 to 'quantum' instructions, the same interleaving for the same
+// seed, to shake out and then reproduce races.
+#[derive(Debug, Clone)]
+pub(crate) struct Scheduler {
+    quantum: u64,
+    rng: Option<u64>, // xorshift64 state, None for round robin
+    left: u64,        // instructions left in the running hart's slice
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::round_robin(DEFAULT_QUANTUM)
+    }
+}
+
+impl Scheduler {
+    pub fn round_robin(quantum: u64) -> Self {
+        assert!(quantum > 0, "the quantum has to be positive");
+        Scheduler {
+            quantum,
+            rng: None,
+            left: quantum,
+        }
+    }
+
+    pub fn random(quantum: u64, seed: u64) -> Self {
+        let mut scheduler = Scheduler::round_robin(quantum);
+        // xorshift never leaves 0, so that seed gets another state
+        let mut rng = match seed ^ 0x9e37_79b9_7f4a_7c15 {
+            0 => 0x2545_f491_4f6c_dd1d,
+            rng => rng,
+        };
+        scheduler.left = 1 + next(&mut rng) % quantum;
+        scheduler.rng = Some(rng);
+        scheduler
+    }
+
+    // Hart to run after one more instruction of 'current'
+    pub fn next(&mut self, current: usize, harts: usize) -> usize {
+        self.left -= 1;
+        if self.left > 0 {
+            return current;
+        }
+        match &mut self.rng {
+            Some(rng) => {
+                let hart = next(rng) as usize % harts;
+                self.left = 1 + next(rng) % self.quantum;
+                hart
+            }
+            None => {
+                self.left = self.quantum;
+                (current + 1) % harts
+            }
+        }
+    }
+}
+
+fn next(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(mut scheduler: Scheduler, steps: usize) -> Vec<usize> {
+        let mut hart = 0;
+        (0..steps)
+            .map(|_| {
+                hart = scheduler.next(hart, 3);
+                hart
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let harts = schedule(Scheduler::round_robin(2), 7);
+        assert_eq!(harts, vec![0, 1, 1, 2, 2, 0, 0]);
+    }
+
+    #[test]
+    fn random_schedules_repeat_with_the_seed() {
+        let a = schedule(Scheduler::random(4, 7), 200);
+        assert_eq!(a, schedule(Scheduler::random(4, 7), 200));
+        assert_ne!(a, schedule(Scheduler::random(4, 8), 200));
+        for hart in 0..3 {
+            assert!(a.contains(&hart));
+        }
+        // the seed that would zero the state still shuffles
+        let zero = schedule(Scheduler::random(4, 0x9e37_79b9_7f4a_7c15), 200);
+        for hart in 0..3 {
+            assert!(zero.contains(&hart));
+        }
+    }
+}
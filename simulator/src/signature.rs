@@ -0,0 +1,55 @@
+use crate::memory::Memory;
+use crate::symbols::Symbols;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// Memory between begin_signature and end_signature of a RISC-V architecture
+// test, dumped at exit the way riscof expects from a DUT: one hex line per
+// 'granularity' bytes, most significant byte first
+pub(crate) struct Signature {
+    begin: u32,
+    end: u32,
+    granularity: usize,
+}
+
+impl Signature {
+    pub fn locate(symbols: &Symbols, granularity: usize) -> Result<Self, String> {
+        if granularity == 0 {
+            return Err("Signature granularity must be at least one byte".to_string());
+        }
+        let begin = symbols
+            .lookup("begin_signature")
+            .ok_or("No begin_signature symbol in the binary")?;
+        let end = symbols
+            .lookup("end_signature")
+            .ok_or("No end_signature symbol in the binary")?;
+        if end < begin || !((end - begin) as usize).is_multiple_of(granularity) {
+            return Err(format!(
+                "Signature 0x{:08x}..0x{:08x} is not a whole number of {} byte lines",
+                begin, end, granularity
+            ));
+        }
+        Ok(Signature {
+            begin,
+            end,
+            granularity,
+        })
+    }
+
+    pub fn dump(&self, mem: &Memory, path: &str) -> Result<(), String> {
+        let len = (self.end - self.begin) as usize;
+        let bytes = mem.try_read(self.begin as usize, len).ok_or_else(|| {
+            format!(
+                "Signature 0x{:08x}..0x{:08x} is not mapped",
+                self.begin, self.end
+            )
+        })?;
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut out = BufWriter::new(file);
+        for line in bytes.chunks(self.granularity) {
+            let hex: String = line.iter().rev().map(|b| format!("{:02x}", b)).collect();
+            writeln!(out, "{}", hex).map_err(|e| format!("{}: {}", path, e))?;
+        }
+        out.flush().map_err(|e| format!("{}: {}", path, e))
+    }
+}
@@ -0,0 +1,176 @@
+use std::fs;
+
+const MAGIC: &[u8; 8] = b"RV32SNAP";
+// Bump whenever the layout written by Memory::save_snapshot changes
+const VERSION: u32 = 11;
+
+pub(crate) struct SnapshotWriter {
+    buf: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> Self {
+        let mut writer = SnapshotWriter { buf: vec![] };
+        writer.bytes(MAGIC);
+        writer.u32(VERSION);
+        writer
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.buf.extend_from_slice(val);
+    }
+
+    pub fn str(&mut self, val: &str) {
+        self.u32(val.len() as u32);
+        self.bytes(val.as_bytes());
+    }
+
+    pub fn u32s(&mut self, val: &[u32]) {
+        self.u32(val.len() as u32);
+        for v in val {
+            self.u32(*v);
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        fs::write(path, &self.buf).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+pub(crate) struct SnapshotReader {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl SnapshotReader {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let buf = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut reader = SnapshotReader { buf, pos: 0 };
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(format!("{}: not a snapshot", path));
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(format!(
+                "{}: snapshot version {} is not supported (expected {})",
+                path, version, VERSION
+            ));
+        }
+        Ok(reader)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let mut val = [0u8; 4];
+        val.clone_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(val))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let mut val = [0u8; 8];
+        val.clone_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(val))
+    }
+
+    pub fn str(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|e| e.to_string())
+    }
+
+    pub fn u32s(&mut self) -> Result<Vec<u32>, String> {
+        (0..self.u32()?).map(|_| self.u32()).collect()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&[u8], String> {
+        let end = self.pos + len;
+        let bytes = self
+            .buf
+            .get(self.pos..end)
+            .ok_or_else(|| "Truncated snapshot".to_string())?;
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::memory::Memory;
+
+    fn temp(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn state(machine: &Machine) -> (u32, Vec<u32>) {
+        let mem = machine.mem();
+        (mem.get_pc(), (0..32).map(|i| mem.get_register(i)).collect())
+    }
+
+    #[test]
+    fn restored_and_forked_runs_finish_alike() {
+        // li a7,500; li a0,1; jal 0x1018; sw a0,-4(sp); addi a0,a0,4;
+        // ecall; addi a0,a0,10; ret
+        let code: Vec<u8> = [
+            0x1f400893u32,
+            0x00100513,
+            0x010000ef,
+            0xfea12e23,
+            0x00450513,
+            0x00000073,
+            0x00a50513,
+            0x00008067,
+        ]
+        .iter()
+        .flat_map(|w| w.to_le_bytes())
+        .collect();
+        let mut machine = Machine::new(Memory::with_code(0x1000, &code));
+        for _ in 0..4 {
+            machine.step();
+        }
+        let path = temp("snapshot");
+        machine.mem().save_snapshot(&path).unwrap();
+        let mut fork = machine.fork();
+        assert_eq!(machine.run(), 15);
+        let mut restored = Machine::new(Memory::load_snapshot(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(restored.run(), 15);
+        assert_eq!(fork.run(), 15);
+        assert_eq!(state(&restored), state(&machine));
+        assert_eq!(state(&fork), state(&machine));
+        let sp = machine.mem().get_register(2) as usize;
+        let stored = machine.mem().read(sp - 4, 4).to_vec();
+        assert_eq!(restored.mem().read(sp - 4, 4).to_vec(), stored);
+        assert_eq!(fork.mem().read(sp - 4, 4).to_vec(), stored);
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let path = temp("old-snapshot");
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(VERSION - 1).to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+        let err = SnapshotReader::open(&path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(err.contains(&format!(
+            "snapshot version {} is not supported",
+            VERSION - 1
+        )));
+    }
+}
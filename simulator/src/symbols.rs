@@ -0,0 +1,94 @@
+use elfloader::ElfBinary;
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{Entry, Type};
+
+#[derive(Debug, Clone)]
+pub(crate) struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Symbols {
+    symbols: Vec<Symbol>, // sorted by address
+}
+
+impl Symbols {
+    pub fn new(binary: &ElfBinary) -> Self {
+        let mut symbols = vec![];
+        let data = binary
+            .file
+            .find_section_by_name(".symtab")
+            .and_then(|s| s.get_data(&binary.file).ok());
+        if let Some(SectionData::SymbolTable32(entries)) = data {
+            for entry in entries {
+                match entry.get_type() {
+                    Ok(Type::Func) | Ok(Type::Object) | Ok(Type::NoType) => {}
+                    _ => continue,
+                }
+                let name = entry.get_name(&binary.file).unwrap_or("");
+                if name.is_empty() || entry.shndx() == 0 {
+                    continue;
+                }
+                symbols.push(Symbol {
+                    name: name.to_string(),
+                    addr: entry.value() as u32,
+                    size: entry.size() as u32,
+                });
+            }
+        }
+        symbols.sort_by_key(|s| s.addr);
+        Symbols { symbols }
+    }
+
+    // Symbols without a binary, for tests
+    #[cfg(test)]
+    pub fn from_list(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|s| s.addr);
+        Symbols { symbols }
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<u32> {
+        self.find_by_name(name).map(|s| s.addr)
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    // Symbol containing 'addr' and the offset into it. Sized symbols win,
+    // otherwise falls back to the closest label before 'addr'.
+    pub fn find(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        let before = self.symbols.iter().rev().filter(|s| s.addr <= addr);
+        let sym = before
+            .clone()
+            .find(|s| s.size != 0 && addr - s.addr < s.size)
+            .or_else(|| before.clone().find(|s| s.size == 0))?;
+        Some((sym, addr - sym.addr))
+    }
+
+    pub fn describe(&self, addr: u32) -> String {
+        match self.find(addr) {
+            Some((sym, 0)) => format!("<{}>", sym.name),
+            Some((sym, off)) => format!("<{}+0x{:x}>", sym.name, off),
+            None => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbols_may_end_at_the_top_of_memory() {
+        let symbols = Symbols::from_list(vec![Symbol {
+            name: "top".to_string(),
+            addr: 0xffff_fff0,
+            size: 16,
+        }]);
+        assert_eq!(symbols.describe(0xffff_fffc), "<top+0xc>");
+        assert_eq!(symbols.describe(0xffff_ffef), "");
+    }
+}
@@ -4,11 +4,26 @@ pub(crate) struct Syscall;
 
 impl Syscall {
     pub fn call(mem: &mut Memory, code: i32, args: [i32; 7]) -> i32 {
+        let ret = Syscall::dispatch(mem, code, args);
+        let instret = mem.hart.instret;
+        match mem.session.as_mut().map(|s| s.syscall(instret, code, ret)) {
+            Some(Ok(ret)) => ret,
+            // the guest stops, the debugger goes on
+            Some(Err(err)) => {
+                mem.abort(&format!("{}\n", err));
+                ret
+            }
+            None => ret,
+        }
+    }
+
+    fn dispatch(mem: &mut Memory, code: i32, args: [i32; 7]) -> i32 {
         match code {
             500 => {
                 //exit
                 //println!("EXIT");
-                std::process::exit(args[0]);
+                mem.exit(args[0]);
+                0
             }
             501 => {
                 //print_int
@@ -23,12 +38,35 @@ impl Syscall {
             }
             503 => {
                 //malloc
-                mem.malloc(args[0] as usize, 0) as i32
+                mem.malloc(args[0] as u32 as usize, 0) as i32
             }
             504 => {
                 //free
                 mem.free(args[0] as u32) as i32
             }
+            505 => {
+                //realloc
+                mem.realloc(args[0] as u32, args[1] as u32 as usize) as i32
+            }
+            506 => {
+                //calloc
+                mem.calloc(args[0] as u32 as usize, args[1] as u32 as usize) as i32
+            }
+            507 => {
+                //aligned_alloc
+                let align = args[0] as u32 as usize;
+                if !align.is_power_of_two() {
+                    return 0;
+                }
+                mem.malloc(args[1] as u32 as usize, align) as i32
+            }
+            508 => {
+                //persist, keeps a block out of the leak report
+                match mem.persist(args[0] as u32) {
+                    true => 0,
+                    false => 1,
+                }
+            }
             _ => -1,
         }
     }
@@ -0,0 +1,361 @@
+use crate::disasm::disassemble_word;
+use crate::instruction::Instruction;
+use crate::memory::Memory;
+use crate::mmu;
+use crate::symbols::Symbols;
+use crate::util::*;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+
+const BINARY_MAGIC: &[u8; 8] = b"RV32TRC2";
+
+const FLAG_RD: u8 = 1;
+const FLAG_MEM: u8 = 2;
+const FLAG_STORE: u8 = 4;
+const MODE_SHIFT: u8 = 3; // privilege mode in the flags
+const FLAG_HART: u8 = 32; // a hart other than 0
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct MemEffect {
+    pub addr: u32,
+    pub len: usize,
+    pub store: Option<u32>, // stored value, None for loads
+}
+
+// Architectural effect of one retired instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Commit {
+    pub hart: u32,
+    pub mode: u8, // privilege mode it ran in
+    pub pc: u32,
+    pub raw: u32,
+    pub rd: Option<(u8, u32)>,
+    pub mem: Option<MemEffect>,
+}
+
+impl Commit {
+    // Builds the commit of the instruction at 'pc' from the state after it
+    // ran, before another hart runs
+    pub fn capture(mode: u8, pc: u32, raw: u32, mem: &Memory) -> Self {
+        let rd = Instruction::try_new(raw)
+            .and_then(|inst| inst.dest_register())
+            .map(|rd| (rd, mem.get_register(rd)));
+        let effect = mem.last_access.map(|access| MemEffect {
+            addr: access.addr,
+            len: access.len,
+            store: if access.write {
+                mmu::peek(mem, access.addr, false)
+                    .and_then(|addr| mem.try_read(addr, access.len))
+                    .map(|bytes| {
+                        let mut word = [0u8; 4];
+                        word[..bytes.len()].clone_from_slice(&bytes);
+                        to_u32(&word)
+                    })
+            } else {
+                None
+            },
+        });
+        Commit {
+            hart: mem.hart_id() as u32,
+            mode,
+            pc,
+            raw,
+            rd,
+            mem: effect,
+        }
+    }
+
+    // Spike --log-commits line without the trailing disassembly
+    pub fn spike_line(&self) -> String {
+        let mut line = format!(
+            "core {:>3}: {} 0x{:08x} (0x{:08x})",
+            self.hart, self.mode, self.pc, self.raw
+        );
+        if let Some((rd, val)) = self.rd {
+            line += &format!(" x{:<2} 0x{:08x}", rd, val);
+        }
+        if let Some(effect) = self.mem {
+            line += &format!(" mem 0x{:08x}", effect.addr);
+            if let Some(val) = effect.store {
+                line += &format!(" 0x{:0width$x}", val, width = effect.len * 2);
+            }
+        }
+        line
+    }
+
+    // Parses a Spike commit log line, None for lines that are not commits.
+    // Load sizes are not part of the format, so loads come back with len 0.
+    pub fn parse_spike(line: &str) -> Option<Self> {
+        let line = line.split(';').next()?;
+        let mut parts = line.split_whitespace();
+        if parts.next()? != "core" {
+            return None;
+        }
+        let hart = parts.next()?.trim_end_matches(':').parse().ok()?;
+        let mode = parts.next()?.parse().ok()?;
+        let pc = parse_number(parts.next()?)?;
+        let raw = parse_number(parts.next()?.trim_matches(|c| c == '(' || c == ')'))?;
+        let mut commit = Commit {
+            hart,
+            mode,
+            pc,
+            raw,
+            rd: None,
+            mem: None,
+        };
+        let parts: Vec<&str> = parts.collect();
+        let mut i = 0;
+        while i < parts.len() {
+            if parts[i] == "mem" {
+                let addr = parse_number(parts.get(i + 1)?)?;
+                let value = parts.get(i + 2).filter(|p| p.starts_with("0x"));
+                let effect = match value {
+                    Some(value) => MemEffect {
+                        addr,
+                        len: (value.len() - 2) / 2,
+                        store: Some(parse_number(value)?),
+                    },
+                    None => MemEffect {
+                        addr,
+                        len: 0,
+                        store: None,
+                    },
+                };
+                commit.mem = Some(effect);
+                i += 2 + value.is_some() as usize;
+            } else if let Some(rd) = parts[i].strip_prefix('x').and_then(|r| r.parse().ok()) {
+                commit.rd = Some((rd, parse_number(parts.get(i + 1)?)?));
+                i += 2;
+            } else {
+                // float registers and CSR writes are not compared
+                i += 1;
+            }
+        }
+        Some(commit)
+    }
+
+    fn write_binary(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut flags = self.mode << MODE_SHIFT;
+        if self.hart != 0 {
+            flags |= FLAG_HART;
+        }
+        if self.rd.is_some() {
+            flags |= FLAG_RD;
+        }
+        if let Some(effect) = self.mem {
+            flags |= FLAG_MEM;
+            if effect.store.is_some() {
+                flags |= FLAG_STORE;
+            }
+        }
+        out.write_all(&self.pc.to_le_bytes())?;
+        out.write_all(&self.raw.to_le_bytes())?;
+        out.write_all(&[flags])?;
+        if self.hart != 0 {
+            out.write_all(&self.hart.to_le_bytes())?;
+        }
+        if let Some((rd, val)) = self.rd {
+            out.write_all(&[rd])?;
+            out.write_all(&val.to_le_bytes())?;
+        }
+        if let Some(effect) = self.mem {
+            out.write_all(&effect.addr.to_le_bytes())?;
+            out.write_all(&[effect.len as u8])?;
+            if let Some(val) = effect.store {
+                out.write_all(&val.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_binary(input: &mut dyn Read) -> io::Result<Option<Self>> {
+        let mut head = [0u8; 9];
+        match input.read_exact(&mut head) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let flags = head[8];
+        let mut commit = Commit {
+            hart: 0,
+            mode: flags >> MODE_SHIFT & 0b11,
+            pc: to_u32(&head[0..4]),
+            raw: to_u32(&head[4..8]),
+            rd: None,
+            mem: None,
+        };
+        if flags & FLAG_HART != 0 {
+            let mut buf = [0u8; 4];
+            input.read_exact(&mut buf)?;
+            commit.hart = to_u32(&buf);
+        }
+        if flags & FLAG_RD != 0 {
+            let mut buf = [0u8; 5];
+            input.read_exact(&mut buf)?;
+            commit.rd = Some((buf[0], to_u32(&buf[1..5])));
+        }
+        if flags & FLAG_MEM != 0 {
+            let mut buf = [0u8; 5];
+            input.read_exact(&mut buf)?;
+            let mut effect = MemEffect {
+                addr: to_u32(&buf[0..4]),
+                len: buf[4] as usize,
+                store: None,
+            };
+            if flags & FLAG_STORE != 0 {
+                let mut val = [0u8; 4];
+                input.read_exact(&mut val)?;
+                effect.store = Some(to_u32(&val));
+            }
+            commit.mem = Some(effect);
+        }
+        Ok(Some(commit))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum TraceFormat {
+    Spike,
+    Binary,
+}
+
+pub(crate) struct Tracer {
+    out: BufWriter<File>,
+    format: TraceFormat,
+    ranges: Vec<(u32, u32)>, // [start, end) pc ranges to trace, empty traces all
+    symbols: Symbols,
+}
+
+impl Tracer {
+    pub fn create(path: &str, format: TraceFormat, symbols: Symbols) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        if format == TraceFormat::Binary {
+            out.write_all(BINARY_MAGIC)?;
+        }
+        Ok(Tracer {
+            out,
+            format,
+            ranges: vec![],
+            symbols,
+        })
+    }
+
+    // 'range' is "start:end" or a symbol name covering the whole symbol
+    pub fn add_range(&mut self, range: &str) -> Result<(), String> {
+        let bounds = match range.find(':') {
+            Some(i) => parse_number(&range[..i]).zip(parse_number(&range[i + 1..])),
+            None => self
+                .symbols
+                .find_by_name(range)
+                .map(|s| (s.addr, s.addr.saturating_add(s.size.max(1)))),
+        };
+        let bounds = bounds.ok_or_else(|| format!("Bad trace range '{}'", range))?;
+        self.ranges.push(bounds);
+        Ok(())
+    }
+
+    pub fn commit(&mut self, commit: &Commit) {
+        if !self.ranges.is_empty()
+            && !self
+                .ranges
+                .iter()
+                .any(|(start, end)| commit.pc >= *start && commit.pc < *end)
+        {
+            return;
+        }
+        let res = match self.format {
+            TraceFormat::Spike => {
+                let text = disassemble_word(commit.raw, commit.pc, Some(&self.symbols));
+                writeln!(
+                    self.out,
+                    "{:<72} ; {}",
+                    commit.spike_line(),
+                    text.replace('\t', " ")
+                )
+            }
+            TraceFormat::Binary => commit.write_binary(&mut self.out),
+        };
+        res.expect("Failed to write trace");
+    }
+}
+
+// Converts a binary trace back into the Spike text format
+pub(crate) fn decode_binary(path: &str, symbols: &Symbols) -> io::Result<()> {
+    let mut input = io::BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != BINARY_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a binary trace",
+        ));
+    }
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    while let Some(commit) = Commit::read_binary(&mut input)? {
+        let text = disassemble_word(commit.raw, commit.pc, Some(symbols));
+        writeln!(
+            out,
+            "{:<72} ; {}",
+            commit.spike_line(),
+            text.replace('\t', " ")
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commits() -> Vec<Commit> {
+        let commit = |hart, rd, mem| Commit {
+            hart,
+            mode: 3,
+            pc: 0x8000_0010,
+            raw: 0x00a12623,
+            rd,
+            mem,
+        };
+        let effect = |len, store| {
+            Some(MemEffect {
+                addr: 0x8000_1000,
+                len,
+                store,
+            })
+        };
+        vec![
+            commit(0, None, None),
+            commit(0, Some((10, 0xffff_fff0)), None),
+            commit(0, None, effect(2, Some(0xbeef))),
+            // Spike lines don't have load sizes
+            commit(0, Some((5, 7)), effect(0, None)),
+            commit(3, Some((31, 1)), effect(4, Some(0x1234_5678))),
+        ]
+    }
+
+    #[test]
+    fn spike_lines_parse_back() {
+        for commit in commits().iter() {
+            assert_eq!(Commit::parse_spike(&commit.spike_line()), Some(*commit));
+        }
+        let line = "core   3: 3 0x80000010 (0x00a12623) x31 0x00000001 ; sw a0,12(sp)";
+        assert_eq!(Commit::parse_spike(line).unwrap().hart, 3);
+        assert_eq!(Commit::parse_spike("warning: tohost"), None);
+    }
+
+    #[test]
+    fn binary_commits_read_back() {
+        let mut buf = vec![];
+        for commit in commits().iter() {
+            commit.write_binary(&mut buf).unwrap();
+        }
+        // the hart only takes space when it isn't 0
+        assert_eq!(buf[8] & FLAG_HART, 0);
+        let mut input = &buf[..];
+        for commit in commits().iter() {
+            assert_eq!(Commit::read_binary(&mut input).unwrap(), Some(*commit));
+        }
+        assert_eq!(Commit::read_binary(&mut input).unwrap(), None);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::disasm::disassemble_word;
+use crate::memory::Memory;
+
+// Synchronous exceptions. They go to the guest's trap handler, without one
+// the guest stops the way a process killed by the matching signal would.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Trap {
+    InstructionAddressMisaligned { addr: u32 },
+    InstructionAccessFault { addr: u32 },
+    IllegalInstruction { raw: u32 },
+    Breakpoint { addr: u32 },
+    LoadAccessFault { addr: u32 },
+    StoreAccessFault { addr: u32 },
+    EnvironmentCall { mode: u8 },
+    InstructionPageFault { addr: u32 },
+    LoadPageFault { addr: u32 },
+    StorePageFault { addr: u32 },
+}
+
+const SIGILL: i32 = 4;
+const SIGTRAP: i32 = 5;
+const SIGBUS: i32 = 7;
+const SIGSEGV: i32 = 11;
+
+impl Trap {
+    // mcause exception code
+    pub fn cause(&self) -> u32 {
+        match self {
+            Trap::InstructionAddressMisaligned { .. } => 0,
+            Trap::InstructionAccessFault { .. } => 1,
+            Trap::IllegalInstruction { .. } => 2,
+            Trap::Breakpoint { .. } => 3,
+            Trap::LoadAccessFault { .. } => 5,
+            Trap::StoreAccessFault { .. } => 7,
+            Trap::EnvironmentCall { mode } => 8 + *mode as u32,
+            Trap::InstructionPageFault { .. } => 12,
+            Trap::LoadPageFault { .. } => 13,
+            Trap::StorePageFault { .. } => 15,
+        }
+    }
+
+    // mtval, the faulting address or instruction
+    pub fn value(&self) -> u32 {
+        match *self {
+            Trap::InstructionAddressMisaligned { addr }
+            | Trap::InstructionAccessFault { addr }
+            | Trap::Breakpoint { addr }
+            | Trap::LoadAccessFault { addr }
+            | Trap::StoreAccessFault { addr }
+            | Trap::InstructionPageFault { addr }
+            | Trap::LoadPageFault { addr }
+            | Trap::StorePageFault { addr } => addr,
+            Trap::IllegalInstruction { raw } => raw,
+            Trap::EnvironmentCall { .. } => 0,
+        }
+    }
+
+    // The fault a store would take instead of the load, for AMOs
+    pub fn as_store(self) -> Self {
+        match self {
+            Trap::LoadAccessFault { addr } => Trap::StoreAccessFault { addr },
+            Trap::LoadPageFault { addr } => Trap::StorePageFault { addr },
+            trap => trap,
+        }
+    }
+
+    // Shell style exit code of a process killed by the signal
+    pub fn exit_code(&self) -> i32 {
+        let signal = match self {
+            Trap::InstructionAddressMisaligned { .. } => SIGBUS,
+            Trap::IllegalInstruction { .. } => SIGILL,
+            Trap::Breakpoint { .. } | Trap::EnvironmentCall { .. } => SIGTRAP,
+            _ => SIGSEGV,
+        };
+        128 + signal
+    }
+
+    // The pc still points at the trapping instruction
+    pub fn describe(&self, mem: &Memory) -> String {
+        let pc = mem.get_pc();
+        if let Trap::LoadAccessFault { addr } | Trap::StoreAccessFault { addr } = *self {
+            if let Some((id, below)) = mem.below_stack(addr) {
+                let function = mem.symbols.find(pc).map_or("??", |(sym, _)| &sym.name);
+                let (start, end) = mem.hart_stack(id);
+                let stack = match mem.hart_count() {
+                    1 => "the".to_string(),
+                    _ => format!("hart {}'s", id),
+                };
+                return format!(
+                    "Stack overflow at pc 0x{:08x} in {}: '{}' accessed 0x{:08x}, \
+                     {} bytes below {} {} byte stack (mcause {})",
+                    pc,
+                    function,
+                    disassemble_word(mem.get_instr(), pc, None).replace('\t', " "),
+                    addr,
+                    below,
+                    stack,
+                    end - start,
+                    self.cause()
+                );
+            }
+        }
+        let mut what = match *self {
+            Trap::InstructionAddressMisaligned { addr } => {
+                format!("Misaligned instruction fetch from 0x{:08x}", addr)
+            }
+            Trap::InstructionAccessFault { addr } => {
+                format!("Invalid instruction fetch from 0x{:08x}", addr)
+            }
+            Trap::IllegalInstruction { raw } => format!("Illegal instruction 0x{:08x}", raw),
+            Trap::Breakpoint { .. } => "Breakpoint".to_string(),
+            Trap::EnvironmentCall { mode } => format!("Environment call from mode {}", mode),
+            Trap::InstructionPageFault { addr } => {
+                format!("Page fault on instruction fetch from 0x{:08x}", addr)
+            }
+            Trap::LoadPageFault { addr } | Trap::StorePageFault { addr } => {
+                let raw = mem.get_instr();
+                format!(
+                    "Page fault at 0x{:08x} by '{}'",
+                    addr,
+                    disassemble_word(raw, pc, None).replace('\t', " ")
+                )
+            }
+            Trap::LoadAccessFault { addr } | Trap::StoreAccessFault { addr } => {
+                let raw = mem.get_instr();
+                format!(
+                    "Invalid memory access at 0x{:08x} by '{}'",
+                    addr,
+                    disassemble_word(raw, pc, None).replace('\t', " ")
+                )
+            }
+        };
+        // faults inside a region are permission violations
+        let region = match *self {
+            Trap::InstructionAccessFault { addr }
+            | Trap::LoadAccessFault { addr }
+            | Trap::StoreAccessFault { addr } => mem.describe_region(addr),
+            _ => None,
+        };
+        if let Some(region) = region {
+            what += &format!(" (not permitted in {})", region);
+        }
+        format!("{} at pc 0x{:08x} (mcause {})", what, pc, self.cause())
+    }
+}
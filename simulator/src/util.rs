@@ -7,3 +7,13 @@ pub fn to_u32(buf: &[u8]) -> u32 {
 pub fn from_u32(val: u32) -> [u8; 4] {
     val.to_le_bytes()
 }
+
+pub fn parse_number(text: &str) -> Option<u32> {
+    if let Some(hex) = text.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(neg) = text.strip_prefix('-') {
+        neg.parse::<u32>().ok().map(|v| v.wrapping_neg())
+    } else {
+        text.parse().ok()
+    }
+}
@@ -0,0 +1,97 @@
+# Exercises the RV32I integer and load/store instructions with operand
+# orders and values that expose swapped or sign-confused operands.
+# Exits with 0; the interesting part is the commit trace.
+
+    .text
+    .globl _start
+_start:
+    li      t0, 100
+    li      t1, 7
+    sub     t2, t0, t1          # 93
+    sub     t3, t1, t0          # -93
+    neg     t4, t0
+
+    li      t0, 0x81
+    li      t1, 3
+    sll     t2, t0, t1          # 0x408
+    srl     t3, t0, t1          # 0x10
+    li      t0, -1024
+    sra     t4, t0, t1          # -128
+    srl     t5, t0, t1          # 0x1fffff80
+    li      t1, 35              # only the low five bits count
+    sll     t6, t0, t1
+    slli    a0, t0, 4
+    srli    a1, t0, 28
+    srai    a2, t0, 28
+
+    li      t0, 5
+    seqz    a3, t0              # 0
+    seqz    a4, zero            # 1
+    sltiu   a5, t0, 6           # 1
+    sltiu   a6, t0, -1          # 1, the immediate is sign extended first
+    snez    a7, t0              # 1
+    snez    s2, zero            # 0
+    li      t1, -1
+    sltu    s3, t0, t1          # 1
+    sltu    s4, t1, t0          # 0
+    slt     s5, t1, t0          # 1
+    slti    s6, t1, 0           # 1
+
+    li      t0, 0x7fffffff
+    addi    t1, t0, 1           # wraps to 0x80000000
+    add     t2, t1, t1          # wraps to 0
+    lui     t3, 0xfffff
+    xori    t4, t3, -1
+    ori     t5, t3, 0x7ff
+    andi    t6, t3, -16
+    xor     s7, t3, t0
+    or      s8, t3, t0
+    and     s9, t3, t0
+
+    # loads and stores on the stack, below the 0x80000000 boundary
+    addi    sp, sp, -16
+    li      t0, 0x80ff7f01
+    sw      t0, 0(sp)
+    lb      t1, 0(sp)
+    lb      t2, 3(sp)
+    lbu     t3, 3(sp)
+    lh      t4, 2(sp)
+    lhu     t5, 2(sp)
+    lw      t6, 0(sp)
+    sb      t0, 4(sp)
+    sh      t0, 6(sp)
+    lw      s10, 4(sp)
+    addi    sp, sp, 16
+
+    # data above 0x80000000
+    auipc   s11, 0
+    lw      t0, 0(s11)
+
+    # branches and calls
+    li      t0, -2
+    li      t1, 1
+    blt     t0, t1, 1f
+    ebreak
+1:  bltu    t0, t1, fail
+    bge     t1, t0, 2f
+    ebreak
+2:  bgeu    t0, t1, 3f
+    ebreak
+3:  call    leaf
+    li      s0, 4
+4:  addi    s0, s0, -1
+    bnez    s0, 4b
+    fence
+
+    li      a0, 0
+    li      a7, 500
+    ecall
+
+fail:
+    li      a0, 1
+    li      a7, 500
+    ecall
+
+leaf:
+    addi    a0, a0, 1
+    ret
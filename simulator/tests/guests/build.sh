@@ -0,0 +1,13 @@
+#!/bin/sh
+# Rebuilds the checked-in guest binaries and reference traces.
+# Needs llvm-mc (any LLVM with the RISC-V target) and python3.
+set -e
+cd "$(dirname "$0")"
+LLVM_MC=${LLVM_MC:-llvm-mc}
+for src in *.s; do
+    name=${src%.s}
+    $LLVM_MC -triple=riscv32 -mattr=-relax -filetype=obj "$src" -o "$name.o"
+    python3 link.py -o "$name.elf" "$name.o"
+    rm "$name.o"
+    python3 rv32i_ref.py "$name.elf" > "$name.spike.log"
+done
@@ -0,0 +1,293 @@
+#!/usr/bin/env python3
+# Minimal static linker for RV32 relocatable objects made by llvm-mc.
+# Places executable sections, then data, then bss at the base address and
+# writes an ELF32 executable with one PT_LOAD per group and a symbol table.
+#
+#   link.py -o out.elf [--base 0x80000000] [--entry _start] a.o b.o ...
+
+import argparse
+import struct
+
+SHT_PROGBITS, SHT_SYMTAB, SHT_STRTAB, SHT_RELA, SHT_NOBITS = 1, 2, 3, 4, 8
+SHF_WRITE, SHF_ALLOC, SHF_EXECINSTR = 1, 2, 4
+SHN_UNDEF, SHN_ABS, SHN_COMMON = 0, 0xFFF1, 0xFFF2
+STB_WEAK = 2
+
+
+class Section:
+    def __init__(self, obj, index, name, typ, flags, data, size, align):
+        self.obj, self.index, self.name = obj, index, name
+        self.typ, self.flags, self.data, self.size = typ, flags, data, size
+        self.align = max(align, 1)
+        self.addr = None
+
+
+def read_object(path, objects):
+    blob = open(path, "rb").read()
+    assert blob[:4] == b"\x7fELF" and blob[4] == 1, path + ": not an ELF32 object"
+    shoff, = struct.unpack_from("<I", blob, 0x20)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", blob, 0x2E)
+    headers = [struct.unpack_from("<IIIIIIIIII", blob, shoff + i * shentsize) for i in range(shnum)]
+    shstr = headers[shstrndx]
+
+    def name_at(table, off):
+        start = table[4] + off
+        return blob[start:blob.index(b"\0", start)].decode()
+
+    obj = {"path": path, "sections": {}, "symbols": [], "relas": []}
+    for i, h in enumerate(headers):
+        name = name_at(shstr, h[0])
+        typ, flags, off, size, link, info, align = h[1], h[2], h[4], h[5], h[6], h[7], h[8]
+        if typ in (SHT_PROGBITS, SHT_NOBITS) and flags & SHF_ALLOC:
+            data = blob[off:off + size] if typ == SHT_PROGBITS else b""
+            obj["sections"][i] = Section(obj, i, name, typ, flags, bytearray(data), size, align)
+        elif typ == SHT_SYMTAB:
+            strtab = headers[link]
+            for j in range(size // 16):
+                st_name, value, sym_size, st_info, _, shndx = struct.unpack_from("<IIIBBH", blob, off + j * 16)
+                obj["symbols"].append({
+                    "name": name_at(strtab, st_name), "value": value, "size": sym_size,
+                    "info": st_info, "shndx": shndx,
+                })
+        elif typ == SHT_RELA:
+            for j in range(size // 12):
+                r_off, r_info, addend = struct.unpack_from("<IIi", blob, off + j * 12)
+                obj["relas"].append((info, r_off, r_info >> 8, r_info & 0xFF, addend))
+    objects.append(obj)
+
+
+def layout(objects, base):
+    groups = [[], [], []]  # text, data, bss
+    for obj in objects:
+        for sec in obj["sections"].values():
+            if sec.flags & SHF_EXECINSTR:
+                groups[0].append(sec)
+            elif sec.typ == SHT_NOBITS:
+                groups[2].append(sec)
+            else:
+                groups[1].append(sec)
+    addr = base
+    segments = []
+    for n, group in enumerate(groups):
+        if not group:
+            continue
+        if n == 1 or (n == 2 and not groups[1]):
+            addr = (addr + 0xFFF) & ~0xFFF  # data starts on a fresh page
+        start = addr
+        for sec in group:
+            addr = (addr + sec.align - 1) & ~(sec.align - 1)
+            sec.addr = addr
+            addr += sec.size
+        segments.append((n, start, addr, group))
+    # bss shares the data segment when there is one
+    if len(segments) == 3 or (len(segments) == 2 and segments[-1][0] == 2 and groups[1]):
+        data, bss = segments[-2], segments[-1]
+        segments = segments[:-2] + [(1, data[1], bss[2], data[3] + bss[3])]
+    return segments
+
+
+def resolve(objects):
+    globals_ = {}
+    for obj in objects:
+        obj["values"] = []
+        for sym in obj["symbols"]:
+            shndx = sym["shndx"]
+            if shndx == SHN_ABS:
+                value = sym["value"]
+            elif shndx in obj["sections"]:
+                value = obj["sections"][shndx].addr + sym["value"]
+            else:
+                value = None
+            obj["values"].append(value)
+            if sym["info"] >> 4 != 0 and value is not None:
+                globals_[sym["name"]] = value
+    for obj in objects:
+        for i, sym in enumerate(obj["symbols"]):
+            if obj["values"][i] is None and i != 0:
+                if sym["name"] in globals_:
+                    obj["values"][i] = globals_[sym["name"]]
+                elif sym["info"] >> 4 == STB_WEAK:
+                    obj["values"][i] = 0  # undefined weak symbols are null
+                else:
+                    raise SystemExit("undefined symbol " + sym["name"])
+    return globals_
+
+
+def patch(sec, off, fn):
+    word, = struct.unpack_from("<I", sec.data, off)
+    struct.pack_into("<I", sec.data, off, fn(word) & 0xFFFFFFFF)
+
+
+def i_imm(word, v):
+    return (word & 0x000FFFFF) | ((v & 0xFFF) << 20)
+
+
+def s_imm(word, v):
+    return (word & 0x01FFF07F) | ((v & 0xFE0) << 20) | ((v & 0x1F) << 7)
+
+
+def u_imm(word, v):
+    return (word & 0xFFF) | (v & 0xFFFFF000)
+
+
+def b_imm(word, v):
+    return (word & 0x01FFF07F) | ((v >> 12 & 1) << 31) | ((v >> 5 & 0x3F) << 25) \
+        | ((v >> 1 & 0xF) << 8) | ((v >> 11 & 1) << 7)
+
+
+def j_imm(word, v):
+    return (word & 0xFFF) | ((v >> 20 & 1) << 31) | ((v >> 1 & 0x3FF) << 21) \
+        | ((v >> 11 & 1) << 20) | (v & 0xFF000)
+
+
+def hi20(v):
+    return (v + 0x800) & 0xFFFFF000
+
+
+def relocate(objects):
+    for obj in objects:
+        pcrel = {}
+        relas = sorted(obj["relas"], key=lambda r: r[3] in (24, 25))  # HI20 first
+        for target, off, sym, typ, addend in relas:
+            if target not in obj["sections"]:
+                continue
+            sec = obj["sections"][target]
+            S, P = obj["values"][sym], sec.addr + off
+            if typ == 1:  # R_RISCV_32
+                struct.pack_into("<I", sec.data, off, (S + addend) & 0xFFFFFFFF)
+            elif typ == 16:  # BRANCH
+                patch(sec, off, lambda w: b_imm(w, S + addend - P))
+            elif typ == 17:  # JAL
+                patch(sec, off, lambda w: j_imm(w, S + addend - P))
+            elif typ in (18, 19):  # CALL, CALL_PLT
+                v = S + addend - P
+                patch(sec, off, lambda w: u_imm(w, hi20(v)))
+                patch(sec, off + 4, lambda w: i_imm(w, v))
+            elif typ == 23:  # PCREL_HI20
+                v = S + addend - P
+                pcrel[P] = v
+                patch(sec, off, lambda w: u_imm(w, hi20(v)))
+            elif typ in (24, 25):  # PCREL_LO12_I / _S, S is the auipc
+                v = pcrel[S]
+                patch(sec, off, (lambda w: i_imm(w, v)) if typ == 24 else (lambda w: s_imm(w, v)))
+            elif typ == 26:  # HI20
+                patch(sec, off, lambda w: u_imm(w, hi20(S + addend)))
+            elif typ == 27:  # LO12_I
+                patch(sec, off, lambda w: i_imm(w, S + addend))
+            elif typ == 28:  # LO12_S
+                patch(sec, off, lambda w: s_imm(w, S + addend))
+            elif typ in (35, 39):  # ADD32, SUB32
+                sign = 1 if typ == 35 else -1
+                old, = struct.unpack_from("<I", sec.data, off)
+                struct.pack_into("<I", sec.data, off, (old + sign * (S + addend)) & 0xFFFFFFFF)
+            elif typ in (43, 51):  # ALIGN, RELAX
+                pass
+            else:
+                raise SystemExit("unsupported relocation type %d in %s" % (typ, obj["path"]))
+
+
+def write_elf(path, objects, segments, entry):
+    out = bytearray(52 + 32 * len(segments))
+    phdrs = []
+    for n, start, end, group in segments:
+        off = len(out)
+        filesz = 0
+        for sec in group:
+            if sec.typ == SHT_PROGBITS:
+                pos = off + sec.addr - start
+                out.extend(b"\0" * (pos + len(sec.data) - len(out)))
+                out[pos:pos + len(sec.data)] = sec.data
+                filesz = sec.addr + sec.size - start
+        flags = 4  # R, plus W and X when any section asks for them
+        if any(sec.flags & SHF_WRITE for sec in group):
+            flags |= 2
+        if any(sec.flags & SHF_EXECINSTR for sec in group):
+            flags |= 1
+        phdrs.append((1, off, start, start, filesz, end - start, flags, 0x1000))
+    for i, ph in enumerate(phdrs):
+        struct.pack_into("<IIIIIIII", out, 52 + 32 * i, *ph)
+
+    # section headers: null, allocated sections, symtab, strtab, shstrtab
+    shstr, strtab, symtab = bytearray(b"\0"), bytearray(b"\0"), bytearray(16)
+
+    def add_str(table, text):
+        pos = len(table)
+        table.extend(text.encode() + b"\0")
+        return pos
+
+    placed = []
+    for _, start, _, group in segments:
+        for sec in group:
+            if not any(p.name == sec.name for p in placed):
+                placed.append(sec)
+    shdrs = [(0,) * 10]
+    sec_index = {}
+    for sec in placed:
+        sec_index[sec.name] = len(shdrs)
+        first = [s for s in placed if s.name == sec.name][0]
+        members = [s for seg in segments for s in seg[3] if s.name == sec.name]
+        size = max(s.addr + s.size for s in members) - first.addr
+        file_off = next(ph[1] + first.addr - ph[2] for ph in phdrs if ph[2] <= first.addr <= ph[2] + ph[5])
+        shdrs.append((add_str(shstr, sec.name), sec.typ, sec.flags, first.addr, file_off, size, 0, 0, sec.align, 0))
+
+    locals_, globals_ = [], []
+    for obj in objects:
+        for i, sym in enumerate(obj["symbols"]):
+            typ = sym["info"] & 0xF
+            if i == 0 or typ == 3 or not sym["name"] or sym["name"].startswith(".L"):
+                continue  # null, section symbols and assembler temporaries
+            if sym["shndx"] == SHN_UNDEF:
+                continue
+            shndx = SHN_ABS
+            if sym["shndx"] in obj["sections"]:
+                shndx = sec_index[obj["sections"][sym["shndx"]].name]
+            entry_ = (sym["name"], obj["values"][i], sym["size"], sym["info"], shndx)
+            (globals_ if sym["info"] >> 4 else locals_).append(entry_)
+    for name, value, size, info, shndx in locals_ + globals_:
+        symtab.extend(struct.pack("<IIIBBH", add_str(strtab, name), value, size, info, 0, shndx))
+
+    def append(data, align=4):
+        out.extend(b"\0" * (-len(out) % align))
+        off = len(out)
+        out.extend(data)
+        return off
+
+    symtab_index = len(shdrs)
+    sym_off = append(symtab)
+    shdrs.append((add_str(shstr, ".symtab"), SHT_SYMTAB, 0, 0, sym_off, len(symtab), symtab_index + 1,
+                  1 + len(locals_), 4, 16))
+    str_off = append(strtab, 1)
+    shdrs.append((add_str(shstr, ".strtab"), SHT_STRTAB, 0, 0, str_off, len(strtab), 0, 0, 1, 0))
+    shstr_name = add_str(shstr, ".shstrtab")
+    shstr_off = append(shstr, 1)
+    shdrs.append((shstr_name, SHT_STRTAB, 0, 0, shstr_off, len(shstr), 0, 0, 1, 0))
+    sh_off = append(b"")
+    for sh in shdrs:
+        out.extend(struct.pack("<IIIIIIIIII", *sh))
+
+    ident = b"\x7fELF" + bytes([1, 1, 1, 0]) + b"\0" * 8
+    struct.pack_into("<16sHHIIIIIHHHHHH", out, 0, ident, 2, 243, 1, entry, 52, sh_off, 0,
+                     52, 32, len(phdrs), 40, len(shdrs), len(shdrs) - 1)
+    open(path, "wb").write(out)
+
+
+def main():
+    parser = argparse.ArgumentParser()
+    parser.add_argument("-o", dest="output", required=True)
+    parser.add_argument("--base", default="0x80000000")
+    parser.add_argument("--entry", default="_start")
+    parser.add_argument("objects", nargs="+")
+    args = parser.parse_args()
+    objects = []
+    for path in args.objects:
+        read_object(path, objects)
+    segments = layout(objects, int(args.base, 0))
+    symbols = resolve(objects)
+    relocate(objects)
+    if args.entry not in symbols:
+        raise SystemExit("entry symbol %s not found" % args.entry)
+    write_elf(args.output, objects, segments, symbols[args.entry])
+
+
+if __name__ == "__main__":
+    main()
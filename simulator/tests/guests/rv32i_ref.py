@@ -0,0 +1,120 @@
+#!/usr/bin/env python3
+# Small, independent RV32I model that writes a Spike --log-commits style
+# trace. Used to produce the reference traces for --lockstep where Spike is
+# not available. Runs until the exit syscall (a7 = 500), which is not logged
+# since Spike would trap on it.
+#
+#   rv32i_ref.py guest.elf > guest.spike.log
+
+import struct
+import sys
+
+STACK_TOP = 0x7FFFFFF0  # initial sp of the simulator
+STACK_SIZE = 1 << 22
+MAX_STEPS = 1_000_000
+
+
+def sext(v, bits):
+    v &= (1 << bits) - 1
+    return v - (1 << bits) if v >> (bits - 1) else v
+
+
+class Model:
+    def __init__(self, path):
+        blob = open(path, "rb").read()
+        entry, phoff = struct.unpack_from("<II", blob, 0x18)
+        phnum, = struct.unpack_from("<H", blob, 0x2C)
+        self.regions = [(STACK_TOP - STACK_SIZE, bytearray(STACK_SIZE))]
+        for i in range(phnum):
+            typ, off, vaddr, _, filesz, memsz, _, _ = struct.unpack_from("<IIIIIIII", blob, phoff + 32 * i)
+            if typ == 1:
+                data = bytearray(memsz)
+                data[:filesz] = blob[off:off + filesz]
+                self.regions.append((vaddr, data))
+        self.pc = entry
+        self.x = [0] * 32
+        self.x[2] = STACK_TOP
+
+    def region(self, addr, n):
+        for start, data in self.regions:
+            if start <= addr and addr + n <= start + len(data):
+                return data, addr - start
+        raise SystemExit("bad access 0x%08x at pc 0x%08x" % (addr, self.pc))
+
+    def load(self, addr, n):
+        data, off = self.region(addr, n)
+        return int.from_bytes(data[off:off + n], "little")
+
+    def store(self, addr, n, val):
+        data, off = self.region(addr, n)
+        data[off:off + n] = (val & ((1 << 8 * n) - 1)).to_bytes(n, "little")
+
+    def step(self):
+        pc, x = self.pc, self.x
+        raw = self.load(pc, 4)
+        op, rd, f3 = raw & 0x7F, raw >> 7 & 31, raw >> 12 & 7
+        rs1, rs2, f7 = raw >> 15 & 31, raw >> 20 & 31, raw >> 25
+        a, b = x[rs1], x[rs2]
+        imm_i = sext(raw >> 20, 12)
+        imm_s = sext((raw >> 25) << 5 | rd, 12)
+        imm_b = sext((raw >> 31) << 12 | (raw >> 7 & 1) << 11 | (raw >> 25 & 0x3F) << 5 | (raw >> 8 & 0xF) << 1, 13)
+        imm_j = sext((raw >> 31) << 20 | (raw >> 12 & 0xFF) << 12 | (raw >> 20 & 1) << 11 | (raw >> 21 & 0x3FF) << 1, 21)
+        next_pc, result, mem = pc + 4, None, ""
+        if op == 0x37:
+            result = raw & 0xFFFFF000
+        elif op == 0x17:
+            result = pc + (raw & 0xFFFFF000)
+        elif op == 0x6F:
+            result, next_pc = pc + 4, pc + imm_j
+        elif op == 0x67:
+            result, next_pc = pc + 4, (a + imm_i) & ~1
+        elif op == 0x63:
+            sa, sb = sext(a, 32), sext(b, 32)
+            taken = [a == b, a != b, None, None, sa < sb, sa >= sb, a < b, a >= b][f3]
+            if taken:
+                next_pc = pc + imm_b
+        elif op == 0x03:
+            addr = (a + imm_i) & 0xFFFFFFFF
+            n = [1, 2, 4, None, 1, 2][f3]
+            val = self.load(addr, n)
+            result = sext(val, 8 * n) if f3 < 4 else val
+            mem = " mem 0x%08x" % addr
+        elif op == 0x23:
+            addr = (a + imm_s) & 0xFFFFFFFF
+            n = [1, 2, 4][f3]
+            self.store(addr, n, b)
+            mem = " mem 0x%08x 0x%0*x" % (addr, 2 * n, b & ((1 << 8 * n) - 1))
+        elif op == 0x13:
+            sh = rs2
+            result = [a + imm_i, a << sh, int(sext(a, 32) < imm_i), int(a < (imm_i & 0xFFFFFFFF)),
+                      a ^ imm_i, sext(a, 32) >> sh if f7 else a >> sh, a | imm_i, a & imm_i][f3]
+        elif op == 0x33:
+            sh = b & 31
+            result = [a - b if f7 else a + b, a << sh, int(sext(a, 32) < sext(b, 32)), int(a < b),
+                      a ^ b, sext(a, 32) >> sh if f7 else a >> sh, a | b, a & b][f3]
+        elif op == 0x0F:
+            pass
+        elif raw == 0x73 and x[17] == 500:
+            return None
+        else:
+            raise SystemExit("unsupported instruction 0x%08x at 0x%08x" % (raw, pc))
+        line = "core   0: 3 0x%08x (0x%08x)" % (pc, raw)
+        if result is not None and rd != 0:
+            x[rd] = result & 0xFFFFFFFF
+            line += " x%-2d 0x%08x" % (rd, x[rd])
+        self.pc = next_pc & 0xFFFFFFFF
+        return line + mem
+
+
+def main():
+    model = Model(sys.argv[1])
+    for _ in range(MAX_STEPS):
+        line = model.step()
+        if line is None:
+            return
+        print(line)
+    raise SystemExit("guest did not exit")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,32 @@
+# Laid out like a RISC-V architecture test: results are stored into the
+# region between begin_signature and end_signature, which starts out
+# filled with 0xdeadbeef. The last three words are left untouched.
+
+    .text
+    .globl _start
+_start:
+    la      s0, begin_signature
+    li      t0, 0x12345678
+    sw      t0, 0(s0)
+    li      t1, -5
+    srai    t2, t1, 1
+    sw      t2, 4(s0)
+    li      t3, 0xab
+    sb      t3, 8(s0)           # only the low byte changes
+    lui     t4, 0x80000
+    sw      t4, 12(s0)
+    sh      t1, 16(s0)
+
+    li      a0, 0
+    li      a7, 500
+    ecall
+
+    .data
+    .align  4
+    .globl  begin_signature
+begin_signature:
+    .fill   8, 4, 0xdeadbeef
+    .align  4
+    .globl  end_signature
+end_signature:
+    .word   0
@@ -0,0 +1,81 @@
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// riscv-tests binaries in tests/isa report through tohost, the simulator
+// exits with 0 once every case passed or with the number of the failing one.
+// The rv32um, rv32uf and rv32ud suites join once the simulator has the M, F
+// and D extensions.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn run(name: &str) {
+    let path = Path::new("tests/isa").join(name);
+    assert!(path.exists(), "{} is not vendored in tests/isa", name);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_simulator"))
+        .arg(&path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run the simulator");
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > TIMEOUT {
+            child.kill().unwrap();
+            panic!("{} did not report to tohost within {:?}", name, TIMEOUT);
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    let mut stderr = String::new();
+    child.stderr.unwrap().read_to_string(&mut stderr).unwrap();
+    match status.code() {
+        Some(0) => {}
+        Some(101) => panic!("{} crashed the simulator\n{}", name, stderr),
+        Some(code) if code > 128 => panic!("{} trapped\n{}", name, stderr),
+        Some(case) => panic!("{} failed test case {}\n{}", name, case, stderr),
+        None => panic!("{} was killed\n{}", name, stderr),
+    }
+}
+
+macro_rules! isa_tests {
+    ($($test:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $test() {
+                // rv32ui_p_fence_i runs rv32ui-p-fence_i
+                run(&stringify!($test).replacen('_', "-", 2));
+            }
+        )*
+    };
+}
+
+isa_tests! {
+    rv32ui_p_add, rv32ui_p_addi, rv32ui_p_and, rv32ui_p_andi, rv32ui_p_auipc,
+    rv32ui_p_beq, rv32ui_p_bge, rv32ui_p_bgeu, rv32ui_p_blt, rv32ui_p_bltu,
+    rv32ui_p_bne, rv32ui_p_fence_i, rv32ui_p_jal, rv32ui_p_jalr, rv32ui_p_lb,
+    rv32ui_p_lbu, rv32ui_p_lh, rv32ui_p_lhu, rv32ui_p_lui, rv32ui_p_lw,
+    rv32ui_p_or, rv32ui_p_ori, rv32ui_p_sb, rv32ui_p_sh, rv32ui_p_simple,
+    rv32ui_p_sll, rv32ui_p_slli, rv32ui_p_slt, rv32ui_p_slti, rv32ui_p_sltiu,
+    rv32ui_p_sltu, rv32ui_p_sra, rv32ui_p_srai, rv32ui_p_srl, rv32ui_p_srli,
+    rv32ui_p_sub, rv32ui_p_sw, rv32ui_p_xor, rv32ui_p_xori,
+}
+
+isa_tests! {
+    rv32ua_p_amoadd_w, rv32ua_p_amoand_w, rv32ua_p_amomax_w, rv32ua_p_amomaxu_w,
+    rv32ua_p_amomin_w, rv32ua_p_amominu_w, rv32ua_p_amoor_w, rv32ua_p_amoswap_w,
+    rv32ua_p_amoxor_w, rv32ua_p_lrsc,
+}
+
+isa_tests! {
+    rv32mi_p_breakpoint, rv32mi_p_csr, rv32mi_p_illegal, rv32mi_p_ma_addr,
+    rv32mi_p_ma_fetch, rv32mi_p_mcsr, rv32mi_p_sbreak, rv32mi_p_scall, rv32mi_p_shamt,
+}
+
+isa_tests! {
+    rv32si_p_csr, rv32si_p_dirty, rv32si_p_ma_fetch, rv32si_p_sbreak, rv32si_p_scall,
+    rv32si_p_wfi,
+}
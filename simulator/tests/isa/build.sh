@@ -0,0 +1,22 @@
+#!/bin/sh
+# Rebuilds the riscv-tests style binaries in this directory from the
+# sources in rv32ui/, rv32ua/, rv32mi/ and rv32si/, using the "p"
+# environment in env/. Needs a C preprocessor, llvm-mc with the RISC-V
+# target and python3.
+#
+# The sources follow upstream riscv-tests and use the same macro names and
+# environment: tests start at _start in machine mode, trap_vector reports
+# through tohost. Official rv32*-p-* binaries built with a RISC-V GCC can be
+# dropped in next to these under their upstream names. Suites that need
+# extensions the simulator lacks (M, F, D) aren't built or listed.
+set -e
+cd "$(dirname "$0")"
+CPP=${CPP:-cpp}
+LLVM_MC=${LLVM_MC:-llvm-mc}
+for src in rv32ui/*.S rv32ua/*.S rv32mi/*.S rv32si/*.S; do
+    name=$(dirname "$src")-p-$(basename "$src" .S)
+    $CPP -P -x assembler-with-cpp -I env "$src" > "$name.s"
+    $LLVM_MC -triple=riscv32 -mattr=+a,-relax -filetype=obj "$name.s" -o "$name.o"
+    python3 ../guests/link.py -o "$name" "$name.o"
+    rm "$name.s" "$name.o"
+done
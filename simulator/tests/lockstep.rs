@@ -0,0 +1,36 @@
+use std::fs;
+use std::process::Command;
+
+const GUEST: &str = "tests/guests/alu.elf";
+const REFERENCE: &str = "tests/guests/alu.spike.log";
+
+fn lockstep(reference: &str) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_simulator"))
+        .args(["--lockstep", reference, GUEST])
+        .output()
+        .expect("failed to run the simulator")
+}
+
+#[test]
+fn matches_reference() {
+    let out = lockstep(REFERENCE);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(out.status.success(), "{}", stderr);
+    assert!(stderr.contains("instructions match"), "{}", stderr);
+}
+
+#[test]
+fn reports_first_divergence() {
+    // corrupt the rd value of the first sub
+    let text = fs::read_to_string(REFERENCE).unwrap();
+    let bad = text.replacen("x7  0x0000005d", "x7  0xffffffa3", 1);
+    assert_ne!(text, bad);
+    let path = std::env::temp_dir().join(format!("lockstep-{}.log", std::process::id()));
+    fs::write(&path, bad).unwrap();
+    let out = lockstep(path.to_str().unwrap());
+    fs::remove_file(&path).unwrap();
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert_eq!(out.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("divergence at instruction 3"), "{}", stderr);
+    assert!(stderr.contains("Registers:"), "{}", stderr);
+}
@@ -0,0 +1,55 @@
+use std::fs;
+use std::process::{Command, Output};
+
+const GUEST: &str = "tests/guests/signature.elf";
+
+fn dump(args: &[&str], guest: &str) -> (Output, Option<String>) {
+    let path = std::env::temp_dir().join(format!(
+        "signature-{}-{}.txt",
+        std::process::id(),
+        args.len()
+    ));
+    let out = Command::new(env!("CARGO_BIN_EXE_simulator"))
+        .arg("--signature")
+        .arg(&path)
+        .args(args)
+        .arg(guest)
+        .output()
+        .expect("failed to run the simulator");
+    let text = fs::read_to_string(&path).ok();
+    let _ = fs::remove_file(&path);
+    (out, text)
+}
+
+#[test]
+fn dumps_one_word_per_line() {
+    let (out, text) = dump(&[], GUEST);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let expected = "12345678\nfffffffd\ndeadbeab\n80000000\ndeadfffb\n\
+                    deadbeef\ndeadbeef\ndeadbeef\n";
+    assert_eq!(text.as_deref(), Some(expected));
+}
+
+#[test]
+fn honours_granularity() {
+    let (out, text) = dump(&["--signature-granularity", "8"], GUEST);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let expected = "fffffffd12345678\n80000000deadbeab\ndeadbeefdeadfffb\ndeadbeefdeadbeef\n";
+    assert_eq!(text.as_deref(), Some(expected));
+}
+
+#[test]
+fn needs_signature_symbols() {
+    let (out, text) = dump(&[], "tests/guests/alu.elf");
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("begin_signature"));
+    assert_eq!(text, None);
+}
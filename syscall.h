@@ -9,6 +9,10 @@ __internal_syscall(long n, long _a0, long _a1, long _a2, long _a3, long _a4, lon
 #define PUT_CHAR 502
 #define MALLOC 503
 #define FREE 504
+#define REALLOC 505
+#define CALLOC 506
+#define ALIGNED_ALLOC 507
+#define PERSIST 508
 
 void exit(int code) {
     __internal_syscall(EXIT, code, 0, 0, 0, 0, 0);
@@ -38,6 +42,22 @@ int free(void* ptr) {
     return __internal_syscall(FREE, (long)ptr, 0,0,0,0,0);
 }
 
+void *realloc(void* ptr, long size) {
+    return (void*)__internal_syscall(REALLOC, (long)ptr, size, 0,0,0,0);
+}
+
+void *calloc(long count, long size) {
+    return (void*)__internal_syscall(CALLOC, count, size, 0,0,0,0);
+}
+
+void *aligned_alloc(long align, long size) {
+    return (void*)__internal_syscall(ALIGNED_ALLOC, align, size, 0,0,0,0);
+}
+
+int persist(void* ptr) {
+    return __internal_syscall(PERSIST, (long)ptr, 0,0,0,0,0);
+}
+
 
 
 